@@ -1,4 +1,671 @@
+//! Compiles todo.proto and generates the REST routes of the gateway from the
+//! `google.api.http` annotations of its RPCs, see src/todo/gateway.rs.
+
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use heck::{CamelCase, SnakeCase};
+use prost::Message;
+
+const PROTO: &str = "../proto/todo.proto";
+const STATUS_PROTO: &str = "../proto/google/rpc/status.proto";
+const ERROR_DETAILS_PROTO: &str = "../proto/google/rpc/error_details.proto";
+const INCLUDE: &str = "../proto";
+const PACKAGE: &str = "todo";
+
+/// The parts of descriptor.proto the gateway needs. Unlike `prost_types`,
+/// `MethodOptions` keeps the `google.api.http` extension.
+mod descriptor {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FileDescriptorSet {
+        #[prost(message, repeated, tag = "1")]
+        pub file: Vec<FileDescriptorProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FileDescriptorProto {
+        #[prost(string, tag = "2")]
+        pub package: String,
+        #[prost(message, repeated, tag = "4")]
+        pub message_type: Vec<DescriptorProto>,
+        #[prost(message, repeated, tag = "6")]
+        pub service: Vec<ServiceDescriptorProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DescriptorProto {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(message, repeated, tag = "2")]
+        pub field: Vec<FieldDescriptorProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FieldDescriptorProto {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(int32, tag = "4")]
+        pub label: i32,
+        #[prost(int32, tag = "5")]
+        pub r#type: i32,
+        #[prost(string, tag = "6")]
+        pub type_name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServiceDescriptorProto {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(message, repeated, tag = "2")]
+        pub method: Vec<MethodDescriptorProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MethodDescriptorProto {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub input_type: String,
+        #[prost(string, tag = "3")]
+        pub output_type: String,
+        #[prost(message, optional, tag = "4")]
+        pub options: Option<MethodOptions>,
+        #[prost(bool, tag = "5")]
+        pub client_streaming: bool,
+        #[prost(bool, tag = "6")]
+        pub server_streaming: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MethodOptions {
+        #[prost(message, optional, tag = "72295728")]
+        pub http: Option<HttpRule>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpRule {
+        #[prost(string, tag = "2")]
+        pub get: String,
+        #[prost(string, tag = "3")]
+        pub put: String,
+        #[prost(string, tag = "4")]
+        pub post: String,
+        #[prost(string, tag = "5")]
+        pub delete: String,
+        #[prost(string, tag = "6")]
+        pub patch: String,
+        #[prost(string, tag = "7")]
+        pub body: String,
+        #[prost(string, tag = "12")]
+        pub response_body: String,
+        #[prost(message, repeated, tag = "11")]
+        pub additional_bindings: Vec<HttpRule>,
+    }
+}
+
+use descriptor::{DescriptorProto, FieldDescriptorProto, HttpRule, MethodDescriptorProto};
+
+const TYPE_DOUBLE: i32 = 1;
+const TYPE_FLOAT: i32 = 2;
+const TYPE_BOOL: i32 = 8;
+const TYPE_STRING: i32 = 9;
+const TYPE_MESSAGE: i32 = 11;
+const TYPE_BYTES: i32 = 12;
+const TYPE_ENUM: i32 = 14;
+const LABEL_REPEATED: i32 = 3;
+
+const EMPTY: &str = ".google.protobuf.Empty";
+const TIMESTAMP: &str = ".google.protobuf.Timestamp";
+const STRING_VALUE: &str = ".google.protobuf.StringValue";
+/// The field of mutating requests set from the `Idempotency-Key` header.
+const REQUEST_ID: &str = "request_id";
+/// Lets gRPC clients update some fields of a todo. A PUT replaces them all.
+const UPDATE_MASK: &str = "update_mask";
+
+/// Messages whose JSON form differs between API versions. Their `Versioned`
+/// impls are written by hand in src/todo/version.rs.
+const VERSIONED: &[&str] = &[".todo.Todo", ".todo.Todos", ".todo.UpdateRequest"];
+
+/// Request fields a JSON body must have, as the routes before the gateway
+/// required them. Other request fields default to their zero values.
+const REQUIRED: &[(&str, &str)] = &[
+    (".todo.CreateRequest", "title"),
+    (".todo.CreateRequest", "body"),
+    (".todo.UpdateRequest", "title"),
+    (".todo.UpdateRequest", "body"),
+    (".todo.UpdateRequest", "is_completed"),
+];
+
+/// One HTTP method and path an RPC is served at.
+struct Binding<'a> {
+    service: &'a str,
+    method: &'a MethodDescriptorProto,
+    verb: &'static str,
+    /// Literal segments and the names of the fields bound to the others.
+    segments: Vec<Segment>,
+    body: String,
+    /// Disambiguates the functions of additional bindings.
+    index: usize,
+}
+
+enum Segment {
+    Literal(String),
+    Field(String),
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../proto/todo.proto")?;
+    println!("cargo:rerun-if-changed={}", INCLUDE);
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    let descriptor_path = out_dir.join("todo-descriptor-set");
+    let status = Command::new(prost_build::protoc())
+        .arg("--include_imports")
+        .arg("-I")
+        .arg(INCLUDE)
+        .arg("-I")
+        .arg(prost_build::protoc_include())
+        .arg("-o")
+        .arg(&descriptor_path)
+        .arg(PROTO)
+        .status()?;
+    if !status.success() {
+        return Err(format!("protoc failed with {}", status).into());
+    }
+    let set = descriptor::FileDescriptorSet::decode(&*fs::read(&descriptor_path)?)?;
+
+    let file = set
+        .file
+        .iter()
+        .find(|f| f.package == PACKAGE)
+        .ok_or("todo.proto has no todo package")?;
+    let messages: HashMap<String, &DescriptorProto> = file
+        .message_type
+        .iter()
+        .map(|m| (format!(".{}.{}", PACKAGE, m.name), m))
+        .collect();
+
+    let mut bindings = Vec::new();
+    for service in file.service.iter() {
+        for method in service.method.iter() {
+            let rule = match method.options.as_ref().and_then(|o| o.http.as_ref()) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            if method.client_streaming || method.server_streaming {
+                return Err(
+                    format!("{}: streaming RPCs can not be transcoded", method.name).into(),
+                );
+            }
+            let rules = std::iter::once(rule).chain(rule.additional_bindings.iter());
+            for (index, rule) in rules.enumerate() {
+                bindings.push(binding(&service.name, method, rule, index, &messages)?);
+            }
+        }
+    }
+
+    // Request and response messages are converted from and to JSON with
+    // serde, and documented in the OpenAPI document. Missing fields of a
+    // response are zero values, those of a request only when not required.
+    let requests = reachable(
+        bindings.iter().map(|b| b.method.input_type.as_str()),
+        &messages,
+    )?;
+    let responses = reachable(
+        bindings.iter().map(|b| b.method.output_type.as_str()),
+        &messages,
+    )?;
+    let mut builder = tonic_build::configure();
+    for name in requests.union(&responses) {
+        let response = responses.contains(name);
+        builder = builder.type_attribute(
+            name,
+            if response {
+                "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)]\n#[serde(default)]"
+            } else {
+                "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)]"
+            },
+        );
+        for field in messages[name].field.iter() {
+            let required = REQUIRED.contains(&(name.as_str(), field.name.as_str()));
+            if !response && !required && ![REQUEST_ID, UPDATE_MASK].contains(&field.name.as_str()) {
+                builder = builder
+                    .field_attribute(format!("{}.{}", name, field.name), "#[serde(default)]");
+            }
+            if field.type_name == TIMESTAMP {
+                builder = builder.field_attribute(
+                    format!("{}.{}", name, field.name),
+                    "#[serde(with = \"crate::todo::gateway::timestamp\")]\n\
+                     #[schema(value_type = Option<String>, format = DateTime, default = json!(null))]",
+                );
+            }
+            if [REQUEST_ID, UPDATE_MASK].contains(&field.name.as_str()) {
+                builder =
+                    builder.field_attribute(format!("{}.{}", name, field.name), "#[serde(skip)]");
+            }
+        }
+    }
+    // Errors carry their details as google.rpc messages.
+    builder.compile(&[PROTO, STATUS_PROTO, ERROR_DETAILS_PROTO], &[INCLUDE])?;
+
+    fs::write(out_dir.join("gateway.rs"), generate(&bindings, &messages)?)?;
+
+    Ok(())
+}
+
+fn binding<'a>(
+    service: &'a str,
+    method: &'a MethodDescriptorProto,
+    rule: &HttpRule,
+    index: usize,
+    messages: &HashMap<String, &DescriptorProto>,
+) -> Result<Binding<'a>, String> {
+    let (verb, path) = [
+        ("get", &rule.get),
+        ("put", &rule.put),
+        ("post", &rule.post),
+        ("delete", &rule.delete),
+        ("patch", &rule.patch),
+    ]
+    .iter()
+    .find(|(_, path)| !path.is_empty())
+    .map(|(verb, path)| (*verb, path.as_str()))
+    .ok_or_else(|| format!("{}: unsupported HTTP rule", method.name))?;
+    if !rule.response_body.is_empty() {
+        return Err(format!("{}: response_body is not supported", method.name));
+    }
+
+    let request = request_fields(method, messages)?;
+    let mut segments = Vec::new();
+    for segment in path.trim_start_matches('/').split('/') {
+        match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(name) => {
+                let field = request
+                    .iter()
+                    .find(|f| f.name == name)
+                    .ok_or_else(|| format!("{}: no field {} in the request", method.name, name))?;
+                kind(field).ok_or_else(|| {
+                    format!("{}: {} can not be bound to the path", method.name, name)
+                })?;
+                segments.push(Segment::Field(name.to_string()));
+            }
+            None if segment.contains(|c| "{}*:".contains(c)) => {
+                return Err(format!(
+                    "{}: unsupported path template {}",
+                    method.name, path
+                ));
+            }
+            None => segments.push(Segment::Literal(segment.to_string())),
+        }
+    }
+    if !rule.body.is_empty() && rule.body != "*" && !request.iter().any(|f| f.name == rule.body) {
+        return Err(format!(
+            "{}: no field {} in the request",
+            method.name, rule.body
+        ));
+    }
+
+    Ok(Binding {
+        service,
+        method,
+        verb,
+        segments,
+        body: rule.body.clone(),
+        index,
+    })
+}
+
+fn request_fields<'a>(
+    method: &MethodDescriptorProto,
+    messages: &HashMap<String, &'a DescriptorProto>,
+) -> Result<&'a [FieldDescriptorProto], String> {
+    if method.input_type == EMPTY {
+        return Ok(&[]);
+    }
+    messages
+        .get(&method.input_type)
+        .map(|m| m.field.as_slice())
+        .ok_or_else(|| format!("{}: unsupported request type", method.name))
+}
+
+/// How a path or query parameter is parsed into a field, see
+/// `gateway::Kind`. Only singular scalar fields can be parameters.
+fn kind(field: &FieldDescriptorProto) -> Option<(&'static str, &'static str)> {
+    if field.label == LABEL_REPEATED {
+        return None;
+    }
+    match field.r#type {
+        TYPE_STRING => Some(("Kind::String", "String")),
+        TYPE_BOOL => Some(("Kind::Bool", "bool")),
+        TYPE_DOUBLE | TYPE_FLOAT => Some(("Kind::Float", "f64")),
+        TYPE_MESSAGE | TYPE_BYTES | TYPE_ENUM => None,
+        _ => Some(("Kind::Integer", "i64")),
+    }
+}
+
+/// Returns the messages that are converted to or from JSON along with
+/// `roots`, the requests or the responses of the bindings.
+fn reachable<'a>(
+    roots: impl Iterator<Item = &'a str>,
+    messages: &'a HashMap<String, &DescriptorProto>,
+) -> Result<BTreeSet<String>, String> {
+    let mut reachable = BTreeSet::new();
+    let mut pending: Vec<&str> = roots.collect();
+    while let Some(name) = pending.pop() {
+        if [EMPTY, TIMESTAMP, STRING_VALUE].contains(&name) || reachable.contains(name) {
+            continue;
+        }
+        let message = messages
+            .get(name)
+            .ok_or_else(|| format!("{} can not be transcoded", name))?;
+        reachable.insert(name.to_string());
+        for field in message.field.iter() {
+            if field.r#type == TYPE_MESSAGE {
+                pending.push(&field.type_name);
+            }
+        }
+    }
+    Ok(reachable)
+}
+
+fn rust_type(name: &str) -> String {
+    if name == EMPTY {
+        return "()".to_string();
+    }
+    let name = name.rsplit('.').next().unwrap();
+    format!("pb::{}", name.to_camel_case())
+}
+
+fn generate(
+    bindings: &[Binding],
+    messages: &HashMap<String, &DescriptorProto>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut code = String::new();
+    let names: Vec<String> = bindings
+        .iter()
+        .map(|b| match b.index {
+            0 => b.method.name.to_snake_case(),
+            n => format!("{}_{}", b.method.name.to_snake_case(), n),
+        })
+        .collect();
+
+    writeln!(code, "#[derive(utoipa::OpenApi)]")?;
+    writeln!(code, "#[openapi(paths({}))]", names.join(", "))?;
+    writeln!(code, "pub struct GatewayDoc;")?;
+    writeln!(code)?;
+
+    let unversioned: BTreeSet<&str> = bindings
+        .iter()
+        .flat_map(|b| vec![b.method.input_type.as_str(), b.method.output_type.as_str()])
+        .filter(|name| *name != EMPTY && !VERSIONED.contains(name))
+        .collect();
+    for name in unversioned {
+        writeln!(code, "impl Versioned for {} {{}}", rust_type(name))?;
+    }
+    writeln!(code)?;
+
+    writeln!(
+        code,
+        "pub fn gateway_filter(server: Server) -> \
+         impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {{"
+    )?;
+    for (i, name) in names.iter().enumerate() {
+        let or = if i == 0 { "" } else { ".or" };
+        writeln!(code, "    {}({}(server.clone()))", or, name)?;
+    }
+    writeln!(code, "}}")?;
+
+    for (binding, name) in bindings.iter().zip(names.iter()) {
+        writeln!(code)?;
+        generate_route(&mut code, binding, name, messages)?;
+    }
+
+    Ok(code)
+}
+
+fn generate_route(
+    code: &mut String,
+    binding: &Binding,
+    name: &str,
+    messages: &HashMap<String, &DescriptorProto>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let method = binding.method;
+    let request = request_fields(method, messages)?;
+    let field = |name: &str| request.iter().find(|f| f.name == name).unwrap();
+    let path_fields: Vec<&str> = binding
+        .segments
+        .iter()
+        .filter_map(|s| match s {
+            Segment::Field(name) => Some(name.as_str()),
+            Segment::Literal(_) => None,
+        })
+        .collect();
+    let query_fields: Vec<&FieldDescriptorProto> = if binding.body == "*" {
+        Vec::new()
+    } else {
+        request
+            .iter()
+            .filter(|f| !path_fields.contains(&f.name.as_str()) && f.name != binding.body)
+            .filter(|f| f.name != REQUEST_ID)
+            .filter(|f| kind(f).is_some())
+            .collect()
+    };
+    let path: String = binding
+        .segments
+        .iter()
+        .map(|s| match s {
+            Segment::Literal(literal) => format!("/{}", literal),
+            Segment::Field(name) => format!("/{{{}}}", name),
+        })
+        .collect();
+    let no_content = method.output_type == EMPTY;
+    let idempotent = request.iter().any(|f| f.name == REQUEST_ID);
+
+    // The OpenAPI operation.
+    let mut params: Vec<String> = path_fields
+        .iter()
+        .map(|name| format!("(\"{}\" = {}, Path)", name, kind(field(name)).unwrap().1))
+        .collect();
+    params.extend(
+        query_fields
+            .iter()
+            .map(|f| format!("(\"{}\" = Option<{}>, Query)", f.name, kind(f).unwrap().1)),
+    );
+    if idempotent {
+        params.push(
+            "(\"Idempotency-Key\" = Option<String>, Header, \
+             description = \"Answers a repeated request with the response to the first\")"
+                .to_string(),
+        );
+    }
+    writeln!(code, "#[utoipa::path(")?;
+    writeln!(code, "    {},", binding.verb)?;
+    writeln!(code, "    path = \"{}\",", path)?;
+    writeln!(code, "    tag = \"{}\",", binding.service)?;
+    writeln!(code, "    operation_id = \"{}\",", name)?;
+    writeln!(code, "    params({}),", params.join(", "))?;
+    match binding.body.as_str() {
+        "" => {}
+        "*" => writeln!(
+            code,
+            "    request_body = {},",
+            rust_type(&method.input_type)
+        )?,
+        body => {
+            let body = field(body);
+            if body.r#type == TYPE_MESSAGE {
+                writeln!(code, "    request_body = {},", rust_type(&body.type_name))?;
+            } else {
+                writeln!(code, "    request_body = {},", kind(body).unwrap().1)?;
+            }
+        }
+    }
+    if no_content {
+        writeln!(
+            code,
+            "    responses((status = 204, description = \"{}\")),",
+            method.name
+        )?;
+    } else {
+        writeln!(
+            code,
+            "    responses((status = 200, description = \"{}\", body = {})),",
+            method.name,
+            rust_type(&method.output_type)
+        )?;
+    }
+    writeln!(code, ")]")?;
+
+    // The route.
+    let segments: Vec<String> = binding
+        .segments
+        .iter()
+        .map(|s| match s {
+            Segment::Literal(literal) => format!("{:?}", literal),
+            Segment::Field(_) => "String".to_string(),
+        })
+        .collect();
+    let mut args: Vec<String> = path_fields
+        .iter()
+        .map(|name| format!("{}: String", name.to_snake_case()))
+        .collect();
+    writeln!(
+        code,
+        "fn {}(server: Server) -> \
+         impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {{",
+        name
+    )?;
+    writeln!(code, "    warp::path!({})", segments.join(" / "))?;
+    writeln!(code, "        .and(warp::{}())", binding.verb)?;
+    if !query_fields.is_empty() {
+        writeln!(
+            code,
+            "        .and(warp::query::<HashMap<String, String>>())"
+        )?;
+        args.push("query: HashMap<String, String>".to_string());
+    }
+    if !binding.body.is_empty() {
+        writeln!(code, "        .and(media::body())")?;
+        args.push("body: Body".to_string());
+    }
+    writeln!(
+        code,
+        "        .and(auth::with_actor(server.actors.clone()))"
+    )?;
+    args.push("actor: Option<String>".to_string());
+    if idempotent {
+        writeln!(code, "        .and(routes::idempotency_key())")?;
+        args.push("idempotency_key: Option<String>".to_string());
+    }
+    if !no_content {
+        // Lists, messages with a single repeated field, can also be CSV.
+        let output = &messages[&method.output_type].field;
+        let list = output.len() == 1 && output[0].label == LABEL_REPEATED;
+        let produces = if list {
+            "Media::Json, Media::MsgPack, Media::Csv, Media::Protobuf"
+        } else {
+            "Media::Json, Media::MsgPack, Media::Protobuf"
+        };
+        writeln!(code, "        .and(media::accept(&[{}]))", produces)?;
+        args.push("media: Media".to_string());
+    }
+    writeln!(code, "        .and(routes::with_server(server))")?;
+    args.push("mut server: Server".to_string());
+    writeln!(
+        code,
+        "        .and_then(|{}| async move {{",
+        args.join(", ")
+    )?;
+
+    let fields = if query_fields.is_empty() && path_fields.is_empty() {
+        "fields"
+    } else {
+        "mut fields"
+    };
+    let mut version = "server.version";
+    match binding.body.as_str() {
+        "" => writeln!(code, "            let {} = Map::new();", fields)?,
+        "*" => {
+            writeln!(
+                code,
+                "            let (version, {}) = body_fields::<{}>(&body, server.version)?;",
+                fields,
+                rust_type(&method.input_type)
+            )?;
+            version = "version";
+        }
+        body => {
+            writeln!(code, "            let mut fields = Map::new();")?;
+            writeln!(
+                code,
+                "            fields.insert({:?}.to_string(), body.decode()?);",
+                body
+            )?;
+        }
+    }
+    if !query_fields.is_empty() {
+        let kinds: Vec<String> = query_fields
+            .iter()
+            .map(|f| format!("({:?}, {})", f.name, kind(f).unwrap().0))
+            .collect();
+        writeln!(
+            code,
+            "            query_fields(&mut fields, query, &[{}])?;",
+            kinds.join(", ")
+        )?;
+    }
+    for name in path_fields.iter() {
+        writeln!(
+            code,
+            "            set_field(&mut fields, {:?}, {}, {})?;",
+            name,
+            kind(field(name)).unwrap().0,
+            name.to_snake_case()
+        )?;
+    }
+    writeln!(
+        code,
+        "            let {}message: {} = message({}, fields)?;",
+        if idempotent { "mut " } else { "" },
+        rust_type(&method.input_type),
+        version
+    )?;
+    if idempotent {
+        writeln!(
+            code,
+            "            message.{} = idempotency_key.unwrap_or_default();",
+            REQUEST_ID
+        )?;
+    }
+    writeln!(
+        code,
+        "            {}server.todo_client.{}(handlers::with_actor(message, actor)).await.map_err(|e| {{",
+        if no_content { "" } else { "let resp = " },
+        method.name.to_snake_case()
+    )?;
+    writeln!(
+        code,
+        "                error!(server.logger, {:?}; \"err\" => e.to_string());",
+        name
+    )?;
+    writeln!(code, "                reject::custom(RPCError(e))")?;
+    writeln!(code, "            }})?;")?;
+    if no_content {
+        writeln!(
+            code,
+            "            Ok::<_, warp::Rejection>(StatusCode::NO_CONTENT)"
+        )?;
+    } else {
+        writeln!(
+            code,
+            "            media.reply_message(&resp.into_inner(), server.version)"
+        )?;
+    }
+    writeln!(code, "        }})")?;
+    writeln!(code, "}}")?;
+
     Ok(())
 }
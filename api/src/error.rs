@@ -1,79 +1,468 @@
 use std::convert::Infallible;
+use std::sync::Arc;
 
+use prost::Message;
 use serde_derive::Serialize;
+use serde_json::{json, Value};
 use tonic::{Code, Status};
-use warp::http::StatusCode;
-use warp::{Rejection, Reply};
+use utoipa::ToSchema;
+use warp::http::header::{HeaderValue, CONTENT_TYPE, WWW_AUTHENTICATE};
+use warp::http::{HeaderMap, StatusCode};
+use warp::path::FullPath;
+use warp::{Filter, Rejection, Reply};
 
-#[derive(Serialize)]
-pub struct ErrorResponse {
-    pub status: String,
+use crate::todo::service::rpc;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/google.rpc.";
+
+/// An error as described by RFC 7807.
+#[derive(Serialize, ToSchema)]
+pub struct Problem {
+    /// Names the kind of problem, relative to the api.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// The path of the request that failed.
+    pub instance: String,
+    pub request_id: String,
+    /// The fields of the request that are not valid, if that is what failed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// Other `google.rpc` details of a failed call, each with its `@type`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schema(value_type = Vec<Object>)]
+    pub details: Vec<Value>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
     pub message: String,
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     RPCError(tonic::Status),
+    InvalidImport(String),
+    EncodeError(String),
+    InvalidUpload(String),
+    InvalidRequest(String),
+    NotAcceptable(String),
+    UnsupportedMediaType(String),
+    RangeNotSatisfiable(i64),
+    Unauthenticated(String),
 }
 
 impl warp::reject::Reject for Error {}
 
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+/// A rejection whose problem is answered with `headers`.
+#[derive(Debug)]
+pub struct WithHeaders {
+    pub headers: HeaderMap,
+    pub rejection: Rejection,
+}
+
+impl warp::reject::Reject for WithHeaders {}
+
+/// Answers every request with an `x-request-id`, the one it came with or a
+/// new one, and every rejection of `filter` with a `Problem`.
+pub fn with_problems<F, T>(
+    logger: slog::Logger,
+    filter: F,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    let generator = Arc::new(libxid::new_generator());
+    let request_id = warp::header::headers_cloned().map(move |headers: HeaderMap| {
+        headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LENGTH
+                    && id.bytes().all(|b| b.is_ascii_graphic())
+            })
+            .map(str::to_string)
+            .or_else(|| generator.new_id().ok().map(|id| id.encode()))
+            .unwrap_or_default()
+    });
+    let outcome = filter
+        .map(|reply: T| Ok(reply.into_response()))
+        .or_else(|err| async move { Ok::<_, Infallible>((Err(err),)) });
+
+    request_id.and(warp::path::full()).and(outcome).map(
+        move |request_id: String, path: FullPath, outcome: Result<_, Rejection>| {
+            let mut response = outcome.unwrap_or_else(|err| {
+                let (err, headers) = match err.find::<WithHeaders>() {
+                    Some(with) => (&with.rejection, Some(&with.headers)),
+                    None => (&err, None),
+                };
+                let problem = problem(&logger, err, path.as_str(), &request_id);
+                if problem.status >= 500 {
+                    error!(logger, "request failed"; "request_id" => &request_id, "status" => problem.status, "detail" => &problem.detail);
+                }
+                let code = StatusCode::from_u16(problem.status)
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                let mut response =
+                    warp::reply::with_status(warp::reply::json(&problem), code).into_response();
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(PROBLEM_CONTENT_TYPE),
+                );
+                if code == StatusCode::UNAUTHORIZED {
+                    response
+                        .headers_mut()
+                        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                }
+                if let Some(headers) = headers {
+                    response.headers_mut().extend(headers.clone());
+                }
+                response
+            });
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            response
+        },
+    )
+}
+
+fn problem(logger: &slog::Logger, err: &Rejection, instance: &str, request_id: &str) -> Problem {
     let code;
-    let status;
-    let message;
+    let title;
+    let detail;
+    let mut errors = Vec::new();
+    let mut details = Vec::new();
 
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
-        status = "not found".to_string();
-        message = "not found".to_string();
-    } else if let Some(_) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        title = "Not found";
+        detail = "not found".to_string();
+    } else if err
+        .find::<warp::filters::body::BodyDeserializeError>()
+        .is_some()
+    {
         code = StatusCode::BAD_REQUEST;
-        status = "invalid body".to_string();
-        message = "invalid body".to_string();
+        title = "Invalid body";
+        detail = "invalid body".to_string();
     } else if let Some(e) = err.find::<Error>() {
         match e {
             Error::RPCError(st) => {
-                code = map_status_code(&st);
-                status = "rpc error".to_string();
-                message = st.to_string();
+                let (status_code, status_title) = map_status_code(st);
+                code = status_code;
+                title = status_title;
+                detail = if is_transport_error(st) {
+                    "the todo service is unavailable".to_string()
+                } else {
+                    st.message().to_string()
+                };
+                errors = field_errors(st);
+                details = other_details(st);
+            }
+            Error::InvalidImport(msg) => {
+                code = StatusCode::UNPROCESSABLE_ENTITY;
+                title = "Invalid import";
+                detail = msg.clone();
+            }
+            Error::EncodeError(msg) => {
+                code = StatusCode::INTERNAL_SERVER_ERROR;
+                title = "Encode error";
+                detail = msg.clone();
+            }
+            Error::InvalidUpload(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                title = "Invalid upload";
+                detail = msg.clone();
+            }
+            Error::InvalidRequest(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                title = "Invalid request";
+                detail = msg.clone();
+            }
+            Error::NotAcceptable(msg) => {
+                code = StatusCode::NOT_ACCEPTABLE;
+                title = "Not acceptable";
+                detail = msg.clone();
+            }
+            Error::UnsupportedMediaType(content_type) => {
+                code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                title = "Unsupported media type";
+                detail = format!("{} is not supported", content_type);
+            }
+            Error::RangeNotSatisfiable(size) => {
+                code = StatusCode::RANGE_NOT_SATISFIABLE;
+                title = "Range not satisfiable";
+                detail = format!("range is outside of the attachment of {} bytes", size);
+            }
+            Error::Unauthenticated(msg) => {
+                code = StatusCode::UNAUTHORIZED;
+                title = "Unauthenticated";
+                detail = msg.clone();
             }
         }
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+        title = "Payload too large";
+        detail = "payload too large".to_string();
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         code = StatusCode::METHOD_NOT_ALLOWED;
-        status = "method not allowed".to_string();
-        message = "method not allowed".to_string();
+        title = "Method not allowed";
+        detail = "method not allowed".to_string();
     } else {
-        eprintln!("unhandled error: {:?}", err);
+        error!(logger, "unhandled rejection"; "request_id" => request_id, "rejection" => format!("{:?}", err));
         code = StatusCode::INTERNAL_SERVER_ERROR;
-        status = "internal server error".to_string();
-        message = "internal server error".to_string();
+        title = "Internal server error";
+        detail = "internal server error".to_string();
     }
 
-    let json = warp::reply::json(&ErrorResponse { status, message });
+    Problem {
+        kind: format!("/problems/{}", title.to_lowercase().replace(' ', "-")),
+        title: title.to_string(),
+        status: code.as_u16(),
+        detail,
+        instance: instance.to_string(),
+        request_id: request_id.to_string(),
+        errors,
+        details,
+    }
+}
 
-    Ok(warp::reply::with_status(json, code))
+/// The `google.rpc` details in `st`, which the todo service sends with a
+/// `google.rpc.Status`.
+fn rpc_details(st: &Status) -> Vec<prost_types::Any> {
+    if st.details().is_empty() {
+        return Vec::new();
+    }
+    rpc::Status::decode(st.details())
+        .map(|status| status.details)
+        .unwrap_or_default()
 }
 
-fn map_status_code(st: &Status) -> StatusCode {
+/// The field violations of a `google.rpc.BadRequest` in the details of `st`.
+pub fn field_errors(st: &Status) -> Vec<FieldError> {
+    rpc_details(st)
+        .iter()
+        .filter(|any| any.type_url == format!("{}BadRequest", TYPE_URL_PREFIX))
+        .filter_map(|any| rpc::BadRequest::decode(&*any.value).ok())
+        .flat_map(|bad_request| bad_request.field_violations)
+        .map(|violation| FieldError {
+            field: violation.field,
+            message: violation.description,
+        })
+        .collect()
+}
+
+/// The details of `st` other than field violations, as JSON. Those of an
+/// unknown type keep only their `@type`.
+fn other_details(st: &Status) -> Vec<Value> {
+    rpc_details(st)
+        .into_iter()
+        .filter_map(|any| {
+            let name = any.type_url.strip_prefix(TYPE_URL_PREFIX).unwrap_or("");
+            let detail =
+                match name {
+                    "BadRequest" => return None,
+                    "ErrorInfo" => rpc::ErrorInfo::decode(&*any.value).ok().map(|info| {
+                        json!({
+                            "reason": info.reason,
+                            "domain": info.domain,
+                            "metadata": info.metadata,
+                        })
+                    }),
+                    "PreconditionFailure" => rpc::PreconditionFailure::decode(&*any.value)
+                        .ok()
+                        .map(|failure| {
+                            let violations: Vec<Value> = failure
+                                .violations
+                                .into_iter()
+                                .map(|v| {
+                                    json!({
+                                        "type": v.r#type,
+                                        "subject": v.subject,
+                                        "description": v.description,
+                                    })
+                                })
+                                .collect();
+                            json!({ "violations": violations })
+                        }),
+                    _ => None,
+                };
+
+            let mut detail = detail.unwrap_or_else(|| json!({}));
+            detail["@type"] = any.type_url.into();
+            Some(detail)
+        })
+        .collect()
+}
+
+/// Whether the call failed to reach the todo service, which tonic reports
+/// as `Unknown`.
+fn is_transport_error(st: &Status) -> bool {
+    st.code() == Code::Unknown && st.message().starts_with("transport error")
+}
+
+/// The HTTP status and problem title of a gRPC status code, following
+/// grpc-gateway except for `InvalidArgument` and `FailedPrecondition`.
+fn map_status_code(st: &Status) -> (StatusCode, &'static str) {
+    if is_transport_error(st) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Unavailable");
+    }
     match st.code() {
-        Code::Ok => StatusCode::OK,
-        Code::Cancelled => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::InvalidArgument => StatusCode::UNPROCESSABLE_ENTITY,
-        Code::DeadlineExceeded => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::NotFound => StatusCode::NOT_FOUND,
-        Code::AlreadyExists => StatusCode::CONFLICT,
-        Code::PermissionDenied => StatusCode::FORBIDDEN,
-        Code::ResourceExhausted => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::FailedPrecondition => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::Aborted => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::OutOfRange => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::Unimplemented => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::Internal => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
-        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
-        Code::__NonExhaustive => StatusCode::INTERNAL_SERVER_ERROR,
+        Code::Ok => (StatusCode::OK, "OK"),
+        Code::Cancelled => (
+            StatusCode::from_u16(499).expect("499 is a valid status code"),
+            "Cancelled",
+        ),
+        Code::Unknown => (StatusCode::INTERNAL_SERVER_ERROR, "Unknown error"),
+        Code::InvalidArgument => (StatusCode::UNPROCESSABLE_ENTITY, "Invalid argument"),
+        Code::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, "Deadline exceeded"),
+        Code::NotFound => (StatusCode::NOT_FOUND, "Not found"),
+        Code::AlreadyExists => (StatusCode::CONFLICT, "Already exists"),
+        Code::PermissionDenied => (StatusCode::FORBIDDEN, "Permission denied"),
+        Code::ResourceExhausted => (StatusCode::TOO_MANY_REQUESTS, "Resource exhausted"),
+        Code::FailedPrecondition => (StatusCode::PRECONDITION_FAILED, "Failed precondition"),
+        Code::Aborted => (StatusCode::CONFLICT, "Aborted"),
+        Code::OutOfRange => (StatusCode::BAD_REQUEST, "Out of range"),
+        Code::Unimplemented => (StatusCode::NOT_IMPLEMENTED, "Not implemented"),
+        Code::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+        Code::Unavailable => (StatusCode::SERVICE_UNAVAILABLE, "Unavailable"),
+        Code::DataLoss => (StatusCode::INTERNAL_SERVER_ERROR, "Data loss"),
+        Code::Unauthenticated => (StatusCode::UNAUTHORIZED, "Unauthenticated"),
+        Code::__NonExhaustive => (StatusCode::INTERNAL_SERVER_ERROR, "Unknown error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::Filter;
+
+    use super::*;
+    use crate::test_util::logger;
+    use crate::todo::fake;
+
+    /// The problem a call that failed with `st` is answered with.
+    fn rpc_problem(st: Status) -> Value {
+        let rejection = warp::reject::custom(Error::RPCError(st));
+        serde_json::to_value(problem(&logger(), &rejection, "/todos", "r1")).unwrap()
+    }
+
+    #[test]
+    fn status_codes() {
+        let cases = [
+            (Status::unavailable("down"), 503),
+            (Status::deadline_exceeded("slow"), 504),
+            (Status::resource_exhausted("busy"), 429),
+            (Status::failed_precondition("changed"), 412),
+            (Status::invalid_argument("bad"), 422),
+            (Status::not_found("gone"), 404),
+            (Status::internal("broken"), 500),
+            (Status::unknown("transport error: connection refused"), 503),
+            (Status::unknown("something"), 500),
+        ];
+        for (st, status) in cases.iter() {
+            let (code, _) = map_status_code(st);
+            assert_eq!(code.as_u16(), *status, "{:?}", st);
+        }
+
+        let transport = rpc_problem(Status::unknown("transport error: connection refused"));
+        assert_eq!(transport["status"], 503);
+        assert_eq!(transport["detail"], "the todo service is unavailable");
+    }
+
+    #[test]
+    fn details_are_passed_through() {
+        let info = rpc::ErrorInfo {
+            reason: "TODO_CHANGED".to_string(),
+            domain: "todo".to_string(),
+            metadata: Default::default(),
+        };
+        let failure = rpc::PreconditionFailure {
+            violations: vec![rpc::precondition_failure::Violation {
+                r#type: "UNCHANGED".to_string(),
+                subject: "todos/t1".to_string(),
+                description: "changed".to_string(),
+            }],
+        };
+        let unknown = prost_types::Any {
+            type_url: "type.googleapis.com/google.rpc.RetryInfo".to_string(),
+            value: vec![],
+        };
+        let st = fake::status(
+            Code::FailedPrecondition,
+            "changed",
+            vec![
+                fake::any("ErrorInfo", &info),
+                fake::any("PreconditionFailure", &failure),
+                unknown,
+            ],
+        );
+
+        let problem = rpc_problem(st);
+        assert_eq!(problem["status"], 412);
+        assert_eq!(problem["type"], "/problems/failed-precondition");
+        assert_eq!(problem["instance"], "/todos");
+        assert!(problem.get("errors").is_none());
+        assert_eq!(
+            problem["details"],
+            json!([
+                {
+                    "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                    "reason": "TODO_CHANGED",
+                    "domain": "todo",
+                    "metadata": {},
+                },
+                {
+                    "@type": "type.googleapis.com/google.rpc.PreconditionFailure",
+                    "violations": [{
+                        "type": "UNCHANGED",
+                        "subject": "todos/t1",
+                        "description": "changed",
+                    }],
+                },
+                { "@type": "type.googleapis.com/google.rpc.RetryInfo" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn request_id_is_echoed() {
+        let failing = warp::any().and_then(|| async {
+            Err::<String, _>(warp::reject::custom(Error::RPCError(Status::unavailable(
+                "down",
+            ))))
+        });
+        let api = with_problems(logger(), failing);
+
+        let response = warp::test::request()
+            .path("/todos/t1")
+            .header(REQUEST_ID_HEADER, "req-42")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()[CONTENT_TYPE], PROBLEM_CONTENT_TYPE);
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "req-42");
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["request_id"], "req-42");
+        assert_eq!(body["instance"], "/todos/t1");
+
+        // One that is not a visible ASCII string is replaced.
+        let response = warp::test::request()
+            .path("/todos/t1")
+            .header(REQUEST_ID_HEADER, "a b")
+            .reply(&api)
+            .await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        let generated = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert_ne!(generated, "a b");
+        assert_eq!(body["request_id"], generated);
     }
 }
@@ -10,11 +10,16 @@ use std::str::FromStr;
 use slog::Drain;
 use warp::Filter;
 
-use todo::routes::todo_filter;
+use todo::auth::Actors;
+use todo::graphql::{graphql_filter, schema};
+use todo::openapi::docs_filter;
 use todo::service::todo_service::todo_service_client::TodoServiceClient;
+use todo::version::versioned_filter;
 
 mod error;
 mod settings;
+#[cfg(test)]
+mod test_util;
 mod todo;
 
 #[tokio::main]
@@ -28,19 +33,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let client = match TodoServiceClient::connect(api_settings.todo_addr).await {
         Ok(v) => v,
         Err(err) => {
-            println!("{}", err.to_string());
+            println!("{}", err);
             std::process::exit(1);
         }
     };
 
+    let actors = Actors::parse(&api_settings.auth_tokens)?;
+
     info!(log, "starting";);
 
     let health_route = warp::path("health").map(|| "OK");
-    let todo_filter = todo_filter(log.clone(), client);
+    let schema = schema(
+        log.clone(),
+        client.clone(),
+        api_settings.graphql_max_depth,
+        api_settings.graphql_max_complexity,
+    );
+    let todo_filter = versioned_filter(
+        log.clone(),
+        client,
+        api_settings.max_upload_size,
+        api_settings.unversioned_deprecation,
+        api_settings.unversioned_sunset,
+        actors.clone(),
+    );
+    let routes = health_route
+        .or(todo_filter)
+        .or(docs_filter())
+        .or(graphql_filter(schema, actors));
     let routes =
-        health_route
-            .or(todo_filter)
-            .recover(error::handle_rejection)
+        error::with_problems(log.clone(), routes)
             .with(warp::log::custom(move |info| {
                 info!(log, "handled request"; "method" => info.method().as_str(), "path" => info.path(), "status" => info.status().as_str());
             }));
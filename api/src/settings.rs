@@ -1,3 +1,4 @@
+use chrono::NaiveDate;
 use config::{Config, ConfigError, Environment};
 
 #[derive(Debug, Deserialize, Clone)]
@@ -5,11 +6,27 @@ pub struct Settings {
     pub log_level: String,
     pub port: u16,
     pub todo_addr: String,
+    pub max_upload_size: u64,
+    pub graphql_max_depth: usize,
+    pub graphql_max_complexity: usize,
+    /// Since when the unversioned routes, aliases of /v1, are deprecated.
+    /// Without it they send no `Deprecation` header.
+    pub unversioned_deprecation: Option<NaiveDate>,
+    /// When the unversioned routes are removed. Without it they send no
+    /// `Sunset` header.
+    pub unversioned_sunset: Option<NaiveDate>,
+    /// Comma-separated `actor=token` pairs of the callers that can
+    /// authenticate, see `todo::auth`.
+    pub auth_tokens: String,
 }
 
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         let mut c = Config::default();
+        c.set_default("max_upload_size", 10 * 1024 * 1024)?;
+        c.set_default("graphql_max_depth", 8)?;
+        c.set_default("graphql_max_complexity", 10_000)?;
+        c.set_default("auth_tokens", "")?;
         c.merge(Environment::with_prefix("API"))?;
 
         c.try_into::<Settings>()
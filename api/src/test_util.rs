@@ -0,0 +1,5 @@
+//! Helpers the tests of several modules share.
+
+pub(crate) fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, o!())
+}
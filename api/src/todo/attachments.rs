@@ -0,0 +1,88 @@
+/// Size of the chunks uploads are streamed to the todo service in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+pub const FILE_FIELD: &str = "file";
+
+/// Resolves a `Range` header against a body of `size` bytes into an
+/// inclusive byte range.
+///
+/// Only single byte ranges are supported; anything else yields `Ok(None)`
+/// and the whole body is served, as allowed by RFC 7233. `Err` means the
+/// range can't be satisfied.
+pub fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if range.0 >= size {
+        return Err(());
+    }
+
+    Ok(Some(range))
+}
+
+pub fn content_disposition(filename: &str) -> String {
+    let filename: String = filename
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c == '"' || c == '\\' { '_' } else { c })
+        .collect();
+    format!("attachment; filename=\"{}\"", filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_single_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some((0, 9))));
+        assert_eq!(parse_range(" bytes= 10-20 ", 100), Ok(Some((10, 20))));
+        assert_eq!(parse_range("bytes=90-200", 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range("bytes=42-", 100), Ok(Some((42, 99))));
+        assert_eq!(parse_range("bytes=99-", 100), Ok(Some((99, 99))));
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=100-120", 100), Err(()));
+    }
+
+    #[test]
+    fn resolves_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range("bytes=-100", 100), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=-500", 100), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=-0", 100), Err(()));
+    }
+
+    /// Ranges that can't be parsed are ignored rather than refused, so the
+    /// whole body is served.
+    #[test]
+    fn ignores_unsupported_ranges() {
+        assert_eq!(parse_range("bytes=20-10", 100), Ok(None));
+        assert_eq!(parse_range("bytes=0-9,20-29", 100), Ok(None));
+        assert_eq!(parse_range("bytes=0-9, 200-", 100), Ok(None));
+        assert_eq!(parse_range("items=0-9", 100), Ok(None));
+        assert_eq!(parse_range("bytes=5", 100), Ok(None));
+        assert_eq!(parse_range("bytes=-", 100), Ok(None));
+        assert_eq!(parse_range("bytes=a-b", 100), Ok(None));
+    }
+
+    #[test]
+    fn refuses_any_range_of_an_empty_attachment() {
+        assert_eq!(parse_range("bytes=0-0", 0), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+        assert_eq!(parse_range("bytes=-1", 0), Err(()));
+        assert_eq!(parse_range("bytes=-500", 0), Err(()));
+    }
+}
@@ -0,0 +1,126 @@
+//! Who makes a request. Callers are known by the bearer tokens configured in
+//! `API_AUTH_TOKENS`, and the actor of a token is passed on to the todo
+//! service, which records it in the history and as the author of comments.
+//! A request without a token is anonymous.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tonic::metadata::MetadataValue;
+use warp::{reject, Filter};
+
+use crate::error::Error::Unauthenticated;
+
+/// The longest actor the todo service accepts, in characters.
+const MAX_ACTOR_LENGTH: usize = 255;
+/// What the todo service calls a caller without an actor.
+const ANONYMOUS: &str = "anonymous";
+const BEARER: &str = "bearer ";
+
+/// The actors by the SHA-256 digests of their tokens, so that looking one up
+/// takes no longer for a token that shares a prefix with a known one.
+#[derive(Clone, Default)]
+pub struct Actors(Arc<HashMap<Vec<u8>, String>>);
+
+impl Actors {
+    /// Reads comma-separated `actor=token` pairs.
+    pub fn parse(pairs: &str) -> Result<Actors, String> {
+        let mut actors = HashMap::new();
+        for pair in pairs.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (actor, token) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not an actor=token pair", pair))?;
+            let (actor, token) = (actor.trim(), token.trim());
+            if actor.is_empty() || token.is_empty() {
+                return Err(format!("{} is not an actor=token pair", pair));
+            }
+            if actor.chars().count() > MAX_ACTOR_LENGTH {
+                return Err(format!(
+                    "actor {} is longer than {} characters",
+                    actor, MAX_ACTOR_LENGTH
+                ));
+            }
+            if actor.eq_ignore_ascii_case(ANONYMOUS) || MetadataValue::from_str(actor).is_err() {
+                return Err(format!("{} can not be an actor", actor));
+            }
+            if actors.insert(digest(token), actor.to_string()).is_some() {
+                return Err(format!("the token of {} is used twice", actor));
+            }
+        }
+        Ok(Actors(Arc::new(actors)))
+    }
+
+    /// The actor of an `Authorization` header, `None` when it is missing.
+    fn authenticate(&self, authorization: Option<String>) -> Result<Option<String>, String> {
+        let authorization = match authorization {
+            Some(authorization) => authorization,
+            None => return Ok(None),
+        };
+        let token = authorization
+            .get(..BEARER.len())
+            .filter(|scheme| scheme.eq_ignore_ascii_case(BEARER))
+            .map(|_| authorization[BEARER.len()..].trim())
+            .ok_or_else(|| "only bearer tokens are accepted".to_string())?;
+        self.0
+            .get(&digest(token))
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| "the token is not known".to_string())
+    }
+}
+
+fn digest(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// The actor of a request, rejecting one whose token is not known.
+pub(crate) fn with_actor(
+    actors: Actors,
+) -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |authorization| {
+        let actors = actors.clone();
+        async move {
+            actors
+                .authenticate(authorization)
+                .map_err(|e| reject::custom(Unauthenticated(e)))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticates_by_token() {
+        let actors = Actors::parse("alice=s3cret, bob = b0b=").unwrap();
+
+        assert_eq!(
+            actors.authenticate(Some("Bearer s3cret".to_string())),
+            Ok(Some("alice".to_string()))
+        );
+        assert_eq!(
+            actors.authenticate(Some("bearer b0b=".to_string())),
+            Ok(Some("bob".to_string()))
+        );
+        assert_eq!(actors.authenticate(None), Ok(None));
+        assert!(actors
+            .authenticate(Some("Bearer alice".to_string()))
+            .is_err());
+        assert!(actors
+            .authenticate(Some("Basic YWxpY2U6czNjcmV0".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_actors() {
+        assert!(Actors::parse("").is_ok());
+        assert!(Actors::parse("alice").is_err());
+        assert!(Actors::parse("=s3cret").is_err());
+        assert!(Actors::parse("anonymous=s3cret").is_err());
+        assert!(Actors::parse("alice=s3cret,bob=s3cret").is_err());
+        assert!(Actors::parse(&format!("{}=s3cret", "a".repeat(MAX_ACTOR_LENGTH))).is_ok());
+        assert!(Actors::parse(&format!("{}=s3cret", "a".repeat(MAX_ACTOR_LENGTH + 1))).is_err());
+    }
+}
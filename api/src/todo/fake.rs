@@ -0,0 +1,456 @@
+//! A todo service that keeps todos in memory, for testing the gateway
+//! without the real one. RPCs the tests do not need are unimplemented.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDate;
+use prost::Message;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+use warp::Filter;
+
+use crate::error;
+use crate::test_util::logger;
+use crate::todo::auth::Actors;
+use crate::todo::service::rpc;
+use crate::todo::service::todo_service as pb;
+use crate::todo::service::todo_service::todo_service_client::TodoServiceClient;
+use crate::todo::service::todo_service::todo_service_server::{TodoService, TodoServiceServer};
+use crate::todo::service::todo_service::upload_attachment_request::Payload;
+use crate::todo::version::versioned_filter;
+
+/// The time of every todo, 2020-09-13T12:26:40Z.
+pub(crate) const TIME: i64 = 1_600_000_000;
+
+#[derive(Default)]
+pub(crate) struct State {
+    pub todos: BTreeMap<String, pb::Todo>,
+    /// The RPCs called, with the actor of each.
+    pub calls: Vec<(String, String)>,
+    /// The `request_id` of every mutation.
+    pub request_ids: Vec<String>,
+    /// The sizes of the chunks of every upload.
+    pub uploads: Vec<Vec<usize>>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct FakeTodoService {
+    pub state: Arc<Mutex<State>>,
+}
+
+impl FakeTodoService {
+    /// Adds a todo with the id `id`.
+    pub fn with_todo(self, id: &str, title: &str, is_completed: bool) -> FakeTodoService {
+        self.state.lock().unwrap().todos.insert(
+            id.to_string(),
+            pb::Todo {
+                id: id.to_string(),
+                title: title.to_string(),
+                body: "body".to_string(),
+                is_completed,
+                created_at: Some(timestamp()),
+                updated_at: Some(timestamp()),
+                comment_count: 0,
+            },
+        );
+        self
+    }
+
+    /// The names of the RPCs called so far.
+    pub fn calls(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.calls.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn request_ids(&self) -> Vec<String> {
+        self.state.lock().unwrap().request_ids.clone()
+    }
+
+    pub fn todo(&self, id: &str) -> Option<pb::Todo> {
+        self.state.lock().unwrap().todos.get(id).cloned()
+    }
+
+    fn call<T>(&self, name: &str, request: &Request<T>) -> std::sync::MutexGuard<'_, State> {
+        let actor = request
+            .metadata()
+            .get("x-actor")
+            .and_then(|actor| actor.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let mut state = self.state.lock().unwrap();
+        state.calls.push((name.to_string(), actor));
+        state
+    }
+}
+
+fn timestamp() -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: TIME,
+        nanos: 0,
+    }
+}
+
+fn not_found(id: &str) -> Status {
+    Status::not_found(format!("todo {} not found", id))
+}
+
+/// What the todo service answers for a field that is not valid.
+fn invalid_argument(field: &str, description: &str) -> Status {
+    let bad_request = rpc::BadRequest {
+        field_violations: vec![rpc::bad_request::FieldViolation {
+            field: field.to_string(),
+            description: description.to_string(),
+        }],
+    };
+    status(
+        tonic::Code::InvalidArgument,
+        &format!("{} {}", field, description),
+        vec![any("BadRequest", &bad_request)],
+    )
+}
+
+/// A status with `google.rpc` details, as the todo service sends them.
+pub(crate) fn status(code: tonic::Code, message: &str, details: Vec<prost_types::Any>) -> Status {
+    let status = rpc::Status {
+        code: code as i32,
+        message: message.to_string(),
+        details,
+    };
+    Status::with_details(code, message, encode(&status).into())
+}
+
+/// `message` as the detail `google.rpc.<name>`.
+pub(crate) fn any<T: Message>(name: &str, message: &T) -> prost_types::Any {
+    prost_types::Any {
+        type_url: format!("type.googleapis.com/google.rpc.{}", name),
+        value: encode(message),
+    }
+}
+
+fn encode<T: Message>(message: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    message.encode(&mut buf).unwrap();
+    buf
+}
+
+/// Serves `service` on a free port and returns a client of it.
+pub(crate) async fn serve(service: FakeTodoService) -> TodoServiceClient<Channel> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(TodoServiceServer::new(service))
+            .serve_with_incoming(listener),
+    );
+    TodoServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap()
+}
+
+/// The REST API as main.rs serves it, in front of `client`. The token
+/// `s3cret` is alice's.
+pub(crate) fn rest(
+    client: TodoServiceClient<Channel>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = std::convert::Infallible> + Clone {
+    let logger = logger();
+    let filter = versioned_filter(
+        logger.clone(),
+        client,
+        1024 * 1024,
+        NaiveDate::from_ymd_opt(2026, 10, 19),
+        NaiveDate::from_ymd_opt(2027, 4, 19),
+        Actors::parse("alice=s3cret").unwrap(),
+    );
+    error::with_problems(logger, filter)
+}
+
+#[tonic::async_trait]
+impl TodoService for FakeTodoService {
+    async fn list(&self, request: Request<pb::ListRequest>) -> Result<Response<pb::Todos>, Status> {
+        let state = self.call("list", &request);
+        Ok(Response::new(pb::Todos {
+            todos: state.todos.values().cloned().collect(),
+        }))
+    }
+
+    async fn create(
+        &self,
+        request: Request<pb::CreateRequest>,
+    ) -> Result<Response<pb::Todo>, Status> {
+        let mut state = self.call("create", &request);
+        let request = request.into_inner();
+        state.request_ids.push(request.request_id.clone());
+        if request.title.trim().is_empty() {
+            return Err(invalid_argument("title", "must not be empty"));
+        }
+        let todo = pb::Todo {
+            id: format!("t{}", state.todos.len() + 1),
+            title: request.title,
+            body: request.body,
+            is_completed: false,
+            created_at: Some(timestamp()),
+            updated_at: Some(timestamp()),
+            comment_count: 0,
+        };
+        state.todos.insert(todo.id.clone(), todo.clone());
+        Ok(Response::new(todo))
+    }
+
+    async fn get_by_id(&self, request: Request<pb::TodoId>) -> Result<Response<pb::Todo>, Status> {
+        let state = self.call("get_by_id", &request);
+        let id = &request.get_ref().id;
+        state
+            .todos
+            .get(id)
+            .cloned()
+            .map(Response::new)
+            .ok_or_else(|| not_found(id))
+    }
+
+    async fn update(
+        &self,
+        request: Request<pb::UpdateRequest>,
+    ) -> Result<Response<pb::Todo>, Status> {
+        let mut state = self.call("update", &request);
+        let request = request.into_inner();
+        state.request_ids.push(request.request_id.clone());
+        let todo = state
+            .todos
+            .get_mut(&request.id)
+            .ok_or_else(|| not_found(&request.id))?;
+        let mask = &request.update_mask;
+        let changes = |field: &str| mask.is_empty() || mask.iter().any(|f| f == field);
+        if changes("title") {
+            todo.title = request.title.clone();
+        }
+        if changes("body") {
+            todo.body = request.body.clone();
+        }
+        if changes("is_completed") {
+            todo.is_completed = request.is_completed;
+        }
+        Ok(Response::new(todo.clone()))
+    }
+
+    async fn delete(&self, request: Request<pb::DeleteRequest>) -> Result<Response<()>, Status> {
+        let mut state = self.call("delete", &request);
+        state.request_ids.push(request.get_ref().request_id.clone());
+        let id = &request.get_ref().id;
+        state.todos.remove(id).ok_or_else(|| not_found(id))?;
+        Ok(Response::new(()))
+    }
+
+    async fn complete(
+        &self,
+        request: Request<pb::CompleteRequest>,
+    ) -> Result<Response<pb::Todo>, Status> {
+        let mut state = self.call("complete", &request);
+        state.request_ids.push(request.get_ref().request_id.clone());
+        let id = &request.get_ref().id;
+        let todo = state.todos.get_mut(id).ok_or_else(|| not_found(id))?;
+        todo.is_completed = true;
+        Ok(Response::new(todo.clone()))
+    }
+
+    async fn export(
+        &self,
+        request: Request<pb::ExportRequest>,
+    ) -> Result<Response<pb::Todos>, Status> {
+        let state = self.call("export", &request);
+        Ok(Response::new(pb::Todos {
+            todos: state.todos.values().cloned().collect(),
+        }))
+    }
+
+    async fn import(
+        &self,
+        request: Request<pb::ImportRequest>,
+    ) -> Result<Response<pb::Todos>, Status> {
+        let mut state = self.call("import", &request);
+        let request = request.into_inner();
+        let mut todos = Vec::new();
+        for mut todo in request.todos {
+            if todo.id.is_empty() {
+                todo.id = format!("t{}", state.todos.len() + todos.len() + 1);
+            }
+            todo.created_at = todo.created_at.or_else(|| Some(timestamp()));
+            todo.updated_at = todo.updated_at.or_else(|| Some(timestamp()));
+            todos.push(todo);
+        }
+        if !request.dry_run {
+            for todo in todos.iter() {
+                state.todos.insert(todo.id.clone(), todo.clone());
+            }
+        }
+        Ok(Response::new(pb::Todos { todos }))
+    }
+
+    async fn export_todo_txt(
+        &self,
+        _: Request<pb::ExportRequest>,
+    ) -> Result<Response<pb::TodoTxt>, Status> {
+        Err(Status::unimplemented("export_todo_txt"))
+    }
+
+    async fn import_todo_txt(
+        &self,
+        _: Request<pb::ImportTodoTxtRequest>,
+    ) -> Result<Response<pb::Todos>, Status> {
+        Err(Status::unimplemented("import_todo_txt"))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<pb::HistoryRequest>,
+    ) -> Result<Response<pb::History>, Status> {
+        let _state = self.call("get_history", &request);
+        Ok(Response::new(pb::History { entries: vec![] }))
+    }
+
+    async fn undo(
+        &self,
+        request: Request<pb::UndoRequest>,
+    ) -> Result<Response<pb::History>, Status> {
+        let _state = self.call("undo", &request);
+        Ok(Response::new(history("undo", request.get_ref().count)))
+    }
+
+    async fn redo(
+        &self,
+        request: Request<pb::UndoRequest>,
+    ) -> Result<Response<pb::History>, Status> {
+        let _state = self.call("redo", &request);
+        Ok(Response::new(history("redo", request.get_ref().count)))
+    }
+
+    async fn add_comment(
+        &self,
+        _: Request<pb::AddCommentRequest>,
+    ) -> Result<Response<pb::Comment>, Status> {
+        Err(Status::unimplemented("add_comment"))
+    }
+
+    async fn list_comments(
+        &self,
+        request: Request<pb::TodoId>,
+    ) -> Result<Response<pb::Comments>, Status> {
+        let _state = self.call("list_comments", &request);
+        Ok(Response::new(pb::Comments { comments: vec![] }))
+    }
+
+    async fn edit_comment(
+        &self,
+        _: Request<pb::EditCommentRequest>,
+    ) -> Result<Response<pb::Comment>, Status> {
+        Err(Status::unimplemented("edit_comment"))
+    }
+
+    async fn delete_comment(
+        &self,
+        request: Request<pb::CommentId>,
+    ) -> Result<Response<()>, Status> {
+        let _state = self.call("delete_comment", &request);
+        Ok(Response::new(()))
+    }
+
+    async fn upload_attachment(
+        &self,
+        request: Request<tonic::Streaming<pb::UploadAttachmentRequest>>,
+    ) -> Result<Response<pb::Attachment>, Status> {
+        drop(self.call("upload_attachment", &request));
+        let mut stream = request.into_inner();
+        let info = match stream.message().await? {
+            Some(pb::UploadAttachmentRequest {
+                payload: Some(Payload::Info(info)),
+            }) => info,
+            _ => return Err(Status::invalid_argument("expected the attachment info")),
+        };
+        let mut chunks = Vec::new();
+        while let Some(message) = stream.message().await? {
+            match message.payload {
+                Some(Payload::Chunk(chunk)) => chunks.push(chunk.len()),
+                _ => return Err(Status::invalid_argument("expected attachment content")),
+            }
+        }
+        let size = chunks.iter().sum::<usize>() as i64;
+        self.state.lock().unwrap().uploads.push(chunks);
+        Ok(Response::new(pb::Attachment {
+            id: "a1".to_string(),
+            todo_id: info.todo_id,
+            filename: info.filename,
+            content_type: info.content_type,
+            size,
+            created_at: Some(timestamp()),
+        }))
+    }
+
+    async fn list_attachments(
+        &self,
+        request: Request<pb::TodoId>,
+    ) -> Result<Response<pb::Attachments>, Status> {
+        let _state = self.call("list_attachments", &request);
+        Ok(Response::new(pb::Attachments {
+            attachments: vec![pb::Attachment {
+                id: "a1".to_string(),
+                todo_id: request.get_ref().id.clone(),
+                filename: "notes.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                size: 42,
+                created_at: Some(timestamp()),
+            }],
+        }))
+    }
+
+    async fn get_attachment(
+        &self,
+        _: Request<pb::AttachmentId>,
+    ) -> Result<Response<pb::Attachment>, Status> {
+        Err(Status::unimplemented("get_attachment"))
+    }
+
+    type DownloadAttachmentStream = mpsc::Receiver<Result<pb::AttachmentChunk, Status>>;
+
+    async fn download_attachment(
+        &self,
+        _: Request<pb::DownloadAttachmentRequest>,
+    ) -> Result<Response<Self::DownloadAttachmentStream>, Status> {
+        Err(Status::unimplemented("download_attachment"))
+    }
+
+    async fn delete_attachment(
+        &self,
+        request: Request<pb::AttachmentId>,
+    ) -> Result<Response<()>, Status> {
+        let _state = self.call("delete_attachment", &request);
+        Ok(Response::new(()))
+    }
+
+    type WatchStream = mpsc::Receiver<Result<pb::Event, Status>>;
+
+    async fn watch(
+        &self,
+        _: Request<pb::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        Err(Status::unimplemented("watch"))
+    }
+}
+
+/// `count` reverted changes.
+fn history(operation: &str, count: i64) -> pb::History {
+    pb::History {
+        entries: (1..=count)
+            .map(|id| pb::HistoryEntry {
+                id,
+                todo_id: "t1".to_string(),
+                actor: "alice".to_string(),
+                operation: operation.to_string(),
+                changes: vec![],
+                created_at: Some(timestamp()),
+                undo_state: String::new(),
+            })
+            .collect(),
+    }
+}
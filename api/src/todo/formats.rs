@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+
+use csv::StringRecord;
+use serde_json::Value;
+
+use crate::todo::models::{Format, ImportTodo, Todo};
+use crate::todo::version::{self, Version, Versioned};
+
+const TODO_TXT_UNSUPPORTED: &str = "todo.txt is handled by the todo service";
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::TodoTxt => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// The format of a body of `content_type`, ignoring its parameters.
+    pub fn from_content_type(content_type: &str) -> Option<Format> {
+        match content_type.split(';').next()?.trim() {
+            "application/json" => Some(Format::Json),
+            "text/csv" => Some(Format::Csv),
+            "text/plain" => Some(Format::TodoTxt),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::TodoTxt => "txt",
+        }
+    }
+}
+
+/// The columns of a todo in CSV, in order. v2 has `status` in place of
+/// `is_completed`.
+const CSV_COLUMNS: [&str; 7] = [
+    "id",
+    "title",
+    "body",
+    "is_completed",
+    "created_at",
+    "updated_at",
+    "comment_count",
+];
+
+fn csv_column(column: &'static str, version: Version) -> &'static str {
+    if column == "is_completed" && version >= Version::V2 {
+        "status"
+    } else {
+        column
+    }
+}
+
+/// Encodes todos as they are represented in `version`.
+pub fn encode(format: Format, todos: &[Todo], version: Version) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Json => {
+            let todos: Vec<Value> = todos.iter().map(|todo| todo.to_json(version)).collect();
+            serde_json::to_vec(&todos).map_err(|e| e.to_string())
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            let columns: Vec<_> = CSV_COLUMNS.iter().map(|c| csv_column(c, version)).collect();
+            writer.write_record(&columns).map_err(|e| e.to_string())?;
+            for todo in todos {
+                let todo = todo.to_json(version);
+                let record = columns.iter().map(|column| match &todo[*column] {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                });
+                writer.write_record(record).map_err(|e| e.to_string())?;
+            }
+            writer.into_inner().map_err(|e| e.to_string())
+        }
+        Format::TodoTxt => Err(TODO_TXT_UNSUPPORTED.to_string()),
+    }
+}
+
+/// Decodes todos represented in `version`.
+pub fn decode(format: Format, data: &[u8], version: Version) -> Result<Vec<ImportTodo>, String> {
+    match format {
+        Format::Json => {
+            let todos: Vec<Value> = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+            todos
+                .into_iter()
+                .enumerate()
+                .map(|(i, todo)| {
+                    version
+                        .decode(todo)
+                        .map_err(|e| format!("todo {}: {}", i + 1, e))
+                })
+                .collect()
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(data);
+            let mut headers = reader.headers().map_err(|e| e.to_string())?.clone();
+            let status = headers
+                .iter()
+                .position(|header| header == "status")
+                .filter(|_| version >= Version::V2);
+            if let Some(status) = status {
+                headers = headers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, header)| if i == status { "is_completed" } else { header })
+                    .collect();
+            }
+            reader
+                .records()
+                .enumerate()
+                .map(|(i, record)| {
+                    let mut record = record.map_err(|e| format!("record {}: {}", i + 1, e))?;
+                    if let Some(status) = status {
+                        record = status_to_completed(&record, status)
+                            .map_err(|e| format!("record {}: {}", i + 1, e))?;
+                    }
+                    record
+                        .deserialize(Some(&headers))
+                        .map_err(|e| format!("record {}: {}", i + 1, e))
+                })
+                .collect()
+        }
+        Format::TodoTxt => Err(TODO_TXT_UNSUPPORTED.to_string()),
+    }
+}
+
+/// Replaces the `status` in the field `index` of a v2 record with whether
+/// it is completed.
+fn status_to_completed(record: &StringRecord, index: usize) -> Result<StringRecord, String> {
+    record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            if i != index {
+                return Ok(field.to_string());
+            }
+            version::completed(field)
+                .map(|completed| completed.to_string())
+                .ok_or_else(|| {
+                    format!(
+                        "invalid status {}, expected \"open\" or \"completed\"",
+                        field
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(StringRecord::from)
+}
+
+/// Checks imported todos before they are sent to the todo service and returns
+/// a description of every problem found.
+pub fn validate(todos: &[ImportTodo]) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let mut ids = HashSet::new();
+
+    for (i, todo) in todos.iter().enumerate() {
+        let n = i + 1;
+        if todo.title.trim().is_empty() {
+            errors.push(format!("todo {}: title is empty", n));
+        }
+        if !todo.id.is_empty() && !ids.insert(todo.id.as_str()) {
+            errors.push(format!("todo {}: duplicate id {}", n, todo.id));
+        }
+        if let (Some(created_at), Some(updated_at)) = (todo.created_at, todo.updated_at) {
+            if updated_at < created_at {
+                errors.push(format!("todo {}: updated_at is before created_at", n));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
@@ -0,0 +1,302 @@
+//! REST routes transcoded to gRPC from the `google.api.http` annotations in
+//! todo.proto. build.rs generates a route for every annotated RPC: path and
+//! query parameters and the body are gathered into a JSON object that is
+//! deserialized into the request message, and the response message is
+//! serialized as the reply.
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+use warp::http::StatusCode;
+use warp::{reject, Filter};
+
+use crate::error::Error::{InvalidRequest, RPCError};
+use crate::todo::auth;
+use crate::todo::handlers;
+use crate::todo::media::{self, Body, Media};
+use crate::todo::routes::{self, Server};
+use crate::todo::service::todo_service as pb;
+use crate::todo::version::{Version, Versioned};
+
+include!(concat!(env!("OUT_DIR"), "/gateway.rs"));
+
+/// How a path or query parameter is parsed into a field. Which kinds are
+/// used depends on the fields bound in todo.proto.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+enum Kind {
+    String,
+    Integer,
+    Float,
+    Bool,
+}
+
+fn set_field(
+    fields: &mut Map<String, Value>,
+    name: &str,
+    kind: Kind,
+    value: String,
+) -> Result<(), warp::Rejection> {
+    let invalid = || reject::custom(InvalidRequest(format!("invalid {}: {}", name, value)));
+    let value = match kind {
+        Kind::String => Value::String(value.clone()),
+        Kind::Integer => value.parse::<i64>().map_err(|_| invalid())?.into(),
+        Kind::Float => value.parse::<f64>().map_err(|_| invalid())?.into(),
+        Kind::Bool => value.parse::<bool>().map_err(|_| invalid())?.into(),
+    };
+    fields.insert(name.to_string(), value);
+    Ok(())
+}
+
+/// Sets the fields given as query parameters. Other parameters are ignored.
+fn query_fields(
+    fields: &mut Map<String, Value>,
+    mut query: HashMap<String, String>,
+    kinds: &[(&str, Kind)],
+) -> Result<(), warp::Rejection> {
+    for (name, kind) in kinds {
+        if let Some(value) = query.remove(*name) {
+            set_field(fields, name, *kind, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// The fields of a body holding the whole request message `T`, and the
+/// version they are in. Protobuf is the same in every version.
+fn body_fields<T>(
+    body: &Body,
+    version: Version,
+) -> Result<(Version, Map<String, Value>), warp::Rejection>
+where
+    T: prost::Message + Default + serde::Serialize,
+{
+    let (version, value) = match body.media() {
+        Media::Protobuf => {
+            let message: T = body.message()?;
+            let value = serde_json::to_value(message)
+                .map_err(|e| reject::custom(InvalidRequest(e.to_string())))?;
+            (Version::V1, value)
+        }
+        _ => (version, body.decode()?),
+    };
+    match value {
+        Value::Object(fields) => Ok((version, fields)),
+        _ => Err(reject::custom(InvalidRequest(
+            "the body must be an object".to_string(),
+        ))),
+    }
+}
+
+fn message<T: Versioned>(
+    version: Version,
+    fields: Map<String, Value>,
+) -> Result<T, warp::Rejection> {
+    version
+        .decode(Value::Object(fields))
+        .map_err(|e| reject::custom(InvalidRequest(e.to_string())))
+}
+
+/// Timestamps in the same form as the hand-written routes, RFC 3339 in UTC.
+pub mod timestamp {
+    use chrono::{DateTime, Utc};
+    use serde::ser::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::todo::models::to_datetime;
+
+    pub fn serialize<S: Serializer>(
+        timestamp: &Option<prost_types::Timestamp>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        timestamp
+            .clone()
+            .map(|t| to_datetime(t).ok_or_else(|| S::Error::custom("timestamp out of range")))
+            .transpose()?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<prost_types::Timestamp>, D::Error> {
+        let time = Option::<DateTime<Utc>>::deserialize(deserializer)?;
+        Ok(time.map(|t| prost_types::Timestamp {
+            seconds: t.timestamp(),
+            nanos: t.timestamp_subsec_nanos() as i32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::todo::fake::{self, FakeTodoService};
+
+    /// Calls the API and returns the status and the JSON of the answer, null
+    /// when it has no body.
+    async fn call(
+        api: &(impl warp::Filter<Extract = (warp::reply::Response,), Error = std::convert::Infallible>
+              + Clone
+              + 'static),
+        method: &str,
+        path: &str,
+        body: Option<Value>,
+    ) -> (u16, Value) {
+        let mut request = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", "Bearer s3cret");
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.reply(api).await;
+        let value = if response.body().is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(response.body()).unwrap()
+        };
+        (response.status().as_u16(), value)
+    }
+
+    fn todo(id: &str, title: &str, body: &str, is_completed: bool) -> Value {
+        json!({
+            "id": id,
+            "title": title,
+            "body": body,
+            "is_completed": is_completed,
+            "created_at": "2020-09-13T12:26:40Z",
+            "updated_at": "2020-09-13T12:26:40Z",
+            "comment_count": 0,
+        })
+    }
+
+    /// Every transcoded route takes and answers the JSON of the hand-written
+    /// route it replaced.
+    #[tokio::test]
+    async fn routes_keep_their_json() {
+        let service = FakeTodoService::default().with_todo("t1", "a", true);
+        let api = fake::rest(fake::serve(service.clone()).await);
+
+        let (status, value) = call(&api, "GET", "/todos", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(value, json!({ "todos": [todo("t1", "a", "body", true)] }));
+
+        let (status, value) = call(&api, "GET", "/todos/t1", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(value, todo("t1", "a", "body", true));
+
+        let created = json!({ "title": "new", "body": "b" });
+        let (status, value) = call(&api, "POST", "/todos", Some(created)).await;
+        assert_eq!(status, 200);
+        assert_eq!(value, todo("t2", "new", "b", false));
+
+        let updated = json!({ "title": "x", "body": "y", "is_completed": false });
+        let (status, value) = call(&api, "PUT", "/todos/t1", Some(updated)).await;
+        assert_eq!(status, 200);
+        assert_eq!(value, todo("t1", "x", "y", false));
+
+        let (status, value) = call(&api, "POST", "/todos/t1/complete", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(value, todo("t1", "x", "y", true));
+
+        let (status, value) = call(&api, "POST", "/undo?count=1", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            value,
+            json!({ "entries": [{
+                "id": 1,
+                "todo_id": "t1",
+                "actor": "alice",
+                "operation": "undo",
+                "changes": [],
+                "created_at": "2020-09-13T12:26:40Z",
+                "undo_state": "",
+            }] })
+        );
+        let (status, value) = call(&api, "POST", "/redo", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(value, json!({ "entries": [] }));
+
+        let (status, value) = call(&api, "GET", "/todos/t1/attachments", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            value,
+            json!({ "attachments": [{
+                "id": "a1",
+                "todo_id": "t1",
+                "filename": "notes.txt",
+                "content_type": "text/plain",
+                "size": 42,
+                "created_at": "2020-09-13T12:26:40Z",
+            }] })
+        );
+
+        for path in [
+            "/todos/t1/comments/c1",
+            "/todos/t1/attachments/a1",
+            "/todos/t2",
+        ]
+        .iter()
+        {
+            assert_eq!(call(&api, "DELETE", path, None).await, (204, Value::Null));
+        }
+        assert!(service.todo("t2").is_none());
+    }
+
+    /// Fields the old routes required are still required, rather than taken
+    /// as zero values.
+    #[tokio::test]
+    async fn required_fields_stay_required() {
+        let service = FakeTodoService::default().with_todo("t1", "a", true);
+        let api = fake::rest(fake::serve(service.clone()).await);
+
+        for body in [json!({ "body": "b" }), json!({ "title": "t" })].iter() {
+            let (status, _) = call(&api, "POST", "/todos", Some(body.clone())).await;
+            assert_eq!(status, 400, "{}", body);
+        }
+        for body in [
+            json!({ "title": "x", "body": "y" }),
+            json!({ "title": "x", "is_completed": false }),
+            json!({ "body": "y", "is_completed": false }),
+        ]
+        .iter()
+        {
+            let (status, _) = call(&api, "PUT", "/todos/t1", Some(body.clone())).await;
+            assert_eq!(status, 400, "{}", body);
+        }
+        let (status, _) = call(
+            &api,
+            "PUT",
+            "/v2/todos/t1",
+            Some(json!({ "title": "x", "body": "y" })),
+        )
+        .await;
+        assert_eq!(status, 400);
+        assert!(service.todo("t1").unwrap().is_completed);
+
+        // The fields no route had are optional.
+        let created = json!({ "title": "t", "body": "" });
+        let (status, value) = call(&api, "POST", "/todos", Some(created)).await;
+        assert_eq!(status, 200);
+        assert_eq!(value["body"], "");
+        assert_eq!(service.calls(), vec!["create"]);
+    }
+
+    /// A field the todo service finds not valid is named in the problem.
+    #[tokio::test]
+    async fn invalid_fields_are_listed() {
+        let service = FakeTodoService::default();
+        let api = fake::rest(fake::serve(service.clone()).await);
+
+        let created = json!({ "title": " ", "body": "b" });
+        let (status, value) = call(&api, "POST", "/todos", Some(created)).await;
+        assert_eq!(status, 422);
+        assert_eq!(value["title"], "Invalid argument");
+        assert_eq!(
+            value["errors"],
+            json!([{ "field": "title", "message": "must not be empty" }])
+        );
+        assert!(service.todo("t1").is_none());
+    }
+}
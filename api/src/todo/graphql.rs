@@ -0,0 +1,475 @@
+//! A GraphQL endpoint at /graphql, with a GraphiQL page for it. Lets a client
+//! fetch a todo along with its comments, attachments and history in a single
+//! request and pick the fields it needs.
+
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{ComplexObject, Context, EmptySubscription, ErrorExtensions, Object, Schema};
+use tonic::transport::Channel;
+use warp::Filter;
+
+use crate::error;
+use crate::todo::auth::{self, Actors};
+use crate::todo::handlers;
+use crate::todo::media;
+use crate::todo::models;
+use crate::todo::service::todo_service as pb;
+use crate::todo::service::todo_service::todo_service_client::TodoServiceClient;
+
+const DEFAULT_LIMIT: i32 = 20;
+/// What a field that calls the todo service once for every todo counts for
+/// in the complexity of a query, on top of its own fields.
+const CALL_COMPLEXITY: usize = 10;
+
+pub type TodoSchema = Schema<Query, Mutation, EmptySubscription>;
+
+struct Client {
+    logger: slog::Logger,
+    todo_client: TodoServiceClient<Channel>,
+}
+
+/// Who makes the request, see `auth::with_actor`.
+struct Actor(Option<String>);
+
+type Result<T> = std::result::Result<T, async_graphql::Error>;
+
+pub fn schema(
+    logger: slog::Logger,
+    todo_client: TodoServiceClient<Channel>,
+    max_depth: usize,
+    max_complexity: usize,
+) -> TodoSchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .data(Client {
+            logger,
+            todo_client,
+        })
+        .limit_depth(max_depth)
+        .limit_complexity(max_complexity)
+        .finish()
+}
+
+pub fn graphql_filter(
+    schema: TodoSchema,
+    actors: Actors,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let graphiql = warp::path!("graphql").and(warp::get()).map(|| {
+        warp::reply::html(
+            GraphiQLSource::build()
+                .endpoint("/graphql")
+                .title("todo")
+                .finish(),
+        )
+    });
+    let query = warp::path!("graphql")
+        .and(warp::post())
+        .and(media::decode())
+        .and(auth::with_actor(actors))
+        .and(warp::any().map(move || schema.clone()))
+        .and_then(execute);
+
+    graphiql.or(query)
+}
+
+async fn execute(
+    request: async_graphql::Request,
+    actor: Option<String>,
+    schema: TodoSchema,
+) -> std::result::Result<impl warp::Reply, warp::Rejection> {
+    let response = schema.execute(request.data(Actor(actor))).await;
+
+    Ok(warp::reply::json(&response))
+}
+
+fn client<'a>(ctx: &Context<'a>) -> (TodoServiceClient<Channel>, &'a slog::Logger) {
+    let client = ctx.data_unchecked::<Client>();
+    (client.todo_client.clone(), &client.logger)
+}
+
+fn request<T>(ctx: &Context<'_>, message: T) -> tonic::Request<T> {
+    let actor = ctx.data_opt::<Actor>().and_then(|actor| actor.0.clone());
+    handlers::with_actor(message, actor)
+}
+
+/// The status of a failed call, with its gRPC code as the `code` extension
+/// and the fields that are not valid, if any, as `errors`.
+fn rpc_error(status: tonic::Status) -> async_graphql::Error {
+    let errors = serde_json::to_value(error::field_errors(&status))
+        .ok()
+        .and_then(|errors| async_graphql::Value::from_json(errors).ok())
+        .filter(|errors| !matches!(errors, async_graphql::Value::List(l) if l.is_empty()));
+    async_graphql::Error::new(status.message()).extend_with(|_, e| {
+        e.set("code", format!("{:?}", status.code()));
+        if let Some(errors) = &errors {
+            e.set("errors", errors.clone());
+        }
+    })
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Todos matching the filters, `limit` at a time starting at `offset`.
+    #[graphql(complexity = "limit.max(0) as usize * child_complexity")]
+    async fn todos(
+        &self,
+        ctx: &Context<'_>,
+        completed: Option<bool>,
+        #[graphql(desc = "Matches the title or body, ignoring case")] search: Option<String>,
+        #[graphql(default, validator(minimum = 0))] offset: i32,
+        #[graphql(default_with = "DEFAULT_LIMIT", validator(minimum = 0, maximum = 100))]
+        limit: i32,
+    ) -> Result<Vec<models::Todo>> {
+        let (mut todo_client, logger) = client(ctx);
+        let resp = todo_client
+            .list(request(ctx, pb::ListRequest {}))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql todos"; "err" => e.to_string());
+                rpc_error(e)
+            })?;
+
+        let search = search.map(|s| s.to_lowercase());
+        Ok(models::Todos::from(resp.into_inner())
+            .todos
+            .into_iter()
+            .filter(|todo| completed.is_none_or(|c| todo.is_completed == c))
+            .filter(|todo| {
+                search.as_ref().is_none_or(|s| {
+                    todo.title.to_lowercase().contains(s) || todo.body.to_lowercase().contains(s)
+                })
+            })
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect())
+    }
+
+    async fn todo(&self, ctx: &Context<'_>, id: String) -> Result<models::Todo> {
+        let (mut todo_client, logger) = client(ctx);
+        let resp = todo_client
+            .get_by_id(request(ctx, pb::TodoId { id: id.clone() }))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql todo"; "err" => e.to_string(), "id" => id);
+                rpc_error(e)
+            })?;
+
+        Ok(models::Todo::from(resp.into_inner()))
+    }
+}
+
+#[ComplexObject]
+impl models::Todo {
+    #[graphql(complexity = "CALL_COMPLEXITY + child_complexity")]
+    async fn comments(&self, ctx: &Context<'_>) -> Result<Vec<models::Comment>> {
+        let (mut todo_client, logger) = client(ctx);
+        let resp = todo_client
+            .list_comments(request(
+                ctx,
+                pb::TodoId {
+                    id: self.id.clone(),
+                },
+            ))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql comments"; "err" => e.to_string(), "id" => &self.id);
+                rpc_error(e)
+            })?;
+
+        Ok(models::Comments::from(resp.into_inner()).comments)
+    }
+
+    #[graphql(complexity = "CALL_COMPLEXITY + child_complexity")]
+    async fn attachments(&self, ctx: &Context<'_>) -> Result<Vec<models::Attachment>> {
+        let (mut todo_client, logger) = client(ctx);
+        let resp = todo_client
+            .list_attachments(request(
+                ctx,
+                pb::TodoId {
+                    id: self.id.clone(),
+                },
+            ))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql attachments"; "err" => e.to_string(), "id" => &self.id);
+                rpc_error(e)
+            })?;
+
+        Ok(resp
+            .into_inner()
+            .attachments
+            .into_iter()
+            .map(models::Attachment::from)
+            .collect())
+    }
+
+    /// Changes made to the todo.
+    #[graphql(complexity = "CALL_COMPLEXITY + limit.max(0) as usize * child_complexity")]
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default, validator(minimum = 0))] offset: i64,
+        #[graphql(
+            default_with = "models::DEFAULT_HISTORY_LIMIT",
+            validator(minimum = 0, maximum = 100)
+        )]
+        limit: i64,
+    ) -> Result<Vec<models::HistoryEntry>> {
+        let (mut todo_client, logger) = client(ctx);
+        let message = pb::HistoryRequest {
+            id: self.id.clone(),
+            offset,
+            limit,
+        };
+        let resp = todo_client
+            .get_history(request(ctx, message))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql history"; "err" => e.to_string(), "id" => &self.id);
+                rpc_error(e)
+            })?;
+
+        Ok(models::History::from(resp.into_inner()).entries)
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    async fn create_todo(
+        &self,
+        ctx: &Context<'_>,
+        title: String,
+        #[graphql(default)] body: String,
+        #[graphql(desc = "Answers a repeated mutation with the result of the first")]
+        idempotency_key: Option<String>,
+    ) -> Result<models::Todo> {
+        let (mut todo_client, logger) = client(ctx);
+        let resp = todo_client
+            .create(request(
+                ctx,
+                pb::CreateRequest {
+                    title,
+                    body,
+                    request_id: idempotency_key.unwrap_or_default(),
+                },
+            ))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql create_todo"; "err" => e.to_string());
+                rpc_error(e)
+            })?;
+
+        Ok(models::Todo::from(resp.into_inner()))
+    }
+
+    /// Changes the given fields and keeps the others, even when they are
+    /// changed at the same time.
+    async fn update_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        title: Option<String>,
+        body: Option<String>,
+        is_completed: Option<bool>,
+        #[graphql(desc = "Answers a repeated mutation with the result of the first")]
+        idempotency_key: Option<String>,
+    ) -> Result<models::Todo> {
+        let (mut todo_client, logger) = client(ctx);
+        let mask: Vec<String> = [
+            ("title", title.is_some()),
+            ("body", body.is_some()),
+            ("is_completed", is_completed.is_some()),
+        ]
+        .iter()
+        .filter(|(_, given)| *given)
+        .map(|(field, _)| field.to_string())
+        .collect();
+        // An empty mask would change every field, and a read needs no key.
+        let resp = if mask.is_empty() {
+            todo_client
+                .get_by_id(request(ctx, pb::TodoId { id: id.clone() }))
+                .await
+        } else {
+            let message = pb::UpdateRequest {
+                id: id.clone(),
+                title: title.unwrap_or_default(),
+                body: body.unwrap_or_default(),
+                is_completed: is_completed.unwrap_or_default(),
+                request_id: idempotency_key.unwrap_or_default(),
+                update_mask: mask,
+            };
+            todo_client.update(request(ctx, message)).await
+        }
+        .map_err(|e| {
+            error!(logger, "graphql update_todo"; "err" => e.to_string(), "id" => &id);
+            rpc_error(e)
+        })?;
+
+        Ok(models::Todo::from(resp.into_inner()))
+    }
+
+    async fn complete_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        #[graphql(desc = "Answers a repeated mutation with the result of the first")]
+        idempotency_key: Option<String>,
+    ) -> Result<models::Todo> {
+        let (mut todo_client, logger) = client(ctx);
+        let resp = todo_client
+            .complete(request(
+                ctx,
+                pb::CompleteRequest {
+                    id: id.clone(),
+                    request_id: idempotency_key.unwrap_or_default(),
+                },
+            ))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql complete_todo"; "err" => e.to_string(), "id" => &id);
+                rpc_error(e)
+            })?;
+
+        Ok(models::Todo::from(resp.into_inner()))
+    }
+
+    /// Returns the id of the deleted todo.
+    async fn delete_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        #[graphql(desc = "Answers a repeated mutation with the result of the first")]
+        idempotency_key: Option<String>,
+    ) -> Result<String> {
+        let (mut todo_client, logger) = client(ctx);
+        todo_client
+            .delete(request(
+                ctx,
+                pb::DeleteRequest {
+                    id: id.clone(),
+                    request_id: idempotency_key.unwrap_or_default(),
+                },
+            ))
+            .await
+            .map_err(|e| {
+                error!(logger, "graphql delete_todo"; "err" => e.to_string(), "id" => &id);
+                rpc_error(e)
+            })?;
+
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::logger;
+    use crate::todo::fake::{self, FakeTodoService};
+
+    async fn execute(schema: &TodoSchema, query: &str) -> async_graphql::Response {
+        schema.execute(async_graphql::Request::new(query)).await
+    }
+
+    fn errors(response: &async_graphql::Response) -> Vec<String> {
+        response.errors.iter().map(|e| e.message.clone()).collect()
+    }
+
+    async fn test_schema(
+        service: FakeTodoService,
+        max_depth: usize,
+        max_complexity: usize,
+    ) -> TodoSchema {
+        schema(
+            logger(),
+            fake::serve(service).await,
+            max_depth,
+            max_complexity,
+        )
+    }
+
+    #[tokio::test]
+    async fn rejects_deep_queries() {
+        let service = FakeTodoService::default().with_todo("t1", "a", false);
+        let schema = test_schema(service, 3, 10_000).await;
+
+        let shallow = execute(
+            &schema,
+            "{ todos { history { id } } todo(id: \"t1\") { id } }",
+        )
+        .await;
+        assert!(shallow.errors.is_empty(), "{:?}", errors(&shallow));
+        let deep = execute(&schema, "{ todos { history { changes { field } } } }").await;
+        assert_eq!(errors(&deep), vec!["Query is nested too deep."]);
+    }
+
+    /// Every todo of a list costs a call for each of its comments,
+    /// attachments and history.
+    #[tokio::test]
+    async fn rejects_complex_queries() {
+        let schema = test_schema(FakeTodoService::default(), 8, 1_000).await;
+
+        let cheap = execute(&schema, "{ todos(limit: 100) { id title } }").await;
+        assert!(cheap.errors.is_empty(), "{:?}", errors(&cheap));
+        let calls = execute(&schema, "{ todos(limit: 50) { id comments { id } } }").await;
+        assert!(calls.errors.is_empty(), "{:?}", errors(&calls));
+
+        for query in [
+            "{ todos(limit: 100) { id comments { id } } }",
+            "{ todos(limit: 100) { id attachments { id } } }",
+            "{ todos(limit: 100) { id history(limit: 1) { id } } }",
+            "{ todos(limit: 20) { history(limit: 100) { id actor } } }",
+        ]
+        .iter()
+        {
+            let response = execute(&schema, query).await;
+            assert!(
+                errors(&response).iter().any(|e| e.contains("too complex")),
+                "{}: {:?}",
+                query,
+                errors(&response)
+            );
+        }
+    }
+
+    /// An update sends only the fields it changes, so that it does not undo
+    /// a concurrent change to the others.
+    #[tokio::test]
+    async fn updates_only_the_given_fields() {
+        let service = FakeTodoService::default().with_todo("t1", "a", false);
+        let schema = test_schema(service.clone(), 8, 10_000).await;
+
+        let response = execute(
+            &schema,
+            "mutation { updateTodo(id: \"t1\", isCompleted: true) { title isCompleted } }",
+        )
+        .await;
+        assert!(response.errors.is_empty(), "{:?}", errors(&response));
+        assert_eq!(service.calls(), vec!["update"]);
+        let todo = service.todo("t1").unwrap();
+        assert_eq!(todo.title, "a");
+        assert_eq!(todo.body, "body");
+        assert!(todo.is_completed);
+    }
+
+    /// Mutations pass their own key on, so that each can be repeated.
+    #[tokio::test]
+    async fn passes_idempotency_keys() {
+        let service = FakeTodoService::default().with_todo("t1", "a", false);
+        let schema = test_schema(service.clone(), 8, 10_000).await;
+
+        let response = execute(
+            &schema,
+            "mutation {
+                createTodo(title: \"b\", idempotencyKey: \"k1\") { id }
+                updateTodo(id: \"t1\", title: \"c\", idempotencyKey: \"k2\") { id }
+                completeTodo(id: \"t1\", idempotencyKey: \"k3\") { id }
+                deleteTodo(id: \"t2\")
+            }",
+        )
+        .await;
+        assert!(response.errors.is_empty(), "{:?}", errors(&response));
+        assert_eq!(service.request_ids(), vec!["k1", "k2", "k3", ""]);
+    }
+}
@@ -1,102 +1,573 @@
-use warp::http::StatusCode;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use tonic::metadata::MetadataValue;
+use warp::http::{Response, StatusCode};
+use warp::hyper::body::{Body, Buf};
+use warp::multipart::{FormData, Part};
 use warp::reject;
 
+use crate::todo::attachments;
+use crate::todo::formats;
+use crate::todo::ics;
+use crate::todo::media::Media;
 use crate::todo::models;
 use crate::todo::routes::Server;
 use crate::todo::service::todo_service as pb;
+use crate::todo::service::todo_service::upload_attachment_request::Payload;
 
-use super::super::error::Error::RPCError;
+use super::super::error::Error::{
+    EncodeError, InvalidImport, InvalidUpload, RPCError, RangeNotSatisfiable, UnsupportedMediaType,
+};
 
-pub(crate) async fn list_todos(mut server: Server) -> Result<impl warp::Reply, warp::Rejection> {
-    let req = tonic::Request::new(pb::ListRequest {});
-    let resp = server.todo_client.list(req).await.map_err(|e| {
-        error!(server.logger, "list_todos"; "err" => e.to_string());
+const ACTOR_METADATA_KEY: &str = "x-actor";
+
+#[utoipa::path(
+    get,
+    path = "/todos/export",
+    tag = "import and export",
+    params(models::ExportParams),
+    responses((status = 200, description = "All todos as a file download", content(
+        (models::Todos = "application/json"),
+        (String = "text/csv"),
+        (String = "text/plain"),
+    ))),
+)]
+pub(crate) async fn export_todos(
+    params: models::ExportParams,
+    mut server: Server,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let format = params.format.unwrap_or(models::Format::Json);
+    let req = tonic::Request::new(pb::ExportRequest {});
+    let body = if format == models::Format::TodoTxt {
+        let resp = server.todo_client.export_todo_txt(req).await.map_err(|e| {
+            error!(server.logger, "export_todos"; "err" => e.to_string());
+            reject::custom(RPCError(e))
+        })?;
+
+        resp.into_inner().content.into_bytes()
+    } else {
+        let resp = server.todo_client.export(req).await.map_err(|e| {
+            error!(server.logger, "export_todos"; "err" => e.to_string());
+            reject::custom(RPCError(e))
+        })?;
+
+        let todos = models::Todos::from(resp.into_inner()).todos;
+        formats::encode(format, &todos, server.version).map_err(|e| {
+            error!(server.logger, "export_todos"; "err" => &e);
+            reject::custom(EncodeError(e))
+        })?
+    };
+    let disposition = format!("attachment; filename=\"todos.{}\"", format.extension());
+
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(body, "content-type", format.content_type()),
+        "content-disposition",
+        disposition,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/todos/import",
+    tag = "import and export",
+    params(models::ImportParams, ("Idempotency-Key" = Option<String>, Header, description = "Answers a repeated request with the response to the first")),
+    request_body(description = "Todos in the given format", content(
+        (Vec<models::ImportTodo> = "application/json"),
+        (String = "text/csv"),
+        (String = "text/plain"),
+    )),
+    responses((status = 200, description = "The imported todos", body = models::ImportResult)),
+)]
+pub(crate) async fn import_todos(
+    params: models::ImportParams,
+    content_type: Option<String>,
+    data: warp::hyper::body::Bytes,
+    actor: Option<String>,
+    idempotency_key: Option<String>,
+    media: Media,
+    server: Server,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let format = match (params.format, content_type) {
+        (Some(format), _) => format,
+        (None, None) => models::Format::Json,
+        (None, Some(content_type)) => models::Format::from_content_type(&content_type)
+            .ok_or_else(|| reject::custom(UnsupportedMediaType(content_type)))?,
+    };
+    if format == models::Format::TodoTxt {
+        return import_todo_txt(params.dry_run, data, actor, idempotency_key, media, server).await;
+    }
+
+    let todos = formats::decode(format, &data, server.version).map_err(|e| {
+        error!(server.logger, "import_todos"; "err" => &e);
+        reject::custom(InvalidImport(e))
+    })?;
+
+    import(todos, params.dry_run, actor, idempotency_key, media, server).await
+}
+
+async fn import_todo_txt(
+    dry_run: bool,
+    data: warp::hyper::body::Bytes,
+    actor: Option<String>,
+    idempotency_key: Option<String>,
+    media: Media,
+    mut server: Server,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let content = String::from_utf8(data.to_vec()).map_err(|e| {
+        error!(server.logger, "import_todo_txt"; "err" => e.to_string());
+        reject::custom(InvalidImport(e.to_string()))
+    })?;
+
+    let req = with_actor(
+        pb::ImportTodoTxtRequest {
+            content,
+            dry_run,
+            request_id: idempotency_key.unwrap_or_default(),
+        },
+        actor,
+    );
+    let resp = server.todo_client.import_todo_txt(req).await.map_err(|e| {
+        error!(server.logger, "import_todo_txt"; "err" => e.to_string());
+        reject::custom(RPCError(e))
+    })?;
+
+    let body = models::ImportResult {
+        dry_run,
+        todos: models::Todos::from(resp.into_inner()).todos,
+    };
+
+    media.reply(&body, server.version)
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos.ics",
+    tag = "import and export",
+    responses((status = 200, description = "All todos as an iCalendar file", body = String, content_type = "text/calendar")),
+)]
+pub(crate) async fn export_ics(mut server: Server) -> Result<impl warp::Reply, warp::Rejection> {
+    let req = tonic::Request::new(pb::ExportRequest {});
+    let resp = server.todo_client.export(req).await.map_err(|e| {
+        error!(server.logger, "export_ics"; "err" => e.to_string());
+        reject::custom(RPCError(e))
+    })?;
+
+    let todos = models::Todos::from(resp.into_inner()).todos;
+
+    Ok(warp::reply::with_header(
+        ics::serialize(&todos),
+        "content-type",
+        ics::CONTENT_TYPE,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/todos/import/ics",
+    tag = "import and export",
+    params(models::DryRunParams, ("Idempotency-Key" = Option<String>, Header, description = "Answers a repeated request with the response to the first")),
+    request_body(description = "An iCalendar file", content = String, content_type = "text/calendar"),
+    responses((status = 200, description = "The imported todos", body = models::ImportResult)),
+)]
+pub(crate) async fn import_ics(
+    params: models::DryRunParams,
+    data: warp::hyper::body::Bytes,
+    actor: Option<String>,
+    idempotency_key: Option<String>,
+    media: Media,
+    server: Server,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let todos = std::str::from_utf8(&data)
+        .map_err(|e| e.to_string())
+        .and_then(ics::parse)
+        .map_err(|e| {
+            error!(server.logger, "import_ics"; "err" => &e);
+            reject::custom(InvalidImport(e))
+        })?;
+
+    import(todos, params.dry_run, actor, idempotency_key, media, server).await
+}
+
+async fn import(
+    todos: Vec<models::ImportTodo>,
+    dry_run: bool,
+    actor: Option<String>,
+    idempotency_key: Option<String>,
+    media: Media,
+    mut server: Server,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    formats::validate(&todos).map_err(|errors| {
+        let e = errors.join("; ");
+        error!(server.logger, "import"; "err" => &e);
+        reject::custom(InvalidImport(e))
+    })?;
+
+    let req = with_actor(
+        pb::ImportRequest {
+            todos: todos.into_iter().map(pb::Todo::from).collect(),
+            dry_run,
+            request_id: idempotency_key.unwrap_or_default(),
+        },
+        actor,
+    );
+    let resp = server.todo_client.import(req).await.map_err(|e| {
+        error!(server.logger, "import"; "err" => e.to_string());
         reject::custom(RPCError(e))
     })?;
 
-    let body: models::Todos = models::Todos::from(resp.into_inner());
+    let body = models::ImportResult {
+        dry_run,
+        todos: models::Todos::from(resp.into_inner()).todos,
+    };
 
-    Ok(warp::reply::json(&body))
+    media.reply(&body, server.version)
 }
 
-pub(crate) async fn create_todo(
-    create: models::CreateTodo,
+#[utoipa::path(
+    get,
+    path = "/todos/{id}/history",
+    tag = "history",
+    params(("id" = String, Path, description = "Todo id"), models::HistoryParams),
+    responses((status = 200, description = "Changes to the todo, newest first", body = models::History)),
+)]
+pub(crate) async fn get_history(
+    id: String,
+    params: models::HistoryParams,
+    media: Media,
     mut server: Server,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let req = tonic::Request::new(pb::CreateRequest {
-        title: create.title,
-        body: create.body,
+    let req = tonic::Request::new(pb::HistoryRequest {
+        id: id.clone(),
+        offset: params.offset.unwrap_or(0),
+        limit: params.limit.unwrap_or(models::DEFAULT_HISTORY_LIMIT),
     });
-    let resp = server.todo_client.create(req).await.map_err(|e| {
-        error!(server.logger, "create_todo"; "err" => e.to_string());
+    let resp = server.todo_client.get_history(req).await.map_err(|e| {
+        error!(server.logger, "get_history"; "err" => e.to_string(), "id" => id);
         reject::custom(RPCError(e))
     })?;
 
-    let body: models::Todo = models::Todo::from(resp.into_inner());
+    let body = models::History::from(resp.into_inner());
 
-    Ok(warp::reply::json(&body))
+    media.reply(&body, server.version)
 }
 
-pub(crate) async fn get_todo(
+#[utoipa::path(
+    get,
+    path = "/todos/{id}/comments",
+    tag = "comments",
+    params(("id" = String, Path, description = "Todo id")),
+    responses((status = 200, description = "Comments on the todo", body = models::Comments)),
+)]
+pub(crate) async fn list_comments(
     id: String,
+    media: Media,
     mut server: Server,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let req = tonic::Request::new(pb::TodoId { id: id.clone() });
-    let resp = server.todo_client.get_by_id(req).await.map_err(|e| {
-        error!(server.logger, "get_todo"; "err" => e.to_string(), "id" => id);
+    let resp = server.todo_client.list_comments(req).await.map_err(|e| {
+        error!(server.logger, "list_comments"; "err" => e.to_string(), "id" => id);
         reject::custom(RPCError(e))
     })?;
 
-    let body: models::Todo = models::Todo::from(resp.into_inner());
+    let body = models::Comments::from(resp.into_inner());
 
-    Ok(warp::reply::json(&body))
+    media.reply(&body, server.version)
 }
 
-pub(crate) async fn update_todo(
+#[utoipa::path(
+    post,
+    path = "/todos/{id}/comments",
+    tag = "comments",
+    params(("id" = String, Path, description = "Todo id"), ("Idempotency-Key" = Option<String>, Header, description = "Answers a repeated request with the response to the first")),
+    request_body = models::AddComment,
+    responses((status = 201, description = "The added comment", body = models::Comment)),
+)]
+pub(crate) async fn add_comment(
     id: String,
-    update: models::UpdateTodo,
+    add: models::AddComment,
+    actor: Option<String>,
+    idempotency_key: Option<String>,
+    media: Media,
     mut server: Server,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let req = tonic::Request::new(pb::UpdateRequest {
-        id: id.clone(),
-        title: update.title,
-        body: update.body,
-        is_completed: update.is_completed,
-    });
-    let resp = server.todo_client.update(req).await.map_err(|e| {
-        error!(server.logger, "update_todo"; "err" => e.to_string(), "id" => id);
+    let req = with_actor(
+        pb::AddCommentRequest {
+            todo_id: id.clone(),
+            parent_id: add.parent_id.unwrap_or_default(),
+            body: add.body,
+            request_id: idempotency_key.unwrap_or_default(),
+        },
+        actor,
+    );
+    let resp = server.todo_client.add_comment(req).await.map_err(|e| {
+        error!(server.logger, "add_comment"; "err" => e.to_string(), "id" => id);
         reject::custom(RPCError(e))
     })?;
 
-    let body: models::Todo = models::Todo::from(resp.into_inner());
+    let body = models::Comment::from(resp.into_inner());
 
-    Ok(warp::reply::json(&body))
+    Ok(warp::reply::with_status(
+        media.reply(&body, server.version)?,
+        StatusCode::CREATED,
+    ))
 }
 
-pub(crate) async fn delete_todo(
+#[utoipa::path(
+    put,
+    path = "/todos/{todo_id}/comments/{id}",
+    tag = "comments",
+    params(("todo_id" = String, Path, description = "Todo id"), ("id" = String, Path, description = "Comment id"), ("Idempotency-Key" = Option<String>, Header, description = "Answers a repeated request with the response to the first")),
+    request_body = models::EditComment,
+    responses((status = 200, description = "The edited comment", body = models::Comment)),
+)]
+pub(crate) async fn edit_comment(
     id: String,
+    comment_id: String,
+    edit: models::EditComment,
+    actor: Option<String>,
+    idempotency_key: Option<String>,
+    media: Media,
     mut server: Server,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let req = tonic::Request::new(pb::TodoId { id: id.clone() });
-    server.todo_client.delete(req).await.map_err(|e| {
-        error!(server.logger, "delete_todo"; "err" => e.to_string(), "id" => id);
+    let req = with_actor(
+        pb::EditCommentRequest {
+            todo_id: id.clone(),
+            id: comment_id.clone(),
+            body: edit.body,
+            request_id: idempotency_key.unwrap_or_default(),
+        },
+        actor,
+    );
+    let resp = server.todo_client.edit_comment(req).await.map_err(|e| {
+        error!(server.logger, "edit_comment"; "err" => e.to_string(), "id" => id, "comment_id" => comment_id);
         reject::custom(RPCError(e))
     })?;
 
-    Ok(StatusCode::NO_CONTENT)
+    let body = models::Comment::from(resp.into_inner());
+
+    media.reply(&body, server.version)
 }
 
-pub(crate) async fn complete_todo(
+#[utoipa::path(
+    post,
+    path = "/todos/{id}/attachments",
+    tag = "attachments",
+    params(("id" = String, Path, description = "Todo id"), ("Idempotency-Key" = Option<String>, Header, description = "Answers a repeated request with the response to the first")),
+    request_body(content = inline(models::AttachmentUpload), content_type = "multipart/form-data"),
+    responses((status = 201, description = "The uploaded attachment", body = models::Attachment)),
+)]
+pub(crate) async fn upload_attachment(
     id: String,
+    mut form: FormData,
+    actor: Option<String>,
+    idempotency_key: Option<String>,
+    media: Media,
     mut server: Server,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let req = tonic::Request::new(pb::TodoId { id: id.clone() });
-    let resp = server.todo_client.complete(req).await.map_err(|e| {
-        error!(server.logger, "complete_todo"; "err" => e.to_string(), "id" => id);
+    let invalid_upload = |e: String| {
+        error!(server.logger, "upload_attachment"; "err" => &e, "id" => &id);
+        reject::custom(InvalidUpload(e))
+    };
+
+    let mut file = None;
+    while let Some(part) = form
+        .try_next()
+        .await
+        .map_err(|e| invalid_upload(e.to_string()))?
+    {
+        if part.name() == attachments::FILE_FIELD {
+            file = Some(part);
+            break;
+        }
+    }
+    let part =
+        file.ok_or_else(|| invalid_upload(format!("missing {} field", attachments::FILE_FIELD)))?;
+
+    let info = pb::AttachmentInfo {
+        todo_id: id.clone(),
+        filename: part.filename().unwrap_or_default().to_string(),
+        content_type: part.content_type().unwrap_or_default().to_string(),
+        request_id: idempotency_key.unwrap_or_default(),
+    };
+    let messages = upload_messages(info, part, server.logger.clone());
+    let req = with_actor(messages, actor);
+    let resp = server
+        .todo_client
+        .upload_attachment(req)
+        .await
+        .map_err(|e| {
+            error!(server.logger, "upload_attachment"; "err" => e.to_string(), "id" => &id);
+            reject::custom(RPCError(e))
+        })?;
+
+    let body = models::Attachment::from(resp.into_inner());
+
+    Ok(warp::reply::with_status(
+        media.reply(&body, server.version)?,
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/{todo_id}/attachments/{id}",
+    tag = "attachments",
+    params(("todo_id" = String, Path, description = "Todo id"), ("id" = String, Path, description = "Attachment id"), ("range" = Option<String>, Header, description = "A single byte range")),
+    responses(
+        (status = 200, description = "The content of the attachment", body = [u8]),
+        (status = 206, description = "The requested range of the attachment", body = [u8]),
+    ),
+)]
+pub(crate) async fn download_attachment(
+    id: String,
+    attachment_id: String,
+    range: Option<String>,
+    mut server: Server,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let req = tonic::Request::new(pb::AttachmentId {
+        todo_id: id.clone(),
+        id: attachment_id.clone(),
+    });
+    let resp = server.todo_client.get_attachment(req).await.map_err(|e| {
+        error!(server.logger, "download_attachment"; "err" => e.to_string(), "id" => &id, "attachment_id" => &attachment_id);
         reject::custom(RPCError(e))
     })?;
+    let attachment = models::Attachment::from(resp.into_inner());
+
+    let size = attachment.size as u64;
+    let range = match range {
+        Some(header) => attachments::parse_range(&header, size)
+            .map_err(|_| reject::custom(RangeNotSatisfiable(attachment.size)))?,
+        None => None,
+    };
+    let (offset, length) = match range {
+        Some((start, end)) => (start, end - start + 1),
+        None => (0, size),
+    };
+
+    let req = tonic::Request::new(pb::DownloadAttachmentRequest {
+        todo_id: id.clone(),
+        id: attachment_id.clone(),
+        offset: offset as i64,
+        length: length as i64,
+    });
+    let resp = server
+        .todo_client
+        .download_attachment(req)
+        .await
+        .map_err(|e| {
+            error!(server.logger, "download_attachment"; "err" => e.to_string(), "id" => &id, "attachment_id" => &attachment_id);
+            reject::custom(RPCError(e))
+        })?;
+    let body = Body::wrap_stream(resp.into_inner().map_ok(|chunk| chunk.data));
+
+    let mut builder = Response::builder()
+        .header("content-type", attachment.content_type)
+        .header("content-length", length)
+        .header("accept-ranges", "bytes")
+        .header(
+            "content-disposition",
+            attachments::content_disposition(&attachment.filename),
+        );
+    if let Some((start, end)) = range {
+        builder = builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header("content-range", format!("bytes {}-{}/{}", start, end, size));
+    }
+
+    builder.body(body).map_err(|e| {
+        error!(server.logger, "download_attachment"; "err" => e.to_string(), "id" => &id, "attachment_id" => &attachment_id);
+        reject::custom(EncodeError(e.to_string()))
+    })
+}
+
+/// The messages of an upload of `part`. warp 0.2 reads the whole form into
+/// memory, up to `max_upload_size`, before the handler runs. From there the
+/// part is sent on in chunks as it is read, without another copy of it. A
+/// part that fails to read ends with a message without payload, which the
+/// todo service refuses.
+fn upload_messages(
+    info: pb::AttachmentInfo,
+    part: Part,
+    logger: slog::Logger,
+) -> impl Stream<Item = pb::UploadAttachmentRequest> + Send + Sync + 'static {
+    let content = part
+        .stream()
+        .map(move |buf| match buf {
+            Ok(buf) => chunks(buf).map(Payload::Chunk).map(Some).left_stream(),
+            Err(e) => {
+                error!(logger, "upload_attachment"; "err" => e.to_string());
+                stream::once(future::ready(None)).right_stream()
+            }
+        })
+        .flatten()
+        .map(|payload| pb::UploadAttachmentRequest { payload });
+    stream::once(future::ready(pb::UploadAttachmentRequest {
+        payload: Some(Payload::Info(info)),
+    }))
+    .chain(content)
+}
+
+/// The bytes of `buf` in chunks of at most `CHUNK_SIZE`, copied out one at a
+/// time as they are taken.
+fn chunks<B: Buf>(mut buf: B) -> impl Stream<Item = Vec<u8>> {
+    stream::iter(std::iter::from_fn(move || {
+        if !buf.has_remaining() {
+            return None;
+        }
+        let len = buf.bytes().len().min(attachments::CHUNK_SIZE);
+        let chunk = buf.bytes()[..len].to_vec();
+        buf.advance(len);
+        Some(chunk)
+    }))
+}
+
+/// Wraps a message into a request that carries the authenticated caller, so
+/// that the todo service can attribute the change.
+pub(crate) fn with_actor<T>(message: T, actor: Option<String>) -> tonic::Request<T> {
+    let mut req = tonic::Request::new(message);
+    if let Some(value) = actor.and_then(|a| MetadataValue::from_str(&a).ok()) {
+        req.metadata_mut().insert(ACTOR_METADATA_KEY, value);
+    }
+    req
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::todo::attachments::{CHUNK_SIZE, FILE_FIELD};
+    use crate::todo::fake::{self, FakeTodoService};
 
-    let body = models::Todo::from(resp.into_inner());
+    /// An upload reaches the todo service in chunks of at most `CHUNK_SIZE`.
+    #[tokio::test]
+    async fn uploads_are_sent_in_chunks() {
+        let service = FakeTodoService::default().with_todo("t1", "a", false);
+        let api = fake::rest(fake::serve(service.clone()).await);
 
-    Ok(warp::reply::json(&body))
+        let content = vec![b'x'; 2 * CHUNK_SIZE + 1];
+        let mut body = format!(
+            "--b\r\ncontent-disposition: form-data; name=\"{}\"; filename=\"a.txt\"\r\n\
+             content-type: text/plain\r\n\r\n",
+            FILE_FIELD
+        )
+        .into_bytes();
+        body.extend(&content);
+        body.extend(b"\r\n--b--\r\n");
+        let response = warp::test::request()
+            .method("POST")
+            .path("/v1/todos/t1/attachments")
+            .header("authorization", "Bearer s3cret")
+            .header("content-type", "multipart/form-data; boundary=b")
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 201);
+        let attachment: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(attachment["filename"], "a.txt");
+        assert_eq!(attachment["size"], content.len());
+        assert_eq!(
+            service.state.lock().unwrap().uploads,
+            vec![vec![CHUNK_SIZE, CHUNK_SIZE, 1]]
+        );
+    }
 }
@@ -0,0 +1,408 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::todo::models::{ImportTodo, Todo};
+
+pub const CONTENT_TYPE: &str = "text/calendar; charset=utf-8";
+
+const PRODID: &str = "-//rust-micro-todo//todo//EN";
+const DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const MAX_LINE_OCTETS: usize = 75;
+const ID_LENGTH: usize = 20;
+const ID_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+/// Serializes todos into a VCALENDAR with one VTODO per todo.
+///
+/// The todo id is used as UID and DTSTAMP is taken from updated_at, so an
+/// unchanged todo always produces the same component and calendar apps
+/// subscribed to the feed don't create duplicates.
+pub fn serialize(todos: &[Todo]) -> String {
+    let mut out = String::new();
+    write_line(&mut out, "BEGIN:VCALENDAR");
+    write_line(&mut out, "VERSION:2.0");
+    write_line(&mut out, &format!("PRODID:{}", PRODID));
+    write_line(&mut out, "CALSCALE:GREGORIAN");
+    write_line(&mut out, "X-WR-CALNAME:Todos");
+
+    for todo in todos {
+        write_line(&mut out, "BEGIN:VTODO");
+        write_line(&mut out, &format!("UID:{}", todo.id));
+        write_line(
+            &mut out,
+            &format!("DTSTAMP:{}", format_date_time(todo.updated_at)),
+        );
+        write_line(
+            &mut out,
+            &format!("CREATED:{}", format_date_time(todo.created_at)),
+        );
+        write_line(
+            &mut out,
+            &format!("LAST-MODIFIED:{}", format_date_time(todo.updated_at)),
+        );
+        write_line(&mut out, &format!("SUMMARY:{}", escape(&todo.title)));
+        if !todo.body.is_empty() {
+            write_line(&mut out, &format!("DESCRIPTION:{}", escape(&todo.body)));
+        }
+        if todo.is_completed {
+            write_line(&mut out, "STATUS:COMPLETED");
+            write_line(
+                &mut out,
+                &format!("COMPLETED:{}", format_date_time(todo.updated_at)),
+            );
+        } else {
+            write_line(&mut out, "STATUS:NEEDS-ACTION");
+        }
+        write_line(&mut out, "END:VTODO");
+    }
+
+    write_line(&mut out, "END:VCALENDAR");
+    out
+}
+
+/// Parses VTODO components of an iCalendar stream, other components are
+/// ignored.
+///
+/// A UID is kept as the todo id when it is an xid, i.e. when the todo was
+/// exported by this service. Foreign UIDs are mapped to an id derived from
+/// them, so that importing a calendar again finds the todos it already
+/// imported instead of duplicating them. Times must be in UTC, as RFC 5545
+/// requires of the properties read here; local times are rejected rather
+/// than shifted.
+pub fn parse(content: &str) -> Result<Vec<ImportTodo>, String> {
+    let mut todos = Vec::new();
+    let mut current: Option<ImportTodo> = None;
+    let mut completed_at = None;
+    let mut depth = 0;
+
+    for (n, line) in unfold(content).iter().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let (name, params, value) =
+            split_content_line(line).ok_or(format!("line {}: malformed content line", n + 1))?;
+
+        match (name.as_str(), value) {
+            ("BEGIN", v) if v.eq_ignore_ascii_case("VTODO") => {
+                current = Some(ImportTodo {
+                    id: String::new(),
+                    title: String::new(),
+                    body: String::new(),
+                    is_completed: false,
+                    created_at: None,
+                    updated_at: None,
+                });
+                completed_at = None;
+            }
+            ("END", v) if v.eq_ignore_ascii_case("VTODO") => {
+                let mut todo = current
+                    .take()
+                    .ok_or(format!("line {}: END:VTODO without BEGIN", n + 1))?;
+                if todo.updated_at.is_none() {
+                    todo.updated_at = completed_at;
+                }
+                todos.push(todo);
+            }
+            ("BEGIN", _) if current.is_some() => depth += 1,
+            ("END", _) if current.is_some() && depth > 0 => depth -= 1,
+            (_, _) if depth > 0 => {}
+            (name, value) => {
+                let todo = match current.as_mut() {
+                    Some(todo) => todo,
+                    None => continue,
+                };
+                match name {
+                    "UID" => todo.id = uid_to_id(value),
+                    "SUMMARY" => todo.title = unescape(value),
+                    "DESCRIPTION" => todo.body = unescape(value),
+                    "STATUS" => todo.is_completed = value.eq_ignore_ascii_case("COMPLETED"),
+                    "COMPLETED" => {
+                        todo.is_completed = true;
+                        completed_at = Some(parse_date_time(name, params, value, n)?);
+                    }
+                    "CREATED" => todo.created_at = Some(parse_date_time(name, params, value, n)?),
+                    "LAST-MODIFIED" => {
+                        todo.updated_at = Some(parse_date_time(name, params, value, n)?)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if current.is_some() {
+        return Err("unterminated VTODO".to_string());
+    }
+
+    Ok(todos)
+}
+
+fn write_line(out: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            out.push_str("\r\n ");
+            octets = 1;
+        }
+        out.push(c);
+        octets += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.chars().next(), lines.last_mut()) {
+            (Some(' '), Some(last)) | (Some('\t'), Some(last)) => last.push_str(&line[1..]),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Splits a content line into its upper-cased name, its parameters without
+/// the leading `;`, and its value. Colons inside quoted parameter values are
+/// skipped.
+fn split_content_line(line: &str) -> Option<(String, &str, &str)> {
+    let mut quoted = false;
+    let mut name_end = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted && name_end.is_none() => name_end = Some(i),
+            ':' if !quoted => {
+                let name = &line[..name_end.unwrap_or(i)];
+                let params = name_end.map(|end| &line[end + 1..i]).unwrap_or("");
+                return Some((name.to_ascii_uppercase(), params, &line[i + 1..]));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn format_date_time(dt: DateTime<Utc>) -> String {
+    dt.format(DATE_TIME_FORMAT).to_string()
+}
+
+/// Parses a DATE-TIME value in UTC. Floating and TZID-qualified times are
+/// rejected, since their offset is not known here.
+fn parse_date_time(
+    name: &str,
+    params: &str,
+    value: &str,
+    line: usize,
+) -> Result<DateTime<Utc>, String> {
+    let has_tzid = params.split(';').any(|param| {
+        param
+            .split('=')
+            .next()
+            .is_some_and(|key| key.trim().eq_ignore_ascii_case("TZID"))
+    });
+    let utc = value.strip_suffix('Z').filter(|_| !has_tzid);
+    match utc.map(|v| NaiveDateTime::parse_from_str(v, "%Y%m%dT%H%M%S")) {
+        Some(Ok(dt)) => Ok(Utc.from_utc_datetime(&dt)),
+        Some(Err(_)) => Err(format!("line {}: invalid date-time {}", line + 1, value)),
+        None => Err(format!(
+            "line {}: {} must be a UTC date-time, not {}",
+            line + 1,
+            name,
+            value
+        )),
+    }
+}
+
+fn is_xid(uid: &str) -> bool {
+    uid.len() == ID_LENGTH && uid.bytes().all(|b| ID_ALPHABET.contains(&b))
+}
+
+/// The todo id of a UID: the UID itself when it is an xid, otherwise 20
+/// characters of base32hex taken from its SHA-256.
+fn uid_to_id(uid: &str) -> String {
+    if is_xid(uid) {
+        return uid.to_string();
+    }
+    let digest = Sha256::digest(uid.as_bytes());
+    // Five bits per character, from the first 100 bits of the digest.
+    (0..ID_LENGTH)
+        .map(|i| {
+            let bit = i * 5;
+            let pair = u16::from(digest[bit / 8]) << 8 | u16::from(digest[bit / 8 + 1]);
+            let index = (pair >> (11 - bit % 8)) & 0x1f;
+            ID_ALPHABET[index as usize] as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn todo(id: &str, title: &str, body: &str, is_completed: bool) -> Todo {
+        Todo {
+            id: id.to_string(),
+            title: title.to_string(),
+            body: body.to_string(),
+            is_completed,
+            created_at: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+            updated_at: Utc.with_ymd_and_hms(2024, 1, 3, 4, 5, 6).unwrap(),
+            comment_count: 0,
+        }
+    }
+
+    fn calendar(properties: &[&str]) -> String {
+        let mut lines = vec!["BEGIN:VCALENDAR", "BEGIN:VTODO"];
+        lines.extend_from_slice(properties);
+        lines.extend_from_slice(&["END:VTODO", "END:VCALENDAR", ""]);
+        lines.join("\r\n")
+    }
+
+    #[test]
+    fn folds_at_75_octets_between_characters() {
+        // Three octets each, so no line can end exactly at 75.
+        let title = "\u{20ac}".repeat(60);
+        let ics = serialize(&[todo("cn9h2s0m8dnt3mr8s0jg", &title, "", false)]);
+
+        for line in ics.split("\r\n") {
+            assert!(line.len() <= MAX_LINE_OCTETS, "{:?}", line);
+        }
+        // "SUMMARY:" and 22 characters take 74 octets, a continuation line
+        // a space and 24 characters.
+        let expected = format!(
+            "SUMMARY:{}\r\n {}\r\n {}\r\n",
+            "\u{20ac}".repeat(22),
+            "\u{20ac}".repeat(24),
+            "\u{20ac}".repeat(14)
+        );
+        assert!(ics.contains(&expected), "{}", ics);
+        assert_eq!(parse(&ics).unwrap()[0].title, title);
+    }
+
+    #[test]
+    fn escapes_text() {
+        let title = "a\\b;c,d";
+        let body = "line one\nline two; with, punctuation";
+        let ics = serialize(&[todo("cn9h2s0m8dnt3mr8s0jg", title, body, false)]);
+
+        assert!(ics.contains("SUMMARY:a\\\\b\\;c\\,d\r\n"), "{}", ics);
+        assert!(
+            ics.contains("DESCRIPTION:line one\\nline two\\; with\\, punctuation\r\n"),
+            "{}",
+            ics
+        );
+        let parsed = parse(&ics).unwrap();
+        assert_eq!(parsed[0].title, title);
+        assert_eq!(parsed[0].body, body);
+        assert_eq!(unescape("a\\Nb\\"), "a\nb\\");
+    }
+
+    #[test]
+    fn round_trips() {
+        let todos = [
+            todo("cn9h2s0m8dnt3mr8s0jg", "Open", "", false),
+            todo("cn9h2s0m8dnt3mr8s0k0", "Done", "notes", true),
+        ];
+        let parsed = parse(&serialize(&todos)).unwrap();
+
+        assert_eq!(parsed.len(), 2);
+        for (todo, parsed) in todos.iter().zip(parsed.iter()) {
+            assert_eq!(parsed.id, todo.id);
+            assert_eq!(parsed.title, todo.title);
+            assert_eq!(parsed.body, todo.body);
+            assert_eq!(parsed.is_completed, todo.is_completed);
+            assert_eq!(parsed.created_at, Some(todo.created_at));
+            assert_eq!(parsed.updated_at, Some(todo.updated_at));
+        }
+    }
+
+    #[test]
+    fn maps_foreign_uids_to_stable_ids() {
+        let id = |uid: &str| {
+            parse(&calendar(&[&format!("UID:{}", uid), "SUMMARY:A"]))
+                .unwrap()
+                .remove(0)
+                .id
+        };
+        let first = id("19970901T130000Z-123401@example.com");
+
+        assert_eq!(first, id("19970901T130000Z-123401@example.com"));
+        assert_ne!(first, id("19970901T130000Z-123402@example.com"));
+        assert!(is_xid(&first), "{}", first);
+        assert_eq!(id("cn9h2s0m8dnt3mr8s0jg"), "cn9h2s0m8dnt3mr8s0jg");
+        assert_eq!(parse(&calendar(&["SUMMARY:A"])).unwrap()[0].id, "");
+    }
+
+    #[test]
+    fn reads_utc_times() {
+        let parsed = parse(&calendar(&[
+            "SUMMARY:A",
+            "CREATED:20240102T030405Z",
+            "COMPLETED;VALUE=DATE-TIME:20240103T040506Z",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            parsed[0].created_at,
+            Some(Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap())
+        );
+        assert_eq!(
+            parsed[0].updated_at,
+            Some(Utc.with_ymd_and_hms(2024, 1, 3, 4, 5, 6).unwrap())
+        );
+        assert!(parsed[0].is_completed);
+    }
+
+    #[test]
+    fn rejects_local_times() {
+        for property in [
+            "CREATED;TZID=Europe/Berlin:20240102T030405",
+            "CREATED;TZID=\"Europe/Berlin\":20240102T030405Z",
+            "LAST-MODIFIED:20240102T030405",
+            "COMPLETED:20240102",
+        ] {
+            let err = parse(&calendar(&["SUMMARY:A", property])).unwrap_err();
+            assert!(
+                err.contains("must be a UTC date-time"),
+                "{}: {}",
+                property,
+                err
+            );
+        }
+        assert!(parse(&calendar(&["CREATED:2024010XT030405Z"]))
+            .unwrap_err()
+            .contains("invalid date-time"));
+    }
+}
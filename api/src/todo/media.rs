@@ -0,0 +1,350 @@
+//! Content negotiation. A response is written in the media type its request
+//! asks for in `Accept`, among those the route produces, and a request body
+//! is read in the one its `Content-Type` names.
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use warp::http::header::{CONTENT_TYPE, VARY};
+use warp::http::Response;
+use warp::hyper::body::Bytes;
+use warp::{reject, Filter, Rejection};
+
+use crate::error::Error::{EncodeError, InvalidRequest, NotAcceptable, UnsupportedMediaType};
+use crate::todo::version::{Version, Versioned};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Media {
+    Json,
+    MsgPack,
+    /// Lists only, one row per item.
+    Csv,
+    /// Proto messages only, the same in every version.
+    Protobuf,
+}
+
+/// What every route produces.
+pub const DOCUMENTS: &[Media] = &[Media::Json, Media::MsgPack];
+/// What routes answering with a list produce.
+pub const LISTS: &[Media] = &[Media::Json, Media::MsgPack, Media::Csv];
+
+impl Media {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Media::Json => "application/json",
+            Media::MsgPack => "application/msgpack",
+            Media::Csv => "text/csv",
+            Media::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    fn parse(essence: &str) -> Option<Media> {
+        match essence {
+            "application/json" => Some(Media::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Media::MsgPack)
+            }
+            "text/csv" => Some(Media::Csv),
+            "application/x-protobuf" | "application/protobuf" => Some(Media::Protobuf),
+            _ => None,
+        }
+    }
+
+    /// How specifically `range` names this media type, `None` if it does
+    /// not: an exact match is more specific than `type/*`, which is more
+    /// specific than `*/*`.
+    fn specificity(self, range: &str) -> Option<u8> {
+        match range.strip_suffix("/*") {
+            Some("*") => Some(0),
+            Some(kind) => Some(1).filter(|_| self.content_type().split('/').next() == Some(kind)),
+            None => Some(2).filter(|_| Media::parse(range) == Some(self)),
+        }
+    }
+
+    /// Writes `value` as it is represented in `version`. Use `reply_message`
+    /// for proto messages, which can also be written as protobuf.
+    pub fn reply<T: Versioned>(
+        self,
+        value: &T,
+        version: Version,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let value = value.to_json(version);
+        let body = match self {
+            Media::Json => serde_json::to_vec(&value).map_err(|e| e.to_string()),
+            Media::MsgPack => rmp_serde::to_vec(&value).map_err(|e| e.to_string()),
+            Media::Csv => csv(&value),
+            Media::Protobuf => Err("only proto messages can be written as protobuf".to_string()),
+        };
+
+        Ok(self.response(body.map_err(|e| reject::custom(EncodeError(e)))?))
+    }
+
+    pub fn reply_message<T: Versioned + prost::Message>(
+        self,
+        message: &T,
+        version: Version,
+    ) -> Result<warp::reply::Response, Rejection> {
+        if self != Media::Protobuf {
+            return self.reply(message, version);
+        }
+        let mut body = Vec::with_capacity(message.encoded_len());
+        message
+            .encode(&mut body)
+            .map_err(|e| reject::custom(EncodeError(e.to_string())))?;
+
+        Ok(self.response(body))
+    }
+
+    fn response(self, body: Vec<u8>) -> warp::reply::Response {
+        Response::builder()
+            .header(CONTENT_TYPE, self.content_type())
+            .header(VARY, "accept")
+            .body(body.into())
+            .expect("failed to build the response")
+    }
+}
+
+/// The media type of `content_type` without its parameters.
+fn essence(content_type: &str) -> String {
+    let essence = content_type.split(';').next().unwrap_or_default();
+    essence.trim().to_ascii_lowercase()
+}
+
+/// Picks the media type to answer in from those a route `produces`, the
+/// first of which is the default. Rejects with `NotAcceptable` when `Accept`
+/// allows none of them.
+pub fn accept(
+    produces: &'static [Media],
+) -> impl Filter<Extract = (Media,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept").and_then(move |accept: Option<String>| async move {
+        let accept = match accept {
+            Some(accept) if !accept.trim().is_empty() => accept,
+            _ => return Ok(produces[0]),
+        };
+        negotiate(&accept, produces).ok_or_else(|| {
+            let produces: Vec<&str> = produces.iter().map(|m| m.content_type()).collect();
+            reject::custom(NotAcceptable(format!(
+                "{} is not available, only {}",
+                accept,
+                produces.join(", ")
+            )))
+        })
+    })
+}
+
+/// Each media type gets the quality of the most specific range that names
+/// it, so `application/json;q=0, */*` excludes JSON. The best quality wins,
+/// and among equals the one named first in `Accept`, then the default.
+fn negotiate(accept: &str, produces: &[Media]) -> Option<Media> {
+    let ranges: Vec<(f32, String)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let essence = essence(params.next()?);
+            let quality = match params.find_map(|p| p.trim().strip_prefix("q=")) {
+                Some(q) => q.trim().parse().ok()?,
+                None => 1.0,
+            };
+            Some((quality, essence))
+        })
+        .collect();
+
+    let mut best: Option<(f32, usize, Media)> = None;
+    for &media in produces {
+        // The most specific range naming `media`, the first among equals.
+        let named = ranges
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (quality, range))| Some((media.specificity(range)?, i, *quality)))
+            .min_by_key(|&(specificity, i, _)| (std::cmp::Reverse(specificity), i));
+        let (position, quality) = match named {
+            Some((_, i, quality)) if quality > 0.0 => (i, quality),
+            _ => continue,
+        };
+        if best.is_none_or(|(q, p, _)| quality > q || (quality == q && position < p)) {
+            best = Some((quality, position, media));
+        }
+    }
+    best.map(|(_, _, media)| media)
+}
+
+/// A request body and the media type it is written in, JSON unless a
+/// `Content-Type` says otherwise.
+pub struct Body {
+    media: Media,
+    bytes: Bytes,
+}
+
+pub fn body() -> impl Filter<Extract = (Body,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::bytes())
+        .and_then(|content_type: Option<String>, bytes: Bytes| async move {
+            let media = match content_type {
+                None => Media::Json,
+                Some(content_type) => Media::parse(&essence(&content_type))
+                    .ok_or_else(|| reject::custom(UnsupportedMediaType(content_type)))?,
+            };
+            Ok::<_, Rejection>(Body { media, bytes })
+        })
+}
+
+/// A body read as `T`, from JSON or MessagePack.
+pub fn decode<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+{
+    body().and_then(|body: Body| async move { body.decode::<T>() })
+}
+
+impl Body {
+    pub fn media(&self) -> Media {
+        self.media
+    }
+
+    /// Reads a JSON or MessagePack body.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, Rejection> {
+        let value = match self.media {
+            Media::Json => serde_json::from_slice(&self.bytes).map_err(|e| e.to_string()),
+            Media::MsgPack => rmp_serde::from_slice(&self.bytes).map_err(|e| e.to_string()),
+            Media::Csv | Media::Protobuf => {
+                return Err(reject::custom(UnsupportedMediaType(
+                    self.media.content_type().to_string(),
+                )))
+            }
+        };
+
+        value.map_err(|e| reject::custom(InvalidRequest(e)))
+    }
+
+    /// Reads a protobuf body.
+    pub fn message<T: prost::Message + Default>(&self) -> Result<T, Rejection> {
+        if self.media != Media::Protobuf {
+            return Err(reject::custom(UnsupportedMediaType(
+                self.media.content_type().to_string(),
+            )));
+        }
+        T::decode(self.bytes.clone()).map_err(|e| reject::custom(InvalidRequest(e.to_string())))
+    }
+}
+
+/// Writes the items of a list, given as an array or as the only array in an
+/// object, one row each. Nested values are written as JSON.
+fn csv(value: &Value) -> Result<Vec<u8>, String> {
+    let items = match value {
+        Value::Array(items) => items,
+        Value::Object(fields) => {
+            let mut arrays = fields.values().filter_map(Value::as_array);
+            match (arrays.next(), arrays.next()) {
+                (Some(items), None) => items,
+                _ => return Err("only lists can be written as CSV".to_string()),
+            }
+        }
+        _ => return Err("only lists can be written as CSV".to_string()),
+    };
+
+    let mut columns: Vec<&str> = Vec::new();
+    for fields in items.iter().filter_map(Value::as_object) {
+        for column in fields.keys() {
+            if !columns.contains(&column.as_str()) {
+                columns.push(column);
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    if !columns.is_empty() {
+        writer.write_record(&columns).map_err(|e| e.to_string())?;
+    }
+    for item in items {
+        let row = columns.iter().map(|column| match item.get(column) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+        });
+        writer.write_record(row).map_err(|e| e.to_string())?;
+    }
+    writer.into_inner().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::todo::fake::{self, FakeTodoService};
+
+    #[test]
+    fn negotiates_by_quality_and_specificity() {
+        assert_eq!(negotiate("*/*", LISTS), Some(Media::Json));
+        assert_eq!(negotiate("text/csv", LISTS), Some(Media::Csv));
+        assert_eq!(negotiate("text/*", LISTS), Some(Media::Csv));
+        assert_eq!(
+            negotiate("application/msgpack, application/json", LISTS),
+            Some(Media::MsgPack)
+        );
+        assert_eq!(
+            negotiate("application/msgpack;q=0.5, application/json", LISTS),
+            Some(Media::Json)
+        );
+        assert_eq!(
+            negotiate("application/json;q=0, */*", LISTS),
+            Some(Media::MsgPack)
+        );
+        assert_eq!(
+            negotiate("*/*;q=0.1, application/*;q=0, text/csv;q=0.2", LISTS),
+            Some(Media::Csv)
+        );
+        assert_eq!(negotiate("application/*;q=0, */*", DOCUMENTS), None);
+        assert_eq!(negotiate("text/csv", DOCUMENTS), None);
+        assert_eq!(negotiate("text/html, image/*", LISTS), None);
+    }
+
+    /// Columns come in the order they are first seen, fields sorted by name
+    /// unless serde_json preserves their order, so these are sorted.
+    #[test]
+    fn writes_lists_as_csv() {
+        let value = json!({ "entries": [
+            { "changes": [{ "field": "title" }], "id": 1 },
+            { "id": 2, "actor": "alice", "note": null },
+        ] });
+        assert_eq!(
+            String::from_utf8(csv(&value).unwrap()).unwrap(),
+            "changes,id,actor,note\n\
+             \"[{\"\"field\"\":\"\"title\"\"}]\",1,,\n\
+             ,2,alice,\n"
+        );
+        assert!(csv(&json!({ "id": 1 })).is_err());
+        assert!(csv(&json!({ "a": [], "b": [] })).is_err());
+        assert_eq!(csv(&json!([])).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn refuses_media_types_it_cannot_handle() {
+        let service = FakeTodoService::default().with_todo("t1", "a", false);
+        let api = fake::rest(fake::serve(service.clone()).await);
+
+        let response = warp::test::request()
+            .path("/v1/todos/t1/comments")
+            .header("accept", "application/json;q=0, */*")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/msgpack");
+
+        let response = warp::test::request()
+            .path("/v1/todos/t1/comments")
+            .header("accept", "text/html")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 406);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/v1/todos/t1/comments")
+            .header("authorization", "Bearer s3cret")
+            .header("content-type", "text/xml")
+            .body("<comment/>")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 415);
+        assert_eq!(service.calls(), vec!["list_comments"]);
+    }
+}
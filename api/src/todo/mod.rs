@@ -1,4 +1,15 @@
+mod attachments;
+pub(crate) mod auth;
+#[cfg(test)]
+pub(crate) mod fake;
+mod formats;
+mod gateway;
+pub(crate) mod graphql;
 mod handlers;
+mod ics;
+mod media;
 mod models;
+pub(crate) mod openapi;
 pub(crate) mod routes;
 pub(crate) mod service;
+pub(crate) mod version;
@@ -1,22 +1,14 @@
+use std::convert::TryFrom;
+
+use async_graphql::SimpleObject;
 use chrono::{DateTime, TimeZone, Utc};
 use serde_derive::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
 use crate::todo::service::todo_service as pb;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct CreateTodo {
-    pub title: String,
-    pub body: String,
-}
-
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct UpdateTodo {
-    pub title: String,
-    pub body: String,
-    pub is_completed: bool,
-}
-
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema, SimpleObject)]
+#[graphql(complex)]
 pub struct Todo {
     pub id: String,
     pub title: String,
@@ -24,6 +16,7 @@ pub struct Todo {
     pub is_completed: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub comment_count: i32,
 }
 
 impl From<pb::Todo> for Todo {
@@ -33,19 +26,48 @@ impl From<pb::Todo> for Todo {
             title: todo.title,
             body: todo.body,
             is_completed: todo.is_completed,
-            created_at: match todo.created_at {
-                Some(v) => chrono::Utc.timestamp(v.seconds, v.nanos as u32),
-                None => chrono::Utc.timestamp(0, 0),
-            },
-            updated_at: match todo.updated_at {
-                Some(v) => chrono::Utc.timestamp(v.seconds, v.nanos as u32),
-                None => chrono::Utc.timestamp(0, 0),
-            },
+            created_at: from_timestamp(todo.created_at),
+            updated_at: from_timestamp(todo.updated_at),
+            comment_count: todo.comment_count,
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl From<Todo> for pb::Todo {
+    fn from(todo: Todo) -> Self {
+        pb::Todo {
+            id: todo.id,
+            title: todo.title,
+            body: todo.body,
+            is_completed: todo.is_completed,
+            created_at: Some(to_timestamp(todo.created_at)),
+            updated_at: Some(to_timestamp(todo.updated_at)),
+            comment_count: todo.comment_count,
+        }
+    }
+}
+
+fn to_timestamp(dt: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: dt.timestamp(),
+        nanos: dt.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// The time of `ts`, or `None` when chrono can not represent it.
+pub(crate) fn to_datetime(ts: prost_types::Timestamp) -> Option<DateTime<Utc>> {
+    u32::try_from(ts.nanos)
+        .ok()
+        .and_then(|nanos| Utc.timestamp_opt(ts.seconds, nanos).single())
+}
+
+/// The time of `ts`, or the epoch when it is missing or out of range.
+fn from_timestamp(ts: Option<prost_types::Timestamp>) -> DateTime<Utc> {
+    ts.and_then(to_datetime)
+        .unwrap_or_else(|| DateTime::<Utc>::from(std::time::UNIX_EPOCH))
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
 pub struct Todos {
     pub todos: Vec<Todo>,
 }
@@ -60,3 +82,211 @@ impl From<pb::Todos> for Todos {
         Todos { todos: v }
     }
 }
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Csv,
+    TodoTxt,
+}
+
+#[derive(Debug, Deserialize, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportParams {
+    pub format: Option<Format>,
+}
+
+#[derive(Debug, Deserialize, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportParams {
+    pub format: Option<Format>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DryRunParams {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct ImportTodo {
+    #[serde(default)]
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub is_completed: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl From<ImportTodo> for pb::Todo {
+    fn from(todo: ImportTodo) -> Self {
+        pb::Todo {
+            id: todo.id,
+            title: todo.title,
+            body: todo.body,
+            is_completed: todo.is_completed,
+            created_at: todo.created_at.map(to_timestamp),
+            updated_at: todo.updated_at.map(to_timestamp),
+            comment_count: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct ImportResult {
+    pub dry_run: bool,
+    pub todos: Vec<Todo>,
+}
+
+pub const DEFAULT_HISTORY_LIMIT: i64 = 50;
+
+#[derive(Debug, Deserialize, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryParams {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema, SimpleObject)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema, SimpleObject)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub todo_id: String,
+    pub actor: String,
+    pub operation: String,
+    pub changes: Vec<FieldChange>,
+    pub undo_state: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<pb::HistoryEntry> for HistoryEntry {
+    fn from(entry: pb::HistoryEntry) -> Self {
+        HistoryEntry {
+            id: entry.id,
+            todo_id: entry.todo_id,
+            actor: entry.actor,
+            operation: entry.operation,
+            changes: entry
+                .changes
+                .into_iter()
+                .map(|c| FieldChange {
+                    field: c.field,
+                    before: c.before,
+                    after: c.after,
+                })
+                .collect(),
+            undo_state: entry.undo_state,
+            created_at: from_timestamp(entry.created_at),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct History {
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl From<pb::History> for History {
+    fn from(history: pb::History) -> Self {
+        History {
+            entries: history
+                .entries
+                .into_iter()
+                .map(HistoryEntry::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct AddComment {
+    pub body: String,
+    pub parent_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct EditComment {
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema, SimpleObject)]
+pub struct Comment {
+    pub id: String,
+    pub todo_id: String,
+    pub parent_id: Option<String>,
+    pub author: String,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+impl From<pb::Comment> for Comment {
+    fn from(comment: pb::Comment) -> Self {
+        Comment {
+            id: comment.id,
+            todo_id: comment.todo_id,
+            parent_id: Some(comment.parent_id).filter(|id| !id.is_empty()),
+            author: comment.author,
+            body: comment.body,
+            created_at: from_timestamp(comment.created_at),
+            edited_at: comment.edited_at.and_then(to_datetime),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct Comments {
+    pub comments: Vec<Comment>,
+}
+
+impl From<pb::Comments> for Comments {
+    fn from(comments: pb::Comments) -> Self {
+        Comments {
+            comments: comments.comments.into_iter().map(Comment::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema, SimpleObject)]
+pub struct Attachment {
+    pub id: String,
+    pub todo_id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<pb::Attachment> for Attachment {
+    fn from(attachment: pb::Attachment) -> Self {
+        Attachment {
+            id: attachment.id,
+            todo_id: attachment.todo_id,
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            size: attachment.size,
+            created_at: from_timestamp(attachment.created_at),
+        }
+    }
+}
+
+/// The multipart form an attachment is uploaded in.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct AttachmentUpload {
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{Ref, RefOr};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::Config;
+use warp::http::{Response, StatusCode, Uri};
+use warp::path::{FullPath, Tail};
+use warp::Filter;
+
+use crate::error::{FieldError, Problem};
+use crate::todo::gateway::GatewayDoc;
+use crate::todo::handlers;
+
+const SPEC_PATH: &str = "/openapi.json";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "todo", description = "REST gateway to the todo service"),
+    servers(
+        (url = "/v1"),
+        (url = "/v2", description = "Todos have a status, open or completed, in place of is_completed"),
+    ),
+    paths(
+        handlers::export_todos,
+        handlers::import_todos,
+        handlers::export_ics,
+        handlers::import_ics,
+        handlers::get_history,
+        handlers::list_comments,
+        handlers::add_comment,
+        handlers::edit_comment,
+        handlers::upload_attachment,
+        handlers::download_attachment,
+    ),
+    components(schemas(Problem, FieldError))
+)]
+struct ApiDoc;
+
+/// Documents both the hand-written and the generated routes.
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    openapi.merge(GatewayDoc::openapi());
+    ErrorResponses.modify(&mut openapi);
+    Authentication.modify(&mut openapi);
+    openapi
+}
+
+/// Every route reports errors the same way, see `error::with_problems`.
+struct ErrorResponses;
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let response = utoipa::openapi::ResponseBuilder::new()
+            .description("The request failed")
+            .content(
+                "application/problem+json",
+                utoipa::openapi::Content::new(Some(Ref::from_schema_name("Problem"))),
+            )
+            .build();
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .responses
+            .insert("Error".to_string(), RefOr::T(response));
+
+        for item in openapi.paths.paths.values_mut() {
+            let operations = vec![
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.delete,
+                &mut item.options,
+                &mut item.head,
+                &mut item.patch,
+                &mut item.trace,
+            ];
+            for operation in operations.into_iter().flatten() {
+                operation.responses.responses.insert(
+                    "default".to_string(),
+                    RefOr::Ref(Ref::from_response_name("Error")),
+                );
+            }
+        }
+    }
+}
+
+/// Every route takes an optional bearer token, see `auth::with_actor`.
+struct Authentication;
+
+impl Modify for Authentication {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        openapi.security = Some(vec![
+            SecurityRequirement::default(),
+            SecurityRequirement::new("bearer", Vec::<String>::new()),
+        ]);
+    }
+}
+
+/// Serves the OpenAPI document and a Swagger UI for it at /docs.
+pub fn docs_filter() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let spec = openapi()
+        .to_json()
+        .expect("failed to serialize the OpenAPI document");
+    let openapi = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(move || warp::reply::with_header(spec.clone(), "content-type", "application/json"));
+
+    let config = Arc::new(Config::from(SPEC_PATH));
+    let ui = warp::path("docs")
+        .and(warp::get())
+        .and(warp::path::full())
+        .and(warp::path::tail())
+        .map(move |full: FullPath, tail: Tail| -> Box<dyn warp::Reply> {
+            // The page refers to its assets relative to the directory.
+            if full.as_str() == "/docs" {
+                return Box::new(warp::redirect::temporary(Uri::from_static("/docs/")));
+            }
+            Box::new(swagger_file(tail.as_str(), config.clone()))
+        });
+
+    openapi.or(ui)
+}
+
+fn swagger_file(path: &str, config: Arc<Config<'static>>) -> Response<Vec<u8>> {
+    let response = Response::builder();
+    match utoipa_swagger_ui::serve(path, config) {
+        Ok(Some(file)) => response
+            .header("content-type", file.content_type)
+            .body(file.bytes.into_owned()),
+        Ok(None) => response.status(StatusCode::NOT_FOUND).body(Vec::new()),
+        Err(e) => response
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(e.to_string().into_bytes()),
+    }
+    .expect("failed to build the response")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::openapi;
+
+    /// Reads the method and path of every `warp::path!` route, hand-written
+    /// or generated, with parameters written as `{}`.
+    fn routes() -> BTreeSet<(String, String)> {
+        let sources = [
+            include_str!("routes.rs"),
+            include_str!(concat!(env!("OUT_DIR"), "/gateway.rs")),
+        ];
+        let mut routes = BTreeSet::new();
+        for route in sources.iter().flat_map(|s| s.split("warp::path!(").skip(1)) {
+            let (segments, rest) = route.split_once(')').unwrap();
+            let path: String = segments
+                .split('/')
+                .map(|segment| {
+                    let segment = segment.trim();
+                    match segment.strip_prefix('"') {
+                        Some(literal) => format!("/{}", literal.trim_end_matches('"')),
+                        None => "/{}".to_string(),
+                    }
+                })
+                .collect();
+            let method = ["get", "post", "put", "delete", "patch"]
+                .iter()
+                .filter_map(|m| rest.find(&format!("warp::{}()", m)).map(|at| (at, m)))
+                .min()
+                .map(|(_, m)| m.to_string())
+                .unwrap_or_else(|| panic!("no method for route {}", path));
+            routes.insert((method, path));
+        }
+        routes
+    }
+
+    fn documented() -> BTreeSet<(String, String)> {
+        let doc = serde_json::to_value(openapi()).unwrap();
+        let mut documented = BTreeSet::new();
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            let path: String = path
+                .split('/')
+                .skip(1)
+                .map(|segment| {
+                    if segment.starts_with('{') {
+                        "/{}".to_string()
+                    } else {
+                        format!("/{}", segment)
+                    }
+                })
+                .collect();
+            for method in item.as_object().unwrap().keys() {
+                documented.insert((method.clone(), path.clone()));
+            }
+        }
+        documented
+    }
+
+    /// Every operation refers to the `Error` response for its problems.
+    #[test]
+    fn every_operation_has_the_error_response() {
+        let doc = serde_json::to_value(openapi()).unwrap();
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            for (method, operation) in item.as_object().unwrap() {
+                assert_eq!(
+                    operation["responses"]["default"]["$ref"], "#/components/responses/Error",
+                    "{} {}",
+                    method, path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let routes = routes();
+        assert!(!routes.is_empty());
+        let documented = documented();
+
+        let undocumented: Vec<_> = routes.difference(&documented).collect();
+        assert!(
+            undocumented.is_empty(),
+            "undocumented routes: {:?}",
+            undocumented
+        );
+        let unrouted: Vec<_> = documented.difference(&routes).collect();
+        assert!(
+            unrouted.is_empty(),
+            "documented but not routed: {:?}",
+            unrouted
+        );
+    }
+}
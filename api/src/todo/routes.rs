@@ -1,101 +1,179 @@
 use tonic::transport::Channel;
 use warp::Filter;
 
+use crate::todo::auth::{self, Actors};
+use crate::todo::gateway;
 use crate::todo::handlers;
+use crate::todo::media;
 use crate::todo::models;
 use crate::todo::service::todo_service::todo_service_client::TodoServiceClient;
+use crate::todo::version::Version;
 
 #[derive(Clone)]
 pub(crate) struct Server {
     pub logger: slog::Logger,
     pub todo_client: TodoServiceClient<Channel>,
+    pub max_upload_size: u64,
+    pub version: Version,
+    pub actors: Actors,
 }
 
 pub fn todo_filter(
     logger: slog::Logger,
     client: TodoServiceClient<Channel>,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    max_upload_size: u64,
+    version: Version,
+    actors: Actors,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let server = Server {
         logger,
         todo_client: client,
+        max_upload_size,
+        version,
+        actors,
     };
 
-    list_todos(server.clone())
-        .or(get_todo(server.clone()))
-        .or(create_todo(server.clone()))
-        .or(update_todo(server.clone()))
-        .or(delete_todo(server.clone()))
-        .or(complete_todo(server.clone()))
+    export_todos(server.clone())
+        .or(import_todos(server.clone()))
+        .or(export_ics(server.clone()))
+        .or(import_ics(server.clone()))
+        .or(get_history(server.clone()))
+        .or(list_comments(server.clone()))
+        .or(add_comment(server.clone()))
+        .or(edit_comment(server.clone()))
+        .or(upload_attachment(server.clone()))
+        .or(download_attachment(server.clone()))
+        .or(gateway::gateway_filter(server))
 }
 
-fn list_todos(
+fn export_todos(
     server: Server,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("todos")
+    warp::path!("todos" / "export")
         .and(warp::get())
+        .and(warp::query::<models::ExportParams>())
         .and(with_server(server))
-        .and_then(handlers::list_todos)
+        .and_then(handlers::export_todos)
 }
 
-fn create_todo(
+fn import_todos(
     server: Server,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("todos")
+    warp::path!("todos" / "import")
         .and(warp::post())
-        .and(json_create_body())
+        .and(warp::query::<models::ImportParams>())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(1024 * 1024 * 4))
+        .and(warp::body::bytes())
+        .and(auth::with_actor(server.actors.clone()))
+        .and(idempotency_key())
+        .and(media::accept(media::DOCUMENTS))
         .and(with_server(server))
-        .and_then(handlers::create_todo)
+        .and_then(handlers::import_todos)
 }
 
-fn get_todo(
+fn export_ics(
     server: Server,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("todos" / String)
+    warp::path!("todos.ics")
         .and(warp::get())
         .and(with_server(server))
-        .and_then(handlers::get_todo)
+        .and_then(handlers::export_ics)
 }
 
-fn update_todo(
+fn import_ics(
     server: Server,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("todos" / String)
-        .and(warp::put())
-        .and(json_update_body())
+    warp::path!("todos" / "import" / "ics")
+        .and(warp::post())
+        .and(warp::query::<models::DryRunParams>())
+        .and(warp::body::content_length_limit(1024 * 1024 * 4))
+        .and(warp::body::bytes())
+        .and(auth::with_actor(server.actors.clone()))
+        .and(idempotency_key())
+        .and(media::accept(media::DOCUMENTS))
         .and(with_server(server))
-        .and_then(handlers::update_todo)
+        .and_then(handlers::import_ics)
 }
 
-fn delete_todo(
+fn get_history(
     server: Server,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("todos" / String)
-        .and(warp::delete())
+    warp::path!("todos" / String / "history")
+        .and(warp::get())
+        .and(warp::query::<models::HistoryParams>())
+        .and(media::accept(media::LISTS))
         .and(with_server(server))
-        .and_then(handlers::delete_todo)
+        .and_then(handlers::get_history)
 }
 
-fn complete_todo(
+fn list_comments(
     server: Server,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("todos" / String / "complete")
+    warp::path!("todos" / String / "comments")
+        .and(warp::get())
+        .and(media::accept(media::LISTS))
+        .and(with_server(server))
+        .and_then(handlers::list_comments)
+}
+
+fn add_comment(
+    server: Server,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / String / "comments")
         .and(warp::post())
+        .and(media::decode::<models::AddComment>())
+        .and(auth::with_actor(server.actors.clone()))
+        .and(idempotency_key())
+        .and(media::accept(media::DOCUMENTS))
         .and(with_server(server))
-        .and_then(handlers::complete_todo)
+        .and_then(handlers::add_comment)
 }
 
-fn with_server(
+fn edit_comment(
     server: Server,
-) -> impl Filter<Extract = (Server,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || server.clone())
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / String / "comments" / String)
+        .and(warp::put())
+        .and(media::decode::<models::EditComment>())
+        .and(auth::with_actor(server.actors.clone()))
+        .and(idempotency_key())
+        .and(media::accept(media::DOCUMENTS))
+        .and(with_server(server))
+        .and_then(handlers::edit_comment)
 }
 
-fn json_create_body(
-) -> impl Filter<Extract = (models::CreateTodo,), Error = warp::Rejection> + Clone {
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+fn upload_attachment(
+    server: Server,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / String / "attachments")
+        .and(warp::post())
+        .and(warp::multipart::form().max_length(server.max_upload_size))
+        .and(auth::with_actor(server.actors.clone()))
+        .and(idempotency_key())
+        .and(media::accept(media::DOCUMENTS))
+        .and(with_server(server))
+        .and_then(handlers::upload_attachment)
 }
 
-fn json_update_body(
-) -> impl Filter<Extract = (models::UpdateTodo,), Error = warp::Rejection> + Clone {
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+fn download_attachment(
+    server: Server,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / String / "attachments" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("range"))
+        .and(with_server(server))
+        .and_then(handlers::download_attachment)
+}
+
+/// The key of a request that may be repeated, passed on as its `request_id`.
+pub(crate) fn idempotency_key(
+) -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("idempotency-key")
+}
+
+pub(crate) fn with_server(
+    server: Server,
+) -> impl Filter<Extract = (Server,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || server.clone())
 }
@@ -1,3 +1,7 @@
 pub mod todo_service {
     tonic::include_proto!("todo");
 }
+
+pub mod rpc {
+    tonic::include_proto!("google.rpc");
+}
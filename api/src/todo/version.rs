@@ -0,0 +1,401 @@
+//! Versions of the REST API. Every version is served by the same routes and
+//! handlers, which work with the JSON models of v1; those follow the proto.
+//! A later version differs only in how models are represented, which
+//! `Versioned` converts to and from.
+
+use std::convert::Infallible;
+
+use chrono::NaiveDate;
+use serde::de::{DeserializeOwned, Error as _};
+use serde::Serialize;
+use serde_json::Value;
+use tonic::transport::Channel;
+use warp::http::{HeaderMap, HeaderValue};
+use warp::path::FullPath;
+use warp::{reject, Filter};
+
+use crate::error::WithHeaders;
+
+use crate::todo::auth::Actors;
+use crate::todo::models;
+use crate::todo::routes::todo_filter;
+use crate::todo::service::todo_service as pb;
+use crate::todo::service::todo_service::todo_service_client::TodoServiceClient;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    V1,
+    /// Todos have a `status` instead of `is_completed`.
+    V2,
+}
+
+impl Version {
+    pub fn decode<T: Versioned>(self, value: Value) -> Result<T, serde_json::Error> {
+        T::from_json(value, self)
+    }
+}
+
+/// A model as it is represented in each version. Models that have not
+/// changed since v1 use the provided methods.
+pub trait Versioned: Serialize + DeserializeOwned {
+    fn to_json(&self, _version: Version) -> Value {
+        serde_json::to_value(self).expect("failed to serialize a model")
+    }
+
+    fn from_json(value: Value, _version: Version) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+}
+
+/// Serves the routes under /v1 and /v2. The unversioned routes of before are
+/// kept as aliases of v1 and point to it, from their problems too. They send
+/// a `Deprecation` header from when `deprecation` is set and a `Sunset`
+/// header when `sunset` is, dates a deployment has to decide on.
+pub fn versioned_filter(
+    logger: slog::Logger,
+    client: TodoServiceClient<Channel>,
+    max_upload_size: u64,
+    deprecation: Option<NaiveDate>,
+    sunset: Option<NaiveDate>,
+    actors: Actors,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let filter = |version| {
+        todo_filter(
+            logger.clone(),
+            client.clone(),
+            max_upload_size,
+            version,
+            actors.clone(),
+        )
+    };
+    let v1 = warp::path("v1").and(filter(Version::V1));
+    let v2 = warp::path("v2").and(filter(Version::V2));
+
+    let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let deprecation = deprecation.map(|date| {
+        let value = format!("@{}", midnight(date).timestamp());
+        HeaderValue::from_str(&value).expect("a timestamp is a valid header")
+    });
+    let sunset = sunset.map(|date| {
+        let value = midnight(date)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        HeaderValue::from_str(&value).expect("a date is a valid header")
+    });
+    let deprecated = move |path: &FullPath| {
+        let mut headers = HeaderMap::new();
+        let successor = format!("</v1{}>; rel=\"successor-version\"", path.as_str());
+        if let Ok(successor) = HeaderValue::from_str(&successor) {
+            headers.insert("link", successor);
+        }
+        if let Some(deprecation) = &deprecation {
+            headers.insert("deprecation", deprecation.clone());
+        }
+        if let Some(sunset) = &sunset {
+            headers.insert("sunset", sunset.clone());
+        }
+        headers
+    };
+    // Rejections carry the headers too, for the problem they are answered
+    // with. Those of requests no route matched are left to the other routes.
+    let outcome = filter(Version::V1)
+        .map(|reply| Ok(warp::Reply::into_response(reply)))
+        .or_else(|err| async move { Ok::<_, Infallible>((Err(err),)) });
+    let unversioned = warp::path::full().and(outcome).and_then(
+        move |path: FullPath, outcome: Result<warp::reply::Response, warp::Rejection>| {
+            let headers = deprecated(&path);
+            async move {
+                match outcome {
+                    Ok(mut response) => {
+                        response.headers_mut().extend(headers);
+                        Ok(response)
+                    }
+                    Err(rejection) if rejection.is_not_found() => Err(rejection),
+                    Err(rejection) => Err(reject::custom(WithHeaders { headers, rejection })),
+                }
+            }
+        },
+    );
+
+    v1.or(v2).or(unversioned)
+}
+
+/// In v2 a todo has a `status`, "open" or "completed", in place of
+/// `is_completed`.
+fn todo_to_v2(mut todo: Value) -> Value {
+    if let Value::Object(fields) = &mut todo {
+        let completed = fields.remove("is_completed").and_then(|v| v.as_bool());
+        let status = if completed == Some(true) {
+            "completed"
+        } else {
+            "open"
+        };
+        fields.insert("status".to_string(), status.into());
+    }
+    todo
+}
+
+/// Whether a todo with `status` is completed, `None` for an unknown status.
+pub(crate) fn completed(status: &str) -> Option<bool> {
+    match status {
+        "open" => Some(false),
+        "completed" => Some(true),
+        _ => None,
+    }
+}
+
+/// A todo without a `status` is left without `is_completed`, which is
+/// rejected where it is required.
+fn todo_from_v2(mut todo: Value) -> Result<Value, serde_json::Error> {
+    if let Value::Object(fields) = &mut todo {
+        let status = match fields.remove("status") {
+            None => return Ok(todo),
+            Some(status) => status,
+        };
+        let completed = status.as_str().and_then(completed).ok_or_else(|| {
+            serde_json::Error::custom(format!(
+                "invalid status {}, expected \"open\" or \"completed\"",
+                status
+            ))
+        })?;
+        fields.insert("is_completed".to_string(), completed.into());
+    }
+    Ok(todo)
+}
+
+/// Represents the todos in `field` of `value` in `version`.
+fn todos_to<T: Versioned>(mut value: Value, field: &str, todos: &[T], version: Version) -> Value {
+    value[field] = todos.iter().map(|todo| todo.to_json(version)).collect();
+    value
+}
+
+impl Versioned for pb::Todo {
+    fn to_json(&self, version: Version) -> Value {
+        let todo = serde_json::to_value(self).expect("failed to serialize a todo");
+        if version >= Version::V2 {
+            return todo_to_v2(todo);
+        }
+        todo
+    }
+}
+
+impl Versioned for pb::Todos {
+    fn to_json(&self, version: Version) -> Value {
+        let value = serde_json::to_value(self).expect("failed to serialize todos");
+        todos_to(value, "todos", &self.todos, version)
+    }
+}
+
+impl Versioned for pb::UpdateRequest {
+    fn from_json(mut value: Value, version: Version) -> Result<Self, serde_json::Error> {
+        if version >= Version::V2 {
+            value = todo_from_v2(value)?;
+        }
+        serde_json::from_value(value)
+    }
+}
+
+impl Versioned for models::Todo {
+    fn to_json(&self, version: Version) -> Value {
+        let todo = serde_json::to_value(self).expect("failed to serialize a todo");
+        if version >= Version::V2 {
+            return todo_to_v2(todo);
+        }
+        todo
+    }
+}
+
+impl Versioned for models::ImportTodo {
+    fn from_json(mut value: Value, version: Version) -> Result<Self, serde_json::Error> {
+        if version >= Version::V2 {
+            value = todo_from_v2(value)?;
+        }
+        serde_json::from_value(value)
+    }
+}
+
+impl Versioned for models::ImportResult {
+    fn to_json(&self, version: Version) -> Value {
+        let value = serde_json::to_value(self).expect("failed to serialize an import");
+        todos_to(value, "todos", &self.todos, version)
+    }
+}
+
+impl Versioned for models::History {}
+impl Versioned for models::Comments {}
+impl Versioned for models::Comment {}
+impl Versioned for models::Attachment {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::logger;
+    use crate::todo::fake::{self, FakeTodoService};
+
+    #[test]
+    fn todos_have_a_status_in_v2() {
+        let todo = json!({ "id": "t1", "is_completed": true });
+        assert_eq!(
+            todo_to_v2(todo),
+            json!({ "id": "t1", "status": "completed" })
+        );
+        let todo = json!({ "id": "t1", "is_completed": false });
+        assert_eq!(todo_to_v2(todo), json!({ "id": "t1", "status": "open" }));
+
+        let todo = json!({ "id": "t1", "status": "completed" });
+        assert_eq!(
+            todo_from_v2(todo).unwrap(),
+            json!({ "id": "t1", "is_completed": true })
+        );
+        let todo = json!({ "id": "t1", "status": "open" });
+        assert_eq!(
+            todo_from_v2(todo).unwrap(),
+            json!({ "id": "t1", "is_completed": false })
+        );
+        assert!(todo_from_v2(json!({ "status": "done" })).is_err());
+        assert!(todo_from_v2(json!({ "status": true })).is_err());
+        assert_eq!(
+            todo_from_v2(json!({ "title": "a" })).unwrap(),
+            json!({ "title": "a" })
+        );
+    }
+
+    #[tokio::test]
+    async fn unversioned_routes_are_deprecated() {
+        let service = FakeTodoService::default().with_todo("t1", "a", true);
+        let api = fake::rest(fake::serve(service).await);
+
+        let response = warp::test::request().path("/todos?x=1").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        let headers = response.headers();
+        assert_eq!(headers["deprecation"], "@1792368000");
+        assert_eq!(headers["sunset"], "Mon, 19 Apr 2027 00:00:00 GMT");
+        assert_eq!(headers["link"], "</v1/todos>; rel=\"successor-version\"");
+
+        for path in ["/v1/todos", "/v2/todos"].iter() {
+            let response = warp::test::request().path(path).reply(&api).await;
+            assert_eq!(response.status(), 200);
+            assert!(response.headers().get("deprecation").is_none());
+            assert!(response.headers().get("sunset").is_none());
+        }
+    }
+
+    /// The problems of the unversioned routes are sent with the same headers.
+    #[tokio::test]
+    async fn unversioned_problems_are_deprecated() {
+        let service = FakeTodoService::default();
+        let api = fake::rest(fake::serve(service).await);
+
+        let missing = warp::test::request().path("/todos/t9").reply(&api).await;
+        let invalid = warp::test::request()
+            .method("POST")
+            .path("/todos")
+            .header("authorization", "Bearer s3cret")
+            .json(&json!({ "title": " ", "body": "b" }))
+            .reply(&api)
+            .await;
+        for (response, status, path) in
+            [(missing, 404, "/todos/t9"), (invalid, 422, "/todos")].iter()
+        {
+            assert_eq!(response.status(), *status);
+            let headers = response.headers();
+            assert_eq!(headers["content-type"], "application/problem+json");
+            assert_eq!(headers["deprecation"], "@1792368000");
+            assert_eq!(headers["sunset"], "Mon, 19 Apr 2027 00:00:00 GMT");
+            assert_eq!(
+                headers["link"],
+                format!("</v1{}>; rel=\"successor-version\"", path)
+            );
+        }
+
+        let response = warp::test::request().path("/v1/todos/t9").reply(&api).await;
+        assert_eq!(response.status(), 404);
+        assert!(response.headers().get("deprecation").is_none());
+        let response = warp::test::request().path("/nowhere").reply(&api).await;
+        assert_eq!(response.status(), 404);
+        assert!(response.headers().get("deprecation").is_none());
+    }
+
+    /// Without dates the unversioned routes only point to v1.
+    #[tokio::test]
+    async fn dates_are_sent_only_when_set() {
+        let service = FakeTodoService::default().with_todo("t1", "a", true);
+        let client = fake::serve(service).await;
+        let actors = Actors::parse("alice=s3cret").unwrap();
+        let api = versioned_filter(logger(), client, 1024, None, None, actors);
+
+        let response = warp::test::request().path("/todos").reply(&api).await;
+        assert_eq!(response.status(), 200);
+        let headers = response.headers();
+        assert!(headers.get("deprecation").is_none());
+        assert!(headers.get("sunset").is_none());
+        assert_eq!(headers["link"], "</v1/todos>; rel=\"successor-version\"");
+    }
+
+    #[tokio::test]
+    async fn exports_and_imports_todos_of_their_version() {
+        let service = FakeTodoService::default().with_todo("t1", "a", true);
+        let api = fake::rest(fake::serve(service.clone()).await);
+
+        let response = warp::test::request()
+            .path("/v2/todos/export")
+            .reply(&api)
+            .await;
+        let todos: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(todos[0]["status"], "completed");
+        assert!(todos[0].get("is_completed").is_none());
+
+        let response = warp::test::request()
+            .path("/v2/todos/export?format=csv")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            std::str::from_utf8(response.body()).unwrap(),
+            "id,title,body,status,created_at,updated_at,comment_count\n\
+             t1,a,body,completed,2020-09-13T12:26:40Z,2020-09-13T12:26:40Z,0\n"
+        );
+        let response = warp::test::request()
+            .path("/v1/todos/export?format=csv")
+            .reply(&api)
+            .await;
+        assert!(std::str::from_utf8(response.body())
+            .unwrap()
+            .contains("t1,a,body,true,"));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/v2/todos/import")
+            .header("authorization", "Bearer s3cret")
+            .json(&json!([{ "title": "b", "status": "completed" }]))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        let result: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(result["todos"][0]["status"], "completed");
+        assert!(service.todo("t2").unwrap().is_completed);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/v2/todos/import")
+            .header("authorization", "Bearer s3cret")
+            .header("content-type", "text/csv")
+            .body("title,status\nc,open\nd,completed\n")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert!(!service.todo("t3").unwrap().is_completed);
+        assert!(service.todo("t4").unwrap().is_completed);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/v2/todos/import")
+            .header("authorization", "Bearer s3cret")
+            .header("content-type", "text/csv")
+            .body("title,status\ne,done\n")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), 422);
+    }
+}
@@ -1,4 +1,14 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../proto/todo.proto")?;
+    tonic_build::configure().compile(
+        &[
+            "../proto/todo.proto",
+            "../proto/google/rpc/status.proto",
+            "../proto/google/rpc/error_details.proto",
+        ],
+        &["../proto"],
+    )?;
+    // Migrations are embedded with `sqlx::migrate!`.
+    println!("cargo:rerun-if-changed=../proto");
+    println!("cargo:rerun-if-changed=migrations");
     Ok(())
 }
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS todo_history
+(
+    id BIGSERIAL PRIMARY KEY,
+    todo_id VARCHAR(20) NOT NULL,
+    actor VARCHAR(255) NOT NULL,
+    operation VARCHAR(16) NOT NULL,
+    changes JSONB NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS todo_history_todo_id_idx ON todo_history (todo_id, id);
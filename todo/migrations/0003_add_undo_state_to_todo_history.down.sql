@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS todo_history_actor_idx;
+
+ALTER TABLE todo_history DROP COLUMN IF EXISTS undo_state;
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS comments;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS comment_count;
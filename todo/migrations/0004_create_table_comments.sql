@@ -0,0 +1,14 @@
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS comment_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS comments
+(
+    id VARCHAR(20) PRIMARY KEY,
+    todo_id VARCHAR(20) NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    parent_id VARCHAR(20) REFERENCES comments (id) ON DELETE CASCADE,
+    author VARCHAR(255) NOT NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    edited_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS comments_todo_id_idx ON comments (todo_id, created_at);
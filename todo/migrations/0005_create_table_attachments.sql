@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS attachments
+(
+    id VARCHAR(20) PRIMARY KEY,
+    todo_id VARCHAR(20) NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    filename VARCHAR(255) NOT NULL,
+    content_type VARCHAR(255) NOT NULL,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS attachments_todo_id_idx ON attachments (todo_id, created_at);
@@ -0,0 +1 @@
+ALTER TABLE idempotency_keys ADD COLUMN IF NOT EXISTS applied BOOLEAN NOT NULL DEFAULT FALSE;
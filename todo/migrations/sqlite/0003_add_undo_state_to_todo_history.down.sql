@@ -0,0 +1,20 @@
+DROP INDEX IF EXISTS todo_history_actor_idx;
+
+CREATE TABLE todo_history_new
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    todo_id VARCHAR(20) NOT NULL,
+    actor VARCHAR(255) NOT NULL,
+    operation VARCHAR(16) NOT NULL,
+    changes TEXT NOT NULL,
+    created_at DATETIME
+);
+
+INSERT INTO todo_history_new (id, todo_id, actor, operation, changes, created_at)
+SELECT id, todo_id, actor, operation, changes, created_at FROM todo_history;
+
+DROP TABLE todo_history;
+
+ALTER TABLE todo_history_new RENAME TO todo_history;
+
+CREATE INDEX IF NOT EXISTS todo_history_todo_id_idx ON todo_history (todo_id, id);
@@ -0,0 +1,3 @@
+ALTER TABLE todo_history ADD COLUMN undo_state VARCHAR(16) NOT NULL DEFAULT 'active';
+
+CREATE INDEX IF NOT EXISTS todo_history_actor_idx ON todo_history (actor, undo_state, id);
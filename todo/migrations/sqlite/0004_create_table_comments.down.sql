@@ -0,0 +1,18 @@
+DROP TABLE IF EXISTS comments;
+
+CREATE TABLE todos_new
+(
+    id VARCHAR(20) PRIMARY KEY,
+    title VARCHAR(255) NOT NULL,
+    body TEXT,
+    is_completed BOOLEAN DEFAULT FALSE,
+    created_at DATETIME,
+    updated_at DATETIME
+);
+
+INSERT INTO todos_new (id, title, body, is_completed, created_at, updated_at)
+SELECT id, title, body, is_completed, created_at, updated_at FROM todos;
+
+DROP TABLE todos;
+
+ALTER TABLE todos_new RENAME TO todos;
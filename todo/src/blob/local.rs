@@ -0,0 +1,134 @@
+use std::io::{self, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::blob::store::{BlobStore, ByteStream};
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Keeps every blob in a file below the root directory.
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub async fn new(root: impl Into<PathBuf>) -> io::Result<LocalBlobStore> {
+        let root = root.into();
+        fs::create_dir_all(&root).await?;
+        Ok(LocalBlobStore { root })
+    }
+
+    /// The file of `key`, which must be a relative path below the root. An
+    /// empty key would be the root itself.
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let key = Path::new(key);
+        if key.as_os_str().is_empty()
+            || !key.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid blob key {}", key.display()),
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, data: ByteStream) -> io::Result<u64> {
+        let path = self.path(key)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        // The content is written next to its final location and moved in
+        // place once complete, so readers never see a partial blob.
+        let partial = path.with_extension("part");
+        match write(&partial, data).await {
+            Ok(size) => {
+                fs::rename(&partial, &path).await?;
+                Ok(size)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&partial).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn get(&self, key: &str, offset: u64, length: Option<u64>) -> io::Result<ByteStream> {
+        let mut file = File::open(self.path(key)?).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let remaining = length.unwrap_or(u64::MAX);
+        let chunks = stream::unfold((file, remaining), |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buf = vec![0; CHUNK_SIZE.min(remaining) as usize];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(buf), (file, remaining - n as u64)))
+                }
+                Err(e) => Some((Err(e), (file, 0))),
+            }
+        });
+
+        Ok(chunks.boxed())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> io::Result<()> {
+        match fs::remove_dir_all(self.path(prefix)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+async fn write(path: &Path, mut data: ByteStream) -> io::Result<u64> {
+    let mut file = File::create(path).await?;
+    let mut size = 0;
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+    file.sync_all().await?;
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_stay_below_the_root() {
+        let store = LocalBlobStore {
+            root: PathBuf::from("/var/blobs"),
+        };
+
+        assert_eq!(
+            store.path("todo/attachment").unwrap(),
+            PathBuf::from("/var/blobs/todo/attachment")
+        );
+        for key in &["", "/", "/etc/passwd", "..", "todo/../..", "./todo"] {
+            let err = store.path(key).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", key);
+        }
+    }
+}
@@ -0,0 +1,2 @@
+pub(crate) mod local;
+pub(crate) mod store;
@@ -0,0 +1,49 @@
+use std::io;
+
+use async_trait::async_trait;
+use config::{Config, Environment};
+use futures::stream::BoxStream;
+
+use crate::blob::local::LocalBlobStore;
+
+pub type ByteStream = BoxStream<'static, io::Result<Vec<u8>>>;
+
+/// Stores attachment contents. Keys are `/`-separated paths, which maps
+/// directly onto both file systems and S3-compatible object stores.
+#[async_trait]
+pub trait BlobStore {
+    /// Writes the stream under `key` and returns the number of bytes written.
+    /// When the stream fails nothing is left behind.
+    async fn put(&self, key: &str, data: ByteStream) -> io::Result<u64>;
+    /// Reads `length` bytes starting at `offset`, or everything after
+    /// `offset` when no length is given.
+    async fn get(&self, key: &str, offset: u64, length: Option<u64>) -> io::Result<ByteStream>;
+    async fn delete(&self, key: &str) -> io::Result<()>;
+    /// Removes every blob whose key starts with `prefix/`.
+    async fn delete_prefix(&self, prefix: &str) -> io::Result<()>;
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LocalBlobSettings {
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub enum BlobStorageSettings {
+    Local,
+}
+
+pub async fn get_blob_store(
+    params: BlobStorageSettings,
+) -> Result<Box<dyn BlobStore + Send + Sync>, Box<dyn std::error::Error>> {
+    match params {
+        BlobStorageSettings::Local => {
+            let mut c = Config::default();
+            c.set_default("path", "attachments")?;
+            c.merge(Environment::with_prefix("TODO_BLOB_LOCAL"))?;
+            let s = c.try_into::<LocalBlobSettings>()?;
+
+            Ok(Box::new(LocalBlobStore::new(s.path).await?))
+        }
+    }
+}
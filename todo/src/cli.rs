@@ -0,0 +1,190 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::copy;
+use crate::repository::migrations::Migrations;
+use crate::repository::repository::{get_repository, Repository, StorageSettings};
+use crate::todotxt;
+
+const CLI_ACTOR: &str = "cli";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "todo", about = "Todo gRPC service")]
+pub struct Cli {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Runs the gRPC server (default)
+    Serve,
+    /// Exports and imports todos in todo.txt format
+    Todotxt(TodoTxtCommand),
+    /// Applies, lists and reverts database migrations
+    Migrate(MigrateCommand),
+    /// Copies all todos from one storage into another
+    ///
+    /// The destination must start out empty, which a copy without progress
+    /// to resume checks before writing: the copy is verified by comparing
+    /// the counts and checksums of both storages as a whole, and history
+    /// ids of the source would overwrite those of the destination.
+    Copy(CopyCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum TodoTxtCommand {
+    /// Writes all todos to a file or to stdout
+    Export {
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Reads todos from a file or from stdin
+    Import {
+        #[structopt(parse(from_os_str))]
+        input: Option<PathBuf>,
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum MigrateCommand {
+    /// Applies all pending migrations
+    Up,
+    /// Lists migrations and whether they are applied
+    Status,
+    /// Reverts all migrations newer than the given version
+    Down { version: i64 },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CopyCommand {
+    /// Postgres, Sqlite, Sled or HashMap, configured as for serving
+    #[structopt(long)]
+    pub from: StorageSettings,
+    #[structopt(long)]
+    pub to: StorageSettings,
+    /// Saves progress to this file and resumes from it
+    #[structopt(long, parse(from_os_str))]
+    pub checkpoint: Option<PathBuf>,
+    #[structopt(long, default_value = "500")]
+    pub batch_size: i64,
+}
+
+pub async fn todotxt(
+    command: TodoTxtCommand,
+    repo: &(dyn Repository + Send + Sync),
+    logger: slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        TodoTxtCommand::Export { output } => {
+            let todos = repo.list().await?;
+            let content = todotxt::serialize(&todos);
+            match output {
+                Some(path) => fs::write(path, content)?,
+                None => io::stdout().write_all(content.as_bytes())?,
+            }
+            info!(logger, "exported todos"; "count" => todos.len());
+        }
+        TodoTxtCommand::Import { input, dry_run } => {
+            let content = match input {
+                Some(path) => fs::read_to_string(path)?,
+                None => {
+                    let mut buf = String::new();
+                    io::stdin().read_to_string(&mut buf)?;
+                    buf
+                }
+            };
+            let todos = todotxt::parse(&content)?;
+            let imported = repo.import(CLI_ACTOR, todos, dry_run).await?;
+            info!(logger, "imported todos"; "count" => imported.len(), "dry_run" => dry_run);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn migrate(
+    command: MigrateCommand,
+    migrations: &(dyn Migrations + Send + Sync),
+    logger: slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        MigrateCommand::Up => {
+            let applied = migrations.migrate_up().await?;
+            info!(logger, "applied migrations"; "versions" => format!("{:?}", applied));
+        }
+        MigrateCommand::Status => {
+            let mut stdout = io::stdout();
+            for m in migrations.migration_status().await? {
+                let state = match (m.applied, m.modified) {
+                    (true, false) => "applied",
+                    (true, true) => "modified",
+                    (false, _) => "pending",
+                };
+                let reversible = if m.reversible { "" } else { " (irreversible)" };
+                writeln!(
+                    stdout,
+                    "{:04} {:<8} {}{}",
+                    m.version, state, m.description, reversible
+                )?;
+            }
+        }
+        MigrateCommand::Down { version } => {
+            let reverted = migrations.migrate_down(version).await?;
+            info!(logger, "reverted migrations"; "versions" => format!("{:?}", reverted));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn copy(
+    command: CopyCommand,
+    logger: slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if command.from == command.to {
+        return Err("source and destination storage are the same".into());
+    }
+    let (from_name, to_name) = (format!("{:?}", command.from), format!("{:?}", command.to));
+    let checkpoint = match command.checkpoint.as_deref() {
+        Some(path) => copy::Checkpoint::load(path, &from_name, &to_name)?,
+        None => copy::Checkpoint::new(&from_name, &to_name),
+    };
+    let from = get_repository(command.from, logger.clone()).await?;
+    let to = get_repository(command.to, logger.clone()).await?;
+
+    let checkpoint = copy::copy(
+        from.as_ref(),
+        to.as_ref(),
+        checkpoint,
+        command.checkpoint.as_deref(),
+        command.batch_size,
+        &logger,
+    )
+    .await?;
+
+    let source = copy::digest(from.as_ref(), command.batch_size).await?;
+    let destination = copy::digest(to.as_ref(), command.batch_size).await?;
+    if source != destination {
+        return Err(format!(
+            "copy verification failed: source has {} todos, {} comments, {} attachments and {} history entries (checksum {:x}), destination has {}, {}, {} and {} (checksum {:x})",
+            source.todos, source.comments, source.attachments, source.history, source.checksum,
+            destination.todos, destination.comments, destination.attachments, destination.history,
+            destination.checksum
+        )
+        .into());
+    }
+    if let Some(path) = command.checkpoint.as_ref() {
+        fs::remove_file(path)?;
+    }
+    info!(logger, "copied and verified todos";
+        "copied" => checkpoint.copied, "todos" => source.todos, "comments" => source.comments,
+        "attachments" => source.attachments, "history" => source.history, "checksum" => format!("{:x}", source.checksum));
+
+    Ok(())
+}
@@ -0,0 +1,478 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use sha2::{Digest as _, Sha256};
+
+use crate::repository::error::Error;
+use crate::repository::model::{Attachment, Comment, HistoryEntry, Todo};
+use crate::repository::repository::Repository;
+
+/// How far a copy from the storage `from` to `to` got: everything up to and
+/// including the todo with id `after` has been written to the destination,
+/// along with its comments and attachments, and so has the history up to and
+/// including the entry with id `history_after`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub from: String,
+    pub to: String,
+    pub after: String,
+    pub copied: u64,
+    #[serde(default)]
+    pub history_after: i64,
+    #[serde(default)]
+    pub history_copied: u64,
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint at `path`, or starts from scratch if there is
+    /// none. A checkpoint of a copy between other storages is refused.
+    pub fn load(path: &Path, from: &str, to: &str) -> io::Result<Checkpoint> {
+        let checkpoint: Checkpoint = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Checkpoint::new(from, to)),
+            Err(e) => return Err(e),
+        };
+        if checkpoint.from != from || checkpoint.to != to {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is the checkpoint of a copy from {} to {}",
+                    path.display(),
+                    checkpoint.from,
+                    checkpoint.to
+                ),
+            ));
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn new(from: &str, to: &str) -> Checkpoint {
+        Checkpoint {
+            from: from.to_string(),
+            to: to.to_string(),
+            ..Checkpoint::default()
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// The number of todos, comments, attachments and history entries in a
+/// repository and a checksum over their contents that does not depend on the
+/// order the repository returns them in.
+#[derive(Debug, Default, PartialEq)]
+pub struct Digest {
+    pub todos: u64,
+    pub comments: u64,
+    pub attachments: u64,
+    pub history: u64,
+    pub checksum: u64,
+}
+
+/// Streams all todos of `from` into `to` in batches of `batch_size`, with
+/// their comments and the metadata of their attachments, and then the
+/// history of all todos, keeping ids and timestamps. The content of
+/// attachments stays in the blob store, which both sides share.
+///
+/// With a checkpoint file, progress is saved after every batch and a later
+/// run continues from there. A copy that starts from scratch refuses a
+/// destination that is not empty, whose rows it would overwrite.
+pub async fn copy(
+    from: &(dyn Repository + Send + Sync),
+    to: &(dyn Repository + Send + Sync),
+    mut checkpoint: Checkpoint,
+    checkpoint_path: Option<&Path>,
+    batch_size: i64,
+    logger: &slog::Logger,
+) -> Result<Checkpoint, Box<dyn std::error::Error>> {
+    if checkpoint.after.is_empty() && checkpoint.history_after == 0 {
+        let empty =
+            to.list_after("", 1).await?.is_empty() && to.list_history_after(0, 1).await?.is_empty();
+        if !empty {
+            return Err("the destination storage is not empty".into());
+        }
+    }
+    if checkpoint.copied > 0 {
+        info!(logger, "resuming copy"; "after" => &checkpoint.after, "copied" => checkpoint.copied);
+    }
+
+    loop {
+        let todos = from.list_after(&checkpoint.after, batch_size).await?;
+        let last = match todos.last() {
+            Some(todo) => todo.id.clone(),
+            None => break,
+        };
+
+        let mut comments = Vec::new();
+        let mut attachments = Vec::new();
+        for todo in todos.iter() {
+            comments.extend(from.list_comments(&todo.id).await?);
+            attachments.extend(from.list_attachments(&todo.id).await?);
+        }
+
+        let count = todos.len() as u64;
+        to.insert_todos(todos).await?;
+        to.insert_comments(comments).await?;
+        to.insert_attachments(attachments).await?;
+
+        checkpoint.after = last;
+        checkpoint.copied += count;
+        if let Some(path) = checkpoint_path {
+            checkpoint.save(path)?;
+        }
+        info!(logger, "copied todos"; "after" => &checkpoint.after, "copied" => checkpoint.copied);
+    }
+
+    loop {
+        let entries = from
+            .list_history_after(checkpoint.history_after, batch_size)
+            .await?;
+        let last = match entries.last() {
+            Some(entry) => entry.id,
+            None => break,
+        };
+
+        let count = entries.len() as u64;
+        to.insert_history(entries).await?;
+
+        checkpoint.history_after = last;
+        checkpoint.history_copied += count;
+        if let Some(path) = checkpoint_path {
+            checkpoint.save(path)?;
+        }
+        info!(logger, "copied history";
+            "after" => checkpoint.history_after, "copied" => checkpoint.history_copied);
+    }
+
+    Ok(checkpoint)
+}
+
+pub async fn digest(
+    repo: &(dyn Repository + Send + Sync),
+    batch_size: i64,
+) -> Result<Digest, Error> {
+    let mut digest = Digest::default();
+    let mut after = String::new();
+    loop {
+        let todos = repo.list_after(&after, batch_size).await?;
+        let last = match todos.last() {
+            Some(todo) => todo.id.clone(),
+            None => break,
+        };
+        for todo in todos.iter() {
+            let comments = repo.list_comments(&todo.id).await?;
+            let attachments = repo.list_attachments(&todo.id).await?;
+            digest.todos += 1;
+            digest.comments += comments.len() as u64;
+            digest.attachments += attachments.len() as u64;
+
+            let hashes = std::iter::once(todo_hash(todo))
+                .chain(comments.iter().map(comment_hash))
+                .chain(attachments.iter().map(attachment_hash));
+            for hash in hashes {
+                digest.checksum = digest.checksum.wrapping_add(hash);
+            }
+        }
+        after = last;
+    }
+
+    let mut after = 0;
+    loop {
+        let entries = repo.list_history_after(after, batch_size).await?;
+        let last = match entries.last() {
+            Some(entry) => entry.id,
+            None => break,
+        };
+        for entry in entries.iter() {
+            digest.history += 1;
+            digest.checksum = digest.checksum.wrapping_add(history_hash(entry));
+        }
+        after = last;
+    }
+
+    Ok(digest)
+}
+
+/// The first 64 bits of the SHA-256 of `fields`, each prefixed with its
+/// length so that no two rows share an encoding.
+fn row_hash(kind: &str, fields: &[&[u8]]) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(kind.as_bytes());
+    for field in fields {
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field);
+    }
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&hasher.finalize()[..8]);
+    u64::from_be_bytes(hash)
+}
+
+/// Timestamps are compared at microsecond precision, which is all Postgres
+/// keeps.
+fn time(time: &chrono::DateTime<chrono::Utc>) -> [u8; 12] {
+    let mut bytes = [0; 12];
+    bytes[..8].copy_from_slice(&time.timestamp().to_be_bytes());
+    bytes[8..].copy_from_slice(&time.timestamp_subsec_micros().to_be_bytes());
+    bytes
+}
+
+fn todo_hash(todo: &Todo) -> u64 {
+    row_hash(
+        "todo",
+        &[
+            todo.id.as_bytes(),
+            todo.title.as_bytes(),
+            todo.body.as_bytes(),
+            &[todo.is_completed as u8],
+            &time(&todo.created_at),
+            &time(&todo.updated_at),
+            &todo.comment_count.to_be_bytes(),
+        ],
+    )
+}
+
+fn comment_hash(comment: &Comment) -> u64 {
+    let edited_at = comment.edited_at.as_ref().map(time);
+    row_hash(
+        "comment",
+        &[
+            comment.id.as_bytes(),
+            comment.todo_id.as_bytes(),
+            comment.parent_id.as_deref().unwrap_or_default().as_bytes(),
+            comment.author.as_bytes(),
+            comment.body.as_bytes(),
+            &time(&comment.created_at),
+            edited_at.as_ref().map_or(&[], |t| &t[..]),
+        ],
+    )
+}
+
+fn attachment_hash(attachment: &Attachment) -> u64 {
+    row_hash(
+        "attachment",
+        &[
+            attachment.id.as_bytes(),
+            attachment.todo_id.as_bytes(),
+            attachment.filename.as_bytes(),
+            attachment.content_type.as_bytes(),
+            &attachment.size.to_be_bytes(),
+            &time(&attachment.created_at),
+        ],
+    )
+}
+
+fn history_hash(entry: &HistoryEntry) -> u64 {
+    let mut changes = Vec::new();
+    for change in entry.changes.iter() {
+        for value in [&change.before, &change.after].iter() {
+            changes.push(value.is_some() as u8);
+            let value = value.as_deref().unwrap_or_default();
+            changes.extend_from_slice(&(value.len() as u64).to_be_bytes());
+            changes.extend_from_slice(value.as_bytes());
+        }
+        changes.extend_from_slice(&(change.field.len() as u64).to_be_bytes());
+        changes.extend_from_slice(change.field.as_bytes());
+    }
+    row_hash(
+        "history",
+        &[
+            &entry.id.to_be_bytes(),
+            entry.todo_id.as_bytes(),
+            entry.actor.as_bytes(),
+            entry.operation.as_str().as_bytes(),
+            &changes,
+            entry.undo_state.as_str().as_bytes(),
+            &time(&entry.created_at),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::repository::hashmap::HashMapRepository;
+    use crate::repository::migrations::Migrations;
+    use crate::repository::model::Direction;
+    use crate::repository::sqlite::SqliteRepository;
+    use crate::test_util::{logger, TempDir};
+
+    /// A repository of three todos with two comments and an attachment
+    /// each, and five history entries, the last one undone.
+    async fn source() -> (HashMapRepository, Vec<String>) {
+        let from = HashMapRepository::new(logger());
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let todo = from
+                .create("copy", format!("t{}", i), "b".to_string())
+                .await
+                .unwrap();
+            let comment = from
+                .add_comment("copy", &todo.id, None, "first".to_string())
+                .await
+                .unwrap();
+            from.add_comment("copy", &todo.id, Some(comment.id), "reply".to_string())
+                .await
+                .unwrap();
+            from.add_attachment(Attachment {
+                id: libxid::new_generator().new_id().unwrap().encode(),
+                todo_id: todo.id.clone(),
+                filename: "notes.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                size: 42,
+                created_at: Utc::now(),
+            })
+            .await
+            .unwrap();
+            ids.push(todo.id);
+        }
+
+        from.complete("copy", &ids[0]).await.unwrap();
+        from.revert("copy", Direction::Undo, 1).await.unwrap();
+        (from, ids)
+    }
+
+    async fn destination() -> SqliteRepository {
+        let to = SqliteRepository::new(":memory:").await.unwrap();
+        to.migrate_up().await.unwrap();
+        to
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn copies_comments_attachments_and_history() {
+        let (from, ids) = source().await;
+        let to = destination().await;
+
+        let checkpoint = copy(&from, &to, Checkpoint::new("a", "b"), None, 2, &logger())
+            .await
+            .unwrap();
+        assert_eq!(checkpoint.copied, 3);
+        assert_eq!(checkpoint.history_copied, 5);
+        let source = digest(&from, 2).await.unwrap();
+        assert_eq!(source.todos, 3);
+        assert_eq!(source.comments, 6);
+        assert_eq!(source.attachments, 3);
+        assert_eq!(source.history, 5);
+        assert_eq!(source, digest(&to, 2).await.unwrap());
+        for id in ids.iter() {
+            assert_eq!(to.get(id).await.unwrap().comment_count, 2);
+        }
+
+        // The copied history can be redone and is continued after its ids.
+        let redone = to.revert("copy", Direction::Redo, 1).await.unwrap();
+        assert_eq!(redone[0].id, 6);
+        assert!(to.get(&ids[0]).await.unwrap().is_completed);
+    }
+
+    /// A copy resumed from a checkpoint that is behind what was written, as
+    /// after a crash between a batch and saving its checkpoint, writes
+    /// every row once.
+    #[tokio::test(threaded_scheduler)]
+    async fn resumes_from_a_checkpoint() {
+        let (from, ids) = source().await;
+        let to = destination().await;
+        let dir = TempDir::new("todo-checkpoint");
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("checkpoint.json");
+
+        // The first two batches of todos and the first of history were
+        // written, but only the first of each was saved.
+        let written = from.list_after("", 4).await.unwrap();
+        to.insert_todos(written.clone()).await.unwrap();
+        for todo in written.iter() {
+            to.insert_comments(from.list_comments(&todo.id).await.unwrap())
+                .await
+                .unwrap();
+            to.insert_attachments(from.list_attachments(&todo.id).await.unwrap())
+                .await
+                .unwrap();
+        }
+        to.insert_history(from.list_history_after(0, 2).await.unwrap())
+            .await
+            .unwrap();
+        let mut saved = Checkpoint::new("a", "b");
+        saved.after = ids[1].clone();
+        saved.copied = 2;
+        saved.history_after = 1;
+        saved.history_copied = 1;
+        saved.save(&path).unwrap();
+
+        let checkpoint = Checkpoint::load(&path, "a", "b").unwrap();
+        let checkpoint = copy(&from, &to, checkpoint, Some(&path), 2, &logger())
+            .await
+            .unwrap();
+        assert_eq!(checkpoint.after, ids[2]);
+        assert_eq!(checkpoint.copied, 3);
+        assert_eq!(checkpoint.history_copied, 5);
+        assert_eq!(Checkpoint::load(&path, "a", "b").unwrap().copied, 3);
+
+        assert_eq!(to.list().await.unwrap().len(), 3);
+        for id in ids.iter() {
+            assert_eq!(to.list_comments(id).await.unwrap().len(), 2);
+            assert_eq!(to.list_attachments(id).await.unwrap().len(), 1);
+            assert_eq!(to.get(id).await.unwrap().comment_count, 2);
+        }
+        let source = digest(&from, 2).await.unwrap();
+        assert_eq!(source.history, 5);
+        assert_eq!(source, digest(&to, 2).await.unwrap());
+
+        // Running it again from the final checkpoint copies nothing more.
+        let again = copy(&from, &to, checkpoint, Some(&path), 2, &logger())
+            .await
+            .unwrap();
+        assert_eq!((again.copied, again.history_copied), (3, 5));
+        assert_eq!(source, digest(&to, 2).await.unwrap());
+    }
+
+    /// Nothing is written to a destination that already has todos or
+    /// history.
+    #[tokio::test(threaded_scheduler)]
+    async fn refuses_a_destination_that_is_not_empty() {
+        let (from, _) = source().await;
+        let to = destination().await;
+        to.create("copy", "kept".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        let before = digest(&to, 2).await.unwrap();
+
+        let err = copy(&from, &to, Checkpoint::new("a", "b"), None, 2, &logger())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the destination storage is not empty");
+        assert_eq!(before, digest(&to, 2).await.unwrap());
+
+        // History alone is enough.
+        let to = destination().await;
+        to.insert_history(from.list_history_after(0, 1).await.unwrap())
+            .await
+            .unwrap();
+        let before = digest(&to, 2).await.unwrap();
+        assert!(
+            copy(&from, &to, Checkpoint::new("a", "b"), None, 2, &logger())
+                .await
+                .is_err()
+        );
+        assert_eq!(before, digest(&to, 2).await.unwrap());
+    }
+
+    #[test]
+    fn checkpoints_belong_to_their_storages() {
+        let dir = TempDir::new("todo-checkpoint");
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("checkpoint.json");
+        assert_eq!(Checkpoint::load(&path, "a", "b").unwrap().copied, 0);
+
+        let mut checkpoint = Checkpoint::new("a", "b");
+        checkpoint.after = "x".to_string();
+        checkpoint.copied = 7;
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path, "a", "b").unwrap().copied, 7);
+        assert!(Checkpoint::load(&path, "a", "c").is_err());
+    }
+}
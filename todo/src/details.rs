@@ -0,0 +1,68 @@
+//! Structured error details, sent as a `google.rpc.Status` along with the
+//! status of a failed call so that clients need not parse its message.
+
+use prost::Message;
+use prost_types::Any;
+use tonic::Code;
+
+pub mod rpc {
+    tonic::include_proto!("google.rpc");
+}
+
+/// The domain of the reasons in `ErrorInfo`.
+const DOMAIN: &str = "todo";
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/google.rpc.";
+
+pub fn status(code: Code, message: impl Into<String>, details: Vec<Any>) -> tonic::Status {
+    let message = message.into();
+    let status = rpc::Status {
+        code: code as i32,
+        message: message.clone(),
+        details,
+    };
+    tonic::Status::with_details(code, message, encode(&status).into())
+}
+
+/// Why a call failed, as an UPPER_SNAKE_CASE constant.
+pub fn error_info(reason: &str) -> Any {
+    any(
+        "ErrorInfo",
+        &rpc::ErrorInfo {
+            reason: reason.to_string(),
+            domain: DOMAIN.to_string(),
+            metadata: Default::default(),
+        },
+    )
+}
+
+pub fn precondition_failure(kind: &str, subject: String, description: String) -> Any {
+    any(
+        "PreconditionFailure",
+        &rpc::PreconditionFailure {
+            violations: vec![rpc::precondition_failure::Violation {
+                r#type: kind.to_string(),
+                subject,
+                description,
+            }],
+        },
+    )
+}
+
+pub fn bad_request(field_violations: Vec<rpc::bad_request::FieldViolation>) -> Any {
+    any("BadRequest", &rpc::BadRequest { field_violations })
+}
+
+fn any<T: Message>(name: &str, message: &T) -> Any {
+    Any {
+        type_url: format!("{}{}", TYPE_URL_PREFIX, name),
+        value: encode(message),
+    }
+}
+
+pub fn encode<T: Message>(message: &T) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    message
+        .encode(&mut buf)
+        .expect("failed to encode a message");
+    buf
+}
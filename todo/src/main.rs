@@ -9,30 +9,95 @@ extern crate slog_bunyan;
 extern crate async_trait;
 
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use slog::Drain;
+use structopt::StructOpt;
 use tonic::transport::Server;
 
 use server::todo_service::todo_service_server::TodoServiceServer;
 use server::TodoServiceImpl;
 
+mod blob;
+mod cli;
+mod copy;
+mod details;
+mod outbox;
 mod repository;
 mod server;
 mod settings;
+#[cfg(test)]
+mod test_util;
+mod todotxt;
+mod validation;
+
+const IDEMPOTENCY_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::Cli::from_args();
     let todo_settings = settings::Settings::new()?;
 
     let log_level =
         slog::Level::from_str(todo_settings.log_level.as_str()).expect("failed to parse log level");
     let log = get_logger(log_level);
 
+    let command = match args.command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Migrate(command) => {
+            let migrations =
+                repository::repository::get_migrations(todo_settings.storage, log.clone()).await?;
+            return cli::migrate(command, migrations.as_ref(), log).await;
+        }
+        cli::Command::Copy(command) => return cli::copy(command, log).await,
+        command => command,
+    };
+
+    let repo = repository::repository::get_repository(todo_settings.storage, log.clone()).await?;
+
+    match command {
+        cli::Command::Todotxt(command) => return cli::todotxt(command, repo.as_ref(), log).await,
+        cli::Command::Serve | cli::Command::Migrate(_) | cli::Command::Copy(_) => {}
+    }
+
+    let mut feed = None;
+    if let Some(events) = repository::repository::get_outbox(todo_settings.storage).await? {
+        let events = Arc::new(events);
+        let settings = outbox::sink::OutboxSettings::new()?;
+        let sinks = outbox::sink::get_sinks(&settings).await?;
+        outbox::relay::Relay::new(events.clone(), sinks, &settings, log.clone()).spawn();
+
+        let events = outbox::feed::Feed::new(events, settings.broadcast_capacity, log.clone());
+        events.spawn().await?;
+        feed = Some(events);
+    }
+
     let addr = format!("0.0.0.0:{}", todo_settings.port)
         .parse()
         .expect("failed to parse socket address");
-    let repo = repository::repository::get_repository(todo_settings.storage, log.clone()).await?;
-    let service = TodoServiceImpl::new(log.clone(), repo);
+    let blobs = blob::store::get_blob_store(todo_settings.blob_storage).await?;
+    let limits = server::AttachmentLimits::new(
+        todo_settings.attachment_max_size,
+        &todo_settings.attachment_content_types,
+    );
+    let idempotency: Arc<dyn repository::idempotency::IdempotencyStore + Send + Sync> =
+        repository::repository::get_idempotency_store(todo_settings.storage)
+            .await?
+            .into();
+    repository::idempotency::spawn_pruning(
+        idempotency.clone(),
+        IDEMPOTENCY_PRUNE_INTERVAL,
+        log.clone(),
+    );
+    let service = TodoServiceImpl::new(
+        log.clone(),
+        repo,
+        blobs,
+        limits,
+        feed,
+        idempotency,
+        Duration::from_secs(todo_settings.idempotency_ttl_secs),
+    );
     info!(log, "started"; "addr" => addr);
     Server::builder()
         .add_service(TodoServiceServer::new(service))
@@ -0,0 +1,171 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::repository::error::Error;
+use crate::repository::model::Event;
+use crate::repository::outbox::PostgresOutbox;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const BATCH_SIZE: i64 = 100;
+
+/// The changes made through every instance sharing a database. Instances
+/// learn about new events through `NOTIFY`, read them from the outbox and
+/// broadcast them to their local subscribers, in order and without gaps.
+#[derive(Clone)]
+pub struct Feed {
+    outbox: Arc<PostgresOutbox>,
+    sender: broadcast::Sender<Event>,
+    /// The id of the last event broadcast.
+    position: Arc<AtomicI64>,
+    logger: slog::Logger,
+}
+
+impl Feed {
+    pub fn new(outbox: Arc<PostgresOutbox>, capacity: usize, logger: slog::Logger) -> Feed {
+        let (sender, _) = broadcast::channel(capacity);
+        Feed {
+            outbox,
+            sender,
+            position: Arc::new(AtomicI64::new(0)),
+            logger,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// The id of the last event broadcast. A subscriber receives every event
+    /// after the one this returns once it has subscribed.
+    pub fn last_id(&self) -> i64 {
+        self.position.load(Ordering::SeqCst)
+    }
+
+    /// Returns up to `limit` past events following the one with id `after`.
+    pub async fn events_after(&self, after: i64, limit: i64) -> Result<Vec<Event>, Error> {
+        self.outbox.events_after(after, limit).await
+    }
+
+    pub async fn spawn(&self) -> Result<(), Error> {
+        let last = self.outbox.last_id().await?;
+        self.position.store(last, Ordering::SeqCst);
+        let feed = self.clone();
+        tokio::spawn(async move { feed.run(last).await });
+
+        Ok(())
+    }
+
+    async fn run(self, mut last: i64) {
+        loop {
+            if let Err(e) = self.listen(&mut last).await {
+                error!(self.logger, "event listener failed"; "err" => %e, "last" => last);
+            }
+            tokio::time::delay_for(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Broadcasts events until the listening connection is lost. Whatever
+    /// was written while not listening is caught up on first.
+    async fn listen(&self, last: &mut i64) -> Result<(), Error> {
+        let mut listener = self.outbox.listen().await?;
+        self.catch_up(last).await?;
+
+        // `try_recv` reports a lost connection instead of silently
+        // reconnecting, which would drop the notifications sent meanwhile.
+        while let Some(notification) = listener.try_recv().await? {
+            let id: i64 = match notification.payload().parse() {
+                Ok(id) => id,
+                Err(_) => {
+                    warn!(self.logger, "ignoring malformed event notification";
+                        "payload" => notification.payload());
+                    continue;
+                }
+            };
+            if id <= *last {
+                continue;
+            }
+            if id > *last + 1 {
+                // Ids of rolled back writes are never used, so a gap may
+                // well be empty; the outbox has the final word.
+                debug!(self.logger, "gap in event notifications"; "last" => *last, "id" => id);
+            }
+            self.catch_up(last).await?;
+        }
+
+        warn!(self.logger, "lost event listener connection"; "last" => *last);
+        Ok(())
+    }
+
+    /// Broadcasts every event after `last`. Events are committed in the order
+    /// of their ids, so once one is visible all earlier ones are as well.
+    async fn catch_up(&self, last: &mut i64) -> Result<(), Error> {
+        loop {
+            let events = self.outbox.events_after(*last, BATCH_SIZE).await?;
+            let done = (events.len() as i64) < BATCH_SIZE;
+            for event in events {
+                *last = event.id;
+                self.position.store(event.id, Ordering::SeqCst);
+                // Fails only when nobody is subscribed.
+                let _ = self.sender.send(event);
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::conformance::postgres_scratch;
+    use crate::repository::postgres::PostgresRepository;
+    use crate::repository::repository::Repository;
+    use crate::test_util::logger;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn broadcasts_new_events_in_order() {
+        let settings = match postgres_scratch("feed").await {
+            Some(settings) => settings,
+            None => return,
+        };
+        let repo = PostgresRepository::new(&settings, logger()).await.unwrap();
+        repo.create("feed", "before".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        let outbox = Arc::new(PostgresOutbox::new(&settings).await.unwrap());
+        let feed = Feed::new(outbox.clone(), 16, logger());
+        feed.spawn().await.unwrap();
+        let before = outbox.last_id().await.unwrap();
+        assert_eq!(feed.last_id(), before);
+
+        let mut live = feed.subscribe();
+        for i in 0..3 {
+            repo.create("feed", format!("t{}", i), "b".to_string())
+                .await
+                .unwrap();
+        }
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let event = tokio::time::timeout(Duration::from_secs(5), live.recv())
+                .await
+                .expect("no event broadcast")
+                .unwrap();
+            assert_eq!(event.actor, "feed");
+            ids.push(event.id);
+        }
+
+        let expected: Vec<i64> = outbox
+            .events_after(before, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, expected);
+        assert_eq!(feed.last_id(), *ids.last().unwrap());
+    }
+}
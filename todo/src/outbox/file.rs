@@ -0,0 +1,39 @@
+use std::io;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::outbox::sink::Sink;
+use crate::repository::model::Event;
+
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub async fn new(path: impl Into<PathBuf>) -> io::Result<FileSink> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.into())
+            .await?;
+        Ok(FileSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn deliver(&self, event: &Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        // The event is marked delivered right after, so it has to be on disk.
+        file.sync_data().await
+    }
+}
@@ -0,0 +1,5 @@
+pub(crate) mod feed;
+pub(crate) mod file;
+pub(crate) mod relay;
+pub(crate) mod sink;
+pub(crate) mod webhook;
@@ -0,0 +1,312 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use sqlx::{Connection, PgConnection};
+
+use crate::outbox::sink::{OutboxSettings, Sinks};
+use crate::repository::error::Error;
+use crate::repository::model::Event;
+use crate::repository::outbox::PostgresOutbox;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delivers the events of the outbox to every sink, in order and at least
+/// once. Of all instances sharing a database only one relays at a time; the
+/// others wait to take over.
+pub struct Relay {
+    outbox: Arc<PostgresOutbox>,
+    sinks: Sinks,
+    batch_size: i64,
+    poll_interval: Duration,
+    backoff_initial: Duration,
+    backoff_max: Duration,
+    retention: Duration,
+    logger: slog::Logger,
+}
+
+impl Relay {
+    pub fn new(
+        outbox: Arc<PostgresOutbox>,
+        sinks: Sinks,
+        settings: &OutboxSettings,
+        logger: slog::Logger,
+    ) -> Relay {
+        Relay {
+            outbox,
+            sinks,
+            batch_size: settings.batch_size,
+            poll_interval: Duration::from_millis(settings.poll_interval_ms),
+            backoff_initial: Duration::from_millis(settings.backoff_initial_ms),
+            backoff_max: Duration::from_millis(settings.backoff_max_ms),
+            retention: Duration::from_secs(settings.retention_secs),
+            logger,
+        }
+    }
+
+    pub fn spawn(self) {
+        tokio::spawn(async move { self.run().await });
+    }
+
+    /// Tries to take the relay lock every poll interval, on one connection
+    /// that is kept until it fails or the relay it led stops. Closing it
+    /// releases the lock for another instance.
+    async fn run(self) {
+        let mut conn: Option<PgConnection> = None;
+        loop {
+            if conn.is_none() {
+                match self.outbox.lock_connection().await {
+                    Ok(c) => conn = Some(c),
+                    Err(e) => {
+                        error!(self.logger, "failed to connect for outbox relay lock"; "err" => %e)
+                    }
+                }
+            }
+            if let Some(lock) = conn.as_mut() {
+                match PostgresOutbox::try_lock(lock).await {
+                    Ok(true) => {
+                        info!(self.logger, "relaying outbox events");
+                        if let Err(e) = self.relay(lock).await {
+                            error!(self.logger, "outbox relay failed"; "err" => %e);
+                        }
+                        conn = None;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!(self.logger, "failed to take outbox relay lock"; "err" => %e);
+                        conn = None;
+                    }
+                }
+            }
+            tokio::time::delay_for(self.poll_interval).await;
+        }
+    }
+
+    /// Relays events for as long as the connection holding the relay lock is
+    /// alive, which is checked before every batch.
+    async fn relay(&self, lock: &mut PgConnection) -> Result<(), Error> {
+        let mut pruned_at: Option<Instant> = None;
+        loop {
+            lock.ping().await?;
+            let wait = self.deliver().await?;
+            if wait.is_zero() {
+                continue;
+            }
+
+            if pruned_at.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                let before = Utc::now() - chrono::Duration::from_std(self.retention).unwrap();
+                let pruned = self.outbox.prune(before).await?;
+                if pruned > 0 {
+                    info!(self.logger, "pruned outbox events"; "count" => pruned);
+                }
+                pruned_at = Some(Instant::now());
+            }
+            tokio::time::delay_for(wait).await;
+        }
+    }
+
+    /// Delivers pending events in order until one fails or is not due yet,
+    /// and returns how long to wait before trying again.
+    async fn deliver(&self) -> Result<Duration, Error> {
+        let pending = self.outbox.pending(self.batch_size).await?;
+        if pending.is_empty() {
+            return Ok(self.poll_interval);
+        }
+
+        for p in pending {
+            let now = Utc::now();
+            if p.next_attempt_at > now {
+                return Ok((p.next_attempt_at - now).to_std().unwrap_or_default());
+            }
+
+            match self.send(&p.event).await {
+                Ok(()) => self.outbox.delivered(p.event.id).await?,
+                Err(e) => {
+                    let attempts = p.attempts + 1;
+                    let delay = self.backoff(attempts);
+                    warn!(self.logger, "failed to deliver outbox event";
+                        "id" => p.event.id, "attempts" => attempts, "err" => &e);
+                    let retry_at = now + chrono::Duration::from_std(delay).unwrap();
+                    self.outbox.failed(p.event.id, retry_at, &e).await?;
+                    return Ok(delay);
+                }
+            }
+        }
+
+        Ok(Duration::from_secs(0))
+    }
+
+    async fn send(&self, event: &Event) -> Result<(), String> {
+        for (params, sink) in self.sinks.iter() {
+            sink.deliver(event)
+                .await
+                .map_err(|e| format!("{:?}: {}", params, e))?;
+        }
+        Ok(())
+    }
+
+    fn backoff(&self, attempts: i32) -> Duration {
+        let factor = 1u32 << (attempts - 1).clamp(0, 16);
+        (self.backoff_initial * factor).min(self.backoff_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::outbox::sink::{Sink, SinkSettings};
+    use crate::repository::conformance::postgres_scratch;
+    use crate::repository::postgres::PostgresRepository;
+    use crate::repository::repository::Repository;
+    use crate::test_util::logger;
+
+    /// Records the ids of the events it is given, after failing `failures`
+    /// times.
+    #[derive(Clone, Default)]
+    struct FakeSink {
+        delivered: Arc<Mutex<Vec<i64>>>,
+        failures: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Sink for FakeSink {
+        async fn deliver(&self, event: &Event) -> io::Result<()> {
+            let failed = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if failed.is_ok() {
+                return Err(io::Error::other("unavailable"));
+            }
+            self.delivered.lock().unwrap().push(event.id);
+            Ok(())
+        }
+    }
+
+    fn outbox_settings() -> OutboxSettings {
+        OutboxSettings {
+            sinks: String::new(),
+            batch_size: 2,
+            poll_interval_ms: 10,
+            backoff_initial_ms: 100,
+            backoff_max_ms: 150,
+            retention_secs: 60,
+            broadcast_capacity: 16,
+        }
+    }
+
+    /// Sets up an outbox holding the events of `count` new todos, and a relay
+    /// delivering them to `sink`.
+    async fn relay(
+        name: &str,
+        count: usize,
+        sink: &FakeSink,
+    ) -> Option<(Arc<PostgresOutbox>, Relay)> {
+        let settings = postgres_scratch(name).await?;
+        let repo = PostgresRepository::new(&settings, logger()).await.unwrap();
+        for i in 0..count {
+            repo.create("relay", format!("t{}", i), "b".to_string())
+                .await
+                .unwrap();
+        }
+        let outbox = Arc::new(PostgresOutbox::new(&settings).await.unwrap());
+        let sinks: Sinks = vec![(SinkSettings::File, Box::new(sink.clone()))];
+        let relay = Relay::new(outbox.clone(), sinks, &outbox_settings(), logger());
+        Some((outbox, relay))
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn delivers_in_order() {
+        let sink = FakeSink::default();
+        let (outbox, relay) = match relay("relay_order", 5, &sink).await {
+            Some(relay) => relay,
+            None => return,
+        };
+
+        // Batches of two: full batches ask to go on right away.
+        assert_eq!(relay.deliver().await.unwrap(), Duration::from_secs(0));
+        assert_eq!(relay.deliver().await.unwrap(), Duration::from_secs(0));
+        assert_eq!(relay.deliver().await.unwrap(), Duration::from_secs(0));
+        assert_eq!(relay.deliver().await.unwrap(), relay.poll_interval);
+
+        let ids: Vec<i64> = outbox
+            .events_after(0, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids.len(), 5);
+        assert_eq!(*sink.delivered.lock().unwrap(), ids);
+        assert!(outbox.pending(10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn retries_with_backoff() {
+        let sink = FakeSink::default();
+        sink.failures.store(2, Ordering::SeqCst);
+        let (outbox, relay) = match relay("relay_retry", 2, &sink).await {
+            Some(relay) => relay,
+            None => return,
+        };
+
+        // The first failure waits the initial backoff, the second doubles it
+        // up to the maximum.
+        for (attempts, backoff) in [(1, 100), (2, 150)].iter() {
+            let before = Utc::now();
+            let wait = relay.deliver().await.unwrap();
+            assert_eq!(wait, Duration::from_millis(*backoff));
+
+            let pending = outbox.pending(10).await.unwrap();
+            assert_eq!(pending.len(), 2);
+            assert_eq!(pending[0].attempts, *attempts);
+            assert_eq!(pending[1].attempts, 0);
+            assert!(pending[0].next_attempt_at > before);
+            assert!(
+                pending[0].next_attempt_at
+                    <= Utc::now() + chrono::Duration::milliseconds(*backoff as i64)
+            );
+
+            // Nothing is tried again, and nothing after it, before it is due.
+            assert!(relay.deliver().await.unwrap() > Duration::from_secs(0));
+            assert!(sink.delivered.lock().unwrap().is_empty());
+            assert_eq!(outbox.pending(10).await.unwrap()[0].attempts, *attempts);
+
+            tokio::time::delay_for(wait).await;
+        }
+
+        assert_eq!(relay.deliver().await.unwrap(), Duration::from_secs(0));
+        assert_eq!(sink.delivered.lock().unwrap().len(), 2);
+        assert!(outbox.pending(10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn takes_over_from_a_lost_leader() {
+        let sink = FakeSink::default();
+        let (outbox, relay) = match relay("relay_leader", 1, &sink).await {
+            Some(relay) => relay,
+            None => return,
+        };
+
+        let mut leader = outbox.lock_connection().await.unwrap();
+        assert!(PostgresOutbox::try_lock(&mut leader).await.unwrap());
+        let mut other = outbox.lock_connection().await.unwrap();
+        assert!(!PostgresOutbox::try_lock(&mut other).await.unwrap());
+        drop(leader);
+
+        relay.spawn();
+        for _ in 0..100 {
+            if !sink.delivered.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        assert_eq!(sink.delivered.lock().unwrap().len(), 1);
+        assert!(!PostgresOutbox::try_lock(&mut other).await.unwrap());
+    }
+}
@@ -0,0 +1,111 @@
+use std::io;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use config::{Config, ConfigError, Environment};
+
+use crate::outbox::file::FileSink;
+use crate::outbox::webhook::WebhookSink;
+use crate::repository::model::Event;
+
+/// Receives the events the relay takes from the outbox, in order.
+#[async_trait]
+pub trait Sink {
+    /// Hands an event on. After an error the relay tries again later, so a
+    /// sink may see the same event more than once.
+    async fn deliver(&self, event: &Event) -> io::Result<()>;
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OutboxSettings {
+    /// Comma-separated list of sinks to deliver events to.
+    pub sinks: String,
+    pub batch_size: i64,
+    pub poll_interval_ms: u64,
+    pub backoff_initial_ms: u64,
+    pub backoff_max_ms: u64,
+    /// How long delivered events are kept.
+    pub retention_secs: u64,
+    pub broadcast_capacity: usize,
+}
+
+impl OutboxSettings {
+    pub fn new() -> Result<Self, ConfigError> {
+        let mut c = Config::default();
+        c.set_default("sinks", "")?;
+        c.set_default("batch_size", 100)?;
+        c.set_default("poll_interval_ms", 500)?;
+        c.set_default("backoff_initial_ms", 1000)?;
+        c.set_default("backoff_max_ms", 60000)?;
+        c.set_default("retention_secs", 7 * 24 * 60 * 60)?;
+        c.set_default("broadcast_capacity", 1024)?;
+        c.merge(Environment::with_prefix("TODO_OUTBOX"))?;
+
+        c.try_into::<OutboxSettings>()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookSinkSettings {
+    pub url: String,
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FileSinkSettings {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SinkSettings {
+    /// POSTs every event as JSON to a URL.
+    Webhook,
+    /// Appends every event as a line of JSON to a file.
+    File,
+}
+
+impl FromStr for SinkSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Webhook" => Ok(SinkSettings::Webhook),
+            "File" => Ok(SinkSettings::File),
+            _ => Err(format!("unknown outbox sink: {}", s)),
+        }
+    }
+}
+
+pub type Sinks = Vec<(SinkSettings, Box<dyn Sink + Send + Sync>)>;
+
+pub async fn get_sinks(settings: &OutboxSettings) -> Result<Sinks, Box<dyn std::error::Error>> {
+    let mut sinks: Sinks = Vec::new();
+    for name in settings
+        .sinks
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let params = name.parse::<SinkSettings>()?;
+        let sink: Box<dyn Sink + Send + Sync> = match params {
+            SinkSettings::Webhook => {
+                let mut c = Config::default();
+                c.set_default("timeout_ms", 5000)?;
+                c.merge(Environment::with_prefix("TODO_OUTBOX_WEBHOOK"))?;
+                let s = c.try_into::<WebhookSinkSettings>()?;
+
+                Box::new(WebhookSink::new(&s)?)
+            }
+            SinkSettings::File => {
+                let mut c = Config::default();
+                c.merge(Environment::with_prefix("TODO_OUTBOX_FILE"))?;
+                let s = c.try_into::<FileSinkSettings>()?;
+
+                Box::new(FileSink::new(s.path).await?)
+            }
+        };
+        sinks.push((params, sink));
+    }
+
+    Ok(sinks)
+}
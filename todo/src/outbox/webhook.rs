@@ -0,0 +1,76 @@
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Uri};
+use hyper_rustls::HttpsConnector;
+
+use crate::outbox::sink::{Sink, WebhookSinkSettings};
+use crate::repository::model::Event;
+
+/// Delivers events to an HTTP or HTTPS endpoint. Certificates are checked
+/// against the Mozilla roots built into the binary.
+pub struct WebhookSink {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: Uri,
+    timeout: Duration,
+}
+
+impl WebhookSink {
+    pub fn new(settings: &WebhookSinkSettings) -> Result<WebhookSink, Box<dyn std::error::Error>> {
+        let url = settings.url.parse::<Uri>()?;
+        if !matches!(url.scheme_str(), Some("http") | Some("https")) {
+            return Err(format!("webhook url must use http or https: {}", settings.url).into());
+        }
+
+        Ok(WebhookSink {
+            client: Client::builder().build(HttpsConnector::new()),
+            url,
+            timeout: Duration::from_millis(settings.timeout_ms),
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn deliver(&self, event: &Event) -> io::Result<()> {
+        let request = Request::post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(event)?))
+            .map_err(io::Error::other)?;
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "webhook timed out"))?
+            .map_err(io::Error::other)?;
+
+        if !response.status().is_success() {
+            return Err(io::Error::other(format!(
+                "webhook responded with {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(url: &str) -> WebhookSinkSettings {
+        WebhookSinkSettings {
+            url: url.to_string(),
+            timeout_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn accepts_http_and_https_urls() {
+        assert!(WebhookSink::new(&settings("http://localhost:8080/events")).is_ok());
+        assert!(WebhookSink::new(&settings("https://example.com/events")).is_ok());
+        assert!(WebhookSink::new(&settings("ftp://example.com/events")).is_err());
+        assert!(WebhookSink::new(&settings("example.com/events")).is_err());
+    }
+}
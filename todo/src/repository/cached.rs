@@ -0,0 +1,272 @@
+use crate::repository::error::Error;
+use crate::repository::model::{Attachment, Comment, Direction, HistoryEntry, Todo, Todos};
+use crate::repository::repository::{CacheSettings, Repository};
+use lru::LruCache;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Entries {
+    todos: LruCache<String, (Instant, Todo)>,
+    list: Option<(Instant, Todos)>,
+    /// Bumped on every invalidation, so that a value read from the
+    /// repository before a concurrent change is not cached after it.
+    generation: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Serves `get` and optionally `list` from a bounded LRU cache in front of
+/// another repository. Every write that changes todos invalidates exactly the
+/// todos it touched and the cached list.
+pub struct CachedRepository<R: Repository> {
+    repo: R,
+    entries: Mutex<Entries>,
+    ttl: Duration,
+    cache_list: bool,
+    stats: Arc<Stats>,
+}
+
+impl<R: Repository> CachedRepository<R> {
+    pub fn new(repo: R, settings: &CacheSettings, logger: slog::Logger) -> CachedRepository<R> {
+        let stats = Arc::new(Stats::default());
+        if settings.stats_interval_secs > 0 {
+            let stats = stats.clone();
+            let interval = Duration::from_secs(settings.stats_interval_secs);
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(interval);
+                loop {
+                    ticks.tick().await;
+                    let hits = stats.hits.swap(0, Ordering::Relaxed);
+                    let misses = stats.misses.swap(0, Ordering::Relaxed);
+                    if hits + misses > 0 {
+                        info!(logger, "cache stats"; "hits" => hits, "misses" => misses);
+                    }
+                }
+            });
+        }
+
+        CachedRepository {
+            repo,
+            entries: Mutex::new(Entries {
+                todos: LruCache::new(settings.capacity),
+                list: None,
+                generation: 0,
+            }),
+            ttl: Duration::from_millis(settings.ttl_ms),
+            cache_list: settings.list,
+            stats,
+        }
+    }
+
+    fn count(&self, hit: bool) {
+        let counter = if hit {
+            &self.stats.hits
+        } else {
+            &self.stats.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn invalidate<'a, I: IntoIterator<Item = &'a str>>(&self, ids: I) {
+        let mut entries = self.entries.lock().unwrap();
+        for id in ids {
+            entries.todos.pop(&id.to_string());
+        }
+        entries.list = None;
+        entries.generation += 1;
+    }
+}
+
+#[async_trait]
+impl<R: Repository + Send + Sync> Repository for CachedRepository<R> {
+    async fn list(&self) -> Result<Todos, Error> {
+        if !self.cache_list {
+            return self.repo.list().await;
+        }
+
+        let generation = {
+            let entries = self.entries.lock().unwrap();
+            if let Some((cached_at, todos)) = entries.list.as_ref() {
+                if cached_at.elapsed() < self.ttl {
+                    self.count(true);
+                    return Ok(todos.clone());
+                }
+            }
+            entries.generation
+        };
+        self.count(false);
+
+        let todos = self.repo.list().await?;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation == generation {
+            entries.list = Some((Instant::now(), todos.clone()));
+        }
+
+        Ok(todos)
+    }
+
+    async fn get(&self, id: &str) -> Result<Todo, Error> {
+        let generation = {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some((cached_at, todo)) = entries.todos.get(&id.to_string()) {
+                if cached_at.elapsed() < self.ttl {
+                    self.count(true);
+                    return Ok(todo.clone());
+                }
+            }
+            entries.generation
+        };
+        self.count(false);
+
+        let todo = self.repo.get(id).await?;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation == generation {
+            entries
+                .todos
+                .put(id.to_string(), (Instant::now(), todo.clone()));
+        }
+
+        Ok(todo)
+    }
+
+    async fn create(&self, actor: &str, title: String, body: String) -> Result<Todo, Error> {
+        let todo = self.repo.create(actor, title, body).await?;
+        self.invalidate(std::iter::empty());
+        Ok(todo)
+    }
+
+    async fn update(
+        &self,
+        actor: &str,
+        id: &str,
+        title: Option<String>,
+        body: Option<String>,
+        is_completed: Option<bool>,
+    ) -> Result<Todo, Error> {
+        let result = self.repo.update(actor, id, title, body, is_completed).await;
+        self.invalidate(Some(id));
+        result
+    }
+
+    async fn delete(&self, actor: &str, id: &str) -> Result<(), Error> {
+        let result = self.repo.delete(actor, id).await;
+        self.invalidate(Some(id));
+        result
+    }
+
+    async fn complete(&self, actor: &str, id: &str) -> Result<Todo, Error> {
+        let result = self.repo.complete(actor, id).await;
+        self.invalidate(Some(id));
+        result
+    }
+
+    async fn import(&self, actor: &str, todos: Todos, dry_run: bool) -> Result<Todos, Error> {
+        let imported = self.repo.import(actor, todos, dry_run).await?;
+        if !dry_run {
+            self.invalidate(imported.iter().map(|t| t.id.as_str()));
+        }
+        Ok(imported)
+    }
+
+    async fn list_after(&self, after: &str, limit: i64) -> Result<Todos, Error> {
+        self.repo.list_after(after, limit).await
+    }
+
+    async fn insert_todos(&self, todos: Todos) -> Result<(), Error> {
+        let ids: Vec<String> = todos.iter().map(|t| t.id.clone()).collect();
+        let result = self.repo.insert_todos(todos).await;
+        self.invalidate(ids.iter().map(String::as_str));
+        result
+    }
+
+    async fn insert_comments(&self, comments: Vec<Comment>) -> Result<(), Error> {
+        let ids: Vec<String> = comments.iter().map(|c| c.todo_id.clone()).collect();
+        let result = self.repo.insert_comments(comments).await;
+        self.invalidate(ids.iter().map(String::as_str));
+        result
+    }
+
+    async fn insert_attachments(&self, attachments: Vec<Attachment>) -> Result<(), Error> {
+        self.repo.insert_attachments(attachments).await
+    }
+
+    async fn history(&self, id: &str, offset: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        self.repo.history(id, offset, limit).await
+    }
+
+    async fn list_history_after(&self, after: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        self.repo.list_history_after(after, limit).await
+    }
+
+    async fn insert_history(&self, entries: Vec<HistoryEntry>) -> Result<(), Error> {
+        self.repo.insert_history(entries).await
+    }
+
+    async fn revert(
+        &self,
+        actor: &str,
+        direction: Direction,
+        count: i64,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let entries = self.repo.revert(actor, direction, count).await?;
+        self.invalidate(entries.iter().map(|e| e.todo_id.as_str()));
+        Ok(entries)
+    }
+
+    async fn add_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        parent_id: Option<String>,
+        body: String,
+    ) -> Result<Comment, Error> {
+        let comment = self
+            .repo
+            .add_comment(author, todo_id, parent_id, body)
+            .await?;
+        // The todo carries the comment count.
+        self.invalidate(Some(todo_id));
+        Ok(comment)
+    }
+
+    async fn list_comments(&self, todo_id: &str) -> Result<Vec<Comment>, Error> {
+        self.repo.list_comments(todo_id).await
+    }
+
+    async fn edit_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        id: &str,
+        body: String,
+    ) -> Result<Comment, Error> {
+        self.repo.edit_comment(author, todo_id, id, body).await
+    }
+
+    async fn delete_comment(&self, author: &str, todo_id: &str, id: &str) -> Result<(), Error> {
+        self.repo.delete_comment(author, todo_id, id).await?;
+        self.invalidate(Some(todo_id));
+        Ok(())
+    }
+
+    async fn add_attachment(&self, attachment: Attachment) -> Result<Attachment, Error> {
+        self.repo.add_attachment(attachment).await
+    }
+
+    async fn list_attachments(&self, todo_id: &str) -> Result<Vec<Attachment>, Error> {
+        self.repo.list_attachments(todo_id).await
+    }
+
+    async fn get_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        self.repo.get_attachment(todo_id, id).await
+    }
+
+    async fn delete_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        self.repo.delete_attachment(todo_id, id).await
+    }
+}
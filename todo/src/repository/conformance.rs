@@ -0,0 +1,976 @@
+//! Behaviour every `Repository` implementation has to agree on. Each backend
+//! runs the same checks; Postgres only when an instance is reachable. The
+//! idempotency stores get the same treatment.
+
+use crate::repository::cached::CachedRepository;
+use crate::repository::error::Error;
+use crate::repository::hashmap::HashMapRepository;
+use crate::repository::idempotency::{
+    Claim, IdempotencyStore, MemoryIdempotencyStore, PostgresIdempotencyStore,
+};
+use crate::repository::migrations::Migrations;
+use crate::repository::model::{Attachment, Direction, Operation, Todo, UndoState};
+use crate::repository::postgres::PostgresRepository;
+use crate::repository::repository::{CacheSettings, HashMapSettings, PostgresSettings, Repository};
+use crate::repository::sled::SledRepository;
+use crate::repository::sqlite::SqliteRepository;
+use crate::repository::wal::FsyncPolicy;
+use crate::test_util::{logger, unique, TempDir};
+use chrono::{Duration, TimeZone, Utc};
+use futures::future::join_all;
+use sqlx::{Connection, PgConnection};
+use std::collections::HashSet;
+
+const POSTGRES_ENV: &str = "TODO_TEST_POSTGRES_CONNECTION_STRING";
+const POSTGRES_DEFAULT: &str = "postgres://postgres@localhost/postgres";
+
+/// Returns an id that no backend has handed out.
+fn missing() -> String {
+    libxid::new_generator().new_id().unwrap().encode()
+}
+
+async fn conformance<R: Repository + Sync>(repo: &R) {
+    crud(repo).await;
+    errors(repo).await;
+    import(repo).await;
+    bulk(repo).await;
+    history(repo).await;
+    revert(repo).await;
+    comments(repo).await;
+    attachments(repo).await;
+    concurrency(repo).await;
+}
+
+async fn crud<R: Repository + Sync>(repo: &R) {
+    let actor = unique("crud");
+    let created = repo
+        .create(&actor, "title".to_string(), "body".to_string())
+        .await
+        .unwrap();
+    assert!(!created.id.is_empty());
+    assert_eq!(created.title, "title");
+    assert_eq!(created.body, "body");
+    assert!(!created.is_completed);
+    assert_eq!(created.comment_count, 0);
+
+    let fetched = repo.get(&created.id).await.unwrap();
+    assert_eq!(fetched.title, created.title);
+    assert_eq!(fetched.created_at, created.created_at);
+    assert!(repo
+        .list()
+        .await
+        .unwrap()
+        .iter()
+        .any(|t| t.id == created.id));
+
+    let updated = repo
+        .update(
+            &actor,
+            &created.id,
+            Some("changed".to_string()),
+            Some("new body".to_string()),
+            Some(false),
+        )
+        .await
+        .unwrap();
+    assert_eq!(updated.id, created.id);
+    assert_eq!(updated.title, "changed");
+    assert_eq!(updated.body, "new body");
+    assert_eq!(updated.created_at, created.created_at);
+    assert!(updated.updated_at >= created.updated_at);
+    assert_eq!(repo.get(&created.id).await.unwrap().title, "changed");
+
+    let completed = repo.complete(&actor, &created.id).await.unwrap();
+    assert!(completed.is_completed);
+    assert!(repo.get(&created.id).await.unwrap().is_completed);
+
+    repo.delete(&actor, &created.id).await.unwrap();
+    assert!(matches!(repo.get(&created.id).await, Err(Error::NotFound)));
+    assert!(!repo
+        .list()
+        .await
+        .unwrap()
+        .iter()
+        .any(|t| t.id == created.id));
+}
+
+async fn errors<R: Repository + Sync>(repo: &R) {
+    let actor = unique("errors");
+    let id = missing();
+    assert!(matches!(repo.get(&id).await, Err(Error::NotFound)));
+    assert!(matches!(
+        repo.update(
+            &actor,
+            &id,
+            Some("t".to_string()),
+            Some("b".to_string()),
+            Some(false)
+        )
+        .await,
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(
+        repo.complete(&actor, &id).await,
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(
+        repo.delete(&actor, &id).await,
+        Err(Error::NotFound)
+    ));
+
+    let todo = repo
+        .create(&actor, "t".to_string(), "b".to_string())
+        .await
+        .unwrap();
+    repo.complete(&actor, &todo.id).await.unwrap();
+    assert!(matches!(
+        repo.complete(&actor, &todo.id).await,
+        Err(Error::AlreadyCompleted)
+    ));
+
+    repo.delete(&actor, &todo.id).await.unwrap();
+    assert!(matches!(
+        repo.delete(&actor, &todo.id).await,
+        Err(Error::NotFound)
+    ));
+    assert!(repo.history(&id, 0, 10).await.unwrap().is_empty());
+}
+
+async fn import<R: Repository + Sync>(repo: &R) {
+    let actor = unique("import");
+    let created_at = Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap();
+    let todo = |id: &str, title: &str| Todo {
+        id: id.to_string(),
+        title: title.to_string(),
+        body: "imported".to_string(),
+        is_completed: true,
+        created_at,
+        updated_at: created_at,
+        comment_count: 0,
+    };
+
+    let kept = missing();
+    let imported = repo
+        .import(
+            &actor,
+            vec![todo(&kept, "kept"), todo("", "generated")],
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(imported.len(), 2);
+    assert_eq!(imported[0].id, kept);
+    assert!(!imported[1].id.is_empty());
+    let fetched = repo.get(&kept).await.unwrap();
+    assert_eq!(fetched.title, "kept");
+    assert!(fetched.is_completed);
+    assert_eq!(fetched.created_at, created_at);
+    repo.get(&imported[1].id).await.unwrap();
+
+    let dry = missing();
+    let planned = repo
+        .import(&actor, vec![todo(&dry, "dry")], true)
+        .await
+        .unwrap();
+    assert_eq!(planned[0].id, dry);
+    assert!(matches!(repo.get(&dry).await, Err(Error::NotFound)));
+
+    // A conflict fails the whole import.
+    let fresh = missing();
+    let result = repo
+        .import(
+            &actor,
+            vec![todo(&fresh, "fresh"), todo(&kept, "again")],
+            false,
+        )
+        .await;
+    assert!(matches!(result, Err(Error::AlreadyExists(id)) if id == kept));
+    assert!(matches!(repo.get(&fresh).await, Err(Error::NotFound)));
+    assert_eq!(repo.get(&kept).await.unwrap().title, "kept");
+
+    // Any time a timestamp may hold, from the first year to the last.
+    let first = Utc.with_ymd_and_hms(1, 1, 1, 0, 0, 0).unwrap();
+    let last = Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap();
+    let (ancient, future) = (missing(), missing());
+    repo.import(
+        &actor,
+        vec![
+            Todo {
+                created_at: first,
+                updated_at: first,
+                ..todo(&ancient, "ancient")
+            },
+            Todo {
+                created_at: last,
+                updated_at: last,
+                ..todo(&future, "future")
+            },
+        ],
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(repo.get(&ancient).await.unwrap().created_at, first);
+    assert_eq!(repo.get(&future).await.unwrap().created_at, last);
+    let listed: HashSet<String> = repo
+        .list()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|t| t.id)
+        .collect();
+    assert!(listed.contains(&ancient) && listed.contains(&future));
+}
+
+async fn bulk<R: Repository + Sync>(repo: &R) {
+    // Ids are at most 20 characters. No xid sorts between these, as they
+    // use no 'w'.
+    let base = format!("{}w", &missing()[..18]);
+    let created_at =
+        Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap() + Duration::microseconds(678_901);
+    let updated_at = Utc.with_ymd_and_hms(2020, 2, 3, 4, 5, 6).unwrap();
+    let todo = |n: u32, title: &str| Todo {
+        id: format!("{}{}", base, n),
+        title: title.to_string(),
+        body: "inserted".to_string(),
+        is_completed: n == 2,
+        created_at,
+        updated_at,
+        comment_count: 0,
+    };
+
+    repo.insert_todos(vec![todo(2, "two"), todo(1, "one"), todo(3, "three")])
+        .await
+        .unwrap();
+    let fetched = repo.get(&todo(1, "").id).await.unwrap();
+    assert_eq!(fetched.title, "one");
+    assert_eq!(fetched.created_at, created_at);
+    assert_eq!(fetched.updated_at, updated_at);
+    assert!(repo.history(&fetched.id, 0, 10).await.unwrap().is_empty());
+
+    let page = repo.list_after(&base, 2).await.unwrap();
+    let ids: Vec<String> = page.into_iter().map(|t| t.id).collect();
+    assert_eq!(ids, vec![todo(1, "").id, todo(2, "").id]);
+    let page = repo.list_after(&ids[1], 2).await.unwrap();
+    assert_eq!(page[0].id, todo(3, "").id);
+
+    // Existing todos are replaced, keeping their comments.
+    repo.add_comment("bulk", &ids[0], None, "comment".to_string())
+        .await
+        .unwrap();
+    repo.insert_todos(vec![todo(1, "replaced")]).await.unwrap();
+    let fetched = repo.get(&ids[0]).await.unwrap();
+    assert_eq!(fetched.title, "replaced");
+    assert_eq!(fetched.comment_count, 1);
+}
+
+async fn history<R: Repository + Sync>(repo: &R) {
+    let actor = unique("history");
+    let todo = repo
+        .create(&actor, "t".to_string(), "b".to_string())
+        .await
+        .unwrap();
+    repo.update(
+        &actor,
+        &todo.id,
+        Some("t2".to_string()),
+        Some("b".to_string()),
+        Some(false),
+    )
+    .await
+    .unwrap();
+    repo.complete(&actor, &todo.id).await.unwrap();
+    repo.delete(&actor, &todo.id).await.unwrap();
+
+    let entries = repo.history(&todo.id, 0, 100).await.unwrap();
+    let operations: Vec<Operation> = entries.iter().map(|e| e.operation).collect();
+    assert_eq!(
+        operations,
+        vec![
+            Operation::Create,
+            Operation::Update,
+            Operation::Complete,
+            Operation::Delete
+        ]
+    );
+    assert!(entries.windows(2).all(|w| w[0].id < w[1].id));
+    assert!(entries
+        .iter()
+        .all(|e| e.actor == actor && e.todo_id == todo.id));
+    let title = entries[1].changes.iter().find(|c| c.field == "title");
+    assert_eq!(title.unwrap().before.as_deref(), Some("t"));
+    assert_eq!(title.unwrap().after.as_deref(), Some("t2"));
+
+    let page = repo.history(&todo.id, 1, 2).await.unwrap();
+    assert_eq!(page.len(), 2);
+    assert_eq!(page[0].id, entries[1].id);
+    assert_eq!(page[1].id, entries[2].id);
+
+    let page = repo.list_history_after(entries[0].id - 1, 2).await.unwrap();
+    assert_eq!(page.len(), 2);
+    assert_eq!(page[0].id, entries[0].id);
+    assert!(page[0].id < page[1].id);
+
+    // Inserted entries keep their ids, replace entries with the same id, and
+    // later entries are numbered after them.
+    let mut copied = entries[3].clone();
+    copied.id += 1000;
+    copied.todo_id = missing();
+    repo.insert_history(vec![copied.clone()]).await.unwrap();
+    copied.undo_state = UndoState::Undone;
+    repo.insert_history(vec![copied.clone()]).await.unwrap();
+    let fetched = repo.history(&copied.todo_id, 0, 10).await.unwrap();
+    assert_eq!(fetched.len(), 1);
+    assert_eq!(fetched[0].id, copied.id);
+    assert_eq!(fetched[0].undo_state, UndoState::Undone);
+    assert_eq!(fetched[0].changes, copied.changes);
+    assert_eq!(fetched[0].created_at, copied.created_at);
+    let page = repo.list_history_after(copied.id - 1, 1).await.unwrap();
+    assert_eq!(page[0].id, copied.id);
+    let created = repo
+        .create(&actor, "t".to_string(), "b".to_string())
+        .await
+        .unwrap();
+    let entries = repo.history(&created.id, 0, 10).await.unwrap();
+    assert!(entries[0].id > copied.id);
+}
+
+async fn revert<R: Repository + Sync>(repo: &R) {
+    let actor = unique("revert");
+    let other = unique("revert-other");
+    let todo = repo
+        .create(&actor, "v1".to_string(), "b".to_string())
+        .await
+        .unwrap();
+    repo.update(
+        &actor,
+        &todo.id,
+        Some("v2".to_string()),
+        Some("b".to_string()),
+        Some(false),
+    )
+    .await
+    .unwrap();
+
+    let undone = repo.revert(&actor, Direction::Undo, 1).await.unwrap();
+    assert_eq!(undone.len(), 1);
+    assert_eq!(undone[0].operation, Operation::Undo);
+    assert_eq!(repo.get(&todo.id).await.unwrap().title, "v1");
+
+    let redone = repo.revert(&actor, Direction::Redo, 1).await.unwrap();
+    assert_eq!(redone.len(), 1);
+    assert_eq!(redone[0].operation, Operation::Redo);
+    assert_eq!(repo.get(&todo.id).await.unwrap().title, "v2");
+
+    // Undoing the creation deletes the todo and redoing it brings it back.
+    let undone = repo.revert(&actor, Direction::Undo, 2).await.unwrap();
+    assert_eq!(undone.len(), 2);
+    assert!(matches!(repo.get(&todo.id).await, Err(Error::NotFound)));
+    repo.revert(&actor, Direction::Redo, 1).await.unwrap();
+    assert_eq!(repo.get(&todo.id).await.unwrap().title, "v1");
+
+    // Changes by someone else are never overwritten.
+    repo.update(
+        &other,
+        &todo.id,
+        Some("theirs".to_string()),
+        Some("b".to_string()),
+        Some(false),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        repo.revert(&actor, Direction::Redo, 1).await,
+        Err(Error::Conflict(id)) if id == todo.id
+    ));
+    assert_eq!(repo.get(&todo.id).await.unwrap().title, "theirs");
+
+    // A new change discards what is left to redo.
+    repo.update(
+        &actor,
+        &todo.id,
+        Some("mine".to_string()),
+        Some("b".to_string()),
+        Some(false),
+    )
+    .await
+    .unwrap();
+    assert!(repo
+        .revert(&actor, Direction::Redo, 10)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+async fn comments<R: Repository + Sync>(repo: &R) {
+    let author = unique("author");
+    let other = unique("other");
+    let todo = repo
+        .create(&author, "t".to_string(), "b".to_string())
+        .await
+        .unwrap();
+
+    let first = repo
+        .add_comment(&author, &todo.id, None, "first".to_string())
+        .await
+        .unwrap();
+    assert_eq!(first.todo_id, todo.id);
+    assert_eq!(first.author, author);
+    assert!(first.parent_id.is_none());
+    assert!(first.edited_at.is_none());
+    let reply = repo
+        .add_comment(
+            &other,
+            &todo.id,
+            Some(first.id.clone()),
+            "reply".to_string(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(reply.parent_id.as_deref(), Some(first.id.as_str()));
+    let second = repo
+        .add_comment(&author, &todo.id, None, "second".to_string())
+        .await
+        .unwrap();
+
+    let listed: Vec<String> = repo
+        .list_comments(&todo.id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    assert_eq!(
+        listed,
+        vec![first.id.clone(), reply.id.clone(), second.id.clone()]
+    );
+    assert_eq!(repo.get(&todo.id).await.unwrap().comment_count, 3);
+
+    let edited = repo
+        .edit_comment(&author, &todo.id, &first.id, "edited".to_string())
+        .await
+        .unwrap();
+    assert_eq!(edited.body, "edited");
+    assert!(edited.edited_at.is_some());
+    assert!(matches!(
+        repo.edit_comment(&other, &todo.id, &first.id, "no".to_string())
+            .await,
+        Err(Error::PermissionDenied)
+    ));
+    assert!(matches!(
+        repo.delete_comment(&other, &todo.id, &first.id).await,
+        Err(Error::PermissionDenied)
+    ));
+
+    // Deleting a comment removes its replies too.
+    repo.delete_comment(&author, &todo.id, &first.id)
+        .await
+        .unwrap();
+    let listed = repo.list_comments(&todo.id).await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, second.id);
+    assert_eq!(repo.get(&todo.id).await.unwrap().comment_count, 1);
+
+    let id = missing();
+    assert!(matches!(
+        repo.add_comment(&author, &id, None, "x".to_string()).await,
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(
+        repo.add_comment(&author, &todo.id, Some(id.clone()), "x".to_string())
+            .await,
+        Err(Error::CommentNotFound)
+    ));
+    assert!(matches!(
+        repo.edit_comment(&author, &todo.id, &id, "x".to_string())
+            .await,
+        Err(Error::CommentNotFound)
+    ));
+    assert!(matches!(
+        repo.delete_comment(&author, &todo.id, &id).await,
+        Err(Error::CommentNotFound)
+    ));
+    assert!(matches!(
+        repo.delete_comment(&author, &id, &second.id).await,
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(
+        repo.list_comments(&id).await,
+        Err(Error::NotFound)
+    ));
+
+    // Comments go away with their todo.
+    repo.delete(&author, &todo.id).await.unwrap();
+    assert!(matches!(
+        repo.edit_comment(&author, &todo.id, &second.id, "x".to_string())
+            .await,
+        Err(Error::CommentNotFound)
+    ));
+}
+
+async fn attachments<R: Repository + Sync>(repo: &R) {
+    let actor = unique("attachments");
+    let todo = repo
+        .create(&actor, "t".to_string(), "b".to_string())
+        .await
+        .unwrap();
+    let attachment = |todo_id: &str| Attachment {
+        id: missing(),
+        todo_id: todo_id.to_string(),
+        filename: "notes.txt".to_string(),
+        content_type: "text/plain".to_string(),
+        size: 42,
+        created_at: Utc::now(),
+    };
+
+    let first = repo.add_attachment(attachment(&todo.id)).await.unwrap();
+    let second = repo.add_attachment(attachment(&todo.id)).await.unwrap();
+    assert_eq!(first.filename, "notes.txt");
+    assert_eq!(first.size, 42);
+
+    let listed: Vec<String> = repo
+        .list_attachments(&todo.id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|a| a.id)
+        .collect();
+    assert_eq!(listed, vec![first.id.clone(), second.id.clone()]);
+    let fetched = repo.get_attachment(&todo.id, &first.id).await.unwrap();
+    assert_eq!(fetched.content_type, "text/plain");
+
+    let deleted = repo.delete_attachment(&todo.id, &first.id).await.unwrap();
+    assert_eq!(deleted.id, first.id);
+    assert!(matches!(
+        repo.get_attachment(&todo.id, &first.id).await,
+        Err(Error::AttachmentNotFound)
+    ));
+    assert!(matches!(
+        repo.delete_attachment(&todo.id, &first.id).await,
+        Err(Error::AttachmentNotFound)
+    ));
+
+    let id = missing();
+    assert!(matches!(
+        repo.add_attachment(attachment(&id)).await,
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(
+        repo.list_attachments(&id).await,
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(
+        repo.get_attachment(&id, &second.id).await,
+        Err(Error::AttachmentNotFound)
+    ));
+
+    // Attachments go away with their todo.
+    repo.delete(&actor, &todo.id).await.unwrap();
+    assert!(matches!(
+        repo.get_attachment(&todo.id, &second.id).await,
+        Err(Error::AttachmentNotFound)
+    ));
+}
+
+async fn concurrency<R: Repository + Sync>(repo: &R) {
+    let actor = unique("concurrency");
+    let created =
+        join_all((0..20).map(|i| repo.create(&actor, format!("todo {}", i), "b".to_string())))
+            .await;
+    let ids: HashSet<String> = created.into_iter().map(|t| t.unwrap().id).collect();
+    assert_eq!(ids.len(), 20);
+    let listed: HashSet<String> = repo
+        .list()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|t| t.id)
+        .collect();
+    assert!(ids.is_subset(&listed));
+
+    let todo = repo
+        .create(&actor, "t".to_string(), "b".to_string())
+        .await
+        .unwrap();
+    let titles: Vec<String> = (0..10).map(|i| format!("title {}", i)).collect();
+    let updated = join_all(titles.iter().map(|title| {
+        repo.update(
+            &actor,
+            &todo.id,
+            Some(title.clone()),
+            Some("b".to_string()),
+            Some(false),
+        )
+    }))
+    .await;
+    assert!(updated.iter().all(|r| r.is_ok()));
+    assert!(titles.contains(&repo.get(&todo.id).await.unwrap().title));
+    let updates = repo
+        .history(&todo.id, 0, 100)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|e| e.operation == Operation::Update)
+        .count();
+    assert_eq!(updates, 10);
+
+    // Updates of different fields keep each other's changes.
+    let (title, body) = futures::future::join(
+        repo.update(&actor, &todo.id, Some("both".to_string()), None, None),
+        repo.update(&actor, &todo.id, None, Some("kept".to_string()), None),
+    )
+    .await;
+    title.unwrap();
+    body.unwrap();
+    let merged = repo.get(&todo.id).await.unwrap();
+    assert_eq!(merged.title, "both");
+    assert_eq!(merged.body, "kept");
+    assert!(!merged.is_completed);
+
+    let completed = join_all((0..10).map(|_| repo.complete(&actor, &todo.id))).await;
+    assert_eq!(completed.iter().filter(|r| r.is_ok()).count(), 1);
+    assert!(completed
+        .iter()
+        .filter_map(|r| r.as_ref().err())
+        .all(|e| matches!(e, Error::AlreadyCompleted)));
+
+    let comments = join_all(
+        (0..10).map(|i| repo.add_comment(&actor, &todo.id, None, format!("comment {}", i))),
+    )
+    .await;
+    assert!(comments.iter().all(|r| r.is_ok()));
+    assert_eq!(repo.get(&todo.id).await.unwrap().comment_count, 10);
+
+    let deleted = join_all((0..5).map(|_| repo.delete(&actor, &todo.id))).await;
+    assert_eq!(deleted.iter().filter(|r| r.is_ok()).count(), 1);
+    assert!(deleted
+        .iter()
+        .filter_map(|r| r.as_ref().err())
+        .all(|e| matches!(e, Error::NotFound)));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn hashmap() {
+    conformance(&HashMapRepository::new(logger())).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn hashmap_with_wal() {
+    let dir = TempDir::new("todo-conformance");
+    let settings = HashMapSettings {
+        data_dir: Some(dir.path().to_string()),
+        fsync: FsyncPolicy::Never,
+        fsync_interval_ms: 0,
+        snapshot_every: 50,
+    };
+    let repo = HashMapRepository::open(logger(), dir.path(), &settings).unwrap();
+    conformance(&repo).await;
+    let todos = repo.list().await.unwrap().len();
+    drop(repo);
+
+    let reopened = HashMapRepository::open(logger(), dir.path(), &settings).unwrap();
+    assert_eq!(reopened.list().await.unwrap().len(), todos);
+    conformance(&reopened).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn sqlite() {
+    let repo = SqliteRepository::new(":memory:").await.unwrap();
+    repo.migrate_up().await.unwrap();
+    conformance(&repo).await;
+}
+
+/// Reverting migrations keeps the data of the remaining schema, and they can
+/// be applied again afterwards.
+#[tokio::test(threaded_scheduler)]
+async fn sqlite_migrate_down() {
+    let repo = SqliteRepository::new(":memory:").await.unwrap();
+    assert_eq!(repo.migrate_up().await.unwrap(), vec![1, 2, 3, 4, 5]);
+    let todo = repo
+        .create(&unique("migrate"), "title".to_string(), "body".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(repo.migrate_down(2).await.unwrap(), vec![5, 4, 3]);
+    let applied = repo
+        .migration_status()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|m| m.applied)
+        .map(|m| m.version)
+        .collect::<Vec<_>>();
+    assert_eq!(applied, vec![1, 2]);
+
+    assert_eq!(repo.migrate_up().await.unwrap(), vec![3, 4, 5]);
+    assert_eq!(repo.get(&todo.id).await.unwrap().title, "title");
+    conformance(&repo).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn sled() {
+    let dir = TempDir::new("todo-conformance");
+    let repo = SledRepository::new(dir.path()).unwrap();
+    conformance(&repo).await;
+    let todos = repo.list().await.unwrap();
+    drop(repo);
+
+    // sled lists todos in the order of the index on created_at.
+    let reopened = SledRepository::new(dir.path()).unwrap();
+    let listed = reopened.list().await.unwrap();
+    assert_eq!(listed.len(), todos.len());
+    assert!(listed
+        .windows(2)
+        .all(|pair| pair[0].created_at <= pair[1].created_at));
+    conformance(&reopened).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn cached() {
+    let settings = CacheSettings {
+        enabled: true,
+        capacity: 4,
+        ttl_ms: 60_000,
+        list: true,
+        stats_interval_secs: 0,
+    };
+    let repo = CachedRepository::new(HashMapRepository::new(logger()), &settings, logger());
+    conformance(&repo).await;
+}
+
+/// Every read below is made right after the same read was cached, well
+/// within the TTL, so only invalidation can make it see the write.
+#[tokio::test(threaded_scheduler)]
+async fn cached_reads_follow_writes() {
+    let settings = CacheSettings {
+        enabled: true,
+        capacity: 16,
+        ttl_ms: 60_000,
+        list: true,
+        stats_interval_secs: 0,
+    };
+    let repo = CachedRepository::new(HashMapRepository::new(logger()), &settings, logger());
+    let actor = unique("cached");
+    let listed_title =
+        |todos: Vec<Todo>, id: &str| todos.into_iter().find(|t| t.id == id).map(|t| t.title);
+
+    repo.list().await.unwrap();
+    let todo = repo
+        .create(&actor, "v1".to_string(), "b".to_string())
+        .await
+        .unwrap();
+    assert_eq!(
+        listed_title(repo.list().await.unwrap(), &todo.id).as_deref(),
+        Some("v1")
+    );
+
+    repo.get(&todo.id).await.unwrap();
+    repo.update(&actor, &todo.id, Some("v2".to_string()), None, None)
+        .await
+        .unwrap();
+    assert_eq!(repo.get(&todo.id).await.unwrap().title, "v2");
+    assert_eq!(
+        listed_title(repo.list().await.unwrap(), &todo.id).as_deref(),
+        Some("v2")
+    );
+
+    repo.get(&todo.id).await.unwrap();
+    repo.complete(&actor, &todo.id).await.unwrap();
+    assert!(repo.get(&todo.id).await.unwrap().is_completed);
+
+    repo.get(&todo.id).await.unwrap();
+    repo.add_comment(&actor, &todo.id, None, "c".to_string())
+        .await
+        .unwrap();
+    assert_eq!(repo.get(&todo.id).await.unwrap().comment_count, 1);
+
+    // Undoing the completion brings back the open todo.
+    repo.get(&todo.id).await.unwrap();
+    repo.list().await.unwrap();
+    repo.revert(&actor, Direction::Undo, 1).await.unwrap();
+    assert!(!repo.get(&todo.id).await.unwrap().is_completed);
+    assert!(repo
+        .list()
+        .await
+        .unwrap()
+        .iter()
+        .any(|t| t.id == todo.id && !t.is_completed));
+
+    repo.get(&todo.id).await.unwrap();
+    repo.list().await.unwrap();
+    repo.delete(&actor, &todo.id).await.unwrap();
+    assert!(matches!(repo.get(&todo.id).await, Err(Error::NotFound)));
+    assert_eq!(listed_title(repo.list().await.unwrap(), &todo.id), None);
+}
+
+async fn idempotency(store: &dyn IdempotencyStore) {
+    let minute = std::time::Duration::from_secs(60);
+    let expired = std::time::Duration::from_secs(0);
+
+    // A repeated request waits for the first, then gets its response, and a
+    // different one with the same key is refused.
+    let key = unique("key");
+    assert_eq!(store.claim(&key, "a", minute).await.unwrap(), Claim::New);
+    assert_eq!(
+        store.claim(&key, "a", minute).await.unwrap(),
+        Claim::InProgress
+    );
+    assert_eq!(
+        store.claim(&key, "b", minute).await.unwrap(),
+        Claim::Mismatch
+    );
+    store
+        .complete(&key, "a", b"response".to_vec(), minute)
+        .await
+        .unwrap();
+    assert_eq!(
+        store.claim(&key, "a", minute).await.unwrap(),
+        Claim::Done(b"response".to_vec())
+    );
+    assert_eq!(
+        store.claim(&key, "b", minute).await.unwrap(),
+        Claim::Mismatch
+    );
+
+    // Only a claim that has no response yet is released.
+    store.release(&key, "a").await.unwrap();
+    assert_eq!(
+        store.claim(&key, "a", minute).await.unwrap(),
+        Claim::Done(b"response".to_vec())
+    );
+
+    // A released claim can be retried.
+    let key = unique("key");
+    assert_eq!(store.claim(&key, "a", minute).await.unwrap(), Claim::New);
+    store.release(&key, "a").await.unwrap();
+    assert_eq!(store.claim(&key, "a", minute).await.unwrap(), Claim::New);
+
+    // An expired key is free for any request, and pruned.
+    let key = unique("key");
+    assert_eq!(store.claim(&key, "a", expired).await.unwrap(), Claim::New);
+    assert_eq!(store.claim(&key, "b", expired).await.unwrap(), Claim::New);
+    store
+        .complete(&key, "b", b"response".to_vec(), expired)
+        .await
+        .unwrap();
+    assert!(store.prune().await.unwrap() >= 1);
+    assert_eq!(store.claim(&key, "a", minute).await.unwrap(), Claim::New);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn idempotency_memory() {
+    idempotency(&MemoryIdempotencyStore::new()).await;
+}
+
+/// Returns settings for `TODO_TEST_POSTGRES_CONNECTION_STRING`, or for a
+/// default local instance if that is unset. Only the default is skipped when
+/// unreachable.
+async fn postgres_settings() -> Option<PostgresSettings> {
+    let (connection_string, required) = match std::env::var(POSTGRES_ENV) {
+        Ok(s) => (s, true),
+        Err(_) => (POSTGRES_DEFAULT.to_string(), false),
+    };
+    // The pool connects lazily, so reachability is checked with a single
+    // connection first.
+    match PgConnection::connect(&connection_string).await {
+        Ok(_) => {}
+        Err(e) if !required => {
+            eprintln!(
+                "skipping postgres conformance, {} is unreachable: {}",
+                POSTGRES_DEFAULT, e
+            );
+            return None;
+        }
+        Err(e) => panic!("failed to connect to postgres: {}", e),
+    }
+
+    Some(PostgresSettings {
+        connection_string,
+        auto_migrate: true,
+        replica_connection_string: None,
+        max_connections: 10,
+        min_connections: 0,
+        acquire_timeout_ms: 30_000,
+        idle_timeout_ms: None,
+        statement_timeout_ms: Some(10_000),
+        application_name: Some("todo-conformance".to_string()),
+        outbox: true,
+    })
+}
+
+/// Returns settings for a migrated database of its own, emptied first, for
+/// tests that need the outbox and its locks to themselves.
+pub(crate) async fn postgres_scratch(name: &str) -> Option<PostgresSettings> {
+    let mut settings = postgres_settings().await?;
+    let mut conn = PgConnection::connect(&settings.connection_string)
+        .await
+        .unwrap();
+    let database = format!("todo_scratch_{}", name);
+    sqlx::query(&format!(
+        "DROP DATABASE IF EXISTS {} WITH (FORCE)",
+        database
+    ))
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    sqlx::query(&format!("CREATE DATABASE {}", database))
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+    let (url, query) = match settings.connection_string.split_once('?') {
+        Some((url, query)) => (url, format!("?{}", query)),
+        None => (settings.connection_string.as_str(), String::new()),
+    };
+    let (server, _) = url
+        .rsplit_once('/')
+        .expect("no database in the connection string");
+    settings.connection_string = format!("{}/{}{}", server, database, query);
+    PostgresRepository::new(&settings, logger())
+        .await
+        .unwrap()
+        .migrate_up()
+        .await
+        .unwrap();
+
+    Some(settings)
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn postgres() {
+    let settings = match postgres_settings().await {
+        Some(settings) => settings,
+        None => return,
+    };
+    let repo = PostgresRepository::new(&settings, logger()).await.unwrap();
+    repo.migrate_up().await.unwrap();
+    conformance(&repo).await;
+}
+
+/// Uses the primary as its own replica, which exercises the read routing.
+#[tokio::test(threaded_scheduler)]
+async fn postgres_with_replica() {
+    let mut settings = match postgres_settings().await {
+        Some(settings) => settings,
+        None => return,
+    };
+    settings.replica_connection_string = Some(settings.connection_string.clone());
+    let repo = PostgresRepository::new(&settings, logger()).await.unwrap();
+    repo.migrate_up().await.unwrap();
+    conformance(&repo).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn idempotency_postgres() {
+    let settings = match postgres_settings().await {
+        Some(settings) => settings,
+        None => return,
+    };
+    PostgresRepository::new(&settings, logger())
+        .await
+        .unwrap()
+        .migrate_up()
+        .await
+        .unwrap();
+    idempotency(&PostgresIdempotencyStore::new(&settings).await.unwrap()).await;
+}
@@ -1,39 +1,140 @@
-use tonic::Status;
+use std::fmt;
+
+use tonic::{Code, Status};
+
+use crate::details;
+
+/// The SQLSTATE of a statement cancelled by Postgres.
+const QUERY_CANCELED: &str = "57014";
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     NotFound,
     IDGenerationError,
     AlreadyCompleted,
+    AlreadyExists(String),
+    Conflict(String),
+    CommentNotFound,
+    AttachmentNotFound,
+    PermissionDenied,
     SQLError(sqlx::Error),
+    IOError(std::io::Error),
+    KVError(sled::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "todo not found"),
+            Error::IDGenerationError => write!(f, "failed to generate id"),
+            Error::AlreadyCompleted => write!(f, "todo already completed"),
+            Error::AlreadyExists(id) => write!(f, "todo {} already exists", id),
+            Error::Conflict(id) => write!(f, "todo {} was changed by another operation", id),
+            Error::CommentNotFound => write!(f, "comment not found"),
+            Error::AttachmentNotFound => write!(f, "attachment not found"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::SQLError(err) => write!(f, "sql error: {}", err),
+            Error::IOError(err) => write!(f, "io error: {}", err),
+            Error::KVError(err) => write!(f, "kv error: {}", err),
+        }
+    }
 }
 
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The `ErrorInfo` reason sent to clients.
+    fn reason(&self) -> &'static str {
+        match self {
+            Error::NotFound => "TODO_NOT_FOUND",
+            Error::IDGenerationError => "ID_GENERATION_FAILED",
+            Error::AlreadyCompleted => "TODO_ALREADY_COMPLETED",
+            Error::AlreadyExists(_) => "TODO_ALREADY_EXISTS",
+            Error::Conflict(_) => "TODO_CHANGED",
+            Error::CommentNotFound => "COMMENT_NOT_FOUND",
+            Error::AttachmentNotFound => "ATTACHMENT_NOT_FOUND",
+            Error::PermissionDenied => "NOT_COMMENT_AUTHOR",
+            Error::SQLError(sqlx::Error::RowNotFound) => "TODO_NOT_FOUND",
+            Error::SQLError(_) if self.is_timeout() => "STORAGE_TIMEOUT",
+            Error::SQLError(_) | Error::IOError(_) | Error::KVError(_) if self.is_unavailable() => {
+                "STORAGE_UNAVAILABLE"
+            }
+            Error::SQLError(_) | Error::IOError(_) | Error::KVError(_) => "STORAGE_ERROR",
+        }
+    }
+
+    /// Whether Postgres cancelled a statement that ran longer than its
+    /// statement_timeout.
+    fn is_timeout(&self) -> bool {
+        match self {
+            Error::SQLError(sqlx::Error::Database(err)) => {
+                err.code().as_deref() == Some(QUERY_CANCELED)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the storage could not be reached, so that the call may succeed
+    /// when retried.
+    fn is_unavailable(&self) -> bool {
+        matches!(
+            self,
+            Error::SQLError(
+                sqlx::Error::Io(_)
+                    | sqlx::Error::Tls(_)
+                    | sqlx::Error::PoolTimedOut
+                    | sqlx::Error::PoolClosed
+                    | sqlx::Error::WorkerCrashed
+            )
+        )
+    }
+}
+
+/// Storage errors are described to clients only by their reason, the rest
+/// is logged where they occur.
 impl From<Error> for Status {
     fn from(err: Error) -> Self {
-        match err {
-            Error::NotFound => Self::not_found("todo not found"),
-            Error::IDGenerationError => Self::internal("failed to generate id"),
-            Error::AlreadyCompleted => Self::invalid_argument("todo already completed"),
-            Error::SQLError(err) => match err {
-                sqlx::error::Error::Configuration(e) => Self::internal(e.to_string()),
-                sqlx::error::Error::Database(e) => Self::internal(e.to_string()),
-                sqlx::error::Error::Io(e) => Self::internal(e.to_string()),
-                sqlx::error::Error::Tls(e) => Self::internal(e.to_string()),
-                sqlx::error::Error::Protocol(e) => Self::internal(e.to_string()),
-                sqlx::error::Error::RowNotFound => Self::not_found("todo not found"),
-                sqlx::error::Error::ColumnIndexOutOfBounds { .. } => {
-                    Self::internal("ColumnIndexOutOfBounds")
-                }
-                sqlx::error::Error::ColumnNotFound(e) => Self::internal(e.to_string()),
-                sqlx::error::Error::ColumnDecode { .. } => Self::internal("ColumnDecode"),
-                sqlx::error::Error::Decode(e) => Self::internal(e.to_string()),
-                sqlx::error::Error::PoolTimedOut => Self::internal("PoolTimedOut"),
-                sqlx::error::Error::PoolClosed => Self::internal("PoolClosed"),
-                sqlx::error::Error::WorkerCrashed => Self::internal("WorkerCrashed"),
-                sqlx::error::Error::Migrate(e) => Self::internal(e.to_string()),
-                _ => Self::internal(format!("unknown sqlx error")),
-            },
-        }
+        let reason = details::error_info(err.reason());
+        let (code, message) = match &err {
+            Error::NotFound => (Code::NotFound, "todo not found".to_string()),
+            Error::IDGenerationError => (Code::Internal, "failed to generate id".to_string()),
+            Error::AlreadyCompleted => (
+                Code::FailedPrecondition,
+                "todo already completed".to_string(),
+            ),
+            Error::AlreadyExists(id) => {
+                (Code::AlreadyExists, format!("todo {} already exists", id))
+            }
+            Error::Conflict(id) => {
+                let message = format!("todo {} was changed by another operation", id);
+                let failure = details::precondition_failure(
+                    "UNCHANGED",
+                    format!("todos/{}", id),
+                    message.clone(),
+                );
+                return details::status(Code::FailedPrecondition, message, vec![reason, failure]);
+            }
+            Error::CommentNotFound => (Code::NotFound, "comment not found".to_string()),
+            Error::AttachmentNotFound => (Code::NotFound, "attachment not found".to_string()),
+            Error::PermissionDenied => (
+                Code::PermissionDenied,
+                "only the author can change a comment".to_string(),
+            ),
+            Error::SQLError(sqlx::Error::RowNotFound) => {
+                (Code::NotFound, "todo not found".to_string())
+            }
+            _ if err.is_timeout() => (
+                Code::DeadlineExceeded,
+                "storage did not answer in time".to_string(),
+            ),
+            _ if err.is_unavailable() => (Code::Unavailable, "storage is unavailable".to_string()),
+            Error::SQLError(_) | Error::IOError(_) | Error::KVError(_) => {
+                (Code::Internal, "storage error".to_string())
+            }
+        };
+
+        details::status(code, message, vec![reason])
     }
 }
 
@@ -43,6 +144,18 @@ impl From<sqlx::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Self {
+        Self::KVError(err)
+    }
+}
+
 impl From<libxid::IDGenerationError> for Error {
     fn from(_: libxid::IDGenerationError) -> Self {
         Self::IDGenerationError
@@ -1,15 +1,239 @@
 use crate::repository::error::Error;
-use crate::repository::model::{Todo, Todos};
-use crate::repository::repository::Repository;
+use crate::repository::model::{
+    diff, Attachment, Comment, Direction, HistoryEntry, Operation, Todo, Todos, UndoState,
+};
+use crate::repository::repository::{HashMapSettings, Repository};
+use crate::repository::wal::{FsyncPolicy, Wal};
 use async_trait::async_trait;
 use chrono::Utc;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::RwLock;
 
+/// A single modification of the store. Every repository operation is turned
+/// into a batch of changes, which is what the write-ahead log records.
+#[derive(Debug, Serialize, Deserialize)]
+enum Change {
+    PutTodo(Todo),
+    RemoveTodo(String),
+    AddHistory(HistoryEntry),
+    SetUndoState { id: i64, state: UndoState },
+    PutComment(Comment),
+    RemoveComment(String),
+    PutAttachment(Attachment),
+    RemoveAttachment(String),
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Store {
+    todos: HashMap<String, Todo>,
+    history: Vec<HistoryEntry>,
+    comments: HashMap<String, Comment>,
+    attachments: HashMap<String, Attachment>,
+    /// The ids of the undone history entries of every actor, which the next
+    /// undoable change of the actor discards.
+    #[serde(skip)]
+    undone: HashMap<String, Vec<i64>>,
+    #[serde(skip)]
+    wal: Option<Arc<Mutex<Wal>>>,
+}
+
+impl Store {
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::PutTodo(todo) => {
+                self.todos.insert(todo.id.clone(), todo);
+            }
+            Change::RemoveTodo(id) => {
+                self.comments.retain(|_, c| c.todo_id != id);
+                self.attachments.retain(|_, a| a.todo_id != id);
+                self.todos.remove(&id);
+            }
+            // Entries are kept ordered by id; copied entries may fill gaps.
+            Change::AddHistory(entry) => {
+                let (id, actor) = (entry.id, entry.actor.clone());
+                let undone = entry.undo_state == UndoState::Undone;
+                match self.position(id) {
+                    Ok(i) => {
+                        let replaced = std::mem::replace(&mut self.history[i], entry);
+                        self.index_undone(&replaced.actor, id, false);
+                    }
+                    Err(i) => self.history.insert(i, entry),
+                }
+                self.index_undone(&actor, id, undone);
+            }
+            Change::SetUndoState { id, state } => {
+                if let Ok(i) = self.position(id) {
+                    self.history[i].undo_state = state;
+                    let actor = self.history[i].actor.clone();
+                    self.index_undone(&actor, id, state == UndoState::Undone);
+                }
+            }
+            Change::PutComment(comment) => {
+                let todo_id = comment.todo_id.clone();
+                self.comments.insert(comment.id.clone(), comment);
+                self.count_comments(&todo_id);
+            }
+            Change::RemoveComment(id) => {
+                if let Some(comment) = self.comments.remove(&id) {
+                    self.count_comments(&comment.todo_id);
+                }
+            }
+            Change::PutAttachment(attachment) => {
+                self.attachments.insert(attachment.id.clone(), attachment);
+            }
+            Change::RemoveAttachment(id) => {
+                self.attachments.remove(&id);
+            }
+        }
+    }
+
+    /// Makes the changes durable when a write-ahead log is configured and
+    /// then applies them.
+    ///
+    /// Writing and syncing the log blocks, so it is done off the async
+    /// workers. The store stays locked meanwhile, which keeps the log in the
+    /// order the changes are applied.
+    async fn commit(&mut self, changes: Vec<Change>, logger: &slog::Logger) -> Result<(), Error> {
+        let wal = match self.wal.clone() {
+            Some(wal) => wal,
+            None => {
+                for change in changes {
+                    self.apply(change);
+                }
+                return Ok(());
+            }
+        };
+
+        let (changes, should_snapshot) = blocking({
+            let wal = wal.clone();
+            move || {
+                let mut wal = wal.lock().unwrap();
+                wal.append(&changes)?;
+                Ok((changes, wal.should_snapshot()))
+            }
+        })
+        .await?;
+        for change in changes {
+            self.apply(change);
+        }
+
+        if should_snapshot {
+            // The changes are already in the log, so a failed snapshot only
+            // delays compaction.
+            let encoded = wal.lock().unwrap().encode_snapshot(&*self);
+            let result = match encoded {
+                Ok(encoded) => blocking(move || Ok(wal.lock().unwrap().snapshot(&encoded)?)).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(_) => info!(logger, "wrote snapshot"; "todos" => self.todos.len()),
+                Err(e) => error!(logger, "failed to write snapshot"; "err" => %e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index_undone(&mut self, actor: &str, id: i64, undone: bool) {
+        let ids = self.undone.entry(actor.to_string()).or_default();
+        ids.retain(|i| *i != id);
+        if undone {
+            ids.push(id);
+        }
+        if ids.is_empty() {
+            self.undone.remove(actor);
+        }
+    }
+
+    /// Indexes the undone entries of a store restored from a snapshot.
+    fn reindex(&mut self) {
+        let mut undone: HashMap<String, Vec<i64>> = HashMap::new();
+        for entry in self.history.iter() {
+            if entry.undo_state == UndoState::Undone {
+                undone
+                    .entry(entry.actor.clone())
+                    .or_default()
+                    .push(entry.id);
+            }
+        }
+        self.undone = undone;
+    }
+
+    fn position(&self, id: i64) -> Result<usize, usize> {
+        self.history.binary_search_by_key(&id, |entry| entry.id)
+    }
+
+    fn count_comments(&mut self, todo_id: &str) {
+        let count = self
+            .comments
+            .values()
+            .filter(|c| c.todo_id == todo_id)
+            .count();
+        if let Some(todo) = self.todos.get_mut(todo_id) {
+            todo.comment_count = count as i32;
+        }
+    }
+
+    fn comment(&self, todo_id: &str, id: &str) -> Result<&Comment, Error> {
+        match self.comments.get(id) {
+            Some(comment) if comment.todo_id == todo_id => Ok(comment),
+            _ => Err(Error::CommentNotFound),
+        }
+    }
+
+    /// Adds the history entry for a change to `changes`, discarding the
+    /// actor's undone entries first when the change can be undone.
+    fn record(
+        &self,
+        changes: &mut Vec<Change>,
+        actor: &str,
+        operation: Operation,
+        before: Option<&Todo>,
+        after: Option<&Todo>,
+    ) {
+        if operation.is_undoable() {
+            for id in self.undone.get(actor).into_iter().flatten() {
+                changes.push(Change::SetUndoState {
+                    id: *id,
+                    state: UndoState::Discarded,
+                });
+            }
+        }
+
+        let pending = changes
+            .iter()
+            .filter(|c| matches!(c, Change::AddHistory(_)))
+            .count();
+        let todo_id = before.or(after).map(|t| t.id.clone()).unwrap_or_default();
+        changes.push(Change::AddHistory(HistoryEntry {
+            id: self.history.last().map_or(0, |entry| entry.id) + pending as i64 + 1,
+            todo_id,
+            actor: actor.to_string(),
+            operation,
+            changes: diff(before, after),
+            undo_state: UndoState::Active,
+            created_at: Utc::now(),
+        }));
+    }
+}
+
+/// Runs blocking work on the blocking thread pool.
+async fn blocking<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::IOError(std::io::Error::other(e)))?
+}
+
 pub struct HashMapRepository {
     logger: slog::Logger,
-    db: Arc<RwLock<HashMap<String, Todo>>>,
+    db: Arc<RwLock<Store>>,
     id_generator: libxid::Generator,
 }
 
@@ -17,10 +241,57 @@ impl HashMapRepository {
     pub fn new(logger: slog::Logger) -> HashMapRepository {
         HashMapRepository {
             logger,
-            db: Arc::new(RwLock::new(HashMap::new())),
+            db: Arc::new(RwLock::new(Store::default())),
             id_generator: libxid::new_generator(),
         }
     }
+
+    /// Restores the store from the snapshot and write-ahead log in
+    /// `data_dir` and keeps logging every change there.
+    pub fn open(
+        logger: slog::Logger,
+        data_dir: &str,
+        settings: &HashMapSettings,
+    ) -> Result<HashMapRepository, Error> {
+        let (wal, mut store, batches) = Wal::open::<Store, Vec<Change>>(
+            Path::new(data_dir),
+            settings.fsync,
+            settings.snapshot_every,
+            &logger,
+        )?;
+        store.reindex();
+        let replayed = batches.len();
+        for changes in batches {
+            for change in changes {
+                store.apply(change);
+            }
+        }
+        info!(logger, "restored todos"; "todos" => store.todos.len(), "replayed" => replayed);
+
+        if settings.fsync == FsyncPolicy::Interval {
+            let file = wal.file();
+            let interval = Duration::from_millis(settings.fsync_interval_ms);
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(interval);
+                loop {
+                    ticks.tick().await;
+                    let file = file.clone();
+                    let result = tokio::task::spawn_blocking(move || file.sync_data()).await;
+                    if let Ok(Err(e)) = result {
+                        error!(logger, "failed to sync write-ahead log"; "err" => %e);
+                    }
+                }
+            });
+        }
+        store.wal = Some(Arc::new(Mutex::new(wal)));
+
+        Ok(HashMapRepository {
+            logger,
+            db: Arc::new(RwLock::new(store)),
+            id_generator: libxid::new_generator(),
+        })
+    }
 }
 
 #[async_trait]
@@ -28,8 +299,8 @@ impl Repository for HashMapRepository {
     async fn list(&self) -> Result<Todos, Error> {
         let lock = self.db.clone();
         let db = lock.read().await;
-        let mut todos = Vec::with_capacity(db.len());
-        for v in db.values() {
+        let mut todos = Vec::with_capacity(db.todos.len());
+        for v in db.todos.values() {
             todos.push(v.clone());
         }
 
@@ -39,7 +310,7 @@ impl Repository for HashMapRepository {
     async fn get(&self, id: &str) -> Result<Todo, Error> {
         let lock = self.db.clone();
         let db = lock.read().await;
-        match db.get(id) {
+        match db.todos.get(id) {
             Some(todo) => Ok(todo.clone()),
             None => {
                 error!(self.logger, "todo not found"; "id" => id);
@@ -48,41 +319,55 @@ impl Repository for HashMapRepository {
         }
     }
 
-    async fn create(&self, title: String, body: String) -> Result<Todo, Error> {
+    async fn create(&self, actor: &str, title: String, body: String) -> Result<Todo, Error> {
         let lock = self.db.clone();
         let mut db = lock.write().await;
         let id = self.id_generator.new_id()?.encode();
         let now = Utc::now();
         let todo = Todo {
-            id: id.clone(),
+            id,
             title,
             body,
             is_completed: false,
             created_at: now,
             updated_at: now,
+            comment_count: 0,
         };
-        db.insert(id, todo.clone());
+        let mut changes = vec![Change::PutTodo(todo.clone())];
+        db.record(&mut changes, actor, Operation::Create, None, Some(&todo));
+        db.commit(changes, &self.logger).await?;
         Ok(todo)
     }
 
     async fn update(
         &self,
+        actor: &str,
         id: &str,
-        title: String,
-        body: String,
-        is_completed: bool,
+        title: Option<String>,
+        body: Option<String>,
+        is_completed: Option<bool>,
     ) -> Result<Todo, Error> {
         let lock = self.db.clone();
         let mut db = lock.write().await;
-        match db.get_mut(id) {
-            Some(mut todo) => {
-                let now = Utc::now();
-                todo.title = title;
-                todo.body = body;
-                todo.is_completed = is_completed;
-                todo.updated_at = now;
+        match db.todos.get(id) {
+            Some(todo) => {
+                let before = todo.clone();
+                let mut after = todo.clone();
+                after.title = title.unwrap_or(after.title);
+                after.body = body.unwrap_or(after.body);
+                after.is_completed = is_completed.unwrap_or(after.is_completed);
+                after.updated_at = Utc::now();
 
-                Ok(todo.clone())
+                let mut changes = vec![Change::PutTodo(after.clone())];
+                db.record(
+                    &mut changes,
+                    actor,
+                    Operation::Update,
+                    Some(&before),
+                    Some(&after),
+                );
+                db.commit(changes, &self.logger).await?;
+                Ok(after)
             }
             None => {
                 error!(self.logger, "todo not found"; "id" => id);
@@ -91,11 +376,16 @@ impl Repository for HashMapRepository {
         }
     }
 
-    async fn delete(&self, id: &str) -> Result<(), Error> {
+    async fn delete(&self, actor: &str, id: &str) -> Result<(), Error> {
         let lock = self.db.clone();
         let mut db = lock.write().await;
-        match db.remove(id) {
-            Some(_) => Ok(()),
+        match db.todos.get(id) {
+            Some(todo) => {
+                let mut changes = vec![Change::RemoveTodo(id.to_string())];
+                db.record(&mut changes, actor, Operation::Delete, Some(todo), None);
+                db.commit(changes, &self.logger).await?;
+                Ok(())
+            }
             None => {
                 error!(self.logger, "todo not found"; "id" => id);
                 Err(Error::NotFound)
@@ -103,19 +393,30 @@ impl Repository for HashMapRepository {
         }
     }
 
-    async fn complete(&self, id: &str) -> Result<Todo, Error> {
+    async fn complete(&self, actor: &str, id: &str) -> Result<Todo, Error> {
         let lock = self.db.clone();
         let mut db = lock.write().await;
-        match db.get_mut(id) {
-            Some(mut todo) => {
+        match db.todos.get(id) {
+            Some(todo) => {
                 if todo.is_completed {
                     return Err(Error::AlreadyCompleted);
                 }
 
-                let now = Utc::now();
-                todo.is_completed = true;
-                todo.updated_at = now;
-                Ok(todo.clone())
+                let before = todo.clone();
+                let mut after = todo.clone();
+                after.is_completed = true;
+                after.updated_at = Utc::now();
+
+                let mut changes = vec![Change::PutTodo(after.clone())];
+                db.record(
+                    &mut changes,
+                    actor,
+                    Operation::Complete,
+                    Some(&before),
+                    Some(&after),
+                );
+                db.commit(changes, &self.logger).await?;
+                Ok(after)
             }
             None => {
                 error!(self.logger, "todo not found"; "id" => id);
@@ -123,4 +424,326 @@ impl Repository for HashMapRepository {
             }
         }
     }
+
+    async fn import(&self, actor: &str, todos: Todos, dry_run: bool) -> Result<Todos, Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let mut imported = Vec::with_capacity(todos.len());
+        for mut todo in todos {
+            if todo.id.is_empty() {
+                todo.id = self.id_generator.new_id()?.encode();
+            }
+            if db.todos.contains_key(&todo.id) || imported.iter().any(|t: &Todo| t.id == todo.id) {
+                error!(self.logger, "todo already exists"; "id" => &todo.id);
+                return Err(Error::AlreadyExists(todo.id));
+            }
+            imported.push(todo);
+        }
+
+        if !dry_run {
+            let mut changes = Vec::with_capacity(imported.len() * 2);
+            for todo in imported.iter() {
+                changes.push(Change::PutTodo(todo.clone()));
+                db.record(&mut changes, actor, Operation::Create, None, Some(todo));
+            }
+            db.commit(changes, &self.logger).await?;
+        }
+
+        Ok(imported)
+    }
+
+    async fn list_after(&self, after: &str, limit: i64) -> Result<Todos, Error> {
+        let lock = self.db.clone();
+        let db = lock.read().await;
+        let mut todos: Todos = db
+            .todos
+            .values()
+            .filter(|todo| todo.id.as_str() > after)
+            .cloned()
+            .collect();
+        todos.sort_by(|a, b| a.id.cmp(&b.id));
+        todos.truncate(limit as usize);
+
+        Ok(todos)
+    }
+
+    async fn insert_todos(&self, todos: Todos) -> Result<(), Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let mut changes = Vec::with_capacity(todos.len());
+        for mut todo in todos {
+            todo.comment_count = db.todos.get(&todo.id).map_or(0, |t| t.comment_count);
+            changes.push(Change::PutTodo(todo));
+        }
+        db.commit(changes, &self.logger).await
+    }
+
+    async fn insert_comments(&self, comments: Vec<Comment>) -> Result<(), Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let changes = comments.into_iter().map(Change::PutComment).collect();
+        db.commit(changes, &self.logger).await
+    }
+
+    async fn insert_attachments(&self, attachments: Vec<Attachment>) -> Result<(), Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let changes = attachments.into_iter().map(Change::PutAttachment).collect();
+        db.commit(changes, &self.logger).await
+    }
+
+    async fn history(&self, id: &str, offset: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        let lock = self.db.clone();
+        let db = lock.read().await;
+        let entries = db
+            .history
+            .iter()
+            .filter(|entry| entry.todo_id == id)
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect();
+
+        Ok(entries)
+    }
+
+    async fn list_history_after(&self, after: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        let lock = self.db.clone();
+        let db = lock.read().await;
+        let start = match db.position(after) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        let entries = db.history[start..]
+            .iter()
+            .take(limit as usize)
+            .cloned()
+            .collect();
+
+        Ok(entries)
+    }
+
+    async fn insert_history(&self, entries: Vec<HistoryEntry>) -> Result<(), Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let changes = entries.into_iter().map(Change::AddHistory).collect();
+        db.commit(changes, &self.logger).await
+    }
+
+    async fn revert(
+        &self,
+        actor: &str,
+        direction: Direction,
+        count: i64,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let mut candidates: Vec<&HistoryEntry> = db
+            .history
+            .iter()
+            .filter(|e| {
+                e.actor == actor
+                    && e.operation.is_undoable()
+                    && e.undo_state == direction.source_state()
+            })
+            .collect();
+        if direction == Direction::Undo {
+            candidates.reverse();
+        }
+        candidates.truncate(count as usize);
+
+        // Everything is checked before anything is applied, so that a
+        // conflict leaves the store untouched.
+        let mut staged: HashMap<String, Option<Todo>> = HashMap::new();
+        let mut changes = Vec::new();
+        for entry in candidates {
+            let current = match staged.get(&entry.todo_id) {
+                Some(todo) => todo.clone(),
+                None => db.todos.get(&entry.todo_id).cloned(),
+            };
+            let target = entry.revert(current.as_ref(), direction)?;
+            staged.insert(entry.todo_id.clone(), target.clone());
+
+            changes.push(match target.as_ref() {
+                Some(todo) => Change::PutTodo(todo.clone()),
+                None => Change::RemoveTodo(entry.todo_id.clone()),
+            });
+            changes.push(Change::SetUndoState {
+                id: entry.id,
+                state: direction.target_state(),
+            });
+            db.record(
+                &mut changes,
+                actor,
+                direction.operation(),
+                current.as_ref(),
+                target.as_ref(),
+            );
+        }
+
+        let entries = changes
+            .iter()
+            .filter_map(|c| match c {
+                Change::AddHistory(entry) => Some(entry.clone()),
+                _ => None,
+            })
+            .collect();
+        db.commit(changes, &self.logger).await?;
+
+        Ok(entries)
+    }
+
+    async fn add_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        parent_id: Option<String>,
+        body: String,
+    ) -> Result<Comment, Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        if !db.todos.contains_key(todo_id) {
+            error!(self.logger, "todo not found"; "id" => todo_id);
+            return Err(Error::NotFound);
+        }
+        if let Some(parent_id) = parent_id.as_ref() {
+            db.comment(todo_id, parent_id)?;
+        }
+
+        let comment = Comment {
+            id: self.id_generator.new_id()?.encode(),
+            todo_id: todo_id.to_string(),
+            parent_id,
+            author: author.to_string(),
+            body,
+            created_at: Utc::now(),
+            edited_at: None,
+        };
+        db.commit(vec![Change::PutComment(comment.clone())], &self.logger)
+            .await?;
+        Ok(comment)
+    }
+
+    async fn list_comments(&self, todo_id: &str) -> Result<Vec<Comment>, Error> {
+        let lock = self.db.clone();
+        let db = lock.read().await;
+        if !db.todos.contains_key(todo_id) {
+            error!(self.logger, "todo not found"; "id" => todo_id);
+            return Err(Error::NotFound);
+        }
+
+        let mut comments: Vec<Comment> = db
+            .comments
+            .values()
+            .filter(|c| c.todo_id == todo_id)
+            .cloned()
+            .collect();
+        comments.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(comments)
+    }
+
+    async fn edit_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        id: &str,
+        body: String,
+    ) -> Result<Comment, Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let mut comment = db.comment(todo_id, id)?.clone();
+        if comment.author != author {
+            return Err(Error::PermissionDenied);
+        }
+
+        comment.body = body;
+        comment.edited_at = Some(Utc::now());
+        db.commit(vec![Change::PutComment(comment.clone())], &self.logger)
+            .await?;
+        Ok(comment)
+    }
+
+    async fn delete_comment(&self, author: &str, todo_id: &str, id: &str) -> Result<(), Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        if !db.todos.contains_key(todo_id) {
+            error!(self.logger, "todo not found"; "id" => todo_id);
+            return Err(Error::NotFound);
+        }
+        if db.comment(todo_id, id)?.author != author {
+            return Err(Error::PermissionDenied);
+        }
+
+        let mut removed = vec![id.to_string()];
+        let mut i = 0;
+        while i < removed.len() {
+            let replies: Vec<String> = db
+                .comments
+                .values()
+                .filter(|c| c.parent_id.as_ref() == Some(&removed[i]))
+                .map(|c| c.id.clone())
+                .collect();
+            removed.extend(replies);
+            i += 1;
+        }
+        let changes = removed.into_iter().map(Change::RemoveComment).collect();
+        db.commit(changes, &self.logger).await?;
+        Ok(())
+    }
+
+    async fn add_attachment(&self, attachment: Attachment) -> Result<Attachment, Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        if !db.todos.contains_key(&attachment.todo_id) {
+            error!(self.logger, "todo not found"; "id" => &attachment.todo_id);
+            return Err(Error::NotFound);
+        }
+
+        db.commit(
+            vec![Change::PutAttachment(attachment.clone())],
+            &self.logger,
+        )
+        .await?;
+        Ok(attachment)
+    }
+
+    async fn list_attachments(&self, todo_id: &str) -> Result<Vec<Attachment>, Error> {
+        let lock = self.db.clone();
+        let db = lock.read().await;
+        if !db.todos.contains_key(todo_id) {
+            error!(self.logger, "todo not found"; "id" => todo_id);
+            return Err(Error::NotFound);
+        }
+
+        let mut attachments: Vec<Attachment> = db
+            .attachments
+            .values()
+            .filter(|a| a.todo_id == todo_id)
+            .cloned()
+            .collect();
+        attachments.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(attachments)
+    }
+
+    async fn get_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        let lock = self.db.clone();
+        let db = lock.read().await;
+        match db.attachments.get(id) {
+            Some(attachment) if attachment.todo_id == todo_id => Ok(attachment.clone()),
+            _ => Err(Error::AttachmentNotFound),
+        }
+    }
+
+    async fn delete_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        let lock = self.db.clone();
+        let mut db = lock.write().await;
+        let attachment = match db.attachments.get(id) {
+            Some(attachment) if attachment.todo_id == todo_id => attachment.clone(),
+            _ => return Err(Error::AttachmentNotFound),
+        };
+
+        db.commit(vec![Change::RemoveAttachment(id.to_string())], &self.logger)
+            .await?;
+        Ok(attachment)
+    }
 }
@@ -0,0 +1,315 @@
+use crate::repository::error::Error;
+use crate::repository::postgres::connect;
+use crate::repository::repository::PostgresSettings;
+use async_trait::async_trait;
+use sqlx::error::Error as SQLxError;
+use sqlx::{Done, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What became of claiming an idempotency key.
+#[derive(Debug, PartialEq)]
+pub enum Claim {
+    /// The key is new, or its earlier request failed or expired. The caller
+    /// runs the request and then completes or releases the key.
+    New,
+    /// A request with the key succeeded with this encoded response.
+    Done(Vec<u8>),
+    /// A request with the key is still running.
+    InProgress,
+    /// A request with the key was applied, but its response was not kept.
+    Applied,
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// Keeps the responses of requests by their idempotency keys. A request is
+/// told apart from others with the same key by its fingerprint.
+#[async_trait]
+pub trait IdempotencyStore {
+    /// Claims `key` for the request with `fingerprint` for up to `timeout`,
+    /// after which a request that never completed may be run again.
+    async fn claim(&self, key: &str, fingerprint: &str, timeout: Duration) -> Result<Claim, Error>;
+    /// Keeps the response of a claimed request for `ttl`.
+    async fn complete(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        response: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+    /// Gives up a claim whose request failed, so that it can be retried. A
+    /// claim whose request was applied is kept.
+    async fn release(&self, key: &str, fingerprint: &str) -> Result<(), Error>;
+    /// Removes expired keys and returns how many.
+    async fn prune(&self) -> Result<u64, Error>;
+}
+
+/// The key of the request being run, see `mark_applied`.
+#[derive(Clone, Debug)]
+pub struct Applying {
+    pub key: String,
+    pub fingerprint: String,
+    pub ttl: Duration,
+}
+
+tokio::task_local! {
+    /// Set while a request with an idempotency key runs.
+    pub static APPLYING: Applying;
+}
+
+/// Marks the key of the request being run as applied, in the transaction
+/// that applies it, so that the request is never applied twice even when
+/// its response can not be kept afterwards. Does nothing outside a request
+/// with a key.
+pub(crate) async fn mark_applied(tx: &mut Transaction<'_, Postgres>) -> Result<(), Error> {
+    let applying = match APPLYING.try_with(Applying::clone) {
+        Ok(applying) => applying,
+        Err(_) => return Ok(()),
+    };
+    let query = r#"
+UPDATE
+    idempotency_keys
+SET
+    applied = TRUE, expires_at = NOW() + $3 * INTERVAL '1 second'
+WHERE
+    key = $1 AND fingerprint = $2
+    "#;
+    sqlx::query(query)
+        .bind(applying.key)
+        .bind(applying.fingerprint)
+        .bind(applying.ttl.as_secs_f64())
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Removes expired keys from `store` every `interval`.
+pub fn spawn_pruning(
+    store: Arc<dyn IdempotencyStore + Send + Sync>,
+    interval: Duration,
+    logger: slog::Logger,
+) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            match store.prune().await {
+                Ok(0) => {}
+                Ok(count) => debug!(logger, "pruned idempotency keys"; "count" => count),
+                Err(e) => error!(logger, "prune idempotency keys"; "err" => ?e),
+            }
+        }
+    });
+}
+
+struct Entry {
+    fingerprint: String,
+    response: Option<Vec<u8>>,
+    expires_at: Instant,
+}
+
+/// Keys kept in memory, by a single instance.
+#[derive(Default)]
+pub struct MemoryIdempotencyStore {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl MemoryIdempotencyStore {
+    pub fn new() -> MemoryIdempotencyStore {
+        MemoryIdempotencyStore::default()
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for MemoryIdempotencyStore {
+    async fn claim(&self, key: &str, fingerprint: &str, timeout: Duration) -> Result<Claim, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if let Some(entry) = entries.get(key).filter(|e| e.expires_at > now) {
+            return Ok(if entry.fingerprint != fingerprint {
+                Claim::Mismatch
+            } else {
+                match &entry.response {
+                    Some(response) => Claim::Done(response.clone()),
+                    None => Claim::InProgress,
+                }
+            });
+        }
+
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint: fingerprint.to_string(),
+                response: None,
+                expires_at: now + timeout,
+            },
+        );
+        Ok(Claim::New)
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        response: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries
+            .get_mut(key)
+            .filter(|e| e.fingerprint == fingerprint)
+        {
+            entry.response = Some(response);
+            entry.expires_at = Instant::now() + ttl;
+        }
+
+        Ok(())
+    }
+
+    async fn release(&self, key: &str, fingerprint: &str) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let claimed = entries
+            .get(key)
+            .map(|e| e.fingerprint == fingerprint && e.response.is_none());
+        if claimed == Some(true) {
+            entries.remove(key);
+        }
+
+        Ok(())
+    }
+
+    async fn prune(&self) -> Result<u64, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        let now = Instant::now();
+        entries.retain(|_, e| e.expires_at > now);
+
+        Ok((before - entries.len()) as u64)
+    }
+}
+
+/// Keys kept in the `idempotency_keys` table, shared by every instance.
+pub struct PostgresIdempotencyStore {
+    pool: PgPool,
+}
+
+impl PostgresIdempotencyStore {
+    pub async fn new(settings: &PostgresSettings) -> Result<PostgresIdempotencyStore, SQLxError> {
+        let mut settings = settings.clone();
+        settings.max_connections = 4;
+        settings.min_connections = 0;
+        Ok(PostgresIdempotencyStore {
+            pool: connect(&settings.connection_string, &settings).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for PostgresIdempotencyStore {
+    async fn claim(&self, key: &str, fingerprint: &str, timeout: Duration) -> Result<Claim, Error> {
+        let claim = r#"
+INSERT INTO
+    idempotency_keys (key, fingerprint, expires_at)
+VALUES
+    ($1, $2, NOW() + $3 * INTERVAL '1 second')
+ON CONFLICT (key) DO UPDATE SET
+    fingerprint = EXCLUDED.fingerprint, response = NULL, applied = FALSE,
+    expires_at = EXCLUDED.expires_at
+WHERE
+    idempotency_keys.expires_at <= NOW()
+RETURNING
+    key
+    "#;
+        let existing = r#"
+SELECT
+    fingerprint, response, applied
+FROM
+    idempotency_keys
+WHERE
+    key = $1 AND expires_at > NOW()
+    "#;
+
+        // The key may expire between the two queries, then it is claimed
+        // again.
+        loop {
+            let claimed: Option<String> = sqlx::query_scalar(claim)
+                .bind(key)
+                .bind(fingerprint)
+                .bind(timeout.as_secs_f64())
+                .fetch_optional(&self.pool)
+                .await?;
+            if claimed.is_some() {
+                return Ok(Claim::New);
+            }
+
+            let row: Option<(String, Option<Vec<u8>>, bool)> = sqlx::query_as(existing)
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+            match row {
+                Some((existing, _, _)) if existing != fingerprint => return Ok(Claim::Mismatch),
+                Some((_, Some(response), _)) => return Ok(Claim::Done(response)),
+                Some((_, None, true)) => return Ok(Claim::Applied),
+                Some((_, None, false)) => return Ok(Claim::InProgress),
+                None => continue,
+            }
+        }
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        response: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let query = r#"
+UPDATE
+    idempotency_keys
+SET
+    response = $3, expires_at = NOW() + $4 * INTERVAL '1 second'
+WHERE
+    key = $1 AND fingerprint = $2
+    "#;
+        sqlx::query(query)
+            .bind(key)
+            .bind(fingerprint)
+            .bind(response)
+            .bind(ttl.as_secs_f64())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn release(&self, key: &str, fingerprint: &str) -> Result<(), Error> {
+        let query = r#"
+DELETE FROM
+    idempotency_keys
+WHERE
+    key = $1 AND fingerprint = $2 AND response IS NULL AND NOT applied
+    "#;
+        sqlx::query(query)
+            .bind(key)
+            .bind(fingerprint)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn prune(&self) -> Result<u64, Error> {
+        let query = r#"
+DELETE FROM
+    idempotency_keys
+WHERE
+    expires_at <= NOW()
+    "#;
+        let result = sqlx::query(query).execute(&self.pool).await?;
+
+        Ok(result.rows_affected())
+    }
+}
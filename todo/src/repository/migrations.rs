@@ -0,0 +1,147 @@
+use sqlx::error::Error as SQLxError;
+use sqlx::migrate::{Migrate, MigrateError, Migration, Migrator};
+
+pub static POSTGRES: Migrator = sqlx::migrate!("./migrations");
+pub static SQLITE: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+#[async_trait]
+pub trait Migrations {
+    /// Applies all pending migrations and returns their versions.
+    async fn migrate_up(&self) -> Result<Vec<i64>, SQLxError>;
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, SQLxError>;
+    /// Reverts every applied migration newer than `version`, newest first,
+    /// and returns their versions.
+    async fn migrate_down(&self, version: i64) -> Result<Vec<i64>, SQLxError>;
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+    /// The migration was applied from a different script than the embedded one.
+    pub modified: bool,
+    pub reversible: bool,
+}
+
+fn ups(migrator: &Migrator) -> impl DoubleEndedIterator<Item = &Migration> {
+    migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+}
+
+fn down_script(migrator: &Migrator, version: i64) -> Option<&Migration> {
+    migrator
+        .iter()
+        .find(|m| m.version == version && m.migration_type.is_down_migration())
+}
+
+/// Same as `Migrator::run`, which would also apply the down scripts.
+pub async fn up<C: Migrate>(migrator: &Migrator, conn: &mut C) -> Result<Vec<i64>, SQLxError> {
+    conn.lock().await?;
+    let applied = apply(migrator, conn).await;
+    unlock(conn, applied).await
+}
+
+async fn apply<C: Migrate>(migrator: &Migrator, conn: &mut C) -> Result<Vec<i64>, SQLxError> {
+    conn.ensure_migrations_table().await?;
+
+    let (current, dirty) = conn.version().await?.unwrap_or((0, false));
+    if dirty {
+        return Err(MigrateError::Dirty(current).into());
+    }
+
+    let mut applied = Vec::new();
+    for migration in ups(migrator) {
+        if migration.version > current {
+            conn.apply(migration).await?;
+            applied.push(migration.version);
+        } else {
+            conn.validate(migration).await?;
+        }
+    }
+
+    Ok(applied)
+}
+
+/// Gives up the migration lock whether or not the migrations succeeded, so
+/// that a failure does not leave it held and the next migration waiting.
+async fn unlock<C: Migrate>(
+    conn: &mut C,
+    result: Result<Vec<i64>, SQLxError>,
+) -> Result<Vec<i64>, SQLxError> {
+    let unlocked = conn.unlock().await;
+    let versions = result?;
+    unlocked?;
+    Ok(versions)
+}
+
+pub async fn status<C: Migrate>(
+    migrator: &Migrator,
+    conn: &mut C,
+) -> Result<Vec<MigrationStatus>, SQLxError> {
+    conn.ensure_migrations_table().await?;
+
+    let mut statuses = Vec::new();
+    for migration in ups(migrator) {
+        let (applied, modified) = match conn.validate(migration).await {
+            Ok(()) => (true, false),
+            Err(MigrateError::VersionMismatch(_)) => (true, true),
+            Err(MigrateError::VersionMissing(_)) => (false, false),
+            Err(e) => return Err(e.into()),
+        };
+        statuses.push(MigrationStatus {
+            version: migration.version,
+            description: migration.description.to_string(),
+            applied,
+            modified,
+            reversible: down_script(migrator, migration.version).is_some(),
+        });
+    }
+
+    Ok(statuses)
+}
+
+pub async fn down<C: Migrate>(
+    migrator: &Migrator,
+    conn: &mut C,
+    version: i64,
+) -> Result<Vec<i64>, SQLxError> {
+    conn.lock().await?;
+    let reverted = revert(migrator, conn, version).await;
+    unlock(conn, reverted).await
+}
+
+async fn revert<C: Migrate>(
+    migrator: &Migrator,
+    conn: &mut C,
+    version: i64,
+) -> Result<Vec<i64>, SQLxError> {
+    conn.ensure_migrations_table().await?;
+
+    let (current, dirty) = conn.version().await?.unwrap_or((0, false));
+    if dirty {
+        return Err(MigrateError::Dirty(current).into());
+    }
+
+    let mut reverted = Vec::new();
+    for migration in ups(migrator).rev() {
+        if migration.version <= version {
+            break;
+        }
+        match conn.validate(migration).await {
+            Ok(()) => {}
+            Err(MigrateError::VersionMissing(_)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        let script = down_script(migrator, migration.version).ok_or_else(|| {
+            MigrateError::Source(
+                format!("migration {} has no down script", migration.version).into(),
+            )
+        })?;
+        conn.revert(script).await?;
+        reverted.push(migration.version);
+    }
+
+    Ok(reverted)
+}
@@ -1,5 +1,15 @@
+pub(crate) mod cached;
+#[cfg(test)]
+pub(crate) mod conformance;
 pub(crate) mod error;
 pub(crate) mod hashmap;
+pub(crate) mod idempotency;
+pub(crate) mod migrations;
 pub(crate) mod model;
+pub(crate) mod outbox;
 pub(crate) mod postgres;
+#[allow(clippy::module_inception)]
 pub(crate) mod repository;
+pub(crate) mod sled;
+pub(crate) mod sqlite;
+pub(crate) mod wal;
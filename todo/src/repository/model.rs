@@ -1,7 +1,10 @@
 use super::super::server::todo_service as pb;
-use chrono::{DateTime, Utc};
+use crate::repository::error::Error;
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::types::Json;
+use std::convert::TryFrom;
 
-#[derive(Debug, Clone, sqlx::FromRow)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Todo {
     pub id: String,
     pub title: String,
@@ -9,36 +12,454 @@ pub struct Todo {
     pub is_completed: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub comment_count: i32,
 }
 
-impl Into<pb::Todo> for Todo {
-    fn into(self) -> pb::Todo {
-        let created_at = prost_types::Timestamp {
-            seconds: self.created_at.timestamp(),
-            nanos: self.created_at.timestamp_subsec_nanos() as i32,
-        };
-        let updated_at = prost_types::Timestamp {
-            seconds: self.updated_at.timestamp(),
-            nanos: self.updated_at.timestamp_subsec_nanos() as i32,
-        };
+impl From<Todo> for pb::Todo {
+    fn from(todo: Todo) -> Self {
         pb::Todo {
-            id: self.id,
-            title: self.title,
-            body: self.body,
-            is_completed: self.is_completed,
-            created_at: Some(created_at),
-            updated_at: Some(updated_at),
+            id: todo.id,
+            title: todo.title,
+            body: todo.body,
+            is_completed: todo.is_completed,
+            created_at: Some(to_timestamp(todo.created_at)),
+            updated_at: Some(to_timestamp(todo.updated_at)),
+            comment_count: todo.comment_count,
+        }
+    }
+}
+
+impl From<pb::Todo> for Todo {
+    fn from(todo: pb::Todo) -> Self {
+        let now = Utc::now();
+        let created_at = todo.created_at.and_then(from_timestamp).unwrap_or(now);
+        let updated_at = todo
+            .updated_at
+            .and_then(from_timestamp)
+            .unwrap_or(created_at);
+        Todo {
+            id: todo.id,
+            title: todo.title,
+            body: todo.body,
+            is_completed: todo.is_completed,
+            created_at,
+            updated_at,
+            comment_count: 0,
         }
     }
 }
 
 pub type Todos = Vec<Todo>;
 
-impl Into<pb::Todos> for Todos {
-    fn into(self) -> pb::Todos {
-        let converted_todos = self.iter().map(|todo| todo.clone().into()).collect();
+impl From<Todos> for pb::Todos {
+    fn from(todos: Todos) -> Self {
+        let converted_todos = todos.into_iter().map(|todo| todo.into()).collect();
         pb::Todos {
             todos: converted_todos,
         }
     }
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Comment {
+    pub id: String,
+    pub todo_id: String,
+    pub parent_id: Option<String>,
+    pub author: String,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+impl From<Comment> for pb::Comment {
+    fn from(comment: Comment) -> Self {
+        pb::Comment {
+            id: comment.id,
+            todo_id: comment.todo_id,
+            parent_id: comment.parent_id.unwrap_or_default(),
+            author: comment.author,
+            body: comment.body,
+            created_at: Some(to_timestamp(comment.created_at)),
+            edited_at: comment.edited_at.map(to_timestamp),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Attachment {
+    pub id: String,
+    pub todo_id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Attachment {
+    /// Returns the key the attachment content is stored under. Keys are
+    /// prefixed with the todo id so that all blobs of a todo can be removed
+    /// at once.
+    pub fn blob_key(&self) -> String {
+        format!("{}/{}", self.todo_id, self.id)
+    }
+}
+
+impl From<Attachment> for pb::Attachment {
+    fn from(attachment: Attachment) -> Self {
+        pb::Attachment {
+            id: attachment.id,
+            todo_id: attachment.todo_id,
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            size: attachment.size,
+            created_at: Some(to_timestamp(attachment.created_at)),
+        }
+    }
+}
+
+fn to_timestamp(dt: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: dt.timestamp(),
+        nanos: dt.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// The earliest and latest seconds a `google.protobuf.Timestamp` may hold,
+/// 0001-01-01T00:00:00Z and 9999-12-31T23:59:59Z.
+const MIN_TIMESTAMP_SECONDS: i64 = -62_135_596_800;
+const MAX_TIMESTAMP_SECONDS: i64 = 253_402_300_799;
+
+/// The time of `ts`, or `None` when it is outside the range of
+/// `google.protobuf.Timestamp` or its `nanos` are not a fraction of a second.
+pub fn from_timestamp(ts: prost_types::Timestamp) -> Option<DateTime<Utc>> {
+    if !(MIN_TIMESTAMP_SECONDS..=MAX_TIMESTAMP_SECONDS).contains(&ts.seconds)
+        || !(0..1_000_000_000).contains(&ts.nanos)
+    {
+        return None;
+    }
+    Utc.timestamp_opt(ts.seconds, ts.nanos as u32).single()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+    Create,
+    Update,
+    Complete,
+    Delete,
+    Undo,
+    Redo,
+}
+
+impl Operation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Complete => "complete",
+            Operation::Delete => "delete",
+            Operation::Undo => "undo",
+            Operation::Redo => "redo",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Operation> {
+        match s {
+            "create" => Some(Operation::Create),
+            "update" => Some(Operation::Update),
+            "complete" => Some(Operation::Complete),
+            "delete" => Some(Operation::Delete),
+            "undo" => Some(Operation::Undo),
+            "redo" => Some(Operation::Redo),
+            _ => None,
+        }
+    }
+
+    /// Whether entries of this operation can be undone. Undo and redo entries
+    /// only record what happened, they are reverted through the entry they
+    /// were applied to.
+    pub fn is_undoable(self) -> bool {
+        !matches!(self, Operation::Undo | Operation::Redo)
+    }
+}
+
+/// Position of a history entry on its actor's undo and redo stacks.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum UndoState {
+    /// The change is in effect and can be undone.
+    Active,
+    /// The change was undone and can be redone.
+    Undone,
+    /// The change was undone and the actor has made other changes since, so
+    /// it can't be redone anymore.
+    Discarded,
+}
+
+impl UndoState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UndoState::Active => "active",
+            UndoState::Undone => "undone",
+            UndoState::Discarded => "discarded",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<UndoState> {
+        match s {
+            "active" => Some(UndoState::Active),
+            "undone" => Some(UndoState::Undone),
+            "discarded" => Some(UndoState::Discarded),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Undo,
+    Redo,
+}
+
+impl Direction {
+    pub fn operation(self) -> Operation {
+        match self {
+            Direction::Undo => Operation::Undo,
+            Direction::Redo => Operation::Redo,
+        }
+    }
+
+    /// Undo state of the entries this direction picks up.
+    pub fn source_state(self) -> UndoState {
+        match self {
+            Direction::Undo => UndoState::Active,
+            Direction::Redo => UndoState::Undone,
+        }
+    }
+
+    /// Undo state of an entry after it was processed.
+    pub fn target_state(self) -> UndoState {
+        match self {
+            Direction::Undo => UndoState::Undone,
+            Direction::Redo => UndoState::Active,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub todo_id: String,
+    pub actor: String,
+    pub operation: Operation,
+    pub changes: Vec<FieldChange>,
+    pub undo_state: UndoState,
+    pub created_at: DateTime<Utc>,
+}
+
+impl HistoryEntry {
+    /// Computes the state the entry's todo moves to when the entry is undone
+    /// or redone, `None` meaning that the todo is deleted.
+    ///
+    /// Fails with `Error::Conflict` unless the todo is exactly in the state the
+    /// entry left it in (for undo) or found it in (for redo), so that changes
+    /// made in between by other actors are never overwritten.
+    pub fn revert(
+        &self,
+        current: Option<&Todo>,
+        direction: Direction,
+    ) -> Result<Option<Todo>, Error> {
+        let (expected_absent, target_absent) = match (self.operation, direction) {
+            (Operation::Create, Direction::Undo) | (Operation::Delete, Direction::Redo) => {
+                (false, true)
+            }
+            (Operation::Delete, Direction::Undo) | (Operation::Create, Direction::Redo) => {
+                (true, false)
+            }
+            _ => (false, false),
+        };
+        let (expected, target): (Vec<_>, Vec<_>) = self
+            .changes
+            .iter()
+            .map(|c| match direction {
+                Direction::Undo => ((&c.field, &c.after), (&c.field, &c.before)),
+                Direction::Redo => ((&c.field, &c.before), (&c.field, &c.after)),
+            })
+            .unzip();
+
+        let conflict = || Error::Conflict(self.todo_id.clone());
+        match current {
+            None if !expected_absent => return Err(conflict()),
+            Some(_) if expected_absent => return Err(conflict()),
+            Some(todo) => {
+                let fields = fields(todo);
+                for (field, value) in expected {
+                    let actual = fields
+                        .iter()
+                        .find(|(name, _)| name == field)
+                        .map(|(_, v)| v);
+                    if actual != value.as_ref() {
+                        return Err(conflict());
+                    }
+                }
+            }
+            None => {}
+        }
+
+        if target_absent {
+            return Ok(None);
+        }
+        let now = Utc::now();
+        let mut todo = current.cloned().unwrap_or(Todo {
+            id: self.todo_id.clone(),
+            title: String::new(),
+            body: String::new(),
+            is_completed: false,
+            created_at: now,
+            updated_at: now,
+            comment_count: 0,
+        });
+        for (field, value) in target {
+            let value = value.as_deref().unwrap_or_default();
+            set_field(&mut todo, field, value).ok_or_else(conflict)?;
+        }
+
+        Ok(Some(todo))
+    }
+
+    /// Tells whether the entry records the removal of its todo.
+    pub fn removes_todo(&self) -> bool {
+        !self.changes.is_empty() && self.changes.iter().all(|c| c.after.is_none())
+    }
+}
+
+/// A `todo_history` row as stored by the SQL backends.
+#[derive(sqlx::FromRow)]
+pub struct HistoryRow {
+    id: i64,
+    todo_id: String,
+    actor: String,
+    operation: String,
+    changes: Json<Vec<FieldChange>>,
+    undo_state: String,
+    created_at: DateTime<Utc>,
+}
+
+impl TryFrom<HistoryRow> for HistoryEntry {
+    type Error = Error;
+
+    fn try_from(row: HistoryRow) -> Result<Self, Self::Error> {
+        let operation = Operation::parse(&row.operation).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown operation {}", row.operation).into())
+        })?;
+        let undo_state = UndoState::parse(&row.undo_state).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown undo state {}", row.undo_state).into())
+        })?;
+        Ok(HistoryEntry {
+            id: row.id,
+            todo_id: row.todo_id,
+            actor: row.actor,
+            operation,
+            changes: row.changes.0,
+            undo_state,
+            created_at: row.created_at,
+        })
+    }
+}
+
+/// A change to a todo, published through the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Increases with every event, in the order they are delivered.
+    pub id: i64,
+    pub todo_id: String,
+    pub actor: String,
+    pub operation: Operation,
+    /// The todo after the change, or `None` if it was deleted.
+    pub todo: Option<Todo>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Lists the fields that differ between two states of a todo. A missing
+/// state stands for a todo that doesn't exist, so every field of the other
+/// state is reported.
+pub fn diff(before: Option<&Todo>, after: Option<&Todo>) -> Vec<FieldChange> {
+    let before = before.map(fields).unwrap_or_default();
+    let after = after.map(fields).unwrap_or_default();
+    let names = if before.is_empty() { &after } else { &before };
+
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, (field, _))| FieldChange {
+            field: field.to_string(),
+            before: before.get(i).map(|(_, v)| v.clone()),
+            after: after.get(i).map(|(_, v)| v.clone()),
+        })
+        .filter(|change| change.before != change.after)
+        .collect()
+}
+
+fn set_field(todo: &mut Todo, field: &str, value: &str) -> Option<()> {
+    match field {
+        "title" => todo.title = value.to_string(),
+        "body" => todo.body = value.to_string(),
+        "is_completed" => todo.is_completed = value.parse().ok()?,
+        "created_at" => todo.created_at = DateTime::parse_from_rfc3339(value).ok()?.into(),
+        "updated_at" => todo.updated_at = DateTime::parse_from_rfc3339(value).ok()?.into(),
+        _ => return None,
+    }
+    Some(())
+}
+
+fn fields(todo: &Todo) -> Vec<(&'static str, String)> {
+    vec![
+        ("title", todo.title.clone()),
+        ("body", todo.body.clone()),
+        ("is_completed", todo.is_completed.to_string()),
+        ("created_at", todo.created_at.to_rfc3339()),
+        ("updated_at", todo.updated_at.to_rfc3339()),
+    ]
+}
+
+impl From<FieldChange> for pb::FieldChange {
+    fn from(change: FieldChange) -> Self {
+        pb::FieldChange {
+            field: change.field,
+            before: change.before,
+            after: change.after,
+        }
+    }
+}
+
+impl From<Event> for pb::Event {
+    fn from(event: Event) -> Self {
+        pb::Event {
+            id: event.id,
+            todo_id: event.todo_id,
+            actor: event.actor,
+            operation: event.operation.as_str().to_string(),
+            todo: event.todo.map(|todo| todo.into()),
+            created_at: Some(to_timestamp(event.created_at)),
+        }
+    }
+}
+
+impl From<HistoryEntry> for pb::HistoryEntry {
+    fn from(entry: HistoryEntry) -> Self {
+        pb::HistoryEntry {
+            id: entry.id,
+            todo_id: entry.todo_id,
+            actor: entry.actor,
+            operation: entry.operation.as_str().to_string(),
+            changes: entry.changes.into_iter().map(|c| c.into()).collect(),
+            undo_state: entry.undo_state.as_str().to_string(),
+            created_at: Some(to_timestamp(entry.created_at)),
+        }
+    }
+}
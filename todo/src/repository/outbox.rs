@@ -0,0 +1,206 @@
+use crate::repository::error::Error;
+use crate::repository::model::{Event, Operation, Todo};
+use crate::repository::postgres::{connect, connect_options};
+use crate::repository::repository::PostgresSettings;
+use chrono::{DateTime, Utc};
+use sqlx::error::Error as SQLxError;
+use sqlx::postgres::{PgConnectOptions, PgListener};
+use sqlx::types::Json;
+use sqlx::{Connection, Done, PgConnection, PgPool};
+use std::convert::TryFrom;
+
+/// Notified with the id of every event written to the outbox.
+pub const EVENTS_CHANNEL: &str = "todo_events";
+
+/// Held by the relay that delivers events, so that there is only one.
+const RELAY_LOCK: i64 = 0x6f75_7462_6f78_0002;
+
+/// An undelivered event and the state of its delivery.
+pub struct Pending {
+    pub event: Event,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingRow {
+    id: i64,
+    todo_id: String,
+    actor: String,
+    operation: String,
+    todo: Option<Json<Todo>>,
+    created_at: DateTime<Utc>,
+    attempts: i32,
+    next_attempt_at: DateTime<Utc>,
+}
+
+impl TryFrom<PendingRow> for Pending {
+    type Error = Error;
+
+    fn try_from(row: PendingRow) -> Result<Self, Self::Error> {
+        let operation = Operation::parse(&row.operation).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown operation {}", row.operation).into())
+        })?;
+        Ok(Pending {
+            event: Event {
+                id: row.id,
+                todo_id: row.todo_id,
+                actor: row.actor,
+                operation,
+                todo: row.todo.map(|todo| todo.0),
+                created_at: row.created_at,
+            },
+            attempts: row.attempts,
+            next_attempt_at: row.next_attempt_at,
+        })
+    }
+}
+
+/// The events `PostgresRepository` writes along with every change.
+pub struct PostgresOutbox {
+    pool: PgPool,
+    options: PgConnectOptions,
+}
+
+impl PostgresOutbox {
+    pub async fn new(settings: &PostgresSettings) -> Result<PostgresOutbox, SQLxError> {
+        let mut settings = settings.clone();
+        // One connection is taken by the listener.
+        settings.max_connections = 4;
+        settings.min_connections = 0;
+        Ok(PostgresOutbox {
+            pool: connect(&settings.connection_string, &settings).await?,
+            options: connect_options(&settings.connection_string, &settings)?,
+        })
+    }
+
+    /// Opens a dedicated connection to take the relay lock on.
+    pub async fn lock_connection(&self) -> Result<PgConnection, Error> {
+        Ok(PgConnection::connect_with(&self.options).await?)
+    }
+
+    /// Tries to take the relay lock on `conn`, which then holds it until it
+    /// is closed.
+    pub async fn try_lock(conn: &mut PgConnection) -> Result<bool, Error> {
+        let locked = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+            .bind(RELAY_LOCK)
+            .fetch_one(conn)
+            .await?;
+
+        Ok(locked)
+    }
+
+    /// Listens for the ids of new events on a connection of its own.
+    pub async fn listen(&self) -> Result<PgListener, Error> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(EVENTS_CHANNEL).await?;
+
+        Ok(listener)
+    }
+
+    /// Returns the id of the latest event, or 0 if there is none.
+    pub async fn last_id(&self) -> Result<i64, Error> {
+        let id: Option<i64> = sqlx::query_scalar("SELECT MAX(id) FROM outbox")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(id.unwrap_or(0))
+    }
+
+    /// Returns up to `limit` events following the one with id `after`,
+    /// delivered or not, in order.
+    pub async fn events_after(&self, after: i64, limit: i64) -> Result<Vec<Event>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, actor, operation, todo, created_at, attempts, next_attempt_at
+FROM
+    outbox
+WHERE
+    id > $1
+ORDER BY
+    id
+LIMIT $2
+    "#;
+        let rows = sqlx::query_as::<_, PendingRow>(query)
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter()
+            .map(|row| Pending::try_from(row).map(|p| p.event))
+            .collect()
+    }
+
+    /// Returns the oldest undelivered events, in order.
+    pub async fn pending(&self, limit: i64) -> Result<Vec<Pending>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, actor, operation, todo, created_at, attempts, next_attempt_at
+FROM
+    outbox
+WHERE
+    delivered_at IS NULL
+ORDER BY
+    id
+LIMIT $1
+    "#;
+        let rows = sqlx::query_as::<_, PendingRow>(query)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(Pending::try_from).collect()
+    }
+
+    pub async fn delivered(&self, id: i64) -> Result<(), Error> {
+        let query = r#"
+UPDATE
+    outbox
+SET
+    delivered_at = NOW(), attempts = attempts + 1, last_error = NULL
+WHERE
+    id = $1
+    "#;
+        sqlx::query(query).bind(id).execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    pub async fn failed(
+        &self,
+        id: i64,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<(), Error> {
+        let query = r#"
+UPDATE
+    outbox
+SET
+    attempts = attempts + 1, next_attempt_at = $2, last_error = $3
+WHERE
+    id = $1
+    "#;
+        sqlx::query(query)
+            .bind(id)
+            .bind(next_attempt_at)
+            .bind(error)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes events delivered before `before` and returns how many.
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<u64, Error> {
+        let query = r#"
+DELETE FROM
+    outbox
+WHERE
+    delivered_at < $1
+    "#;
+        let result = sqlx::query(query).bind(before).execute(&self.pool).await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -1,31 +1,262 @@
 use crate::repository::error::Error;
-use crate::repository::model::{Todo, Todos};
-use crate::repository::repository::Repository;
+use crate::repository::idempotency::mark_applied;
+use crate::repository::migrations::{self, MigrationStatus, Migrations};
+use crate::repository::model::{
+    diff, Attachment, Comment, Direction, HistoryEntry, HistoryRow, Operation, Todo, Todos,
+    UndoState,
+};
+use crate::repository::outbox::EVENTS_CHANNEL;
+use crate::repository::repository::{PostgresSettings, Repository};
 use sqlx::error::Error as SQLxError;
-use sqlx::migrate::Migrator;
-use sqlx::PgPool;
-use std::path::Path;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::types::Json;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
 
 pub struct PostgresRepository {
+    logger: slog::Logger,
     pool: PgPool,
+    replica: Option<Replica>,
+    /// Whether changes are written to the outbox.
+    outbox: bool,
     id_generator: libxid::Generator,
 }
 
+/// Held by the transaction writing an outbox event until it commits, so that
+/// events become visible in id order and the relay never skips one. Ordering
+/// by transaction id instead would not hold: a snapshot's xmax is the last
+/// completed id plus one, so it cannot tell a reader which earlier ids are
+/// still being written.
+const OUTBOX_WRITE_LOCK: i64 = 0x6f75_7462_6f78_0001;
+
+/// A read replica, with the WAL positions (as byte offsets) the primary was
+/// at after this repository's last write and the replica was last seen at.
+struct Replica {
+    pool: PgPool,
+    written: AtomicI64,
+    replayed: AtomicI64,
+}
+
 impl PostgresRepository {
-    pub async fn new(connection_string: &str) -> Result<PostgresRepository, SQLxError> {
-        let pool = PgPool::connect(connection_string).await?;
+    pub async fn new(
+        settings: &PostgresSettings,
+        logger: slog::Logger,
+    ) -> Result<PostgresRepository, SQLxError> {
+        let pool = connect(&settings.connection_string, settings).await?;
+        let replica = match settings.replica_connection_string.as_ref() {
+            Some(connection_string) => Some(Replica {
+                pool: connect(connection_string, settings).await?,
+                written: AtomicI64::new(0),
+                replayed: AtomicI64::new(0),
+            }),
+            None => None,
+        };
+
         Ok(PostgresRepository {
+            logger,
             pool,
+            replica,
+            outbox: settings.outbox,
             id_generator: libxid::new_generator(),
         })
     }
 
-    pub async fn run_migrations(&self, migrations_path: &str) -> Result<(), SQLxError> {
-        let migrations_path = Path::new(migrations_path);
-        let migrator = Migrator::new(migrations_path).await?;
-        migrator.run(&self.pool).await?;
+    /// Returns the pool for `list` and `get`. That is the replica once it has
+    /// replayed everything written through this repository, so that callers
+    /// always read their own writes.
+    async fn read_pool(&self) -> Result<&PgPool, Error> {
+        let replica = match self.replica.as_ref() {
+            Some(replica) => replica,
+            None => return Ok(&self.pool),
+        };
+        let written = replica.written.load(Ordering::SeqCst);
+        if replica.replayed.load(Ordering::SeqCst) >= written {
+            return Ok(&replica.pool);
+        }
 
-        Ok(())
+        // A server that is not in recovery has no replay position and is
+        // always up to date.
+        let query = r#"
+SELECT
+    (COALESCE(pg_last_wal_replay_lsn(), pg_current_wal_lsn()) - '0/0'::pg_lsn)::BIGINT
+    "#;
+        let replayed: i64 = sqlx::query_scalar(query).fetch_one(&replica.pool).await?;
+        replica.replayed.fetch_max(replayed, Ordering::SeqCst);
+        if replayed >= written {
+            Ok(&replica.pool)
+        } else {
+            Ok(&self.pool)
+        }
+    }
+
+    /// Remembers how far the primary's WAL got after a committed write. The
+    /// write stands either way, so when that is not known reads go to the
+    /// primary until a later write finds out.
+    async fn written(&self) {
+        let replica = match self.replica.as_ref() {
+            Some(replica) => replica,
+            None => return,
+        };
+        let query = r#"
+SELECT
+    (pg_current_wal_lsn() - '0/0'::pg_lsn)::BIGINT
+    "#;
+        let unknown = replica.written.load(Ordering::SeqCst) == i64::MAX;
+        match sqlx::query_scalar::<_, i64>(query)
+            .fetch_one(&self.pool)
+            .await
+        {
+            // A position read after the unknown one was marked is past it.
+            Ok(written) if unknown => {
+                let _ = replica.written.compare_exchange(
+                    i64::MAX,
+                    written,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+            }
+            Ok(written) => {
+                replica.written.fetch_max(written, Ordering::SeqCst);
+            }
+            Err(e) => {
+                error!(self.logger, "read the position of the primary"; "err" => %e);
+                replica.written.store(i64::MAX, Ordering::SeqCst);
+            }
+        }
+    }
+
+    async fn record(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        actor: &str,
+        operation: Operation,
+        before: Option<&Todo>,
+        after: Option<&Todo>,
+    ) -> Result<HistoryEntry, Error> {
+        if operation.is_undoable() {
+            let query = r#"
+UPDATE
+    todo_history
+SET
+    undo_state = $3
+WHERE
+    actor = $1 AND undo_state = $2
+    "#;
+            sqlx::query(query)
+                .bind(actor)
+                .bind(UndoState::Undone.as_str())
+                .bind(UndoState::Discarded.as_str())
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        let query = r#"
+INSERT INTO
+    todo_history (todo_id, actor, operation, changes, undo_state, created_at)
+VALUES
+    ($1, $2, $3, $4, $5, NOW())
+RETURNING
+    id, todo_id, actor, operation, changes, undo_state, created_at
+    "#;
+        let todo_id = before.or(after).map(|t| t.id.as_str()).unwrap_or_default();
+        let row = sqlx::query_as::<_, HistoryRow>(query)
+            .bind(todo_id)
+            .bind(actor)
+            .bind(operation.as_str())
+            .bind(Json(diff(before, after)))
+            .bind(UndoState::Active.as_str())
+            .fetch_one(&mut *tx)
+            .await?;
+
+        if self.outbox {
+            sqlx::query("SELECT pg_advisory_xact_lock($1)")
+                .bind(OUTBOX_WRITE_LOCK)
+                .execute(&mut *tx)
+                .await?;
+            let query = r#"
+INSERT INTO
+    outbox (todo_id, actor, operation, todo)
+VALUES
+    ($1, $2, $3, $4)
+RETURNING
+    id
+    "#;
+            let id: i64 = sqlx::query_scalar(query)
+                .bind(todo_id)
+                .bind(actor)
+                .bind(operation.as_str())
+                .bind(after.map(Json))
+                .fetch_one(&mut *tx)
+                .await?;
+            // Sent on commit, to the listeners of every instance.
+            sqlx::query("SELECT pg_notify($1, $2)")
+                .bind(EVENTS_CHANNEL)
+                .bind(id.to_string())
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        HistoryEntry::try_from(row)
+    }
+}
+
+pub(crate) fn connect_options(
+    connection_string: &str,
+    settings: &PostgresSettings,
+) -> Result<PgConnectOptions, SQLxError> {
+    let options = PgConnectOptions::from_str(connection_string)?;
+    Ok(match settings.application_name.as_ref() {
+        Some(application_name) => options.application_name(application_name),
+        None => options,
+    })
+}
+
+pub(crate) async fn connect(
+    connection_string: &str,
+    settings: &PostgresSettings,
+) -> Result<PgPool, SQLxError> {
+    let options = connect_options(connection_string, settings)?;
+    let statement_timeout = settings.statement_timeout_ms;
+    PgPoolOptions::new()
+        .max_connections(settings.max_connections)
+        .min_connections(settings.min_connections)
+        .connect_timeout(Duration::from_millis(settings.acquire_timeout_ms))
+        .idle_timeout(settings.idle_timeout_ms.map(Duration::from_millis))
+        .after_connect(move |conn| {
+            Box::pin(async move {
+                if let Some(timeout) = statement_timeout {
+                    conn.execute(format!("SET statement_timeout = {}", timeout).as_str())
+                        .await?;
+                }
+                Ok(())
+            })
+        })
+        .connect_with(options)
+        .await
+}
+
+#[async_trait]
+impl Migrations for PostgresRepository {
+    // Migrations hold a session-level advisory lock, so they run on a
+    // connection taken out of the pool. It is closed afterwards, which also
+    // gives up the lock if unlocking failed.
+    async fn migrate_up(&self) -> Result<Vec<i64>, SQLxError> {
+        let mut conn = self.pool.acquire().await?.release();
+        migrations::up(&migrations::POSTGRES, &mut conn).await
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, SQLxError> {
+        let mut conn = self.pool.acquire().await?;
+        migrations::status(&migrations::POSTGRES, &mut *conn).await
+    }
+
+    async fn migrate_down(&self, version: i64) -> Result<Vec<i64>, SQLxError> {
+        let mut conn = self.pool.acquire().await?.release();
+        migrations::down(&migrations::POSTGRES, &mut conn, version).await
     }
 }
 
@@ -34,12 +265,12 @@ impl Repository for PostgresRepository {
     async fn list(&self) -> Result<Todos, Error> {
         let query = r#"
 SELECT
-    id, title, body, is_completed, created_at, updated_at
+    id, title, body, is_completed, created_at, updated_at, comment_count
 FROM
     todos
     "#;
         let todos = sqlx::query_as::<_, Todo>(query)
-            .fetch_all(&self.pool)
+            .fetch_all(self.read_pool().await?)
             .await?;
 
         Ok(todos)
@@ -48,7 +279,7 @@ FROM
     async fn get(&self, id: &str) -> Result<Todo, Error> {
         let query = r#"
 SELECT
-    id, title, body, is_completed, created_at, updated_at
+    id, title, body, is_completed, created_at, updated_at, comment_count
 FROM
     todos
 WHERE
@@ -56,38 +287,45 @@ WHERE
     "#;
         let todo = sqlx::query_as::<_, Todo>(query)
             .bind(id)
-            .fetch_one(&self.pool)
+            .fetch_optional(self.read_pool().await?)
             .await?;
 
-        Ok(todo)
+        todo.ok_or(Error::NotFound)
     }
 
-    async fn create(&self, title: String, body: String) -> Result<Todo, Error> {
+    async fn create(&self, actor: &str, title: String, body: String) -> Result<Todo, Error> {
         let query = r#"
 INSERT INTO
     todos (id, title, body, is_completed, created_at, updated_at)
 VALUES
     ($1, $2, $3, FALSE, NOW(), NOW())
 RETURNING
-    id, title, body, is_completed, created_at, updated_at
+    id, title, body, is_completed, created_at, updated_at, comment_count
     "#;
         let id = self.id_generator.new_id()?.encode();
+        let mut tx = self.pool.begin().await?;
         let todo = sqlx::query_as::<_, Todo>(query)
             .bind(id)
             .bind(title)
             .bind(body)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut tx)
+            .await?;
+        self.record(&mut tx, actor, Operation::Create, None, Some(&todo))
             .await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
 
         Ok(todo)
     }
 
     async fn update(
         &self,
+        actor: &str,
         id: &str,
-        title: String,
-        body: String,
-        is_completed: bool,
+        title: Option<String>,
+        body: Option<String>,
+        is_completed: Option<bool>,
     ) -> Result<Todo, Error> {
         let query = r#"
 UPDATE
@@ -97,32 +335,57 @@ SET
 WHERE
     id = $1
 RETURNING
-    id, title, body, is_completed, created_at, updated_at
+    id, title, body, is_completed, created_at, updated_at, comment_count
     "#;
+        let mut tx = self.pool.begin().await?;
+        let before = lock_todo(&mut tx, id).await?.ok_or(Error::NotFound)?;
         let todo = sqlx::query_as::<_, Todo>(query)
             .bind(id)
-            .bind(title)
-            .bind(body)
-            .bind(is_completed)
-            .fetch_one(&self.pool)
+            .bind(title.unwrap_or_else(|| before.title.clone()))
+            .bind(body.unwrap_or_else(|| before.body.clone()))
+            .bind(is_completed.unwrap_or(before.is_completed))
+            .fetch_one(&mut tx)
             .await?;
+        self.record(
+            &mut tx,
+            actor,
+            Operation::Update,
+            Some(&before),
+            Some(&todo),
+        )
+        .await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
 
         Ok(todo)
     }
 
-    async fn delete(&self, id: &str) -> Result<(), Error> {
+    async fn delete(&self, actor: &str, id: &str) -> Result<(), Error> {
         let query = r#"
 DELETE FROM
     todos
 WHERE
     id = $1
+RETURNING
+    id, title, body, is_completed, created_at, updated_at, comment_count
     "#;
-        sqlx::query(query).bind(id).execute(&self.pool).await?;
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query_as::<_, Todo>(query)
+            .bind(id)
+            .fetch_optional(&mut tx)
+            .await?;
+        let todo = deleted.ok_or(Error::NotFound)?;
+        self.record(&mut tx, actor, Operation::Delete, Some(&todo), None)
+            .await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
 
         Ok(())
     }
 
-    async fn complete(&self, id: &str) -> Result<Todo, Error> {
+    async fn complete(&self, actor: &str, id: &str) -> Result<Todo, Error> {
         let query = r#"
 UPDATE
     todos
@@ -131,13 +394,676 @@ SET
 WHERE
     id = $1
 RETURNING
-    id, title, body, is_completed, created_at, updated_at
+    id, title, body, is_completed, created_at, updated_at, comment_count
     "#;
+        let mut tx = self.pool.begin().await?;
+        let before = lock_todo(&mut tx, id).await?.ok_or(Error::NotFound)?;
+        if before.is_completed {
+            return Err(Error::AlreadyCompleted);
+        }
         let todo = sqlx::query_as::<_, Todo>(query)
             .bind(id)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut tx)
             .await?;
+        self.record(
+            &mut tx,
+            actor,
+            Operation::Complete,
+            Some(&before),
+            Some(&todo),
+        )
+        .await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
 
         Ok(todo)
     }
+
+    async fn import(&self, actor: &str, todos: Todos, dry_run: bool) -> Result<Todos, Error> {
+        let query = r#"
+INSERT INTO
+    todos (id, title, body, is_completed, created_at, updated_at)
+VALUES
+    ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (id) DO NOTHING
+RETURNING
+    id, title, body, is_completed, created_at, updated_at, comment_count
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let mut imported = Vec::with_capacity(todos.len());
+        for todo in todos {
+            let id = if todo.id.is_empty() {
+                self.id_generator.new_id()?.encode()
+            } else {
+                todo.id
+            };
+            let inserted = sqlx::query_as::<_, Todo>(query)
+                .bind(&id)
+                .bind(todo.title)
+                .bind(todo.body)
+                .bind(todo.is_completed)
+                .bind(todo.created_at)
+                .bind(todo.updated_at)
+                .fetch_optional(&mut tx)
+                .await?;
+            match inserted {
+                Some(todo) => {
+                    self.record(&mut tx, actor, Operation::Create, None, Some(&todo))
+                        .await?;
+                    imported.push(todo);
+                }
+                None => return Err(Error::AlreadyExists(id)),
+            }
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            mark_applied(&mut tx).await?;
+            tx.commit().await?;
+            self.written().await;
+        }
+
+        Ok(imported)
+    }
+
+    async fn list_after(&self, after: &str, limit: i64) -> Result<Todos, Error> {
+        let query = r#"
+SELECT
+    id, title, body, is_completed, created_at, updated_at, comment_count
+FROM
+    todos
+WHERE
+    id > $1
+ORDER BY
+    id
+LIMIT $2
+    "#;
+        let todos = sqlx::query_as::<_, Todo>(query)
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(todos)
+    }
+
+    async fn insert_todos(&self, todos: Todos) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    todos (id, title, body, is_completed, created_at, updated_at)
+SELECT
+    *
+FROM
+    UNNEST($1::VARCHAR[], $2::VARCHAR[], $3::TEXT[], $4::BOOLEAN[], $5::TIMESTAMPTZ[], $6::TIMESTAMPTZ[])
+ON CONFLICT (id) DO UPDATE SET
+    title = excluded.title,
+    body = excluded.body,
+    is_completed = excluded.is_completed,
+    created_at = excluded.created_at,
+    updated_at = excluded.updated_at
+    "#;
+        let mut ids = Vec::with_capacity(todos.len());
+        let mut titles = Vec::with_capacity(todos.len());
+        let mut bodies = Vec::with_capacity(todos.len());
+        let mut completed = Vec::with_capacity(todos.len());
+        let mut created = Vec::with_capacity(todos.len());
+        let mut updated = Vec::with_capacity(todos.len());
+        for todo in todos {
+            ids.push(todo.id);
+            titles.push(todo.title);
+            bodies.push(todo.body);
+            completed.push(todo.is_completed);
+            created.push(todo.created_at);
+            updated.push(todo.updated_at);
+        }
+        sqlx::query(query)
+            .bind(ids)
+            .bind(titles)
+            .bind(bodies)
+            .bind(completed)
+            .bind(created)
+            .bind(updated)
+            .execute(&self.pool)
+            .await?;
+        self.written().await;
+
+        Ok(())
+    }
+
+    async fn insert_comments(&self, comments: Vec<Comment>) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    comments (id, todo_id, parent_id, author, body, created_at, edited_at)
+VALUES
+    ($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT (id) DO UPDATE SET
+    todo_id = excluded.todo_id,
+    parent_id = excluded.parent_id,
+    author = excluded.author,
+    body = excluded.body,
+    created_at = excluded.created_at,
+    edited_at = excluded.edited_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let mut todo_ids = BTreeSet::new();
+        for comment in comments {
+            sqlx::query(query)
+                .bind(comment.id)
+                .bind(&comment.todo_id)
+                .bind(comment.parent_id)
+                .bind(comment.author)
+                .bind(comment.body)
+                .bind(comment.created_at)
+                .bind(comment.edited_at)
+                .execute(&mut tx)
+                .await?;
+            todo_ids.insert(comment.todo_id);
+        }
+        for todo_id in todo_ids {
+            count_comments(&mut tx, &todo_id).await?;
+        }
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(())
+    }
+
+    async fn insert_attachments(&self, attachments: Vec<Attachment>) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    attachments (id, todo_id, filename, content_type, size, created_at)
+VALUES
+    ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (id) DO UPDATE SET
+    todo_id = excluded.todo_id,
+    filename = excluded.filename,
+    content_type = excluded.content_type,
+    size = excluded.size,
+    created_at = excluded.created_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        for attachment in attachments {
+            sqlx::query(query)
+                .bind(attachment.id)
+                .bind(attachment.todo_id)
+                .bind(attachment.filename)
+                .bind(attachment.content_type)
+                .bind(attachment.size)
+                .bind(attachment.created_at)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(())
+    }
+
+    async fn history(&self, id: &str, offset: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, actor, operation, changes, undo_state, created_at
+FROM
+    todo_history
+WHERE
+    todo_id = $1
+ORDER BY
+    id
+OFFSET $2
+LIMIT $3
+    "#;
+        let rows = sqlx::query_as::<_, HistoryRow>(query)
+            .bind(id)
+            .bind(offset)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(HistoryEntry::try_from).collect()
+    }
+
+    async fn list_history_after(&self, after: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, actor, operation, changes, undo_state, created_at
+FROM
+    todo_history
+WHERE
+    id > $1
+ORDER BY
+    id
+LIMIT $2
+    "#;
+        let rows = sqlx::query_as::<_, HistoryRow>(query)
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(HistoryEntry::try_from).collect()
+    }
+
+    async fn insert_history(&self, entries: Vec<HistoryEntry>) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    todo_history (id, todo_id, actor, operation, changes, undo_state, created_at)
+VALUES
+    ($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT (id) DO UPDATE SET
+    todo_id = excluded.todo_id,
+    actor = excluded.actor,
+    operation = excluded.operation,
+    changes = excluded.changes,
+    undo_state = excluded.undo_state,
+    created_at = excluded.created_at
+    "#;
+        // Ids handed out later follow the inserted ones.
+        let sequence = r#"
+SELECT
+    setval(pg_get_serial_sequence('todo_history', 'id'), MAX(id))
+FROM
+    todo_history
+HAVING
+    MAX(id) IS NOT NULL
+    "#;
+        let mut tx = self.pool.begin().await?;
+        for entry in entries {
+            sqlx::query(query)
+                .bind(entry.id)
+                .bind(entry.todo_id)
+                .bind(entry.actor)
+                .bind(entry.operation.as_str())
+                .bind(Json(entry.changes))
+                .bind(entry.undo_state.as_str())
+                .bind(entry.created_at)
+                .execute(&mut tx)
+                .await?;
+        }
+        sqlx::query(sequence).execute(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(())
+    }
+
+    async fn revert(
+        &self,
+        actor: &str,
+        direction: Direction,
+        count: i64,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let query = format!(
+            r#"
+SELECT
+    id, todo_id, actor, operation, changes, undo_state, created_at
+FROM
+    todo_history
+WHERE
+    actor = $1 AND undo_state = $2 AND operation NOT IN ($3, $4)
+ORDER BY
+    id {}
+LIMIT $5
+FOR UPDATE
+    "#,
+            match direction {
+                Direction::Undo => "DESC",
+                Direction::Redo => "ASC",
+            }
+        );
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query_as::<_, HistoryRow>(&query)
+            .bind(actor)
+            .bind(direction.source_state().as_str())
+            .bind(Operation::Undo.as_str())
+            .bind(Operation::Redo.as_str())
+            .bind(count)
+            .fetch_all(&mut tx)
+            .await?;
+
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in rows {
+            let entry = HistoryEntry::try_from(row)?;
+            let current = lock_todo(&mut tx, &entry.todo_id).await?;
+            let target = entry.revert(current.as_ref(), direction)?;
+            put_todo(&mut tx, &entry.todo_id, current.is_some(), target.as_ref()).await?;
+
+            let query = r#"
+UPDATE
+    todo_history
+SET
+    undo_state = $2
+WHERE
+    id = $1
+    "#;
+            sqlx::query(query)
+                .bind(entry.id)
+                .bind(direction.target_state().as_str())
+                .execute(&mut tx)
+                .await?;
+
+            let recorded = self
+                .record(
+                    &mut tx,
+                    actor,
+                    direction.operation(),
+                    current.as_ref(),
+                    target.as_ref(),
+                )
+                .await?;
+            entries.push(recorded);
+        }
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(entries)
+    }
+
+    async fn add_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        parent_id: Option<String>,
+        body: String,
+    ) -> Result<Comment, Error> {
+        let query = r#"
+INSERT INTO
+    comments (id, todo_id, parent_id, author, body, created_at)
+VALUES
+    ($1, $2, $3, $4, $5, NOW())
+RETURNING
+    id, todo_id, parent_id, author, body, created_at, edited_at
+    "#;
+        let id = self.id_generator.new_id()?.encode();
+        let mut tx = self.pool.begin().await?;
+        lock_todo(&mut tx, todo_id).await?.ok_or(Error::NotFound)?;
+        if let Some(parent_id) = parent_id.as_ref() {
+            find_comment(&mut tx, todo_id, parent_id).await?;
+        }
+        let comment = sqlx::query_as::<_, Comment>(query)
+            .bind(id)
+            .bind(todo_id)
+            .bind(parent_id)
+            .bind(author)
+            .bind(body)
+            .fetch_one(&mut tx)
+            .await?;
+        count_comments(&mut tx, todo_id).await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(comment)
+    }
+
+    async fn list_comments(&self, todo_id: &str) -> Result<Vec<Comment>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, parent_id, author, body, created_at, edited_at
+FROM
+    comments
+WHERE
+    todo_id = $1
+ORDER BY
+    created_at, id
+    "#;
+        self.get(todo_id).await?;
+        let comments = sqlx::query_as::<_, Comment>(query)
+            .bind(todo_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(comments)
+    }
+
+    async fn edit_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        id: &str,
+        body: String,
+    ) -> Result<Comment, Error> {
+        let query = r#"
+UPDATE
+    comments
+SET
+    body = $2, edited_at = NOW()
+WHERE
+    id = $1
+RETURNING
+    id, todo_id, parent_id, author, body, created_at, edited_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        if find_comment(&mut tx, todo_id, id).await?.author != author {
+            return Err(Error::PermissionDenied);
+        }
+        let comment = sqlx::query_as::<_, Comment>(query)
+            .bind(id)
+            .bind(body)
+            .fetch_one(&mut tx)
+            .await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(comment)
+    }
+
+    async fn delete_comment(&self, author: &str, todo_id: &str, id: &str) -> Result<(), Error> {
+        let query = r#"
+DELETE FROM
+    comments
+WHERE
+    id = $1
+    "#;
+        let mut tx = self.pool.begin().await?;
+        lock_todo(&mut tx, todo_id).await?.ok_or(Error::NotFound)?;
+        if find_comment(&mut tx, todo_id, id).await?.author != author {
+            return Err(Error::PermissionDenied);
+        }
+        sqlx::query(query).bind(id).execute(&mut tx).await?;
+        count_comments(&mut tx, todo_id).await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(())
+    }
+
+    async fn add_attachment(&self, attachment: Attachment) -> Result<Attachment, Error> {
+        let query = r#"
+INSERT INTO
+    attachments (id, todo_id, filename, content_type, size, created_at)
+VALUES
+    ($1, $2, $3, $4, $5, $6)
+RETURNING
+    id, todo_id, filename, content_type, size, created_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        lock_todo(&mut tx, &attachment.todo_id)
+            .await?
+            .ok_or(Error::NotFound)?;
+        let attachment = sqlx::query_as::<_, Attachment>(query)
+            .bind(attachment.id)
+            .bind(attachment.todo_id)
+            .bind(attachment.filename)
+            .bind(attachment.content_type)
+            .bind(attachment.size)
+            .bind(attachment.created_at)
+            .fetch_one(&mut tx)
+            .await?;
+        mark_applied(&mut tx).await?;
+        tx.commit().await?;
+        self.written().await;
+
+        Ok(attachment)
+    }
+
+    async fn list_attachments(&self, todo_id: &str) -> Result<Vec<Attachment>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, filename, content_type, size, created_at
+FROM
+    attachments
+WHERE
+    todo_id = $1
+ORDER BY
+    created_at, id
+    "#;
+        self.get(todo_id).await?;
+        let attachments = sqlx::query_as::<_, Attachment>(query)
+            .bind(todo_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(attachments)
+    }
+
+    async fn get_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, filename, content_type, size, created_at
+FROM
+    attachments
+WHERE
+    id = $1 AND todo_id = $2
+    "#;
+        let attachment = sqlx::query_as::<_, Attachment>(query)
+            .bind(id)
+            .bind(todo_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        attachment.ok_or(Error::AttachmentNotFound)
+    }
+
+    async fn delete_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        let query = r#"
+DELETE FROM
+    attachments
+WHERE
+    id = $1 AND todo_id = $2
+RETURNING
+    id, todo_id, filename, content_type, size, created_at
+    "#;
+        let attachment = sqlx::query_as::<_, Attachment>(query)
+            .bind(id)
+            .bind(todo_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(Error::AttachmentNotFound)?;
+        self.written().await;
+
+        Ok(attachment)
+    }
+}
+
+async fn lock_todo(tx: &mut Transaction<'_, Postgres>, id: &str) -> Result<Option<Todo>, Error> {
+    let query = r#"
+SELECT
+    id, title, body, is_completed, created_at, updated_at, comment_count
+FROM
+    todos
+WHERE
+    id = $1
+FOR UPDATE
+    "#;
+    let todo = sqlx::query_as::<_, Todo>(query)
+        .bind(id)
+        .fetch_optional(tx)
+        .await?;
+
+    Ok(todo)
+}
+
+/// Writes the full state of a todo, deleting it when `todo` is `None`.
+async fn put_todo(
+    tx: &mut Transaction<'_, Postgres>,
+    id: &str,
+    exists: bool,
+    todo: Option<&Todo>,
+) -> Result<(), Error> {
+    let todo = match todo {
+        Some(todo) => todo,
+        None => {
+            let query = r#"
+DELETE FROM
+    todos
+WHERE
+    id = $1
+    "#;
+            sqlx::query(query).bind(id).execute(tx).await?;
+            return Ok(());
+        }
+    };
+
+    let query = if exists {
+        r#"
+UPDATE
+    todos
+SET
+    title = $2, body = $3, is_completed = $4, created_at = $5, updated_at = $6
+WHERE
+    id = $1
+    "#
+    } else {
+        r#"
+INSERT INTO
+    todos (id, title, body, is_completed, created_at, updated_at)
+VALUES
+    ($1, $2, $3, $4, $5, $6)
+    "#
+    };
+    sqlx::query(query)
+        .bind(id)
+        .bind(&todo.title)
+        .bind(&todo.body)
+        .bind(todo.is_completed)
+        .bind(todo.created_at)
+        .bind(todo.updated_at)
+        .execute(tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn find_comment(
+    tx: &mut Transaction<'_, Postgres>,
+    todo_id: &str,
+    id: &str,
+) -> Result<Comment, Error> {
+    let query = r#"
+SELECT
+    id, todo_id, parent_id, author, body, created_at, edited_at
+FROM
+    comments
+WHERE
+    id = $1 AND todo_id = $2
+FOR UPDATE
+    "#;
+    let comment = sqlx::query_as::<_, Comment>(query)
+        .bind(id)
+        .bind(todo_id)
+        .fetch_optional(tx)
+        .await?;
+
+    comment.ok_or(Error::CommentNotFound)
+}
+
+/// Refreshes the denormalized comment count of a todo. Counting instead of
+/// incrementing keeps it right when deleting a comment cascades to replies.
+async fn count_comments(tx: &mut Transaction<'_, Postgres>, todo_id: &str) -> Result<(), Error> {
+    let query = r#"
+UPDATE
+    todos
+SET
+    comment_count = (SELECT COUNT(*) FROM comments WHERE todo_id = $1)
+WHERE
+    id = $1
+    "#;
+    sqlx::query(query).bind(todo_id).execute(tx).await?;
+
+    Ok(())
 }
@@ -1,53 +1,300 @@
+use crate::repository::cached::CachedRepository;
 use crate::repository::error::Error;
 use crate::repository::hashmap::HashMapRepository;
-use crate::repository::model::{Todo, Todos};
+use crate::repository::idempotency::{
+    IdempotencyStore, MemoryIdempotencyStore, PostgresIdempotencyStore,
+};
+use crate::repository::migrations::Migrations;
+use crate::repository::model::{Attachment, Comment, Direction, HistoryEntry, Todo, Todos};
+use crate::repository::outbox::PostgresOutbox;
 use crate::repository::postgres::PostgresRepository;
+use crate::repository::sled::SledRepository;
+use crate::repository::sqlite::SqliteRepository;
+use crate::repository::wal::FsyncPolicy;
 use async_trait::async_trait;
-use config::{Config, Environment};
+use config::{Config, ConfigError, Environment};
+use std::str::FromStr;
 
 #[async_trait]
 pub trait Repository {
     async fn list(&self) -> Result<Todos, Error>;
     async fn get(&self, id: &str) -> Result<Todo, Error>;
-    async fn create(&self, title: String, body: String) -> Result<Todo, Error>;
+    async fn create(&self, actor: &str, title: String, body: String) -> Result<Todo, Error>;
+    /// Changes the fields given and keeps the others, as they are when the
+    /// change is made.
     async fn update(
         &self,
+        actor: &str,
         id: &str,
-        title: String,
-        body: String,
-        is_completed: bool,
+        title: Option<String>,
+        body: Option<String>,
+        is_completed: Option<bool>,
     ) -> Result<Todo, Error>;
-    async fn delete(&self, id: &str) -> Result<(), Error>;
-    async fn complete(&self, id: &str) -> Result<Todo, Error>;
+    async fn delete(&self, actor: &str, id: &str) -> Result<(), Error>;
+    async fn complete(&self, actor: &str, id: &str) -> Result<Todo, Error>;
+    async fn import(&self, actor: &str, todos: Todos, dry_run: bool) -> Result<Todos, Error>;
+    /// Returns up to `limit` todos with ids greater than `after`, ordered by
+    /// id.
+    async fn list_after(&self, after: &str, limit: i64) -> Result<Todos, Error>;
+    /// Writes todos with their ids and timestamps as they are, replacing
+    /// existing ones, without recording history. Comment counts are kept.
+    async fn insert_todos(&self, todos: Todos) -> Result<(), Error>;
+    /// Writes comments as they are, replacing existing ones, and refreshes
+    /// the comment counts of their todos. A reply comes after its parent.
+    async fn insert_comments(&self, comments: Vec<Comment>) -> Result<(), Error>;
+    /// Writes the metadata of attachments as it is, replacing existing ones.
+    async fn insert_attachments(&self, attachments: Vec<Attachment>) -> Result<(), Error>;
+    async fn history(&self, id: &str, offset: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error>;
+    /// Returns up to `limit` history entries of any todo with ids greater
+    /// than `after`, ordered by id.
+    async fn list_history_after(&self, after: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error>;
+    /// Writes history entries with their ids as they are, replacing existing
+    /// ones. Entries recorded later get greater ids than all of them.
+    async fn insert_history(&self, entries: Vec<HistoryEntry>) -> Result<(), Error>;
+    /// Undoes or redoes the actor's `count` most recent changes atomically and
+    /// returns the history entries recorded for them.
+    async fn revert(
+        &self,
+        actor: &str,
+        direction: Direction,
+        count: i64,
+    ) -> Result<Vec<HistoryEntry>, Error>;
+    async fn add_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        parent_id: Option<String>,
+        body: String,
+    ) -> Result<Comment, Error>;
+    async fn list_comments(&self, todo_id: &str) -> Result<Vec<Comment>, Error>;
+    /// Changes the body of a comment. Only its author may edit it.
+    async fn edit_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        id: &str,
+        body: String,
+    ) -> Result<Comment, Error>;
+    /// Deletes a comment together with its replies. Only its author may
+    /// delete it.
+    async fn delete_comment(&self, author: &str, todo_id: &str, id: &str) -> Result<(), Error>;
+    /// Stores the metadata of an attachment whose content was already
+    /// written to the blob store.
+    async fn add_attachment(&self, attachment: Attachment) -> Result<Attachment, Error>;
+    async fn list_attachments(&self, todo_id: &str) -> Result<Vec<Attachment>, Error>;
+    async fn get_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error>;
+    async fn delete_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error>;
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PostgresSettings {
     pub connection_string: String,
-    pub migrations_path: String,
+    /// Applies pending migrations in `get_repository`. Turn it off to run
+    /// them with `todo migrate up` instead.
+    pub auto_migrate: bool,
+    /// Serves `list` and `get` when set.
+    pub replica_connection_string: Option<String>,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_ms: u64,
+    pub idle_timeout_ms: Option<u64>,
+    pub statement_timeout_ms: Option<u64>,
+    pub application_name: Option<String>,
+    /// Writes every change to the outbox table for the relay to deliver.
+    /// Each write then holds one lock shared by all instances from its
+    /// outbox insert until it commits, so writes commit one at a time.
+    pub outbox: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
+pub struct SqliteSettings {
+    pub path: String,
+    pub auto_migrate: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SledSettings {
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HashMapSettings {
+    pub data_dir: Option<String>,
+    pub fsync: FsyncPolicy,
+    pub fsync_interval_ms: u64,
+    pub snapshot_every: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheSettings {
+    pub enabled: bool,
+    pub capacity: usize,
+    pub ttl_ms: u64,
+    pub list: bool,
+    pub stats_interval_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
 pub enum StorageSettings {
     Postgres,
+    Sqlite,
+    Sled,
     HashMap,
 }
 
+impl FromStr for StorageSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Postgres" => Ok(StorageSettings::Postgres),
+            "Sqlite" => Ok(StorageSettings::Sqlite),
+            "Sled" => Ok(StorageSettings::Sled),
+            "HashMap" => Ok(StorageSettings::HashMap),
+            _ => Err(format!("unknown storage: {}", s)),
+        }
+    }
+}
+
 pub async fn get_repository(
     params: StorageSettings,
     logger: slog::Logger,
 ) -> Result<Box<dyn Repository + Send + Sync>, Box<dyn std::error::Error>> {
+    let mut c = Config::default();
+    c.set_default("enabled", false)?;
+    c.set_default("capacity", 10000)?;
+    c.set_default("ttl_ms", 5000)?;
+    c.set_default("list", false)?;
+    c.set_default("stats_interval_secs", 60)?;
+    c.merge(Environment::with_prefix("TODO_CACHE"))?;
+    let cache = c.try_into::<CacheSettings>()?;
+
     match params {
         StorageSettings::Postgres => {
+            let s = postgres_settings()?;
+
+            let repo = PostgresRepository::new(&s, logger.clone()).await?;
+            if s.auto_migrate {
+                repo.migrate_up().await?;
+            }
+
+            Ok(with_cache(repo, &cache, logger))
+        }
+        StorageSettings::Sqlite => {
+            let s = sqlite_settings()?;
+
+            let repo = SqliteRepository::new(s.path.as_str()).await?;
+            if s.auto_migrate {
+                repo.migrate_up().await?;
+            }
+
+            Ok(with_cache(repo, &cache, logger))
+        }
+        StorageSettings::Sled => {
+            let mut c = Config::default();
+            c.merge(Environment::with_prefix("TODO_SLED"))?;
+            let s = c.try_into::<SledSettings>()?;
+
+            let repo = SledRepository::new(s.path.as_str())?;
+
+            Ok(with_cache(repo, &cache, logger))
+        }
+        StorageSettings::HashMap => {
             let mut c = Config::default();
-            c.merge(Environment::with_prefix("TODO_POSTGRES"))?;
-            let s = c.try_into::<PostgresSettings>()?;
+            c.set_default("fsync", "Always")?;
+            c.set_default("fsync_interval_ms", 1000)?;
+            c.set_default("snapshot_every", 10000)?;
+            c.merge(Environment::with_prefix("TODO_HASHMAP"))?;
+            let s = c.try_into::<HashMapSettings>()?;
 
-            let repo = PostgresRepository::new(s.connection_string.as_str()).await?;
-            repo.run_migrations(s.migrations_path.as_str()).await?;
+            let repo = match s.data_dir.as_ref() {
+                Some(dir) => HashMapRepository::open(logger.clone(), dir, &s)?,
+                None => HashMapRepository::new(logger.clone()),
+            };
 
-            Ok(Box::new(repo))
+            Ok(with_cache(repo, &cache, logger))
         }
-        StorageSettings::HashMap => Ok(Box::new(HashMapRepository::new(logger))),
+    }
+}
+
+/// Connects to the storage without migrating it, for `todo migrate`.
+pub async fn get_migrations(
+    params: StorageSettings,
+    logger: slog::Logger,
+) -> Result<Box<dyn Migrations + Send + Sync>, Box<dyn std::error::Error>> {
+    match params {
+        StorageSettings::Postgres => {
+            let s = postgres_settings()?;
+            Ok(Box::new(PostgresRepository::new(&s, logger).await?))
+        }
+        StorageSettings::Sqlite => {
+            let s = sqlite_settings()?;
+            Ok(Box::new(SqliteRepository::new(s.path.as_str()).await?))
+        }
+        StorageSettings::Sled | StorageSettings::HashMap => {
+            Err(format!("{:?} storage has no migrations", params).into())
+        }
+    }
+}
+
+/// Connects to the outbox of the storage, if it has one enabled.
+pub async fn get_outbox(
+    params: StorageSettings,
+) -> Result<Option<PostgresOutbox>, Box<dyn std::error::Error>> {
+    match params {
+        StorageSettings::Postgres => {
+            let s = postgres_settings()?;
+            if !s.outbox {
+                return Ok(None);
+            }
+            Ok(Some(PostgresOutbox::new(&s).await?))
+        }
+        StorageSettings::Sqlite | StorageSettings::Sled | StorageSettings::HashMap => Ok(None),
+    }
+}
+
+/// Keeps idempotency keys in Postgres when it is the storage, so that every
+/// instance sees them, and in memory otherwise.
+pub async fn get_idempotency_store(
+    params: StorageSettings,
+) -> Result<Box<dyn IdempotencyStore + Send + Sync>, Box<dyn std::error::Error>> {
+    match params {
+        StorageSettings::Postgres => {
+            let s = postgres_settings()?;
+            Ok(Box::new(PostgresIdempotencyStore::new(&s).await?))
+        }
+        StorageSettings::Sqlite | StorageSettings::Sled | StorageSettings::HashMap => {
+            Ok(Box::new(MemoryIdempotencyStore::new()))
+        }
+    }
+}
+
+fn postgres_settings() -> Result<PostgresSettings, ConfigError> {
+    let mut c = Config::default();
+    c.set_default("auto_migrate", true)?;
+    c.set_default("max_connections", 10)?;
+    c.set_default("min_connections", 0)?;
+    c.set_default("acquire_timeout_ms", 30000)?;
+    c.set_default("outbox", false)?;
+    c.merge(Environment::with_prefix("TODO_POSTGRES"))?;
+    c.try_into::<PostgresSettings>()
+}
+
+fn sqlite_settings() -> Result<SqliteSettings, ConfigError> {
+    let mut c = Config::default();
+    c.set_default("auto_migrate", true)?;
+    c.merge(Environment::with_prefix("TODO_SQLITE"))?;
+    c.try_into::<SqliteSettings>()
+}
+
+fn with_cache<R: Repository + Send + Sync + 'static>(
+    repo: R,
+    settings: &CacheSettings,
+    logger: slog::Logger,
+) -> Box<dyn Repository + Send + Sync> {
+    if settings.enabled {
+        Box::new(CachedRepository::new(repo, settings, logger))
+    } else {
+        Box::new(repo)
     }
 }
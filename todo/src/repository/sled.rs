@@ -0,0 +1,870 @@
+use crate::repository::error::Error;
+use crate::repository::model::{
+    diff, Attachment, Comment, Direction, HistoryEntry, Operation, Todo, Todos, UndoState,
+};
+use crate::repository::repository::Repository;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::transaction::{ConflictableTransactionResult, TransactionError};
+use sled::{Transactional, Tree};
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::Mutex;
+
+/// The trees the repository keeps its data and indexes in.
+#[derive(Clone, Copy)]
+enum Table {
+    /// todo id -> todo
+    Todos,
+    /// created_at, todo id -> todo id
+    TodosByCreatedAt,
+    /// history id -> history entry
+    History,
+    /// todo id, history id -> ()
+    HistoryByTodo,
+    /// actor, history id -> ()
+    HistoryByActor,
+    /// actor, history id -> (), the entries an actor has undone
+    UndoneByActor,
+    /// todo id, comment id -> comment
+    Comments,
+    /// todo id, attachment id -> attachment
+    Attachments,
+}
+
+const TABLES: [&str; 8] = [
+    "todos",
+    "todos_by_created_at",
+    "history",
+    "history_by_todo",
+    "history_by_actor",
+    "undone_by_actor",
+    "comments",
+    "attachments",
+];
+/// The indexes rebuilt from the records they point to when their keys
+/// change.
+const INDEXES: [Table; 2] = [Table::TodosByCreatedAt, Table::UndoneByActor];
+/// Indexes of earlier versions that are no longer kept, dropped on rebuild.
+const DROPPED_INDEXES: [&str; 1] = ["todos_by_completion"];
+/// Bumped whenever the keys of an index change.
+const INDEX_VERSION: &[u8] = &[3];
+const INDEX_VERSION_KEY: &[u8] = b"index_version";
+
+/// The writes of one repository operation, committed in a single
+/// transaction. Todos written earlier in the batch are visible to later
+/// reads through it, and so is the largest history id it writes.
+#[derive(Default)]
+struct Batch {
+    writes: Vec<(Table, Vec<u8>, Option<Vec<u8>>)>,
+    todos: HashMap<String, Option<Todo>>,
+    last_history: u64,
+}
+
+impl Batch {
+    fn insert<V: Serialize>(&mut self, table: Table, key: Vec<u8>, value: &V) -> Result<(), Error> {
+        let value = serde_json::to_vec(value).map_err(std::io::Error::from)?;
+        self.writes.push((table, key, Some(value)));
+        Ok(())
+    }
+
+    fn insert_index(&mut self, table: Table, key: Vec<u8>, value: &[u8]) {
+        self.writes.push((table, key, Some(value.to_vec())));
+    }
+
+    fn remove(&mut self, table: Table, key: Vec<u8>) {
+        self.writes.push((table, key, None));
+    }
+}
+
+/// A repository on an embedded sled database, with secondary indexes kept
+/// in the same transactions as the records they point to.
+///
+/// Reads never block. Writers are serialized by `writer`: every operation
+/// reads what it is about to change outside of the transaction that changes
+/// it, including prefix scans such as the comments of a todo or the history
+/// of an actor, and sled transactions can only check single keys for
+/// conflicts, not scans. sled opens a database in one process only, so the
+/// lock covers every writer. It is held only while a batch is built and
+/// applied, which touches memory; the flush to disk happens after.
+pub struct SledRepository {
+    db: sled::Db,
+    trees: Vec<Tree>,
+    writer: Mutex<()>,
+    /// Overlapping flushes can hang sled, so they take turns. Writers keep
+    /// applying batches meanwhile, and a flush covers every batch applied
+    /// before it.
+    flusher: tokio::sync::Mutex<()>,
+    id_generator: libxid::Generator,
+}
+
+impl SledRepository {
+    pub fn new(path: &str) -> Result<SledRepository, Error> {
+        let db = sled::open(path)?;
+        let trees = TABLES
+            .iter()
+            .map(|name| db.open_tree(name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let repo = SledRepository {
+            db,
+            trees,
+            writer: Mutex::new(()),
+            flusher: tokio::sync::Mutex::new(()),
+            id_generator: libxid::new_generator(),
+        };
+        if repo.db.get(INDEX_VERSION_KEY)?.as_deref() != Some(INDEX_VERSION) {
+            repo.rebuild_indexes()?;
+        }
+
+        Ok(repo)
+    }
+
+    /// Writes every index anew from the todos and the history, for a
+    /// database whose indexes have keys of an earlier version.
+    fn rebuild_indexes(&self) -> Result<(), Error> {
+        for name in DROPPED_INDEXES.iter() {
+            self.db.drop_tree(name)?;
+        }
+        for table in INDEXES.iter() {
+            self.tree(*table).clear()?;
+        }
+        for item in self.tree(Table::Todos).iter() {
+            let todo: Todo = decode(&item?.1)?;
+            self.tree(Table::TodosByCreatedAt)
+                .insert(created_key(&todo), todo.id.as_bytes())?;
+        }
+        for item in self.tree(Table::History).iter() {
+            let entry: HistoryEntry = decode(&item?.1)?;
+            if entry.undo_state == UndoState::Undone {
+                let key = history_key(&entry.actor, entry.id as u64);
+                self.tree(Table::UndoneByActor).insert(key, &[])?;
+            }
+        }
+        self.db.insert(INDEX_VERSION_KEY, INDEX_VERSION)?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn tree(&self, table: Table) -> &Tree {
+        &self.trees[table as usize]
+    }
+
+    fn read<V: DeserializeOwned>(&self, table: Table, key: &[u8]) -> Result<Option<V>, Error> {
+        match self.tree(table).get(key)? {
+            Some(value) => Ok(Some(decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn scan<V: DeserializeOwned>(&self, table: Table, prefix: &[u8]) -> Result<Vec<V>, Error> {
+        let mut values = Vec::new();
+        for item in self.tree(table).scan_prefix(prefix) {
+            let (_, value) = item?;
+            values.push(decode(&value)?);
+        }
+
+        Ok(values)
+    }
+
+    /// Returns the history ids stored in the keys of an index under `prefix`.
+    fn scan_history_ids(&self, table: Table, prefix: &[u8]) -> Result<Vec<u64>, Error> {
+        let mut ids = Vec::new();
+        for item in self.tree(table).scan_prefix(prefix) {
+            let (key, _) = item?;
+            let mut id = [0; 8];
+            id.copy_from_slice(&key[key.len() - 8..]);
+            ids.push(u64::from_be_bytes(id));
+        }
+
+        Ok(ids)
+    }
+
+    fn find_entry(&self, id: u64) -> Result<HistoryEntry, Error> {
+        self.read(Table::History, &id.to_be_bytes())?
+            .ok_or(Error::NotFound)
+    }
+
+    fn find_todo(&self, batch: &Batch, id: &str) -> Result<Option<Todo>, Error> {
+        match batch.todos.get(id) {
+            Some(todo) => Ok(todo.clone()),
+            None => self.read(Table::Todos, id.as_bytes()),
+        }
+    }
+
+    fn find_comment(&self, todo_id: &str, id: &str) -> Result<Comment, Error> {
+        self.read(Table::Comments, &child_key(todo_id, id))?
+            .ok_or(Error::CommentNotFound)
+    }
+
+    fn find_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        self.read(Table::Attachments, &child_key(todo_id, id))?
+            .ok_or(Error::AttachmentNotFound)
+    }
+
+    /// Writes the full state of a todo together with its index entries,
+    /// deleting it when `todo` is `None`.
+    fn put_todo(&self, batch: &mut Batch, id: &str, todo: Option<&Todo>) -> Result<(), Error> {
+        if let Some(current) = self.find_todo(batch, id)? {
+            batch.remove(Table::TodosByCreatedAt, created_key(&current));
+        }
+
+        match todo {
+            Some(todo) => {
+                batch.insert(Table::Todos, id.as_bytes().to_vec(), todo)?;
+                batch.insert_index(Table::TodosByCreatedAt, created_key(todo), id.as_bytes());
+            }
+            None => {
+                batch.remove(Table::Todos, id.as_bytes().to_vec());
+                let prefix = parent_prefix(id);
+                for item in self.tree(Table::Comments).scan_prefix(&prefix) {
+                    batch.remove(Table::Comments, item?.0.to_vec());
+                }
+                for item in self.tree(Table::Attachments).scan_prefix(&prefix) {
+                    batch.remove(Table::Attachments, item?.0.to_vec());
+                }
+            }
+        }
+        batch.todos.insert(id.to_string(), todo.cloned());
+
+        Ok(())
+    }
+
+    fn put_entry(&self, batch: &mut Batch, entry: &HistoryEntry) -> Result<(), Error> {
+        let id = entry.id as u64;
+        batch.last_history = batch.last_history.max(id);
+        batch.insert(Table::History, id.to_be_bytes().to_vec(), entry)?;
+        batch.insert_index(Table::HistoryByTodo, history_key(&entry.todo_id, id), &[]);
+        batch.insert_index(Table::HistoryByActor, history_key(&entry.actor, id), &[]);
+        let undone = history_key(&entry.actor, id);
+        if entry.undo_state == UndoState::Undone {
+            batch.insert_index(Table::UndoneByActor, undone, &[]);
+        } else {
+            batch.remove(Table::UndoneByActor, undone);
+        }
+
+        Ok(())
+    }
+
+    fn record(
+        &self,
+        batch: &mut Batch,
+        actor: &str,
+        operation: Operation,
+        before: Option<&Todo>,
+        after: Option<&Todo>,
+    ) -> Result<HistoryEntry, Error> {
+        if operation.is_undoable() {
+            for id in self.scan_history_ids(Table::UndoneByActor, &parent_prefix(actor))? {
+                let mut entry = self.find_entry(id)?;
+                entry.undo_state = UndoState::Discarded;
+                self.put_entry(batch, &entry)?;
+            }
+        }
+
+        // Ids follow the last entry, which may have been copied in with its id.
+        let last = match self.tree(Table::History).last()? {
+            Some((key, _)) => {
+                let mut id = [0; 8];
+                id.copy_from_slice(&key);
+                u64::from_be_bytes(id)
+            }
+            None => 0,
+        };
+        let todo_id = before.or(after).map(|t| t.id.clone()).unwrap_or_default();
+        let entry = HistoryEntry {
+            id: last.max(batch.last_history) as i64 + 1,
+            todo_id,
+            actor: actor.to_string(),
+            operation,
+            changes: diff(before, after),
+            undo_state: UndoState::Active,
+            created_at: Utc::now(),
+        };
+        self.put_entry(batch, &entry)?;
+
+        Ok(entry)
+    }
+
+    /// Refreshes the denormalized comment count of a todo, given the comment
+    /// keys the batch adds and removes.
+    fn count_comments(
+        &self,
+        batch: &mut Batch,
+        todo_id: &str,
+        added: usize,
+        removed: usize,
+    ) -> Result<(), Error> {
+        let count = self
+            .tree(Table::Comments)
+            .scan_prefix(parent_prefix(todo_id))
+            .count();
+        if let Some(mut todo) = self.find_todo(batch, todo_id)? {
+            todo.comment_count = (count + added - removed) as i32;
+            batch.insert(Table::Todos, todo_id.as_bytes().to_vec(), &todo)?;
+            batch.todos.insert(todo_id.to_string(), Some(todo));
+        }
+
+        Ok(())
+    }
+
+    /// Builds a batch with `write` and applies it under the writer lock, then
+    /// flushes it to disk with the lock released. A batch without writes,
+    /// such as that of a dry run, is not applied.
+    async fn write<T>(
+        &self,
+        write: impl FnOnce(&mut Batch) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let value = {
+            let _writer = self.writer.lock().unwrap();
+            let mut batch = Batch::default();
+            let value = write(&mut batch)?;
+            if batch.writes.is_empty() {
+                return Ok(value);
+            }
+            self.apply(&batch)?;
+            value
+        };
+        let _flusher = self.flusher.lock().await;
+        self.db.flush_async().await?;
+
+        Ok(value)
+    }
+
+    fn apply(&self, batch: &Batch) -> Result<(), Error> {
+        let writes = &batch.writes;
+        self.trees
+            .as_slice()
+            .transaction(|trees| -> ConflictableTransactionResult<(), Error> {
+                for (table, key, value) in writes {
+                    let tree = &trees[*table as usize];
+                    match value {
+                        Some(value) => tree.insert(key.as_slice(), value.as_slice())?,
+                        None => tree.remove(key.as_slice())?,
+                    };
+                }
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })
+    }
+}
+
+#[async_trait]
+impl Repository for SledRepository {
+    async fn list(&self) -> Result<Todos, Error> {
+        let mut todos = Vec::new();
+        for item in self.tree(Table::TodosByCreatedAt).iter() {
+            let (_, id) = item?;
+            // A todo deleted since the scan started is simply skipped.
+            if let Some(todo) = self.read(Table::Todos, &id)? {
+                todos.push(todo);
+            }
+        }
+
+        Ok(todos)
+    }
+
+    async fn get(&self, id: &str) -> Result<Todo, Error> {
+        self.read(Table::Todos, id.as_bytes())?
+            .ok_or(Error::NotFound)
+    }
+
+    async fn create(&self, actor: &str, title: String, body: String) -> Result<Todo, Error> {
+        self.write(|batch| {
+            let now = Utc::now();
+            let todo = Todo {
+                id: self.id_generator.new_id()?.encode(),
+                title,
+                body,
+                is_completed: false,
+                created_at: now,
+                updated_at: now,
+                comment_count: 0,
+            };
+            self.put_todo(batch, &todo.id, Some(&todo))?;
+            self.record(batch, actor, Operation::Create, None, Some(&todo))?;
+
+            Ok(todo)
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        actor: &str,
+        id: &str,
+        title: Option<String>,
+        body: Option<String>,
+        is_completed: Option<bool>,
+    ) -> Result<Todo, Error> {
+        self.write(|batch| {
+            let before = self.find_todo(batch, id)?.ok_or(Error::NotFound)?;
+            let mut todo = before.clone();
+            todo.title = title.unwrap_or(todo.title);
+            todo.body = body.unwrap_or(todo.body);
+            todo.is_completed = is_completed.unwrap_or(todo.is_completed);
+            todo.updated_at = Utc::now();
+            self.put_todo(batch, id, Some(&todo))?;
+            self.record(batch, actor, Operation::Update, Some(&before), Some(&todo))?;
+
+            Ok(todo)
+        })
+        .await
+    }
+
+    async fn delete(&self, actor: &str, id: &str) -> Result<(), Error> {
+        self.write(|batch| {
+            let todo = self.find_todo(batch, id)?.ok_or(Error::NotFound)?;
+            self.put_todo(batch, id, None)?;
+            self.record(batch, actor, Operation::Delete, Some(&todo), None)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn complete(&self, actor: &str, id: &str) -> Result<Todo, Error> {
+        self.write(|batch| {
+            let before = self.find_todo(batch, id)?.ok_or(Error::NotFound)?;
+            if before.is_completed {
+                return Err(Error::AlreadyCompleted);
+            }
+            let mut todo = before.clone();
+            todo.is_completed = true;
+            todo.updated_at = Utc::now();
+            self.put_todo(batch, id, Some(&todo))?;
+            self.record(
+                batch,
+                actor,
+                Operation::Complete,
+                Some(&before),
+                Some(&todo),
+            )?;
+
+            Ok(todo)
+        })
+        .await
+    }
+
+    async fn import(&self, actor: &str, todos: Todos, dry_run: bool) -> Result<Todos, Error> {
+        self.write(|batch| {
+            let mut imported = Vec::with_capacity(todos.len());
+            for mut todo in todos {
+                if todo.id.is_empty() {
+                    todo.id = self.id_generator.new_id()?.encode();
+                }
+                if self.find_todo(batch, &todo.id)?.is_some() {
+                    return Err(Error::AlreadyExists(todo.id));
+                }
+                todo.comment_count = 0;
+                self.put_todo(batch, &todo.id, Some(&todo))?;
+                if !dry_run {
+                    self.record(batch, actor, Operation::Create, None, Some(&todo))?;
+                }
+                imported.push(todo);
+            }
+            if dry_run {
+                batch.writes.clear();
+            }
+
+            Ok(imported)
+        })
+        .await
+    }
+
+    async fn list_after(&self, after: &str, limit: i64) -> Result<Todos, Error> {
+        let mut todos = Vec::new();
+        let range = (Bound::Excluded(after.as_bytes()), Bound::Unbounded);
+        for item in self
+            .tree(Table::Todos)
+            .range::<&[u8], _>(range)
+            .take(limit as usize)
+        {
+            let (_, value) = item?;
+            todos.push(decode(&value)?);
+        }
+
+        Ok(todos)
+    }
+
+    async fn insert_todos(&self, todos: Todos) -> Result<(), Error> {
+        self.write(|batch| {
+            for mut todo in todos {
+                todo.comment_count = self
+                    .find_todo(batch, &todo.id)?
+                    .map_or(0, |t| t.comment_count);
+                self.put_todo(batch, &todo.id, Some(&todo))?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_comments(&self, comments: Vec<Comment>) -> Result<(), Error> {
+        self.write(|batch| {
+            let mut added: HashMap<String, HashSet<Vec<u8>>> = HashMap::new();
+            for comment in comments {
+                let key = child_key(&comment.todo_id, &comment.id);
+                batch.insert(Table::Comments, key.clone(), &comment)?;
+                added.entry(comment.todo_id).or_default().insert(key);
+            }
+            for (todo_id, mut keys) in added {
+                for item in self
+                    .tree(Table::Comments)
+                    .scan_prefix(parent_prefix(&todo_id))
+                {
+                    keys.insert(item?.0.to_vec());
+                }
+                if let Some(mut todo) = self.find_todo(batch, &todo_id)? {
+                    todo.comment_count = keys.len() as i32;
+                    batch.insert(Table::Todos, todo_id.as_bytes().to_vec(), &todo)?;
+                    batch.todos.insert(todo_id, Some(todo));
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_attachments(&self, attachments: Vec<Attachment>) -> Result<(), Error> {
+        self.write(|batch| {
+            for attachment in attachments {
+                let key = child_key(&attachment.todo_id, &attachment.id);
+                batch.insert(Table::Attachments, key, &attachment)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn history(&self, id: &str, offset: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        self.scan_history_ids(Table::HistoryByTodo, &parent_prefix(id))?
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|id| self.find_entry(id))
+            .collect()
+    }
+
+    async fn list_history_after(&self, after: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        let start = (after.max(0) as u64).to_be_bytes();
+        let mut entries = Vec::new();
+        for item in self
+            .tree(Table::History)
+            .range(start..)
+            .take(limit as usize + 1)
+        {
+            let entry: HistoryEntry = decode(&item?.1)?;
+            if entry.id > after {
+                entries.push(entry);
+            }
+        }
+        entries.truncate(limit as usize);
+
+        Ok(entries)
+    }
+
+    async fn insert_history(&self, entries: Vec<HistoryEntry>) -> Result<(), Error> {
+        self.write(|batch| {
+            for entry in entries {
+                let id = (entry.id as u64).to_be_bytes();
+                if let Some(current) = self.read::<HistoryEntry>(Table::History, &id)? {
+                    batch.remove(
+                        Table::HistoryByTodo,
+                        history_key(&current.todo_id, current.id as u64),
+                    );
+                    batch.remove(
+                        Table::HistoryByActor,
+                        history_key(&current.actor, current.id as u64),
+                    );
+                    batch.remove(
+                        Table::UndoneByActor,
+                        history_key(&current.actor, current.id as u64),
+                    );
+                }
+                self.put_entry(batch, &entry)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn revert(
+        &self,
+        actor: &str,
+        direction: Direction,
+        count: i64,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        self.write(|batch| {
+            let mut ids = self.scan_history_ids(Table::HistoryByActor, &parent_prefix(actor))?;
+            if direction == Direction::Undo {
+                ids.reverse();
+            }
+
+            let mut entries = Vec::new();
+            for id in ids {
+                if entries.len() as i64 >= count {
+                    break;
+                }
+                let mut entry = self.find_entry(id)?;
+                if !entry.operation.is_undoable() || entry.undo_state != direction.source_state() {
+                    continue;
+                }
+
+                let current = self.find_todo(batch, &entry.todo_id)?;
+                let target = entry.revert(current.as_ref(), direction)?;
+                self.put_todo(batch, &entry.todo_id, target.as_ref())?;
+
+                entry.undo_state = direction.target_state();
+                self.put_entry(batch, &entry)?;
+
+                let recorded = self.record(
+                    batch,
+                    actor,
+                    direction.operation(),
+                    current.as_ref(),
+                    target.as_ref(),
+                )?;
+                entries.push(recorded);
+            }
+
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn add_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        parent_id: Option<String>,
+        body: String,
+    ) -> Result<Comment, Error> {
+        self.write(|batch| {
+            self.find_todo(batch, todo_id)?.ok_or(Error::NotFound)?;
+            if let Some(parent_id) = parent_id.as_ref() {
+                self.find_comment(todo_id, parent_id)?;
+            }
+
+            let comment = Comment {
+                id: self.id_generator.new_id()?.encode(),
+                todo_id: todo_id.to_string(),
+                parent_id,
+                author: author.to_string(),
+                body,
+                created_at: Utc::now(),
+                edited_at: None,
+            };
+            batch.insert(Table::Comments, child_key(todo_id, &comment.id), &comment)?;
+            self.count_comments(batch, todo_id, 1, 0)?;
+
+            Ok(comment)
+        })
+        .await
+    }
+
+    async fn list_comments(&self, todo_id: &str) -> Result<Vec<Comment>, Error> {
+        self.get(todo_id).await?;
+        let mut comments: Vec<Comment> = self.scan(Table::Comments, &parent_prefix(todo_id))?;
+        comments.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+
+        Ok(comments)
+    }
+
+    async fn edit_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        id: &str,
+        body: String,
+    ) -> Result<Comment, Error> {
+        self.write(|batch| {
+            let mut comment = self.find_comment(todo_id, id)?;
+            if comment.author != author {
+                return Err(Error::PermissionDenied);
+            }
+
+            comment.body = body;
+            comment.edited_at = Some(Utc::now());
+            batch.insert(Table::Comments, child_key(todo_id, id), &comment)?;
+
+            Ok(comment)
+        })
+        .await
+    }
+
+    async fn delete_comment(&self, author: &str, todo_id: &str, id: &str) -> Result<(), Error> {
+        self.write(|batch| {
+            self.read::<Todo>(Table::Todos, todo_id.as_bytes())?
+                .ok_or(Error::NotFound)?;
+            if self.find_comment(todo_id, id)?.author != author {
+                return Err(Error::PermissionDenied);
+            }
+
+            let comments: Vec<Comment> = self.scan(Table::Comments, &parent_prefix(todo_id))?;
+            let mut removed = vec![id.to_string()];
+            let mut i = 0;
+            while i < removed.len() {
+                let replies = comments
+                    .iter()
+                    .filter(|c| c.parent_id.as_ref() == Some(&removed[i]))
+                    .map(|c| c.id.clone());
+                removed.extend(replies.collect::<Vec<_>>());
+                i += 1;
+            }
+
+            for id in removed.iter() {
+                batch.remove(Table::Comments, child_key(todo_id, id));
+            }
+            self.count_comments(batch, todo_id, 0, removed.len())?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_attachment(&self, attachment: Attachment) -> Result<Attachment, Error> {
+        self.write(|batch| {
+            self.find_todo(batch, &attachment.todo_id)?
+                .ok_or(Error::NotFound)?;
+            batch.insert(
+                Table::Attachments,
+                child_key(&attachment.todo_id, &attachment.id),
+                &attachment,
+            )?;
+
+            Ok(attachment)
+        })
+        .await
+    }
+
+    async fn list_attachments(&self, todo_id: &str) -> Result<Vec<Attachment>, Error> {
+        self.get(todo_id).await?;
+        let mut attachments: Vec<Attachment> =
+            self.scan(Table::Attachments, &parent_prefix(todo_id))?;
+        attachments.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+
+        Ok(attachments)
+    }
+
+    async fn get_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        self.find_attachment(todo_id, id)
+    }
+
+    async fn delete_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        self.write(|batch| {
+            let attachment = self.find_attachment(todo_id, id)?;
+            batch.remove(Table::Attachments, child_key(todo_id, id));
+
+            Ok(attachment)
+        })
+        .await
+    }
+}
+
+fn decode<V: DeserializeOwned>(value: &[u8]) -> Result<V, Error> {
+    serde_json::from_slice(value).map_err(|e| std::io::Error::from(e).into())
+}
+
+/// Encodes a timestamp so that byte order matches time order: the seconds
+/// with their sign bit flipped, then the nanoseconds. Covers every time
+/// chrono can represent, unlike nanoseconds since the epoch.
+fn time_key(time: DateTime<Utc>) -> [u8; 12] {
+    let mut key = [0; 12];
+    key[..8].copy_from_slice(&((time.timestamp() as u64) ^ (1 << 63)).to_be_bytes());
+    key[8..].copy_from_slice(&time.timestamp_subsec_nanos().to_be_bytes());
+    key
+}
+
+fn created_key(todo: &Todo) -> Vec<u8> {
+    let mut key = time_key(todo.created_at).to_vec();
+    key.extend_from_slice(todo.id.as_bytes());
+    key
+}
+
+/// Returns the prefix shared by the keys of everything belonging to
+/// `parent`. The separator keeps one id from matching another it prefixes.
+fn parent_prefix(parent: &str) -> Vec<u8> {
+    let mut key = parent.as_bytes().to_vec();
+    key.push(0);
+    key
+}
+
+fn child_key(parent: &str, id: &str) -> Vec<u8> {
+    let mut key = parent_prefix(parent);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+fn history_key(parent: &str, id: u64) -> Vec<u8> {
+    let mut key = parent_prefix(parent);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn index(repo: &SledRepository, table: Table) -> Vec<(Vec<u8>, Vec<u8>)> {
+        repo.tree(table)
+            .iter()
+            .map(|item| item.map(|(key, value)| (key.to_vec(), value.to_vec())))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn keeps_indexes_in_sync() {
+        let dir = TempDir::new("todo-sled");
+        let repo = SledRepository::new(dir.path()).unwrap();
+        let open = repo
+            .create("alice", "open".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        let done = repo
+            .create("alice", "done".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        let done = repo.complete("alice", &done.id).await.unwrap();
+        let deleted = repo
+            .create("alice", "deleted".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        repo.delete("alice", &deleted.id).await.unwrap();
+        assert_eq!(
+            index(&repo, Table::TodosByCreatedAt),
+            vec![
+                (created_key(&open), open.id.as_bytes().to_vec()),
+                (created_key(&done), done.id.as_bytes().to_vec()),
+            ]
+        );
+
+        // Undone entries are indexed until a new change discards them.
+        repo.revert("alice", Direction::Undo, 2).await.unwrap();
+        assert_eq!(index(&repo, Table::UndoneByActor).len(), 2);
+        let undone = index(&repo, Table::UndoneByActor);
+        repo.revert("bob", Direction::Undo, 1).await.unwrap();
+        assert_eq!(index(&repo, Table::UndoneByActor), undone);
+        repo.create("alice", "new".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        assert!(index(&repo, Table::UndoneByActor).is_empty());
+        repo.revert("alice", Direction::Undo, 1).await.unwrap();
+
+        let indexes: Vec<_> = INDEXES.iter().map(|t| index(&repo, *t)).collect();
+        repo.rebuild_indexes().unwrap();
+        let rebuilt: Vec<_> = INDEXES.iter().map(|t| index(&repo, *t)).collect();
+        assert_eq!(rebuilt, indexes);
+    }
+}
@@ -0,0 +1,876 @@
+use crate::repository::error::Error;
+use crate::repository::migrations::{self, MigrationStatus, Migrations};
+use crate::repository::model::{
+    diff, Attachment, Comment, Direction, HistoryEntry, HistoryRow, Operation, Todo, Todos,
+    UndoState,
+};
+use crate::repository::repository::Repository;
+use chrono::Utc;
+use sqlx::error::Error as SQLxError;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::types::Json;
+use sqlx::{Done, Sqlite, SqlitePool, Transaction};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+
+pub struct SqliteRepository {
+    pool: SqlitePool,
+    id_generator: libxid::Generator,
+}
+
+impl SqliteRepository {
+    pub async fn new(path: &str) -> Result<SqliteRepository, SQLxError> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        // SQLite allows a single writer anyway. Running every transaction on
+        // the same connection serializes them instead of failing with
+        // SQLITE_BUSY, and keeps an in-memory database shared.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        Ok(SqliteRepository {
+            pool,
+            id_generator: libxid::new_generator(),
+        })
+    }
+}
+
+#[async_trait]
+impl Migrations for SqliteRepository {
+    async fn migrate_up(&self) -> Result<Vec<i64>, SQLxError> {
+        let mut conn = self.pool.acquire().await?;
+        migrations::up(&migrations::SQLITE, &mut *conn).await
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, SQLxError> {
+        let mut conn = self.pool.acquire().await?;
+        migrations::status(&migrations::SQLITE, &mut *conn).await
+    }
+
+    async fn migrate_down(&self, version: i64) -> Result<Vec<i64>, SQLxError> {
+        let mut conn = self.pool.acquire().await?;
+        migrations::down(&migrations::SQLITE, &mut *conn, version).await
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    async fn list(&self) -> Result<Todos, Error> {
+        let query = r#"
+SELECT
+    id, title, body, is_completed, created_at, updated_at, comment_count
+FROM
+    todos
+    "#;
+        let todos = sqlx::query_as::<_, Todo>(query)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(todos)
+    }
+
+    async fn get(&self, id: &str) -> Result<Todo, Error> {
+        let query = r#"
+SELECT
+    id, title, body, is_completed, created_at, updated_at, comment_count
+FROM
+    todos
+WHERE
+    id = ?1
+    "#;
+        let todo = sqlx::query_as::<_, Todo>(query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        todo.ok_or(Error::NotFound)
+    }
+
+    async fn create(&self, actor: &str, title: String, body: String) -> Result<Todo, Error> {
+        let query = r#"
+INSERT INTO
+    todos (id, title, body, is_completed, created_at, updated_at)
+VALUES
+    (?1, ?2, ?3, FALSE, ?4, ?4)
+    "#;
+        let id = self.id_generator.new_id()?.encode();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(query)
+            .bind(&id)
+            .bind(title)
+            .bind(body)
+            .bind(Utc::now())
+            .execute(&mut tx)
+            .await?;
+        let todo = find_todo(&mut tx, &id).await?.ok_or(Error::NotFound)?;
+        record(&mut tx, actor, Operation::Create, None, Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn update(
+        &self,
+        actor: &str,
+        id: &str,
+        title: Option<String>,
+        body: Option<String>,
+        is_completed: Option<bool>,
+    ) -> Result<Todo, Error> {
+        let query = r#"
+UPDATE
+    todos
+SET
+    title = ?2, body = ?3, is_completed = ?4, updated_at = ?5
+WHERE
+    id = ?1
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let before = find_todo(&mut tx, id).await?.ok_or(Error::NotFound)?;
+        sqlx::query(query)
+            .bind(id)
+            .bind(title.unwrap_or_else(|| before.title.clone()))
+            .bind(body.unwrap_or_else(|| before.body.clone()))
+            .bind(is_completed.unwrap_or(before.is_completed))
+            .bind(Utc::now())
+            .execute(&mut tx)
+            .await?;
+        let todo = find_todo(&mut tx, id).await?.ok_or(Error::NotFound)?;
+        record(
+            &mut tx,
+            actor,
+            Operation::Update,
+            Some(&before),
+            Some(&todo),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn delete(&self, actor: &str, id: &str) -> Result<(), Error> {
+        let query = r#"
+DELETE FROM
+    todos
+WHERE
+    id = ?1
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let todo = find_todo(&mut tx, id).await?.ok_or(Error::NotFound)?;
+        sqlx::query(query).bind(id).execute(&mut tx).await?;
+        record(&mut tx, actor, Operation::Delete, Some(&todo), None).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn complete(&self, actor: &str, id: &str) -> Result<Todo, Error> {
+        let query = r#"
+UPDATE
+    todos
+SET
+    is_completed = TRUE, updated_at = ?2
+WHERE
+    id = ?1
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let before = find_todo(&mut tx, id).await?.ok_or(Error::NotFound)?;
+        if before.is_completed {
+            return Err(Error::AlreadyCompleted);
+        }
+        sqlx::query(query)
+            .bind(id)
+            .bind(Utc::now())
+            .execute(&mut tx)
+            .await?;
+        let todo = find_todo(&mut tx, id).await?.ok_or(Error::NotFound)?;
+        record(
+            &mut tx,
+            actor,
+            Operation::Complete,
+            Some(&before),
+            Some(&todo),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn import(&self, actor: &str, todos: Todos, dry_run: bool) -> Result<Todos, Error> {
+        let query = r#"
+INSERT INTO
+    todos (id, title, body, is_completed, created_at, updated_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (id) DO NOTHING
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let mut imported = Vec::with_capacity(todos.len());
+        for todo in todos {
+            let id = if todo.id.is_empty() {
+                self.id_generator.new_id()?.encode()
+            } else {
+                todo.id
+            };
+            let inserted = sqlx::query(query)
+                .bind(&id)
+                .bind(todo.title)
+                .bind(todo.body)
+                .bind(todo.is_completed)
+                .bind(todo.created_at)
+                .bind(todo.updated_at)
+                .execute(&mut tx)
+                .await?;
+            if inserted.rows_affected() == 0 {
+                return Err(Error::AlreadyExists(id));
+            }
+            let todo = find_todo(&mut tx, &id).await?.ok_or(Error::NotFound)?;
+            record(&mut tx, actor, Operation::Create, None, Some(&todo)).await?;
+            imported.push(todo);
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+
+        Ok(imported)
+    }
+
+    async fn list_after(&self, after: &str, limit: i64) -> Result<Todos, Error> {
+        let query = r#"
+SELECT
+    id, title, body, is_completed, created_at, updated_at, comment_count
+FROM
+    todos
+WHERE
+    id > ?1
+ORDER BY
+    id
+LIMIT ?2
+    "#;
+        let todos = sqlx::query_as::<_, Todo>(query)
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(todos)
+    }
+
+    async fn insert_todos(&self, todos: Todos) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    todos (id, title, body, is_completed, created_at, updated_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (id) DO UPDATE SET
+    title = excluded.title,
+    body = excluded.body,
+    is_completed = excluded.is_completed,
+    created_at = excluded.created_at,
+    updated_at = excluded.updated_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        for todo in todos {
+            sqlx::query(query)
+                .bind(todo.id)
+                .bind(todo.title)
+                .bind(todo.body)
+                .bind(todo.is_completed)
+                .bind(todo.created_at)
+                .bind(todo.updated_at)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn insert_comments(&self, comments: Vec<Comment>) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    comments (id, todo_id, parent_id, author, body, created_at, edited_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+ON CONFLICT (id) DO UPDATE SET
+    todo_id = excluded.todo_id,
+    parent_id = excluded.parent_id,
+    author = excluded.author,
+    body = excluded.body,
+    created_at = excluded.created_at,
+    edited_at = excluded.edited_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let mut todo_ids = BTreeSet::new();
+        for comment in comments {
+            sqlx::query(query)
+                .bind(comment.id)
+                .bind(&comment.todo_id)
+                .bind(comment.parent_id)
+                .bind(comment.author)
+                .bind(comment.body)
+                .bind(comment.created_at)
+                .bind(comment.edited_at)
+                .execute(&mut tx)
+                .await?;
+            todo_ids.insert(comment.todo_id);
+        }
+        for todo_id in todo_ids {
+            count_comments(&mut tx, &todo_id).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn insert_attachments(&self, attachments: Vec<Attachment>) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    attachments (id, todo_id, filename, content_type, size, created_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (id) DO UPDATE SET
+    todo_id = excluded.todo_id,
+    filename = excluded.filename,
+    content_type = excluded.content_type,
+    size = excluded.size,
+    created_at = excluded.created_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        for attachment in attachments {
+            sqlx::query(query)
+                .bind(attachment.id)
+                .bind(attachment.todo_id)
+                .bind(attachment.filename)
+                .bind(attachment.content_type)
+                .bind(attachment.size)
+                .bind(attachment.created_at)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn history(&self, id: &str, offset: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, actor, operation, changes, undo_state, created_at
+FROM
+    todo_history
+WHERE
+    todo_id = ?1
+ORDER BY
+    id
+LIMIT ?3
+OFFSET ?2
+    "#;
+        let rows = sqlx::query_as::<_, HistoryRow>(query)
+            .bind(id)
+            .bind(offset)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(HistoryEntry::try_from).collect()
+    }
+
+    async fn list_history_after(&self, after: i64, limit: i64) -> Result<Vec<HistoryEntry>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, actor, operation, changes, undo_state, created_at
+FROM
+    todo_history
+WHERE
+    id > ?1
+ORDER BY
+    id
+LIMIT ?2
+    "#;
+        let rows = sqlx::query_as::<_, HistoryRow>(query)
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(HistoryEntry::try_from).collect()
+    }
+
+    async fn insert_history(&self, entries: Vec<HistoryEntry>) -> Result<(), Error> {
+        let query = r#"
+INSERT INTO
+    todo_history (id, todo_id, actor, operation, changes, undo_state, created_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+ON CONFLICT (id) DO UPDATE SET
+    todo_id = excluded.todo_id,
+    actor = excluded.actor,
+    operation = excluded.operation,
+    changes = excluded.changes,
+    undo_state = excluded.undo_state,
+    created_at = excluded.created_at
+    "#;
+        let mut tx = self.pool.begin().await?;
+        for entry in entries {
+            sqlx::query(query)
+                .bind(entry.id)
+                .bind(entry.todo_id)
+                .bind(entry.actor)
+                .bind(entry.operation.as_str())
+                .bind(Json(entry.changes))
+                .bind(entry.undo_state.as_str())
+                .bind(entry.created_at)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn revert(
+        &self,
+        actor: &str,
+        direction: Direction,
+        count: i64,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let query = format!(
+            r#"
+SELECT
+    id, todo_id, actor, operation, changes, undo_state, created_at
+FROM
+    todo_history
+WHERE
+    actor = ?1 AND undo_state = ?2 AND operation NOT IN (?3, ?4)
+ORDER BY
+    id {}
+LIMIT ?5
+    "#,
+            match direction {
+                Direction::Undo => "DESC",
+                Direction::Redo => "ASC",
+            }
+        );
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query_as::<_, HistoryRow>(&query)
+            .bind(actor)
+            .bind(direction.source_state().as_str())
+            .bind(Operation::Undo.as_str())
+            .bind(Operation::Redo.as_str())
+            .bind(count)
+            .fetch_all(&mut tx)
+            .await?;
+
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in rows {
+            let entry = HistoryEntry::try_from(row)?;
+            let current = find_todo(&mut tx, &entry.todo_id).await?;
+            let target = entry.revert(current.as_ref(), direction)?;
+            put_todo(&mut tx, &entry.todo_id, current.is_some(), target.as_ref()).await?;
+
+            let query = r#"
+UPDATE
+    todo_history
+SET
+    undo_state = ?2
+WHERE
+    id = ?1
+    "#;
+            sqlx::query(query)
+                .bind(entry.id)
+                .bind(direction.target_state().as_str())
+                .execute(&mut tx)
+                .await?;
+
+            let recorded = record(
+                &mut tx,
+                actor,
+                direction.operation(),
+                current.as_ref(),
+                target.as_ref(),
+            )
+            .await?;
+            entries.push(recorded);
+        }
+        tx.commit().await?;
+
+        Ok(entries)
+    }
+
+    async fn add_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        parent_id: Option<String>,
+        body: String,
+    ) -> Result<Comment, Error> {
+        let query = r#"
+INSERT INTO
+    comments (id, todo_id, parent_id, author, body, created_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6)
+    "#;
+        let id = self.id_generator.new_id()?.encode();
+        let mut tx = self.pool.begin().await?;
+        find_todo(&mut tx, todo_id).await?.ok_or(Error::NotFound)?;
+        if let Some(parent_id) = parent_id.as_ref() {
+            find_comment(&mut tx, todo_id, parent_id).await?;
+        }
+        sqlx::query(query)
+            .bind(&id)
+            .bind(todo_id)
+            .bind(parent_id)
+            .bind(author)
+            .bind(body)
+            .bind(Utc::now())
+            .execute(&mut tx)
+            .await?;
+        let comment = find_comment(&mut tx, todo_id, &id).await?;
+        count_comments(&mut tx, todo_id).await?;
+        tx.commit().await?;
+
+        Ok(comment)
+    }
+
+    async fn list_comments(&self, todo_id: &str) -> Result<Vec<Comment>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, parent_id, author, body, created_at, edited_at
+FROM
+    comments
+WHERE
+    todo_id = ?1
+ORDER BY
+    created_at, id
+    "#;
+        self.get(todo_id).await?;
+        let comments = sqlx::query_as::<_, Comment>(query)
+            .bind(todo_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(comments)
+    }
+
+    async fn edit_comment(
+        &self,
+        author: &str,
+        todo_id: &str,
+        id: &str,
+        body: String,
+    ) -> Result<Comment, Error> {
+        let query = r#"
+UPDATE
+    comments
+SET
+    body = ?2, edited_at = ?3
+WHERE
+    id = ?1
+    "#;
+        let mut tx = self.pool.begin().await?;
+        if find_comment(&mut tx, todo_id, id).await?.author != author {
+            return Err(Error::PermissionDenied);
+        }
+        sqlx::query(query)
+            .bind(id)
+            .bind(body)
+            .bind(Utc::now())
+            .execute(&mut tx)
+            .await?;
+        let comment = find_comment(&mut tx, todo_id, id).await?;
+        tx.commit().await?;
+
+        Ok(comment)
+    }
+
+    async fn delete_comment(&self, author: &str, todo_id: &str, id: &str) -> Result<(), Error> {
+        let query = r#"
+DELETE FROM
+    comments
+WHERE
+    id = ?1
+    "#;
+        let mut tx = self.pool.begin().await?;
+        find_todo(&mut tx, todo_id).await?.ok_or(Error::NotFound)?;
+        if find_comment(&mut tx, todo_id, id).await?.author != author {
+            return Err(Error::PermissionDenied);
+        }
+        sqlx::query(query).bind(id).execute(&mut tx).await?;
+        count_comments(&mut tx, todo_id).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn add_attachment(&self, attachment: Attachment) -> Result<Attachment, Error> {
+        let query = r#"
+INSERT INTO
+    attachments (id, todo_id, filename, content_type, size, created_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6)
+    "#;
+        let mut tx = self.pool.begin().await?;
+        find_todo(&mut tx, &attachment.todo_id)
+            .await?
+            .ok_or(Error::NotFound)?;
+        sqlx::query(query)
+            .bind(&attachment.id)
+            .bind(&attachment.todo_id)
+            .bind(&attachment.filename)
+            .bind(&attachment.content_type)
+            .bind(attachment.size)
+            .bind(attachment.created_at)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(attachment)
+    }
+
+    async fn list_attachments(&self, todo_id: &str) -> Result<Vec<Attachment>, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, filename, content_type, size, created_at
+FROM
+    attachments
+WHERE
+    todo_id = ?1
+ORDER BY
+    created_at, id
+    "#;
+        self.get(todo_id).await?;
+        let attachments = sqlx::query_as::<_, Attachment>(query)
+            .bind(todo_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(attachments)
+    }
+
+    async fn get_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        let query = r#"
+SELECT
+    id, todo_id, filename, content_type, size, created_at
+FROM
+    attachments
+WHERE
+    id = ?1 AND todo_id = ?2
+    "#;
+        let attachment = sqlx::query_as::<_, Attachment>(query)
+            .bind(id)
+            .bind(todo_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        attachment.ok_or(Error::AttachmentNotFound)
+    }
+
+    async fn delete_attachment(&self, todo_id: &str, id: &str) -> Result<Attachment, Error> {
+        let query = r#"
+DELETE FROM
+    attachments
+WHERE
+    id = ?1
+    "#;
+        let mut tx = self.pool.begin().await?;
+        let attachment = find_attachment(&mut tx, todo_id, id).await?;
+        sqlx::query(query).bind(id).execute(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(attachment)
+    }
+}
+
+async fn find_todo(tx: &mut Transaction<'_, Sqlite>, id: &str) -> Result<Option<Todo>, Error> {
+    let query = r#"
+SELECT
+    id, title, body, is_completed, created_at, updated_at, comment_count
+FROM
+    todos
+WHERE
+    id = ?1
+    "#;
+    let todo = sqlx::query_as::<_, Todo>(query)
+        .bind(id)
+        .fetch_optional(tx)
+        .await?;
+
+    Ok(todo)
+}
+
+/// Writes the full state of a todo, deleting it when `todo` is `None`.
+async fn put_todo(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: &str,
+    exists: bool,
+    todo: Option<&Todo>,
+) -> Result<(), Error> {
+    let todo = match todo {
+        Some(todo) => todo,
+        None => {
+            let query = r#"
+DELETE FROM
+    todos
+WHERE
+    id = ?1
+    "#;
+            sqlx::query(query).bind(id).execute(tx).await?;
+            return Ok(());
+        }
+    };
+
+    let query = if exists {
+        r#"
+UPDATE
+    todos
+SET
+    title = ?2, body = ?3, is_completed = ?4, created_at = ?5, updated_at = ?6
+WHERE
+    id = ?1
+    "#
+    } else {
+        r#"
+INSERT INTO
+    todos (id, title, body, is_completed, created_at, updated_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6)
+    "#
+    };
+    sqlx::query(query)
+        .bind(id)
+        .bind(&todo.title)
+        .bind(&todo.body)
+        .bind(todo.is_completed)
+        .bind(todo.created_at)
+        .bind(todo.updated_at)
+        .execute(tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn record(
+    tx: &mut Transaction<'_, Sqlite>,
+    actor: &str,
+    operation: Operation,
+    before: Option<&Todo>,
+    after: Option<&Todo>,
+) -> Result<HistoryEntry, Error> {
+    if operation.is_undoable() {
+        let query = r#"
+UPDATE
+    todo_history
+SET
+    undo_state = ?3
+WHERE
+    actor = ?1 AND undo_state = ?2
+    "#;
+        sqlx::query(query)
+            .bind(actor)
+            .bind(UndoState::Undone.as_str())
+            .bind(UndoState::Discarded.as_str())
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let query = r#"
+INSERT INTO
+    todo_history (todo_id, actor, operation, changes, undo_state, created_at)
+VALUES
+    (?1, ?2, ?3, ?4, ?5, ?6)
+    "#;
+    let todo_id = before.or(after).map(|t| t.id.as_str()).unwrap_or_default();
+    let id = sqlx::query(query)
+        .bind(todo_id)
+        .bind(actor)
+        .bind(operation.as_str())
+        .bind(Json(diff(before, after)))
+        .bind(UndoState::Active.as_str())
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+
+    let query = r#"
+SELECT
+    id, todo_id, actor, operation, changes, undo_state, created_at
+FROM
+    todo_history
+WHERE
+    id = ?1
+    "#;
+    let row = sqlx::query_as::<_, HistoryRow>(query)
+        .bind(id)
+        .fetch_one(tx)
+        .await?;
+
+    HistoryEntry::try_from(row)
+}
+
+async fn find_comment(
+    tx: &mut Transaction<'_, Sqlite>,
+    todo_id: &str,
+    id: &str,
+) -> Result<Comment, Error> {
+    let query = r#"
+SELECT
+    id, todo_id, parent_id, author, body, created_at, edited_at
+FROM
+    comments
+WHERE
+    id = ?1 AND todo_id = ?2
+    "#;
+    let comment = sqlx::query_as::<_, Comment>(query)
+        .bind(id)
+        .bind(todo_id)
+        .fetch_optional(tx)
+        .await?;
+
+    comment.ok_or(Error::CommentNotFound)
+}
+
+async fn find_attachment(
+    tx: &mut Transaction<'_, Sqlite>,
+    todo_id: &str,
+    id: &str,
+) -> Result<Attachment, Error> {
+    let query = r#"
+SELECT
+    id, todo_id, filename, content_type, size, created_at
+FROM
+    attachments
+WHERE
+    id = ?1 AND todo_id = ?2
+    "#;
+    let attachment = sqlx::query_as::<_, Attachment>(query)
+        .bind(id)
+        .bind(todo_id)
+        .fetch_optional(tx)
+        .await?;
+
+    attachment.ok_or(Error::AttachmentNotFound)
+}
+
+/// Refreshes the denormalized comment count of a todo. Counting instead of
+/// incrementing keeps it right when deleting a comment cascades to replies.
+async fn count_comments(tx: &mut Transaction<'_, Sqlite>, todo_id: &str) -> Result<(), Error> {
+    let query = r#"
+UPDATE
+    todos
+SET
+    comment_count = (SELECT COUNT(*) FROM comments WHERE todo_id = ?1)
+WHERE
+    id = ?1
+    "#;
+    sqlx::query(query).bind(todo_id).execute(tx).await?;
+
+    Ok(())
+}
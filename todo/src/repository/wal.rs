@@ -0,0 +1,349 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const LOG_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// Syncs after every appended record.
+    Always,
+    /// Syncs in the background every `fsync_interval_ms`.
+    Interval,
+    /// Leaves flushing to the operating system.
+    Never,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record<T> {
+    seq: u64,
+    changes: T,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<S> {
+    seq: u64,
+    state: S,
+}
+
+/// An append-only log of change batches next to a snapshot of the state they
+/// apply to.
+///
+/// Each record is one line holding the CRC32 of its JSON payload followed by
+/// the payload, so torn or damaged writes at the end of the file are detected
+/// on replay.
+pub struct Wal {
+    dir: PathBuf,
+    file: Arc<File>,
+    fsync: FsyncPolicy,
+    seq: u64,
+    snapshot_every: u64,
+    since_snapshot: u64,
+}
+
+impl Wal {
+    /// Opens the log in `dir` and returns it together with the last snapshot
+    /// and the change batches recorded after it, in order.
+    pub fn open<S, T>(
+        dir: &Path,
+        fsync: FsyncPolicy,
+        snapshot_every: u64,
+        logger: &slog::Logger,
+    ) -> io::Result<(Wal, S, Vec<T>)>
+    where
+        S: DeserializeOwned + Default,
+        T: DeserializeOwned,
+    {
+        fs::create_dir_all(dir)?;
+
+        let snapshot = match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot {
+                seq: 0,
+                state: S::default(),
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut seq = snapshot.seq;
+        let mut batches = Vec::new();
+        let mut offset = 0;
+        while offset < content.len() {
+            let record = content[offset..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .and_then(|end| {
+                    let record = decode::<T>(&content[offset..offset + end])?;
+                    Some((record, end + 1))
+                });
+            match record {
+                Some((record, len)) => {
+                    if record.seq > seq {
+                        seq = record.seq;
+                        batches.push(record.changes);
+                    }
+                    offset += len;
+                }
+                None => {
+                    // Only the tail can be torn by a crash. A damaged record
+                    // that valid ones follow is refused rather than dropped
+                    // with them.
+                    if content[offset..]
+                        .split(|b| *b == b'\n')
+                        .skip(1)
+                        .any(|line| decode::<T>(line).is_some())
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "write-ahead log record at offset {} is corrupted, but valid records follow it",
+                                offset
+                            ),
+                        ));
+                    }
+                    warn!(logger, "truncating corrupted write-ahead log tail";
+                        "offset" => offset, "dropped_bytes" => content.len() - offset);
+                    file.set_len(offset as u64)?;
+                    file.sync_all()?;
+                    break;
+                }
+            }
+        }
+
+        let wal = Wal {
+            dir: dir.to_path_buf(),
+            file: Arc::new(file),
+            fsync,
+            seq,
+            snapshot_every,
+            since_snapshot: batches.len() as u64,
+        };
+
+        Ok((wal, snapshot.state, batches))
+    }
+
+    pub fn append<T: Serialize>(&mut self, changes: &T) -> io::Result<()> {
+        let record = Record {
+            seq: self.seq + 1,
+            changes,
+        };
+        let payload = serde_json::to_vec(&record)?;
+        let mut line = format!("{:08x} ", checksum(&payload)).into_bytes();
+        line.extend_from_slice(&payload);
+        line.push(b'\n');
+
+        // The whole record goes out in a single write so that a crash can
+        // only leave a partial record at the very end of the file.
+        let len = self.file.metadata()?.len();
+        let result = (&*self.file).write_all(&line).and_then(|_| {
+            if self.fsync == FsyncPolicy::Always {
+                self.file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            // A failed change must not be replayed, and a partial record
+            // would hide every record appended after it.
+            if self.file.set_len(len).is_err() {
+                // The record may stay, so its seq is not given to the next
+                // one, which replay would skip.
+                self.seq += 1;
+            }
+            return Err(e);
+        }
+        self.seq += 1;
+        self.since_snapshot += 1;
+
+        Ok(())
+    }
+
+    pub fn should_snapshot(&self) -> bool {
+        self.snapshot_every > 0 && self.since_snapshot >= self.snapshot_every
+    }
+
+    /// Encodes `state` as a snapshot of every record appended so far, to be
+    /// written by `snapshot` before anything else is appended.
+    pub fn encode_snapshot<S: Serialize>(&self, state: &S) -> io::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&Snapshot {
+            seq: self.seq,
+            state,
+        })?)
+    }
+
+    /// Writes an encoded snapshot and empties the log. The snapshot replaces
+    /// the previous one atomically, and records it already covers are
+    /// skipped on replay, so a crash at any point loses nothing.
+    pub fn snapshot(&mut self, encoded: &[u8]) -> io::Result<()> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp = path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writer.write_all(encoded)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.since_snapshot = 0;
+
+        Ok(())
+    }
+
+    /// Returns the log file, for syncing it in the background.
+    pub fn file(&self) -> Arc<File> {
+        self.file.clone()
+    }
+}
+
+fn decode<T: DeserializeOwned>(line: &[u8]) -> Option<Record<T>> {
+    if line.len() < 9 || line[8] != b' ' {
+        return None;
+    }
+    let expected = u32::from_str_radix(std::str::from_utf8(&line[..8]).ok()?, 16).ok()?;
+    let payload = &line[9..];
+    if checksum(payload) != expected {
+        return None;
+    }
+    serde_json::from_slice(payload).ok()
+}
+
+fn checksum(payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{logger, TempDir};
+
+    type Batch = Vec<String>;
+
+    fn log(dir: &TempDir) -> PathBuf {
+        dir.join(LOG_FILE)
+    }
+
+    fn open(dir: &TempDir) -> (Wal, Vec<Batch>) {
+        let (wal, (), batches) =
+            Wal::open::<(), Batch>(Path::new(dir.path()), FsyncPolicy::Never, 0, &logger())
+                .unwrap();
+        (wal, batches)
+    }
+
+    fn batch(i: usize) -> Batch {
+        vec![format!("change {}", i)]
+    }
+
+    fn write(dir: &TempDir, count: usize) {
+        let (mut wal, _) = open(dir);
+        for i in 0..count {
+            wal.append(&batch(i)).unwrap();
+        }
+    }
+
+    fn record_offsets(dir: &TempDir) -> Vec<usize> {
+        let content = fs::read(log(dir)).unwrap();
+        let mut offsets = vec![0];
+        offsets.extend(
+            content
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| **b == b'\n')
+                .map(|(i, _)| i + 1),
+        );
+        offsets
+    }
+
+    #[test]
+    fn replays_every_record() {
+        let dir = TempDir::new("wal-replays");
+        write(&dir, 3);
+
+        let (_, batches) = open(&dir);
+        assert_eq!(batches, (0..3).map(batch).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drops_a_torn_tail() {
+        let dir = TempDir::new("wal-torn");
+        write(&dir, 3);
+        let offsets = record_offsets(&dir);
+        let torn = offsets[2] + (offsets[3] - offsets[2]) / 2;
+        OpenOptions::new()
+            .write(true)
+            .open(log(&dir))
+            .unwrap()
+            .set_len(torn as u64)
+            .unwrap();
+
+        let (mut wal, batches) = open(&dir);
+        assert_eq!(batches, (0..2).map(batch).collect::<Vec<_>>());
+        assert_eq!(fs::metadata(log(&dir)).unwrap().len(), offsets[2] as u64);
+
+        // Records appended after the truncation are replayed.
+        wal.append(&batch(3)).unwrap();
+        let (_, batches) = open(&dir);
+        assert_eq!(batches, vec![batch(0), batch(1), batch(3)]);
+    }
+
+    /// A damaged record that valid ones follow is not a torn tail.
+    #[test]
+    fn refuses_a_damaged_record_before_valid_ones() {
+        let dir = TempDir::new("wal-damaged");
+        write(&dir, 3);
+        let offsets = record_offsets(&dir);
+        let mut content = fs::read(log(&dir)).unwrap();
+        // A byte of the payload of the second record.
+        content[offsets[1] + 12] ^= 1;
+        fs::write(log(&dir), &content).unwrap();
+
+        let err = Wal::open::<(), Batch>(Path::new(dir.path()), FsyncPolicy::Never, 0, &logger())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("offset {}", offsets[1])));
+        assert_eq!(fs::read(log(&dir)).unwrap(), content);
+    }
+
+    #[test]
+    fn drops_a_damaged_last_record() {
+        let dir = TempDir::new("wal-damaged-last");
+        write(&dir, 3);
+        let offsets = record_offsets(&dir);
+        let mut content = fs::read(log(&dir)).unwrap();
+        content[offsets[2] + 12] ^= 1;
+        fs::write(log(&dir), &content).unwrap();
+
+        let (_, batches) = open(&dir);
+        assert_eq!(batches, (0..2).map(batch).collect::<Vec<_>>());
+        assert_eq!(fs::metadata(log(&dir)).unwrap().len(), offsets[2] as u64);
+    }
+
+    #[test]
+    fn skips_records_in_the_snapshot() {
+        let dir = TempDir::new("wal-snapshot");
+        let (mut wal, _) = open(&dir);
+        wal.append(&batch(0)).unwrap();
+        let encoded = wal.encode_snapshot(&()).unwrap();
+        wal.snapshot(&encoded).unwrap();
+        wal.append(&batch(1)).unwrap();
+
+        let (_, batches) = open(&dir);
+        assert_eq!(batches, vec![batch(1)]);
+    }
+}
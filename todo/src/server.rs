@@ -1,23 +1,108 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::StreamExt;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tokio::sync::{broadcast, mpsc};
+use tonic::Code;
+
 use todo_service as pb;
 use todo_service::todo_service_server::TodoService;
+use todo_service::upload_attachment_request::Payload;
 
+use crate::blob::store::BlobStore;
+use crate::details;
+use crate::details::rpc::bad_request::FieldViolation;
+use crate::outbox::feed::Feed;
+use crate::repository::idempotency::{Applying, Claim, IdempotencyStore, APPLYING};
+use crate::repository::model::{from_timestamp, Attachment, Direction, Event, Todo};
 use crate::repository::repository::Repository;
+use crate::todotxt;
+use crate::validation::Violations;
 
 pub mod todo_service {
     tonic::include_proto!("todo");
 }
 
+const ACTOR_METADATA_KEY: &str = "x-actor";
+const ANONYMOUS_ACTOR: &str = "anonymous";
+const MAX_HISTORY_LIMIT: i64 = 100;
+const MAX_UNDO_COUNT: i64 = 100;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+const WATCH_BATCH_SIZE: i64 = 100;
+/// The fields of a todo an update can change.
+const UPDATE_FIELDS: &[&str] = &["title", "body", "is_completed"];
+/// How long a request holds its idempotency key before a retry may run it
+/// again, should it never finish.
+const IDEMPOTENCY_CLAIM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often keeping the response to a request is tried, waiting twice as
+/// long after each failure.
+const IDEMPOTENCY_COMPLETE_ATTEMPTS: u32 = 3;
+const IDEMPOTENCY_COMPLETE_BACKOFF: Duration = Duration::from_millis(100);
+
+pub struct AttachmentLimits {
+    max_size: u64,
+    content_types: Vec<String>,
+}
+
+impl AttachmentLimits {
+    pub(crate) fn new(max_size: u64, content_types: &str) -> AttachmentLimits {
+        AttachmentLimits {
+            max_size,
+            content_types: content_types
+                .split(',')
+                .map(|t| t.trim().to_ascii_lowercase())
+                .filter(|t| !t.is_empty())
+                .collect(),
+        }
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        let content_type = content_type.to_ascii_lowercase();
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types.iter().any(|allowed| {
+            allowed == "*"
+                || allowed == essence
+                || allowed.strip_suffix("/*") == essence.split('/').next()
+        })
+    }
+}
+
 pub struct TodoServiceImpl {
     logger: slog::Logger,
     repo: Box<dyn Repository + Send + Sync>,
+    blobs: Box<dyn BlobStore + Send + Sync>,
+    limits: AttachmentLimits,
+    feed: Option<Feed>,
+    idempotency: Arc<dyn IdempotencyStore + Send + Sync>,
+    idempotency_ttl: Duration,
+    id_generator: libxid::Generator,
 }
 
 impl TodoServiceImpl {
     pub(crate) fn new(
         logger: slog::Logger,
         repo: Box<dyn Repository + Send + Sync>,
+        blobs: Box<dyn BlobStore + Send + Sync>,
+        limits: AttachmentLimits,
+        feed: Option<Feed>,
+        idempotency: Arc<dyn IdempotencyStore + Send + Sync>,
+        idempotency_ttl: Duration,
     ) -> TodoServiceImpl {
-        TodoServiceImpl { logger, repo }
+        TodoServiceImpl {
+            logger,
+            repo,
+            blobs,
+            limits,
+            feed,
+            idempotency,
+            idempotency_ttl,
+            id_generator: libxid::new_generator(),
+        }
     }
 }
 
@@ -49,20 +134,31 @@ impl TodoService for TodoServiceImpl {
     ) -> Result<tonic::Response<pb::Todo>, tonic::Status> {
         debug!(self.logger, "create");
 
-        let title = request.get_ref().title.clone();
-        let body = request.get_ref().body.clone();
+        let actor = actor(&request);
+        let request = request.into_inner();
+        self.validate(
+            "create",
+            Violations::new()
+                .text("title", &request.title)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &actor),
+        )?;
+        let key = IdempotencyKey::of("create", &actor, &request.request_id, &request);
 
-        let result = self.repo.create(title, body).await;
-        match result {
-            Ok(todo) => {
-                debug!(self.logger, "create result"; "result" => ?todo);
-                Ok(tonic::Response::new(todo.into()))
+        self.idempotent("create", key, async {
+            let result = self.repo.create(&actor, request.title, request.body).await;
+            match result {
+                Ok(todo) => {
+                    debug!(self.logger, "create result"; "result" => ?todo);
+                    Ok(tonic::Response::new(todo.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "create"; "err" => ?e);
+                    Err(e.into())
+                }
             }
-            Err(e) => {
-                error!(self.logger, "create"; "err" => ?e);
-                Err(e.into())
-            }
-        }
+        })
+        .await
     }
 
     async fn get_by_id(
@@ -72,6 +168,7 @@ impl TodoService for TodoServiceImpl {
         debug!(self.logger, "get_by_id";);
 
         let id = &request.get_ref().id;
+        self.validate("get_by_id", Violations::new().id("id", id))?;
 
         let result = self.repo.get(id).await;
         match result {
@@ -92,60 +189,1327 @@ impl TodoService for TodoServiceImpl {
     ) -> Result<tonic::Response<pb::Todo>, tonic::Status> {
         debug!(self.logger, "update";);
 
-        let id = &request.get_ref().id;
-        let title = request.get_ref().title.clone();
-        let body = request.get_ref().body.clone();
-        let is_completed = request.get_ref().is_completed;
+        let actor = actor(&request);
+        let request = request.into_inner();
+        let mask = &request.update_mask;
+        let changes = |field: &str| mask.is_empty() || mask.iter().any(|f| f == field);
+        let mut violations = Violations::new()
+            .id("id", &request.id)
+            .fields("update_mask", mask, UPDATE_FIELDS)
+            .max_length("request_id", &request.request_id)
+            .max_length(ACTOR_METADATA_KEY, &actor);
+        if changes("title") {
+            violations = violations.text("title", &request.title);
+        }
+        self.validate("update", violations)?;
+        let key = IdempotencyKey::of("update", &actor, &request.request_id, &request);
+        let title = Some(request.title.clone()).filter(|_| changes("title"));
+        let body = Some(request.body.clone()).filter(|_| changes("body"));
+        let is_completed = Some(request.is_completed).filter(|_| changes("is_completed"));
+
+        self.idempotent("update", key, async {
+            let result = self
+                .repo
+                .update(&actor, &request.id, title, body, is_completed)
+                .await;
+            match result {
+                Ok(todo) => {
+                    debug!(self.logger, "update result"; "result" => ?todo);
+                    Ok(tonic::Response::new(todo.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "update"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn delete(
+        &self,
+        request: tonic::Request<pb::DeleteRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        debug!(self.logger, "delete";);
+
+        let actor = actor(&request);
+        let request = request.get_ref();
+        self.validate(
+            "delete",
+            Violations::new()
+                .id("id", &request.id)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &actor),
+        )?;
+        let key = IdempotencyKey::of("delete", &actor, &request.request_id, request);
+
+        self.idempotent("delete", key, async {
+            let result = self.repo.delete(&actor, &request.id).await;
+            match result {
+                Ok(_) => {
+                    self.purge_attachments(&request.id).await;
+                    Ok(tonic::Response::new(()))
+                }
+                Err(e) => {
+                    error!(self.logger, "delete"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn complete(
+        &self,
+        request: tonic::Request<pb::CompleteRequest>,
+    ) -> Result<tonic::Response<pb::Todo>, tonic::Status> {
+        debug!(self.logger, "complete";);
+
+        let actor = actor(&request);
+        let request = request.get_ref();
+        self.validate(
+            "complete",
+            Violations::new()
+                .id("id", &request.id)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &actor),
+        )?;
+        let key = IdempotencyKey::of("complete", &actor, &request.request_id, request);
+
+        self.idempotent("complete", key, async {
+            let result = self.repo.complete(&actor, &request.id).await;
+            match result {
+                Ok(todo) => {
+                    debug!(self.logger, "complete result"; "result" => ?todo);
+                    Ok(tonic::Response::new(todo.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "complete"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn export(
+        &self,
+        _request: tonic::Request<pb::ExportRequest>,
+    ) -> Result<tonic::Response<pb::Todos>, tonic::Status> {
+        debug!(self.logger, "export";);
+
+        let result = self.repo.list().await;
 
-        let result = self.repo.update(id, title, body, is_completed).await;
         match result {
-            Ok(todo) => {
-                debug!(self.logger, "update result"; "result" => ?todo);
-                Ok(tonic::Response::new(todo.into()))
+            Ok(todos) => {
+                debug!(self.logger, "export result"; "count" => todos.len());
+                Ok(tonic::Response::new(todos.into()))
             }
             Err(e) => {
-                error!(self.logger, "update"; "err" => ?e);
+                error!(self.logger, "export"; "err" => ?e);
                 Err(e.into())
             }
         }
     }
 
-    async fn delete(
+    async fn import(
+        &self,
+        request: tonic::Request<pb::ImportRequest>,
+    ) -> Result<tonic::Response<pb::Todos>, tonic::Status> {
+        debug!(self.logger, "import";);
+
+        let actor = actor(&request);
+        let request = request.into_inner();
+        let dry_run = request.dry_run;
+        let key = IdempotencyKey::of("import", &actor, &request.request_id, &request);
+        import_times(&request.todos).inspect_err(|status| {
+            error!(self.logger, "import"; "err" => status.message());
+        })?;
+        let todos: Vec<Todo> = request.todos.into_iter().map(Todo::from).collect();
+        self.validate(
+            "import",
+            Violations::new()
+                .todos("todos", &todos)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &actor),
+        )?;
+
+        self.idempotent("import", key, async {
+            let result = self.repo.import(&actor, todos, dry_run).await;
+            match result {
+                Ok(todos) => {
+                    debug!(self.logger, "import result"; "count" => todos.len(), "dry_run" => dry_run);
+                    Ok(tonic::Response::new(todos.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "import"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn export_todo_txt(
+        &self,
+        _request: tonic::Request<pb::ExportRequest>,
+    ) -> Result<tonic::Response<pb::TodoTxt>, tonic::Status> {
+        debug!(self.logger, "export_todo_txt";);
+
+        let result = self.repo.list().await;
+
+        match result {
+            Ok(todos) => {
+                debug!(self.logger, "export_todo_txt result"; "count" => todos.len());
+                Ok(tonic::Response::new(pb::TodoTxt {
+                    content: todotxt::serialize(&todos),
+                }))
+            }
+            Err(e) => {
+                error!(self.logger, "export_todo_txt"; "err" => ?e);
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn import_todo_txt(
+        &self,
+        request: tonic::Request<pb::ImportTodoTxtRequest>,
+    ) -> Result<tonic::Response<pb::Todos>, tonic::Status> {
+        debug!(self.logger, "import_todo_txt";);
+
+        let actor = actor(&request);
+        let request = request.into_inner();
+        let dry_run = request.dry_run;
+        let todos = todotxt::parse(&request.content).map_err(|e| {
+            error!(self.logger, "import_todo_txt"; "err" => %e);
+            tonic::Status::invalid_argument(e.to_string())
+        })?;
+        self.validate(
+            "import_todo_txt",
+            Violations::new()
+                .todos("content", &todos)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &actor),
+        )?;
+        let key = IdempotencyKey::of("import_todo_txt", &actor, &request.request_id, &request);
+
+        self.idempotent("import_todo_txt", key, async {
+            let result = self.repo.import(&actor, todos, dry_run).await;
+            match result {
+                Ok(todos) => {
+                    debug!(self.logger, "import_todo_txt result"; "count" => todos.len(), "dry_run" => dry_run);
+                    Ok(tonic::Response::new(todos.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "import_todo_txt"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn get_history(
+        &self,
+        request: tonic::Request<pb::HistoryRequest>,
+    ) -> Result<tonic::Response<pb::History>, tonic::Status> {
+        debug!(self.logger, "get_history";);
+
+        let request = request.get_ref();
+        self.validate("get_history", Violations::new().id("id", &request.id))?;
+        let limit = if request.limit <= 0 || request.limit > MAX_HISTORY_LIMIT {
+            MAX_HISTORY_LIMIT
+        } else {
+            request.limit
+        };
+        let offset = request.offset.max(0);
+
+        let result = self.repo.history(&request.id, offset, limit).await;
+        match result {
+            Ok(entries) => {
+                debug!(self.logger, "get_history result"; "count" => entries.len());
+                Ok(tonic::Response::new(pb::History {
+                    entries: entries.into_iter().map(|e| e.into()).collect(),
+                }))
+            }
+            Err(e) => {
+                error!(self.logger, "get_history"; "err" => ?e);
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn undo(
+        &self,
+        request: tonic::Request<pb::UndoRequest>,
+    ) -> Result<tonic::Response<pb::History>, tonic::Status> {
+        debug!(self.logger, "undo";);
+
+        self.revert(request, Direction::Undo).await
+    }
+
+    async fn redo(
+        &self,
+        request: tonic::Request<pb::UndoRequest>,
+    ) -> Result<tonic::Response<pb::History>, tonic::Status> {
+        debug!(self.logger, "redo";);
+
+        self.revert(request, Direction::Redo).await
+    }
+
+    async fn add_comment(
+        &self,
+        request: tonic::Request<pb::AddCommentRequest>,
+    ) -> Result<tonic::Response<pb::Comment>, tonic::Status> {
+        debug!(self.logger, "add_comment";);
+
+        let author = actor(&request);
+        let request = request.into_inner();
+        self.validate(
+            "add_comment",
+            Violations::new()
+                .id("todo_id", &request.todo_id)
+                .optional_id("parent_id", &request.parent_id)
+                .required("body", &request.body)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &author),
+        )?;
+        let key = IdempotencyKey::of("add_comment", &author, &request.request_id, &request);
+
+        self.idempotent("add_comment", key, async {
+            let parent_id = Some(request.parent_id).filter(|id| !id.is_empty());
+            let result = self
+                .repo
+                .add_comment(&author, &request.todo_id, parent_id, request.body)
+                .await;
+            match result {
+                Ok(comment) => {
+                    debug!(self.logger, "add_comment result"; "result" => ?comment);
+                    Ok(tonic::Response::new(comment.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "add_comment"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn list_comments(
         &self,
         request: tonic::Request<pb::TodoId>,
-    ) -> Result<tonic::Response<()>, tonic::Status> {
-        debug!(self.logger, "delete";);
+    ) -> Result<tonic::Response<pb::Comments>, tonic::Status> {
+        debug!(self.logger, "list_comments";);
 
-        let id = &request.get_ref().id.clone();
-        let result = self.repo.delete(id).await;
+        let id = &request.get_ref().id;
+        self.validate("list_comments", Violations::new().id("id", id))?;
 
+        let result = self.repo.list_comments(id).await;
         match result {
-            Ok(_) => Ok(tonic::Response::new(())),
+            Ok(comments) => {
+                debug!(self.logger, "list_comments result"; "count" => comments.len());
+                Ok(tonic::Response::new(pb::Comments {
+                    comments: comments.into_iter().map(|c| c.into()).collect(),
+                }))
+            }
             Err(e) => {
-                error!(self.logger, "delete"; "err" => ?e);
+                error!(self.logger, "list_comments"; "err" => ?e);
                 Err(e.into())
             }
         }
     }
 
-    async fn complete(
+    async fn edit_comment(
+        &self,
+        request: tonic::Request<pb::EditCommentRequest>,
+    ) -> Result<tonic::Response<pb::Comment>, tonic::Status> {
+        debug!(self.logger, "edit_comment";);
+
+        let author = self.require_actor("edit_comment", &request)?;
+        let request = request.into_inner();
+        self.validate(
+            "edit_comment",
+            Violations::new()
+                .id("todo_id", &request.todo_id)
+                .id("id", &request.id)
+                .required("body", &request.body)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &author),
+        )?;
+        let key = IdempotencyKey::of("edit_comment", &author, &request.request_id, &request);
+
+        self.idempotent("edit_comment", key, async {
+            let result = self
+                .repo
+                .edit_comment(&author, &request.todo_id, &request.id, request.body)
+                .await;
+            match result {
+                Ok(comment) => {
+                    debug!(self.logger, "edit_comment result"; "result" => ?comment);
+                    Ok(tonic::Response::new(comment.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "edit_comment"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn delete_comment(
+        &self,
+        request: tonic::Request<pb::CommentId>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        debug!(self.logger, "delete_comment";);
+
+        let author = self.require_actor("delete_comment", &request)?;
+        let request = request.get_ref();
+        self.validate(
+            "delete_comment",
+            ids(&request.todo_id, &request.id)
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &author),
+        )?;
+        let key = IdempotencyKey::of("delete_comment", &author, &request.request_id, request);
+
+        self.idempotent("delete_comment", key, async {
+            let result = self
+                .repo
+                .delete_comment(&author, &request.todo_id, &request.id)
+                .await;
+            match result {
+                Ok(_) => Ok(tonic::Response::new(())),
+                Err(e) => {
+                    error!(self.logger, "delete_comment"; "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn upload_attachment(
+        &self,
+        request: tonic::Request<tonic::Streaming<pb::UploadAttachmentRequest>>,
+    ) -> Result<tonic::Response<pb::Attachment>, tonic::Status> {
+        debug!(self.logger, "upload_attachment";);
+
+        let actor = actor(&request);
+        let mut stream = request.into_inner();
+        let info = match stream.message().await? {
+            Some(pb::UploadAttachmentRequest {
+                payload: Some(Payload::Info(info)),
+            }) => info,
+            _ => {
+                return Err(tonic::Status::invalid_argument(
+                    "first message must carry the attachment info",
+                ))
+            }
+        };
+        self.validate(
+            "upload_attachment",
+            Violations::new()
+                .id("info.todo_id", &info.todo_id)
+                .text("info.filename", &info.filename)
+                .max_length("info.content_type", &info.content_type)
+                .max_length("info.request_id", &info.request_id)
+                .max_length(ACTOR_METADATA_KEY, &actor),
+        )?;
+        let content_type = if info.content_type.is_empty() {
+            DEFAULT_CONTENT_TYPE.to_string()
+        } else {
+            info.content_type.clone()
+        };
+        if !self.limits.allows(&content_type) {
+            error!(self.logger, "upload_attachment"; "err" => "content type not allowed", "content_type" => &content_type);
+            return Err(tonic::Status::invalid_argument(format!(
+                "content type {} is not allowed",
+                content_type
+            )));
+        }
+        // A repeated upload is answered before its content is read.
+        let key = IdempotencyKey::of("upload_attachment", &actor, &info.request_id, &info);
+
+        self.idempotent("upload_attachment", key, async {
+            self.repo.get(&info.todo_id).await.map_err(|e| {
+                error!(self.logger, "upload_attachment"; "err" => ?e);
+                tonic::Status::from(e)
+            })?;
+
+            let mut attachment = Attachment {
+                id: self
+                    .id_generator
+                    .new_id()
+                    .map_err(|_| tonic::Status::internal("failed to generate id"))?
+                    .encode(),
+                todo_id: info.todo_id,
+                filename: info.filename,
+                content_type,
+                size: 0,
+                created_at: Utc::now(),
+            };
+            let key = attachment.blob_key();
+
+            let max_size = self.limits.max_size;
+            let mut size = 0;
+            let data = stream
+                .map(move |message| match message {
+                    Ok(pb::UploadAttachmentRequest {
+                        payload: Some(Payload::Chunk(chunk)),
+                    }) => {
+                        size += chunk.len() as u64;
+                        if size > max_size {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("attachment exceeds {} bytes", max_size),
+                            ));
+                        }
+                        Ok(chunk)
+                    }
+                    Ok(_) => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "expected attachment content",
+                    )),
+                    Err(status) => Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        status.to_string(),
+                    )),
+                })
+                .boxed();
+            attachment.size = self.blobs.put(&key, data).await.map_err(|e| {
+                error!(self.logger, "upload_attachment"; "err" => %e);
+                blob_status(e)
+            })? as i64;
+
+            let result = self.repo.add_attachment(attachment).await;
+            match result {
+                Ok(attachment) => {
+                    debug!(self.logger, "upload_attachment result"; "result" => ?attachment);
+                    Ok(tonic::Response::new(attachment.into()))
+                }
+                Err(e) => {
+                    error!(self.logger, "upload_attachment"; "err" => ?e);
+                    if let Err(e) = self.blobs.delete(&key).await {
+                        error!(self.logger, "upload_attachment"; "err" => %e, "key" => &key);
+                    }
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn list_attachments(
         &self,
         request: tonic::Request<pb::TodoId>,
-    ) -> Result<tonic::Response<pb::Todo>, tonic::Status> {
-        debug!(self.logger, "complete";);
+    ) -> Result<tonic::Response<pb::Attachments>, tonic::Status> {
+        debug!(self.logger, "list_attachments";);
 
         let id = &request.get_ref().id;
-        let result = self.repo.complete(id).await;
+        self.validate("list_attachments", Violations::new().id("id", id))?;
 
+        let result = self.repo.list_attachments(id).await;
         match result {
-            Ok(todo) => {
-                debug!(self.logger, "complete result"; "result" => ?todo);
-                Ok(tonic::Response::new(todo.into()))
+            Ok(attachments) => {
+                debug!(self.logger, "list_attachments result"; "count" => attachments.len());
+                Ok(tonic::Response::new(pb::Attachments {
+                    attachments: attachments.into_iter().map(|a| a.into()).collect(),
+                }))
             }
             Err(e) => {
-                error!(self.logger, "complete"; "err" => ?e);
+                error!(self.logger, "list_attachments"; "err" => ?e);
                 Err(e.into())
             }
         }
     }
+
+    async fn get_attachment(
+        &self,
+        request: tonic::Request<pb::AttachmentId>,
+    ) -> Result<tonic::Response<pb::Attachment>, tonic::Status> {
+        debug!(self.logger, "get_attachment";);
+
+        let request = request.get_ref();
+        self.validate("get_attachment", ids(&request.todo_id, &request.id))?;
+
+        let result = self
+            .repo
+            .get_attachment(&request.todo_id, &request.id)
+            .await;
+        match result {
+            Ok(attachment) => {
+                debug!(self.logger, "get_attachment result"; "result" => ?attachment);
+                Ok(tonic::Response::new(attachment.into()))
+            }
+            Err(e) => {
+                error!(self.logger, "get_attachment"; "err" => ?e);
+                Err(e.into())
+            }
+        }
+    }
+
+    type DownloadAttachmentStream = mpsc::Receiver<Result<pb::AttachmentChunk, tonic::Status>>;
+
+    async fn download_attachment(
+        &self,
+        request: tonic::Request<pb::DownloadAttachmentRequest>,
+    ) -> Result<tonic::Response<Self::DownloadAttachmentStream>, tonic::Status> {
+        debug!(self.logger, "download_attachment";);
+
+        let request = request.get_ref();
+        self.validate("download_attachment", ids(&request.todo_id, &request.id))?;
+
+        let attachment = self
+            .repo
+            .get_attachment(&request.todo_id, &request.id)
+            .await
+            .map_err(|e| {
+                error!(self.logger, "download_attachment"; "err" => ?e);
+                tonic::Status::from(e)
+            })?;
+        if request.offset < 0 || request.offset > attachment.size || request.length < 0 {
+            return Err(tonic::Status::out_of_range(format!(
+                "range is outside of the attachment of {} bytes",
+                attachment.size
+            )));
+        }
+        let length = Some(request.length as u64).filter(|l| *l > 0);
+
+        let mut data = self
+            .blobs
+            .get(&attachment.blob_key(), request.offset as u64, length)
+            .await
+            .map_err(|e| {
+                error!(self.logger, "download_attachment"; "err" => %e);
+                blob_status(e)
+            })?;
+
+        let (mut tx, rx) = mpsc::channel(4);
+        let logger = self.logger.clone();
+        tokio::spawn(async move {
+            while let Some(chunk) = data.next().await {
+                let message = chunk.map(|data| pb::AttachmentChunk { data }).map_err(|e| {
+                    error!(logger, "download_attachment"; "err" => %e);
+                    blob_status(e)
+                });
+                if tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(rx))
+    }
+
+    async fn delete_attachment(
+        &self,
+        request: tonic::Request<pb::AttachmentId>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        debug!(self.logger, "delete_attachment";);
+
+        let request = request.get_ref();
+        self.validate("delete_attachment", ids(&request.todo_id, &request.id))?;
+
+        let result = self
+            .repo
+            .delete_attachment(&request.todo_id, &request.id)
+            .await;
+        match result {
+            Ok(attachment) => {
+                if let Err(e) = self.blobs.delete(&attachment.blob_key()).await {
+                    error!(self.logger, "delete_attachment"; "err" => %e, "id" => &attachment.id);
+                }
+                Ok(tonic::Response::new(()))
+            }
+            Err(e) => {
+                error!(self.logger, "delete_attachment"; "err" => ?e);
+                Err(e.into())
+            }
+        }
+    }
+
+    type WatchStream = mpsc::Receiver<Result<pb::Event, tonic::Status>>;
+
+    async fn watch(
+        &self,
+        request: tonic::Request<pb::WatchRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status> {
+        debug!(self.logger, "watch";);
+
+        let feed = self.feed.clone().ok_or_else(|| {
+            tonic::Status::failed_precondition("watching requires Postgres storage with the outbox")
+        })?;
+        let after_id = request.get_ref().after_id;
+
+        // Subscribes before catching up, so that nothing falls in between.
+        // Without an `after_id` the watch starts with the events the feed has
+        // not broadcast yet.
+        let live = feed.subscribe();
+        let last = if after_id > 0 {
+            after_id
+        } else {
+            feed.last_id()
+        };
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(forward(feed, live, last, tx, self.logger.clone()));
+
+        Ok(tonic::Response::new(rx))
+    }
+}
+
+/// Sends the events after `last` to a watcher, from the outbox until it has
+/// caught up and then as they are broadcast. A watcher that falls behind
+/// catches up from the outbox again.
+async fn forward(
+    feed: Feed,
+    mut live: broadcast::Receiver<Event>,
+    mut last: i64,
+    mut tx: mpsc::Sender<Result<pb::Event, tonic::Status>>,
+    logger: slog::Logger,
+) {
+    if replay(&feed, &mut last, &mut tx, &logger).await.is_err() {
+        return;
+    }
+    loop {
+        let event = match live.recv().await {
+            Ok(event) => event,
+            Err(broadcast::RecvError::Lagged(skipped)) => {
+                warn!(logger, "watch"; "err" => "lagged", "skipped" => skipped);
+                if replay(&feed, &mut last, &mut tx, &logger).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Err(broadcast::RecvError::Closed) => return,
+        };
+        if event.id <= last {
+            continue;
+        }
+        last = event.id;
+        if tx.send(Ok(event.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends the events after `last` from the outbox. Fails if the client went
+/// away or the outbox could not be read, after telling the client.
+async fn replay(
+    feed: &Feed,
+    last: &mut i64,
+    tx: &mut mpsc::Sender<Result<pb::Event, tonic::Status>>,
+    logger: &slog::Logger,
+) -> Result<(), ()> {
+    loop {
+        let events = match feed.events_after(*last, WATCH_BATCH_SIZE).await {
+            Ok(events) => events,
+            Err(e) => {
+                error!(logger, "watch"; "err" => ?e);
+                let _ = tx.send(Err(e.into())).await;
+                return Err(());
+            }
+        };
+        let done = (events.len() as i64) < WATCH_BATCH_SIZE;
+        for event in events {
+            *last = event.id;
+            tx.send(Ok(event.into())).await.map_err(|_| ())?;
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+impl TodoServiceImpl {
+    /// Rejects a request with invalid fields before it does anything.
+    #[allow(clippy::result_large_err)]
+    fn validate(&self, method: &str, violations: Violations) -> Result<(), tonic::Status> {
+        violations.check().inspect_err(|status| {
+            error!(self.logger, "{}", method; "err" => status.message());
+        })
+    }
+
+    /// The caller of a request that an anonymous one may not make, as anyone
+    /// could be behind it.
+    #[allow(clippy::result_large_err)]
+    fn require_actor<T>(
+        &self,
+        method: &str,
+        request: &tonic::Request<T>,
+    ) -> Result<String, tonic::Status> {
+        known_actor(request).ok_or_else(|| {
+            error!(self.logger, "{}", method; "err" => "anonymous caller");
+            details::status(
+                Code::Unauthenticated,
+                format!("{} needs a known actor", method),
+                vec![details::error_info("ACTOR_REQUIRED")],
+            )
+        })
+    }
+
+    /// Removes the content of every attachment of a todo that no longer
+    /// exists. Their metadata goes away together with the todo.
+    async fn purge_attachments(&self, todo_id: &str) {
+        if let Err(e) = self.blobs.delete_prefix(todo_id).await {
+            error!(self.logger, "purge_attachments"; "err" => %e, "id" => todo_id);
+        }
+    }
+
+    async fn revert(
+        &self,
+        request: tonic::Request<pb::UndoRequest>,
+        direction: Direction,
+    ) -> Result<tonic::Response<pb::History>, tonic::Status> {
+        let method = match direction {
+            Direction::Undo => "undo",
+            Direction::Redo => "redo",
+        };
+        // Anonymous callers would revert each other's changes.
+        let actor = self.require_actor(method, &request)?;
+        let request = request.get_ref();
+        self.validate(
+            method,
+            Violations::new()
+                .max_length("request_id", &request.request_id)
+                .max_length(ACTOR_METADATA_KEY, &actor),
+        )?;
+        let key = IdempotencyKey::of(method, &actor, &request.request_id, request);
+        let count = if request.count <= 0 {
+            1
+        } else {
+            request.count.min(MAX_UNDO_COUNT)
+        };
+
+        self.idempotent(method, key, async {
+            let result = self.repo.revert(&actor, direction, count).await;
+            match result {
+                Ok(entries) => {
+                    debug!(self.logger, "revert result"; "direction" => ?direction, "count" => entries.len());
+                    for entry in entries.iter().filter(|e| e.removes_todo()) {
+                        self.purge_attachments(&entry.todo_id).await;
+                    }
+                    Ok(tonic::Response::new(pb::History {
+                        entries: entries.into_iter().map(|e| e.into()).collect(),
+                    }))
+                }
+                Err(e) => {
+                    error!(self.logger, "revert"; "direction" => ?direction, "err" => ?e);
+                    Err(e.into())
+                }
+            }
+        })
+        .await
+    }
+
+    /// Runs `call` once for an idempotency key and answers requests repeating
+    /// the key with its response. A request that fails gives the key up, so
+    /// that it can be retried.
+    async fn idempotent<R, F>(
+        &self,
+        method: &str,
+        key: Option<IdempotencyKey>,
+        call: F,
+    ) -> Result<tonic::Response<R>, tonic::Status>
+    where
+        R: Message + Default,
+        F: Future<Output = Result<tonic::Response<R>, tonic::Status>>,
+    {
+        let key = match key {
+            Some(key) => key,
+            None => return call.await,
+        };
+
+        let claim = self
+            .idempotency
+            .claim(&key.key, &key.fingerprint, IDEMPOTENCY_CLAIM_TIMEOUT)
+            .await
+            .map_err(|e| {
+                error!(self.logger, "{}", method; "err" => ?e, "request_id" => &key.request_id);
+                tonic::Status::from(e)
+            })?;
+        match claim {
+            Claim::New => {}
+            Claim::Done(response) => {
+                debug!(self.logger, "{} repeated", method; "request_id" => &key.request_id);
+                return R::decode(response.as_slice())
+                    .map(tonic::Response::new)
+                    .map_err(|e| {
+                        error!(self.logger, "{}", method; "err" => %e, "request_id" => &key.request_id);
+                        tonic::Status::internal("stored response is not valid")
+                    });
+            }
+            Claim::InProgress => {
+                return Err(details::status(
+                    Code::Aborted,
+                    "a request with this request_id is in progress",
+                    vec![details::error_info("IDEMPOTENCY_KEY_IN_USE")],
+                ))
+            }
+            Claim::Applied => {
+                return Err(details::status(
+                    Code::AlreadyExists,
+                    "a request with this request_id was applied, but its response was not kept",
+                    vec![details::error_info("IDEMPOTENCY_KEY_APPLIED")],
+                ))
+            }
+            Claim::Mismatch => {
+                let description = "was used for a different request";
+                return Err(details::status(
+                    Code::InvalidArgument,
+                    format!("request_id {}", description),
+                    vec![
+                        details::bad_request(vec![FieldViolation {
+                            field: "request_id".to_string(),
+                            description: description.to_string(),
+                        }]),
+                        details::error_info("IDEMPOTENCY_KEY_REUSED"),
+                    ],
+                ));
+            }
+        }
+
+        // A repository that can marks the key as applied in the transaction
+        // that applies the request, so that a retry never applies it again
+        // even if the response below is not kept.
+        let applying = Applying {
+            key: key.key.clone(),
+            fingerprint: key.fingerprint.clone(),
+            ttl: self.idempotency_ttl,
+        };
+        let result = APPLYING.scope(applying, call).await;
+        let response = match &result {
+            Ok(response) => details::encode(response.get_ref()),
+            Err(_) => {
+                if let Err(e) = self.idempotency.release(&key.key, &key.fingerprint).await {
+                    error!(self.logger, "{}", method; "err" => ?e, "request_id" => &key.request_id);
+                }
+                return result;
+            }
+        };
+        let mut backoff = IDEMPOTENCY_COMPLETE_BACKOFF;
+        for attempt in 1..=IDEMPOTENCY_COMPLETE_ATTEMPTS {
+            let kept = self
+                .idempotency
+                .complete(
+                    &key.key,
+                    &key.fingerprint,
+                    response.clone(),
+                    self.idempotency_ttl,
+                )
+                .await;
+            match kept {
+                Ok(()) => break,
+                Err(e) => {
+                    error!(self.logger, "{}", method; "err" => ?e, "request_id" => &key.request_id, "attempt" => attempt);
+                    if attempt < IDEMPOTENCY_COMPLETE_ATTEMPTS {
+                        tokio::time::delay_for(backoff).await;
+                        backoff *= 2;
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// The idempotency key of a request and what tells it apart from another
+/// request with the same key.
+struct IdempotencyKey {
+    /// The `request_id` as the caller gave it.
+    request_id: String,
+    /// The key kept in the store, the `request_id` of the actor, so that
+    /// actors can not use or learn of each other's keys.
+    key: String,
+    fingerprint: String,
+}
+
+impl IdempotencyKey {
+    /// `None` when the request has no key.
+    fn of<T: Message>(
+        method: &str,
+        actor: &str,
+        request_id: &str,
+        request: &T,
+    ) -> Option<IdempotencyKey> {
+        if request_id.is_empty() {
+            return None;
+        }
+
+        let mut key = Sha256::new();
+        key.update(actor.as_bytes());
+        key.update([0]);
+        key.update(request_id.as_bytes());
+        let mut fingerprint = Sha256::new();
+        fingerprint.update(method.as_bytes());
+        fingerprint.update([0]);
+        fingerprint.update(details::encode(request));
+        Some(IdempotencyKey {
+            request_id: request_id.to_string(),
+            key: format!("{:x}", key.finalize()),
+            fingerprint: format!("{:x}", fingerprint.finalize()),
+        })
+    }
+}
+
+/// Returns the caller set by the api gateway in the `x-actor` metadata, or
+/// `anonymous` for a request without one.
+fn actor<T>(request: &tonic::Request<T>) -> String {
+    known_actor(request).unwrap_or_else(|| ANONYMOUS_ACTOR.to_string())
+}
+
+/// The caller of a request, `None` when it is anonymous.
+fn known_actor<T>(request: &tonic::Request<T>) -> Option<String> {
+    request
+        .metadata()
+        .get(ACTOR_METADATA_KEY)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && *v != ANONYMOUS_ACTOR)
+        .map(str::to_string)
+}
+
+/// The ids of a comment or an attachment and of its todo.
+fn ids(todo_id: &str, id: &str) -> Violations {
+    Violations::new().id("todo_id", todo_id).id("id", id)
+}
+
+/// Refuses todos to import whose times a `google.protobuf.Timestamp` can not
+/// hold, before they are converted.
+#[allow(clippy::result_large_err)]
+fn import_times(todos: &[pb::Todo]) -> Result<(), tonic::Status> {
+    let mut violations = Vec::new();
+    for (i, todo) in todos.iter().enumerate() {
+        let times = [
+            ("created_at", &todo.created_at),
+            ("updated_at", &todo.updated_at),
+        ];
+        for (name, time) in times.iter() {
+            if time
+                .as_ref()
+                .is_some_and(|ts| from_timestamp(ts.clone()).is_none())
+            {
+                violations.push(FieldViolation {
+                    field: format!("todos[{}].{}", i, name),
+                    description:
+                        "must be a time from 0001-01-01 to 9999-12-31 with nanos below a second"
+                            .to_string(),
+                });
+            }
+        }
+    }
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let message = violations
+        .iter()
+        .map(|v| format!("{} {}", v.field, v.description))
+        .collect::<Vec<_>>()
+        .join("; ");
+    Err(details::status(
+        Code::InvalidArgument,
+        message,
+        vec![details::bad_request(violations)],
+    ))
+}
+
+fn blob_status(err: io::Error) -> tonic::Status {
+    match err.kind() {
+        io::ErrorKind::InvalidData => tonic::Status::invalid_argument(err.to_string()),
+        io::ErrorKind::NotFound => tonic::Status::not_found("attachment content not found"),
+        _ => tonic::Status::internal("attachment storage error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::local::LocalBlobStore;
+    use crate::repository::conformance::postgres_scratch;
+    use crate::repository::error::Error;
+    use crate::repository::hashmap::HashMapRepository;
+    use crate::repository::idempotency::{MemoryIdempotencyStore, PostgresIdempotencyStore};
+    use crate::repository::outbox::PostgresOutbox;
+    use crate::repository::postgres::PostgresRepository;
+    use crate::test_util::{logger, TempDir};
+    use todo_service::todo_service_client::TodoServiceClient;
+    use todo_service::todo_service_server::TodoServiceServer;
+
+    /// Waits until the feed has broadcast everything in the outbox.
+    async fn caught_up(feed: &Feed, outbox: &PostgresOutbox) -> i64 {
+        let last = outbox.last_id().await.unwrap();
+        for _ in 0..250 {
+            if feed.last_id() >= last {
+                return last;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        panic!("the feed did not catch up");
+    }
+
+    /// A watcher without an `after_id` that falls behind before its first
+    /// event catches up from where it started, not from the beginning.
+    #[tokio::test(threaded_scheduler)]
+    async fn watch_catches_up_from_where_it_started() {
+        let settings = match postgres_scratch("watch").await {
+            Some(settings) => settings,
+            None => return,
+        };
+        let repo = PostgresRepository::new(&settings, logger()).await.unwrap();
+        let outbox = Arc::new(PostgresOutbox::new(&settings).await.unwrap());
+        let feed = Feed::new(outbox.clone(), 2, logger());
+        feed.spawn().await.unwrap();
+        repo.create("watch", "before".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        let start = caught_up(&feed, &outbox).await;
+
+        let live = feed.subscribe();
+        assert_eq!(feed.last_id(), start);
+        for i in 0..5 {
+            repo.create("watch", format!("t{}", i), "b".to_string())
+                .await
+                .unwrap();
+        }
+        caught_up(&feed, &outbox).await;
+
+        let (tx, mut rx) = mpsc::channel(16);
+        tokio::spawn(forward(feed.clone(), live, start, tx, logger()));
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("no event sent")
+                .unwrap()
+                .unwrap();
+            ids.push(event.id);
+        }
+        let expected: Vec<i64> = outbox
+            .events_after(start, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, expected);
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .is_err());
+    }
+
+    fn create(actor: &str, title: &str) -> tonic::Request<pb::CreateRequest> {
+        let mut request = tonic::Request::new(pb::CreateRequest {
+            title: title.to_string(),
+            body: "b".to_string(),
+            request_id: "r1".to_string(),
+        });
+        request
+            .metadata_mut()
+            .insert(ACTOR_METADATA_KEY, actor.parse().unwrap());
+        request
+    }
+
+    /// A service keeping attachments in `dir`.
+    async fn service(
+        repo: Box<dyn Repository + Send + Sync>,
+        idempotency: Arc<dyn IdempotencyStore + Send + Sync>,
+        dir: &TempDir,
+    ) -> TodoServiceImpl {
+        let blobs = LocalBlobStore::new(dir.path()).await.unwrap();
+        TodoServiceImpl::new(
+            logger(),
+            repo,
+            Box::new(blobs),
+            AttachmentLimits::new(1024, "*"),
+            None,
+            idempotency,
+            Duration::from_secs(60),
+        )
+    }
+
+    /// Every actor has keys of their own.
+    #[tokio::test(threaded_scheduler)]
+    async fn idempotency_keys_belong_to_their_actors() {
+        let dir = TempDir::new("todo-server");
+        let service = service(
+            Box::new(HashMapRepository::new(logger())),
+            Arc::new(MemoryIdempotencyStore::new()),
+            &dir,
+        )
+        .await;
+
+        let alice = service.create(create("alice", "a")).await.unwrap();
+        let bob = service.create(create("bob", "a")).await.unwrap();
+        assert_ne!(alice.get_ref().id, bob.get_ref().id);
+        let repeated = service.create(create("alice", "a")).await.unwrap();
+        assert_eq!(repeated.get_ref(), alice.get_ref());
+        let status = service.create(create("bob", "b")).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(service.repo.list().await.unwrap().len(), 2);
+    }
+
+    /// A repeated upload is answered with the attachment of the first.
+    #[tokio::test(threaded_scheduler)]
+    async fn uploads_are_idempotent() {
+        let dir = TempDir::new("todo-server");
+        let repo = HashMapRepository::new(logger());
+        let todo = repo
+            .create("alice", "a".to_string(), "b".to_string())
+            .await
+            .unwrap();
+        let service = service(
+            Box::new(repo),
+            Arc::new(MemoryIdempotencyStore::new()),
+            &dir,
+        )
+        .await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TodoServiceServer::new(service))
+                .serve_with_incoming(listener),
+        );
+        let mut client = TodoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let upload = |content: &'static [u8]| {
+            let info = pb::AttachmentInfo {
+                todo_id: todo.id.clone(),
+                filename: "a.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                request_id: "r1".to_string(),
+            };
+            let messages = vec![
+                pb::UploadAttachmentRequest {
+                    payload: Some(Payload::Info(info)),
+                },
+                pb::UploadAttachmentRequest {
+                    payload: Some(Payload::Chunk(content.to_vec())),
+                },
+            ];
+            let mut request = tonic::Request::new(futures::stream::iter(messages));
+            request
+                .metadata_mut()
+                .insert(ACTOR_METADATA_KEY, "alice".parse().unwrap());
+            request
+        };
+        let first = client.upload_attachment(upload(b"abc")).await.unwrap();
+        let repeated = client.upload_attachment(upload(b"abc")).await.unwrap();
+        assert_eq!(repeated.get_ref(), first.get_ref());
+        let attachments = client
+            .list_attachments(pb::TodoId {
+                id: todo.id.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(attachments.get_ref().attachments.len(), 1);
+    }
+
+    /// Keeps no responses, as when the store fails after a request was
+    /// applied.
+    struct Forgetful(PostgresIdempotencyStore);
+
+    #[async_trait::async_trait]
+    impl IdempotencyStore for Forgetful {
+        async fn claim(
+            &self,
+            key: &str,
+            fingerprint: &str,
+            timeout: Duration,
+        ) -> Result<Claim, Error> {
+            self.0.claim(key, fingerprint, timeout).await
+        }
+
+        async fn complete(&self, _: &str, _: &str, _: Vec<u8>, _: Duration) -> Result<(), Error> {
+            Err(Error::NotFound)
+        }
+
+        async fn release(&self, key: &str, fingerprint: &str) -> Result<(), Error> {
+            self.0.release(key, fingerprint).await
+        }
+
+        async fn prune(&self) -> Result<u64, Error> {
+            self.0.prune().await
+        }
+    }
+
+    /// A request is never applied twice, even when its response could not be
+    /// kept: Postgres marks its key in the transaction that applies it.
+    #[tokio::test(threaded_scheduler)]
+    async fn applies_a_request_once_when_its_response_is_lost() {
+        let settings = match postgres_scratch("idempotency").await {
+            Some(settings) => settings,
+            None => return,
+        };
+        let repo = PostgresRepository::new(&settings, logger()).await.unwrap();
+        let store = Forgetful(PostgresIdempotencyStore::new(&settings).await.unwrap());
+        let dir = TempDir::new("todo-server");
+        let service = service(Box::new(repo), Arc::new(store), &dir).await;
+
+        service.create(create("alice", "a")).await.unwrap();
+        let status = service.create(create("alice", "a")).await.unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(service.repo.list().await.unwrap().len(), 1);
+    }
+
+    fn timestamp(seconds: i64, nanos: i32) -> Option<prost_types::Timestamp> {
+        Some(prost_types::Timestamp { seconds, nanos })
+    }
+
+    /// The fields named by the `BadRequest` in the details of `status`.
+    fn violated_fields(status: &tonic::Status) -> Vec<String> {
+        let status = details::rpc::Status::decode(status.details()).unwrap();
+        details::rpc::BadRequest::decode(&*status.details[0].value)
+            .unwrap()
+            .field_violations
+            .into_iter()
+            .map(|violation| violation.field)
+            .collect()
+    }
+
+    #[test]
+    fn import_times_out_of_range() {
+        let todos: Vec<pb::Todo> = [
+            timestamp(0, -1),
+            timestamp(0, 1_000_000_000),
+            timestamp(i64::MIN, 0),
+            timestamp(253_402_300_800, 0),
+        ]
+        .iter()
+        .map(|created_at| pb::Todo {
+            created_at: created_at.clone(),
+            ..Default::default()
+        })
+        .collect();
+
+        let status = import_times(&todos).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            violated_fields(&status),
+            [
+                "todos[0].created_at",
+                "todos[1].created_at",
+                "todos[2].created_at",
+                "todos[3].created_at"
+            ]
+        );
+    }
+
+    #[test]
+    fn import_times_in_range() {
+        let todos = vec![
+            pb::Todo {
+                created_at: timestamp(-62_135_596_800, 0),
+                updated_at: timestamp(253_402_300_799, 999_999_999),
+                ..Default::default()
+            },
+            pb::Todo::default(),
+        ];
+
+        assert!(import_times(&todos).is_ok());
+    }
 }
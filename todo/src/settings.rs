@@ -1,3 +1,4 @@
+use crate::blob::store;
 use crate::repository::repository;
 use config::{Config, ConfigError, Environment};
 
@@ -6,11 +7,22 @@ pub struct Settings {
     pub log_level: String,
     pub port: u16,
     pub storage: repository::StorageSettings,
+    pub blob_storage: store::BlobStorageSettings,
+    pub attachment_max_size: u64,
+    /// Comma-separated list of accepted content types, `type/*` matches any
+    /// subtype and `*` anything.
+    pub attachment_content_types: String,
+    /// How long the response to a request with an idempotency key is kept.
+    pub idempotency_ttl_secs: u64,
 }
 
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         let mut c = Config::default();
+        c.set_default("blob_storage", "Local")?;
+        c.set_default("attachment_max_size", 10 * 1024 * 1024)?;
+        c.set_default("attachment_content_types", "*")?;
+        c.set_default("idempotency_ttl_secs", 24 * 60 * 60)?;
         c.merge(Environment::with_prefix("TODO"))?;
 
         c.try_into::<Settings>()
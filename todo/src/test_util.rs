@@ -0,0 +1,38 @@
+//! Helpers the tests of several modules share.
+
+use std::path::PathBuf;
+
+pub(crate) fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, o!())
+}
+
+pub(crate) fn unique(name: &str) -> String {
+    format!(
+        "{}-{}",
+        name,
+        libxid::new_generator().new_id().unwrap().encode()
+    )
+}
+
+/// A scratch directory of one test, removed again when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> TempDir {
+        TempDir(std::env::temp_dir().join(unique(name)))
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    pub(crate) fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
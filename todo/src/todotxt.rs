@@ -0,0 +1,394 @@
+use std::fmt;
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+use crate::repository::model::{Todo, Todos};
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const ID_TAG: &str = "id";
+const BODY_TAG: &str = "body";
+const TITLE_TAG: &str = "title";
+const PRIORITY_TAG: &str = "pri";
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Serializes todos into todo.txt lines.
+///
+/// The title becomes the description, so `+project` and `@context` tokens stay
+/// where they are. A title that would not be read back as it is, because it
+/// has `key:value` words or spacing other than single spaces, is also stored
+/// in an escaped `title:` tag. Tokens kept in the body by `parse` (priority
+/// and `key:value` tags) are written back in their todo.txt positions, any
+/// other body text is stored in an escaped `body:` tag.
+pub fn serialize(todos: &[Todo]) -> String {
+    let mut out = String::new();
+    for todo in todos {
+        out.push_str(&to_line(todo));
+        out.push('\n');
+    }
+    out
+}
+
+/// Parses todo.txt content, skipping blank lines.
+pub fn parse(content: &str) -> Result<Todos, ParseError> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            from_line(line).map_err(|message| ParseError {
+                line: i + 1,
+                message,
+            })
+        })
+        .collect()
+}
+
+pub fn to_line(todo: &Todo) -> String {
+    let (priority, tags) = split_body(&todo.body);
+    let mut parts = Vec::new();
+
+    if todo.is_completed {
+        parts.push("x".to_string());
+        parts.push(format_date(todo.updated_at));
+    } else if let Some(p) = priority {
+        parts.push(format!("({})", p));
+    }
+    parts.push(format_date(todo.created_at));
+    let plain = is_plain(&todo.title);
+    if plain {
+        parts.push(todo.title.clone());
+    } else {
+        // What can be read of the title stays in the description.
+        parts.extend(
+            todo.title
+                .split_whitespace()
+                .filter(|t| parse_tag(t).is_none())
+                .map(str::to_string),
+        );
+    }
+    parts.extend(tags);
+    if !plain {
+        parts.push(format!("{}:{}", TITLE_TAG, escape(&todo.title)));
+    }
+    if todo.is_completed {
+        if let Some(p) = priority {
+            parts.push(format!("{}:{}", PRIORITY_TAG, p));
+        }
+    }
+    if !todo.id.is_empty() {
+        parts.push(format!("{}:{}", ID_TAG, todo.id));
+    }
+
+    parts.join(" ")
+}
+
+pub fn from_line(line: &str) -> Result<Todo, String> {
+    let mut tokens = line.split_whitespace().peekable();
+
+    let is_completed = tokens.peek() == Some(&"x");
+    if is_completed {
+        tokens.next();
+    }
+
+    let mut priority = tokens.peek().and_then(|t| parse_priority(t));
+    if priority.is_some() {
+        tokens.next();
+    }
+
+    let max_dates = if is_completed { 2 } else { 1 };
+    let mut dates = Vec::with_capacity(max_dates);
+    while dates.len() < max_dates {
+        match tokens.peek().and_then(|t| parse_date(t)) {
+            Some(date) => {
+                dates.push(date);
+                tokens.next();
+            }
+            None => break,
+        }
+    }
+    let (completed_at, created_at) = match (is_completed, dates.as_slice()) {
+        (true, [completed, created]) => (Some(*completed), Some(*created)),
+        (true, [completed]) => (Some(*completed), None),
+        (false, [created]) => (None, Some(*created)),
+        _ => (None, None),
+    };
+
+    let mut id = String::new();
+    let mut title = None;
+    let mut body = None;
+    let mut description = Vec::new();
+    let mut extra = Vec::new();
+    for token in tokens {
+        match parse_tag(token) {
+            Some((ID_TAG, value)) => id = value.to_string(),
+            Some((TITLE_TAG, value)) => title = Some(unescape(value)?),
+            Some((BODY_TAG, value)) => body = Some(unescape(value)?),
+            Some((PRIORITY_TAG, value)) if is_completed && priority.is_none() => {
+                priority = Some(parse_priority_letter(value)?)
+            }
+            Some(_) => extra.push(token.to_string()),
+            None => description.push(token),
+        }
+    }
+    let title = match title {
+        Some(title) => title,
+        None if description.is_empty() => return Err("missing description".to_string()),
+        None => description.join(" "),
+    };
+
+    if let Some(p) = priority {
+        extra.insert(0, format!("({})", p));
+    }
+    let body = match body {
+        Some(text) if extra.is_empty() => text,
+        Some(text) => format!("{} {}", extra.join(" "), text),
+        None => extra.join(" "),
+    };
+
+    let now = Utc::now();
+    let created_at = created_at.unwrap_or(now);
+    Ok(Todo {
+        id,
+        title,
+        body,
+        is_completed,
+        created_at,
+        updated_at: completed_at.unwrap_or(created_at),
+        comment_count: 0,
+    })
+}
+
+/// Whether a title is read back from the description as it is: words that
+/// are not tags, separated by single spaces.
+fn is_plain(title: &str) -> bool {
+    let tokens: Vec<&str> = title.split_whitespace().collect();
+    !tokens.is_empty() && tokens.join(" ") == title && tokens.iter().all(|t| parse_tag(t).is_none())
+}
+
+/// Splits a body into its priority and todo.txt tags. When the body holds
+/// anything else, tags `parse` reads itself, or spacing `parse` would not
+/// restore, it is returned as a single escaped `body:` tag.
+fn split_body(body: &str) -> (Option<char>, Vec<String>) {
+    let escaped = || (None, vec![format!("{}:{}", BODY_TAG, escape(body))]);
+    let mut priority = None;
+    let mut tags = Vec::new();
+    for token in body.split_whitespace() {
+        if let (None, Some(p)) = (priority, parse_priority(token)) {
+            priority = Some(p);
+        } else if let Some((key, _)) = parse_tag(token) {
+            if [ID_TAG, BODY_TAG, TITLE_TAG, PRIORITY_TAG].contains(&key) {
+                return escaped();
+            }
+            tags.push(token.to_string());
+        } else {
+            return escaped();
+        }
+    }
+
+    // `parse` puts the priority first and joins with single spaces.
+    let restored: Vec<String> = priority
+        .map(|p| format!("({})", p))
+        .into_iter()
+        .chain(tags.iter().cloned())
+        .collect();
+    if restored.join(" ") != body {
+        return escaped();
+    }
+
+    (priority, tags)
+}
+
+fn parse_priority(token: &str) -> Option<char> {
+    let bytes = token.as_bytes();
+    if bytes.len() == 3 && bytes[0] == b'(' && bytes[2] == b')' && bytes[1].is_ascii_uppercase() {
+        Some(bytes[1] as char)
+    } else {
+        None
+    }
+}
+
+fn parse_priority_letter(value: &str) -> Result<char, String> {
+    parse_priority(&format!("({})", value)).ok_or(format!("invalid priority {}", value))
+}
+
+fn parse_date(token: &str) -> Option<DateTime<Utc>> {
+    NaiveDate::parse_from_str(token, DATE_FORMAT)
+        .ok()
+        .map(|date| Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()))
+}
+
+fn format_date(dt: DateTime<Utc>) -> String {
+    dt.format(DATE_FORMAT).to_string()
+}
+
+/// Recognizes `key:value` tags. Keys must start with a letter and values
+/// starting with `/` are left alone, so that times and urls stay part of the
+/// description.
+fn parse_tag(token: &str) -> Option<(&str, &str)> {
+    let (key, value) = token.split_once(':')?;
+    let valid_key = key.starts_with(|c: char| c.is_ascii_alphabetic())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid_key && !value.is_empty() && !value.starts_with('/') {
+        Some((key, value))
+    } else {
+        None
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' => out.push_str("%25"),
+            ' ' => out.push_str("%20"),
+            '\t' => out.push_str("%09"),
+            '\n' => out.push_str("%0A"),
+            '\r' => out.push_str("%0D"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let code: String = chars.by_ref().take(2).collect();
+        match u8::from_str_radix(&code, 16) {
+            Ok(b) if code.len() == 2 => out.push(b as char),
+            _ => return Err(format!("invalid escape sequence %{}", code)),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn todo(title: &str, body: &str, is_completed: bool) -> Todo {
+        let created_at = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap();
+        Todo {
+            id: "cn9h2s0m8dnt3mr8s0jg".to_string(),
+            title: title.to_string(),
+            body: body.to_string(),
+            is_completed,
+            created_at,
+            updated_at: if is_completed {
+                Utc.with_ymd_and_hms(2024, 1, 3, 0, 0, 0).unwrap()
+            } else {
+                created_at
+            },
+            comment_count: 0,
+        }
+    }
+
+    fn round_trip(todo: &Todo) -> Todo {
+        let line = to_line(todo);
+        let read = from_line(&line).unwrap_or_else(|e| panic!("{}: {}", line, e));
+        assert_eq!(read.id, todo.id, "{}", line);
+        assert_eq!(read.title, todo.title, "{}", line);
+        assert_eq!(read.body, todo.body, "{}", line);
+        assert_eq!(read.is_completed, todo.is_completed, "{}", line);
+        assert_eq!(read.created_at, todo.created_at, "{}", line);
+        assert_eq!(read.updated_at, todo.updated_at, "{}", line);
+        read
+    }
+
+    #[test]
+    fn plain_title_stays_in_description() {
+        let todo = todo("Call mom +family @phone", "", false);
+        assert_eq!(
+            to_line(&todo),
+            "2024-01-02 Call mom +family @phone id:cn9h2s0m8dnt3mr8s0jg"
+        );
+        round_trip(&todo);
+    }
+
+    #[test]
+    fn titles_round_trip() {
+        for title in [
+            "Pay rent due:friday",
+            "due:friday",
+            "a:b c:d",
+            "Two  spaces",
+            " Leading and trailing ",
+            "Tab\there",
+            "id:someone-else",
+            "Percent 100% done",
+            "x marks the spot",
+            "(A) not a priority",
+            "2024-05-06 not a date",
+            "Read https://example.com/a:b",
+        ] {
+            round_trip(&todo(title, "", false));
+            round_trip(&todo(title, "", true));
+        }
+    }
+
+    #[test]
+    fn bodies_round_trip() {
+        for body in [
+            "",
+            "(A)",
+            "(B) due:2024-02-01",
+            "due:2024-02-01 (B)",
+            "due:2024-02-01  rec:1w",
+            "id:other",
+            "title:other",
+            "pri:C",
+            "Some notes\nover two lines",
+            "(A) (B)",
+        ] {
+            round_trip(&todo("Title", body, false));
+            round_trip(&todo("Title", body, true));
+        }
+    }
+
+    #[test]
+    fn priority_of_completed_todo_is_a_tag() {
+        let todo = todo("Done", "(A)", true);
+        assert_eq!(
+            to_line(&todo),
+            "x 2024-01-03 2024-01-02 Done pri:A id:cn9h2s0m8dnt3mr8s0jg"
+        );
+        round_trip(&todo);
+    }
+
+    #[test]
+    fn tags_only_title_is_read_back() {
+        let todo = todo("due:friday +home", "", false);
+        let line = to_line(&todo);
+        assert_eq!(
+            line,
+            "2024-01-02 +home title:due:friday%20+home id:cn9h2s0m8dnt3mr8s0jg"
+        );
+        round_trip(&todo);
+    }
+
+    #[test]
+    fn missing_description() {
+        assert!(from_line("2024-01-02 due:friday").is_err());
+        assert!(parse("first\n\n(A) due:x\n").unwrap_err().line == 3);
+    }
+}
@@ -0,0 +1,209 @@
+//! Checks the fields of a request before it reaches the repository, so that
+//! every storage accepts the same input. All violations of a request are
+//! returned at once, as `InvalidArgument` with `google.rpc.BadRequest`
+//! details.
+
+use tonic::Code;
+
+use crate::details;
+use crate::details::rpc::bad_request::FieldViolation;
+use crate::repository::model::Todo;
+
+/// The longest text kept in a VARCHAR(255) column, in characters.
+const MAX_LENGTH: usize = 255;
+const ID_LENGTH: usize = 20;
+const ID_ALPHABET: &str = "0123456789abcdefghijklmnopqrstuv";
+
+#[derive(Default)]
+pub struct Violations(Vec<FieldViolation>);
+
+impl Violations {
+    pub fn new() -> Violations {
+        Violations::default()
+    }
+
+    fn add(&mut self, field: &str, description: String) {
+        self.0.push(FieldViolation {
+            field: field.to_string(),
+            description,
+        });
+    }
+
+    /// An id generated by the service: an xid of 20 characters in base32hex.
+    pub fn id(mut self, field: &str, id: &str) -> Violations {
+        if id.is_empty() {
+            self.add(field, "must not be empty".to_string());
+        } else if id.chars().count() != ID_LENGTH || !id.chars().all(|c| ID_ALPHABET.contains(c)) {
+            self.add(
+                field,
+                format!("must be an id of {} characters from 0-9 and a-v", ID_LENGTH),
+            );
+        }
+        self
+    }
+
+    pub fn optional_id(self, field: &str, id: &str) -> Violations {
+        if id.is_empty() {
+            return self;
+        }
+        self.id(field, id)
+    }
+
+    pub fn required(mut self, field: &str, value: &str) -> Violations {
+        if value.trim().is_empty() {
+            self.add(field, "must not be empty".to_string());
+        }
+        self
+    }
+
+    pub fn max_length(mut self, field: &str, value: &str) -> Violations {
+        if value.chars().count() > MAX_LENGTH {
+            self.add(field, format!("must be at most {} characters", MAX_LENGTH));
+        }
+        self
+    }
+
+    /// Names of fields, each of which must be one of `allowed`.
+    pub fn fields(mut self, field: &str, names: &[String], allowed: &[&str]) -> Violations {
+        for (i, name) in names.iter().enumerate() {
+            if !allowed.contains(&name.as_str()) {
+                self.add(
+                    &format!("{}[{}]", field, i),
+                    format!("must be one of {}", allowed.join(", ")),
+                );
+            }
+        }
+        self
+    }
+
+    /// A short text that must be given, such as a title.
+    pub fn text(self, field: &str, value: &str) -> Violations {
+        if value.trim().is_empty() {
+            return self.required(field, value);
+        }
+        self.max_length(field, value)
+    }
+
+    /// Todos to import, whose ids are generated when missing.
+    pub fn todos(self, field: &str, todos: &[Todo]) -> Violations {
+        todos
+            .iter()
+            .enumerate()
+            .fold(self, |violations, (i, todo)| {
+                violations
+                    .optional_id(&format!("{}[{}].id", field, i), &todo.id)
+                    .text(&format!("{}[{}].title", field, i), &todo.title)
+            })
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn check(self) -> Result<(), tonic::Status> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let message = self
+            .0
+            .iter()
+            .map(|v| format!("{} {}", v.field, v.description))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Err(details::status(
+            Code::InvalidArgument,
+            message,
+            vec![details::bad_request(self.0)],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::details::rpc;
+
+    /// The fields named by the `BadRequest` in the details of `status`.
+    fn violated_fields(status: &tonic::Status) -> Vec<String> {
+        let status = rpc::Status::decode(status.details()).unwrap();
+        assert_eq!(status.details.len(), 1);
+        assert!(status.details[0]
+            .type_url
+            .ends_with("google.rpc.BadRequest"));
+        rpc::BadRequest::decode(&*status.details[0].value)
+            .unwrap()
+            .field_violations
+            .into_iter()
+            .map(|violation| violation.field)
+            .collect()
+    }
+
+    #[test]
+    fn invalid_titles() {
+        let too_long = "x".repeat(MAX_LENGTH + 1);
+        for title in ["", "  \t", too_long.as_str()].iter() {
+            let status = Violations::new().text("title", title).check().unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(violated_fields(&status), ["title"], "{:?}", title);
+        }
+    }
+
+    #[test]
+    fn valid_titles() {
+        let longest = "ü".repeat(MAX_LENGTH);
+        for title in ["a", " a ", longest.as_str()].iter() {
+            assert!(Violations::new().text("title", title).check().is_ok());
+        }
+    }
+
+    #[test]
+    fn invalid_ids() {
+        for id in [
+            "",
+            "bu2j3o3ipt39m2h5jpa",
+            "bu2j3o3ipt39m2h5jpa00",
+            "bu2j3o3ipt39m2h5jpaw",
+            "BU2J3O3IPT39M2H5JPA0",
+            "../../../../etc/pass",
+        ]
+        .iter()
+        {
+            let status = Violations::new().id("id", id).check().unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(violated_fields(&status), ["id"], "{:?}", id);
+        }
+        assert!(Violations::new()
+            .id("id", "bu2j3o3ipt39m2h5jpa0")
+            .check()
+            .is_ok());
+    }
+
+    #[test]
+    fn all_violations_at_once() {
+        let status = Violations::new()
+            .id("id", "1")
+            .text("title", "")
+            .max_length("body", &"x".repeat(MAX_LENGTH + 1))
+            .check()
+            .unwrap_err();
+        assert_eq!(violated_fields(&status), ["id", "title", "body"]);
+        assert!(status
+            .message()
+            .starts_with("id must be an id of 20 characters"));
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let allowed = ["title", "body"];
+        let names = vec!["body".to_string(), "id".to_string(), "title".to_string()];
+
+        let violations = Violations::new().fields("update_mask", &names, &allowed);
+        let fields: Vec<&str> = violations.0.iter().map(|v| v.field.as_str()).collect();
+        assert_eq!(fields, ["update_mask[1]"]);
+        assert!(Violations::new()
+            .fields("update_mask", &[], &allowed)
+            .check()
+            .is_ok());
+    }
+}